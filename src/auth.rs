@@ -0,0 +1,263 @@
+use crate::resp::{Cmd, QueueCmd, ScheduleCmd};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::path::Path;
+use std::{fs, io};
+
+/// Credentials accepted when no other provider is configured.
+pub(crate) const ADMIN: &str = "admin";
+pub(crate) const ADMIN_PW: &str = "password";
+
+pub const ENV_USER: &str = "INFINITY_Q_USER";
+pub const ENV_PASSWORD: &str = "INFINITY_Q_PASSWORD";
+
+/// Checks the credentials of `HELLO AUTH` and says what the user may do. Identity
+/// systems such as LDAP or OIDC token introspection plug in by implementing this and
+/// setting `ServerConfig::auth`; providers needing extra dependencies belong behind a
+/// cargo feature.
+pub trait AuthProvider: Debug + Send + Sync {
+    /// The user's permissions, or `None` if the credentials are wrong.
+    fn verify(&self, user: &str, password: &str) -> Option<Acl>;
+}
+
+/// What an authenticated connection may run.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Acl {
+    /// May run `SERVER`, `DEBUG` and `SHUTDOWN`.
+    #[serde(default)]
+    pub admin: bool,
+    /// Queues the user may create, push to, pop from and ack on. A trailing `*` matches
+    /// any suffix, so `["*"]` is every queue.
+    #[serde(default)]
+    pub queues: Vec<String>,
+}
+
+impl Acl {
+    /// Everything, for operators.
+    pub fn full() -> Acl {
+        Acl {
+            admin: true,
+            queues: vec!["*".to_string()],
+        }
+    }
+
+    pub fn allows(&self, cmd: &Cmd) -> bool {
+        match cmd {
+            Cmd::SHUTDOWN { .. }
+            | Cmd::SERVER(_)
+            | Cmd::DEBUG(_)
+            | Cmd::JOB(_)
+            | Cmd::SCHEDULE(ScheduleCmd::DEL { .. } | ScheduleCmd::LIST)
+            | Cmd::INFO { .. } => self.admin,
+            Cmd::SCHEDULE(ScheduleCmd::ADD { queue, .. }) => self.allows_queue(queue),
+            Cmd::PUSH { queue, .. }
+            | Cmd::MPUSH { queue, .. }
+            | Cmd::PEEK { queue, .. }
+            | Cmd::POP { queue, .. }
+            | Cmd::ACK { queue, .. }
+            | Cmd::NACK { queue, .. }
+            | Cmd::TOUCH { queue, .. }
+            | Cmd::PROGRESS { queue, .. }
+            | Cmd::CANCEL { queue, .. }
+            | Cmd::USE { queue } => self.allows_queue(queue),
+            Cmd::QUEUE(
+                QueueCmd::CREATE { name, .. }
+                | QueueCmd::DIGEST { name }
+                | QueueCmd::STATS { name }
+                | QueueCmd::INFLIGHT { name, .. }
+                | QueueCmd::GETCONFIG { name, .. }
+                | QueueCmd::DELETE { name, .. },
+            ) => self.allows_queue(name),
+            // Names queues the user may not otherwise touch.
+            Cmd::QUEUE(QueueCmd::LIST) => self.admin,
+            // Long running jobs, stopping delivery and changing settings are for operators.
+            Cmd::QUEUE(
+                QueueCmd::PAUSE { name }
+                | QueueCmd::RESUME { name }
+                | QueueCmd::SETCONFIG { name, .. }
+                | QueueCmd::PURGE { name }
+                | QueueCmd::EXPORT { name, .. }
+                | QueueCmd::REDRIVE { name, .. },
+            ) => self.admin && self.allows_queue(name),
+            Cmd::QUEUE(
+                QueueCmd::CLONE {
+                    source: name,
+                    destination: target,
+                }
+                | QueueCmd::REDRIVETO { name, target, .. },
+            ) => self.admin && self.allows_queue(name) && self.allows_queue(target),
+            Cmd::FANOUT { queues, .. } => queues.iter().all(|queue| self.allows_queue(queue)),
+            Cmd::LPOP { key, .. } | Cmd::LPUSH { key, .. } | Cmd::SADD { key, .. } => {
+                self.allows_queue(key)
+            }
+            Cmd::CHANNEL { cmd, .. } => self.allows(cmd),
+            Cmd::HELLO { .. } | Cmd::PREFETCH { .. } | Cmd::COMMAND(_) | Cmd::Unknown => true,
+        }
+    }
+
+    pub fn allows_queue(&self, queue: &str) -> bool {
+        self.queues
+            .iter()
+            .any(|pattern| match pattern.strip_suffix('*') {
+                Some(prefix) => queue.starts_with(prefix),
+                None => pattern == queue,
+            })
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct StaticUser {
+    user: String,
+    password: String,
+    #[serde(flatten)]
+    acl: Acl,
+}
+
+/// A fixed set of users. The default is the single built-in `admin` account.
+#[derive(Debug, Clone)]
+pub struct StaticAuth {
+    users: HashMap<String, StaticUser>,
+}
+
+impl StaticAuth {
+    /// One user allowed to do everything.
+    pub fn single(user: &str, password: &str) -> StaticAuth {
+        StaticAuth::from_users(vec![StaticUser {
+            user: user.to_string(),
+            password: password.to_string(),
+            acl: Acl::full(),
+        }])
+    }
+
+    /// Reads a JSON array of users, e.g.
+    /// `[{"user": "etl", "password": "...", "queues": ["jobs", "etl.*"]}]`.
+    /// `admin` and `queues` default to nothing.
+    pub fn from_file(path: &Path) -> io::Result<StaticAuth> {
+        let users: Vec<StaticUser> = serde_json::from_str(&fs::read_to_string(path)?)?;
+        Ok(StaticAuth::from_users(users))
+    }
+
+    fn from_users(users: Vec<StaticUser>) -> StaticAuth {
+        StaticAuth {
+            users: users
+                .into_iter()
+                .map(|user| (user.user.clone(), user))
+                .collect(),
+        }
+    }
+}
+
+impl Default for StaticAuth {
+    fn default() -> Self {
+        StaticAuth::single(ADMIN, ADMIN_PW)
+    }
+}
+
+impl AuthProvider for StaticAuth {
+    fn verify(&self, user: &str, password: &str) -> Option<Acl> {
+        self.users
+            .get(user)
+            .filter(|known| known.password == password)
+            .map(|known| known.acl.clone())
+    }
+}
+
+/// A single full-access user taken from environment variables, as injected by most
+/// secret managers. They are read on every HELLO, so a rotated secret applies to new
+/// connections once the variables are updated.
+#[derive(Debug, Clone)]
+pub struct EnvAuth {
+    user_var: String,
+    password_var: String,
+}
+
+impl EnvAuth {
+    pub fn new(user_var: &str, password_var: &str) -> EnvAuth {
+        EnvAuth {
+            user_var: user_var.to_string(),
+            password_var: password_var.to_string(),
+        }
+    }
+}
+
+impl Default for EnvAuth {
+    fn default() -> Self {
+        EnvAuth::new(ENV_USER, ENV_PASSWORD)
+    }
+}
+
+impl AuthProvider for EnvAuth {
+    fn verify(&self, user: &str, password: &str) -> Option<Acl> {
+        let expected_user = std::env::var(&self.user_var).ok()?;
+        let expected_password = std::env::var(&self.password_var).ok()?;
+        (user == expected_user && password == expected_password).then(Acl::full)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::auth::*;
+    use crate::resp::parse_cmd;
+
+    fn allows(acl: &Acl, args: &[&str]) -> bool {
+        let frame = crate::test_utils::frame(args);
+        acl.allows(&parse_cmd(&frame).unwrap())
+    }
+
+    #[test]
+    fn test_static_auth() {
+        let auth = StaticAuth::default();
+        assert_eq!(auth.verify(ADMIN, ADMIN_PW), Some(Acl::full()));
+        assert_eq!(auth.verify(ADMIN, "nope"), None);
+        assert_eq!(auth.verify("nobody", ADMIN_PW), None);
+    }
+
+    #[test]
+    fn test_users_file() {
+        let path =
+            std::env::temp_dir().join(format!("infinity_q_users_{}.json", uuid::Uuid::new_v4()));
+        fs::write(
+            &path,
+            r#"[
+                {"user": "ops", "password": "ops-pw", "admin": true, "queues": ["*"]},
+                {"user": "etl", "password": "etl-pw", "queues": ["jobs", "etl.*"]}
+            ]"#,
+        )
+        .unwrap();
+        let auth = StaticAuth::from_file(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(auth.verify("ops", "ops-pw"), Some(Acl::full()));
+        assert_eq!(auth.verify(ADMIN, ADMIN_PW), None);
+        let acl = auth.verify("etl", "etl-pw").unwrap();
+        assert!(!acl.admin);
+        assert!(allows(&acl, &["PUSH", "jobs", "x"]));
+        assert!(allows(&acl, &["POP", "etl.daily"]));
+        assert!(allows(&acl, &["CHANNEL", "1", "ACK", "etl.daily", "id"]));
+        assert!(!allows(&acl, &["POP", "jobs2"]));
+        assert!(!allows(&acl, &["QUEUE", "CREATE", "billing"]));
+        assert!(!allows(&acl, &["SERVER", "DRAIN"]));
+        assert!(!allows(&acl, &["SHUTDOWN"]));
+        assert!(!allows(&acl, &["QUEUE", "PURGE", "jobs"]));
+        assert!(!allows(&acl, &["JOB", "LIST"]));
+
+        let ops = Acl {
+            admin: true,
+            queues: vec!["etl.*".to_string()],
+        };
+        assert!(allows(&ops, &["QUEUE", "CLONE", "etl.a", "etl.b"]));
+        assert!(!allows(&ops, &["QUEUE", "CLONE", "etl.a", "jobs"]));
+    }
+
+    #[test]
+    fn test_env_auth() {
+        let auth = EnvAuth::new("INFINITY_Q_TEST_USER", "INFINITY_Q_TEST_PASSWORD");
+        assert_eq!(auth.verify("svc", "secret"), None);
+
+        std::env::set_var("INFINITY_Q_TEST_USER", "svc");
+        std::env::set_var("INFINITY_Q_TEST_PASSWORD", "secret");
+        assert_eq!(auth.verify("svc", "secret"), Some(Acl::full()));
+        assert_eq!(auth.verify("svc", "old"), None);
+    }
+}
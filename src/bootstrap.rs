@@ -0,0 +1,92 @@
+use crate::client::{Client, Reply};
+use crate::config::BootstrapSource;
+use crate::snapshot::SNAPSHOT_CHUNK_BYTES;
+use tracing::{info, warn};
+
+/// Transfers started over before bootstrap gives up, when the primary keeps dropping
+/// them for newer ones.
+const MAX_RESTARTS: usize = 3;
+
+/// A snapshot being fetched: the transfer id on the primary, its length and the bytes
+/// received so far.
+struct Transfer {
+    id: String,
+    len: usize,
+    data: Vec<u8>,
+}
+
+/// Fetches a snapshot of every queue from the primary at `source`, in chunks of
+/// `SNAPSHOT_CHUNK_BYTES` over an ordinary client connection; there is no replication
+/// stream to carry it. The client reconnects on its own when the connection drops, and
+/// the chunk that failed is asked for again, so the transfer carries on from the last
+/// byte received. It starts over only if the primary has dropped the transfer since.
+pub fn fetch_snapshot(source: &BootstrapSource) -> Result<Vec<u8>, String> {
+    let failed = |e| format!("bootstrap from {}: {}", source.address, e);
+    let mut client = Client::connect(source.address.as_str()).map_err(failed)?;
+    let reply = client
+        .call(&["HELLO", "3", "AUTH", &source.user, &source.password])
+        .map_err(failed)?;
+    if !matches!(reply, Reply::Map(_)) {
+        return Err(failed(format!("handshake failed: {:?}", reply)));
+    }
+    for _ in 0..=MAX_RESTARTS {
+        let mut transfer = begin(&mut client).map_err(failed)?;
+        if fetch_chunks(&mut client, &mut transfer).map_err(failed)? {
+            return Ok(transfer.data);
+        }
+        warn!(
+            primary = %source.address,
+            received = transfer.data.len(),
+            "snapshot transfer dropped by the primary, starting over"
+        );
+    }
+    Err(failed(format!(
+        "transfer dropped {} times",
+        MAX_RESTARTS + 1
+    )))
+}
+
+fn begin(client: &mut Client) -> Result<Transfer, String> {
+    let reply = client.call(&["SERVER", "SNAPSHOT"])?;
+    let Reply::Array(fields) = &reply else {
+        return Err(format!("expected a snapshot transfer, got {:?}", reply));
+    };
+    match fields.as_slice() {
+        [Reply::Bulk(id), Reply::Integer(len)] if *len >= 0 => {
+            let id = String::from_utf8_lossy(id).into_owned();
+            info!(transfer = %id, bytes = len, "snapshot transfer started");
+            Ok(Transfer {
+                id,
+                len: *len as usize,
+                data: Vec::new(),
+            })
+        }
+        _ => Err(format!("expected a snapshot transfer, got {:?}", reply)),
+    }
+}
+
+/// Reads the rest of `transfer`, logging every tenth of it. `false` means the primary
+/// no longer has it.
+fn fetch_chunks(client: &mut Client, transfer: &mut Transfer) -> Result<bool, String> {
+    let step = (transfer.len / 10).max(1);
+    let count = SNAPSHOT_CHUNK_BYTES.to_string();
+    while transfer.data.len() < transfer.len {
+        let offset = transfer.data.len().to_string();
+        match client.call(&["SERVER", "SNAPSHOTREAD", &transfer.id, &offset, &count])? {
+            Reply::Bulk(chunk) if !chunk.is_empty() => {
+                let before = transfer.data.len() / step;
+                transfer.data.extend_from_slice(&chunk);
+                if transfer.data.len() / step > before {
+                    info!(
+                        received = transfer.data.len(),
+                        total = transfer.len,
+                        "snapshot transfer progress"
+                    );
+                }
+            }
+            Reply::Error(_) => return Ok(false),
+            other => return Err(format!("unexpected snapshot chunk {:?}", other)),
+        }
+    }
+    Ok(true)
+}
@@ -0,0 +1,364 @@
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::thread;
+use std::time::Duration;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+/// How long a reply may take before the connection is given up on.
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, PartialEq)]
+pub(crate) enum Reply {
+    Simple(String),
+    Error(String),
+    Integer(i64),
+    /// Kept as bytes: message bodies needn't be UTF-8, and snapshot chunks may end in
+    /// the middle of a character.
+    Bulk(Vec<u8>),
+    Array(Vec<Reply>),
+    Map(Vec<(Reply, Reply)>),
+}
+
+/// When and where the client connects again after losing its connection.
+#[derive(Debug, Clone)]
+pub(crate) struct ReconnectPolicy {
+    /// Wait before the first attempt; it doubles with every attempt that fails.
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    /// Attempts, counting every endpoint tried, before a command fails.
+    pub max_attempts: usize,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        ReconnectPolicy {
+            initial_backoff: Duration::from_millis(50),
+            max_backoff: Duration::from_secs(2),
+            max_attempts: 10,
+        }
+    }
+}
+
+impl ReconnectPolicy {
+    /// Somewhere between half of `backoff` and all of it, so clients that lost the same
+    /// server don't all come back at the same moment.
+    fn jittered(backoff: Duration) -> Duration {
+        let half = backoff / 2;
+        let spread = half.as_micros().max(1);
+        let jitter = Uuid::new_v4().as_u128() % spread;
+        half + Duration::from_micros(jitter as u64)
+    }
+}
+
+struct Connection {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+}
+
+impl Connection {
+    fn open(endpoint: &str) -> Result<Connection, String> {
+        let writer = TcpStream::connect(endpoint).map_err(|e| e.to_string())?;
+        writer
+            .set_read_timeout(Some(READ_TIMEOUT))
+            .map_err(|e| e.to_string())?;
+        let reader = BufReader::new(writer.try_clone().map_err(|e| e.to_string())?);
+        Ok(Connection { reader, writer })
+    }
+
+    fn call(&mut self, frame: &[u8]) -> Result<Reply, String> {
+        self.writer.write_all(frame).map_err(|e| e.to_string())?;
+        self.read_reply()
+    }
+
+    fn read_line(&mut self) -> Result<String, String> {
+        let mut line = String::new();
+        self.reader
+            .read_line(&mut line)
+            .map_err(|e| e.to_string())?;
+        Ok(line.trim_end_matches("\r\n").to_string())
+    }
+
+    fn read_len(value: &str) -> Result<i64, String> {
+        value
+            .parse::<i64>()
+            .map_err(|_| format!("bad length {}", value))
+    }
+
+    fn read_reply(&mut self) -> Result<Reply, String> {
+        let line = self.read_line()?;
+        let (prefix, value) = line.split_at(line.len().min(1));
+        match prefix {
+            "+" => Ok(Reply::Simple(value.to_string())),
+            "-" => Ok(Reply::Error(value.to_string())),
+            ":" => Ok(Reply::Integer(Self::read_len(value)?)),
+            "$" => {
+                let len = Self::read_len(value)? as usize;
+                let mut data = vec![0u8; len + 2];
+                self.reader
+                    .read_exact(&mut data)
+                    .map_err(|e| e.to_string())?;
+                data.truncate(len);
+                Ok(Reply::Bulk(data))
+            }
+            "*" => {
+                let len = Self::read_len(value)?;
+                let mut items = Vec::new();
+                for _ in 0..len.max(0) {
+                    items.push(self.read_reply()?);
+                }
+                Ok(Reply::Array(items))
+            }
+            "%" => {
+                let len = Self::read_len(value)?;
+                let mut entries = Vec::new();
+                for _ in 0..len {
+                    entries.push((self.read_reply()?, self.read_reply()?));
+                }
+                Ok(Reply::Map(entries))
+            }
+            // Out-of-band pushes aren't replies to anything we sent.
+            ">" => {
+                let len = Self::read_len(value)?;
+                for _ in 0..len {
+                    self.read_reply()?;
+                }
+                self.read_reply()
+            }
+            // Attributes are metadata about the reply that follows; skip them.
+            "|" => {
+                let len = Self::read_len(value)?;
+                for _ in 0..len * 2 {
+                    self.read_reply()?;
+                }
+                self.read_reply()
+            }
+            // An empty line is the server closing the connection.
+            _ => Err(format!("unexpected reply {:?}", line)),
+        }
+    }
+}
+
+/// The bundled blocking RESP client. It is what `--self-test` drives the broker with
+/// and what a replica fetches its snapshot with; see `bootstrap`.
+///
+/// A lost connection is made again, to the next endpoint in the list when the one in
+/// use doesn't answer, with exponential backoff and jitter between attempts. The last
+/// successful HELLO is sent again first, so the new connection is authenticated and
+/// speaks the same protocol, and then the command that failed is retried. A retried
+/// PUSH may therefore be stored twice, which at-least-once consumers handle anyway.
+/// Leases outlive the connection they were taken on, so messages popped before the
+/// reconnect can still be acked on the same broker.
+pub(crate) struct Client {
+    endpoints: Vec<String>,
+    /// Index in `endpoints` of the one connected to, or tried next.
+    current: usize,
+    policy: ReconnectPolicy,
+    connection: Option<Connection>,
+    hello: Option<Vec<u8>>,
+    reconnects: usize,
+}
+
+impl Client {
+    pub fn connect(endpoint: impl Into<String>) -> Result<Client, String> {
+        Self::with_failover(vec![endpoint.into()], ReconnectPolicy::default())
+    }
+
+    /// Connects to the first of `endpoints` that answers, trying them in turn.
+    pub fn with_failover(
+        endpoints: Vec<String>,
+        policy: ReconnectPolicy,
+    ) -> Result<Client, String> {
+        if endpoints.is_empty() {
+            return Err("no endpoints to connect to".to_string());
+        }
+        let mut client = Client {
+            endpoints,
+            current: 0,
+            policy,
+            connection: None,
+            hello: None,
+            reconnects: 0,
+        };
+        client.reconnect()?;
+        Ok(client)
+    }
+
+    /// The endpoint connected to.
+    pub fn endpoint(&self) -> &str {
+        &self.endpoints[self.current]
+    }
+
+    /// Times the connection was lost and made again.
+    pub fn reconnects(&self) -> usize {
+        self.reconnects
+    }
+
+    /// Sends `args` as one command. They are sent as bulk strings, so message bodies
+    /// can be any bytes.
+    pub fn call<A: AsRef<[u8]>>(&mut self, args: &[A]) -> Result<Reply, String> {
+        let mut frame = format!("*{}\r\n", args.len()).into_bytes();
+        for arg in args {
+            let arg = arg.as_ref();
+            frame.extend_from_slice(format!("${}\r\n", arg.len()).as_bytes());
+            frame.extend_from_slice(arg);
+            frame.extend_from_slice(b"\r\n");
+        }
+        let mut retries = 0;
+        loop {
+            let result = match self.connection.as_mut() {
+                Some(connection) => connection.call(&frame),
+                None => Err("not connected".to_string()),
+            };
+            match result {
+                Ok(reply) => {
+                    let is_hello = args
+                        .first()
+                        .is_some_and(|cmd| cmd.as_ref().eq_ignore_ascii_case(b"HELLO"));
+                    if is_hello && matches!(reply, Reply::Map(_)) {
+                        self.hello = Some(frame);
+                    }
+                    return Ok(reply);
+                }
+                Err(e) if retries < self.policy.max_attempts => {
+                    warn!(endpoint = self.endpoint(), error = %e, "connection lost, reconnecting");
+                    self.reconnect()?;
+                    self.reconnects += 1;
+                    retries += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Tries the endpoints in turn, starting with the one just lost, until one answers
+    /// and takes the replayed HELLO.
+    fn reconnect(&mut self) -> Result<(), String> {
+        self.connection = None;
+        let mut backoff = self.policy.initial_backoff;
+        let mut last_error = String::new();
+        for attempt in 0..self.policy.max_attempts {
+            if attempt > 0 {
+                thread::sleep(ReconnectPolicy::jittered(backoff));
+                backoff = (backoff * 2).min(self.policy.max_backoff);
+            }
+            match self.open() {
+                Ok(connection) => {
+                    info!(endpoint = self.endpoint(), attempt, "connected");
+                    self.connection = Some(connection);
+                    return Ok(());
+                }
+                Err(e) => {
+                    last_error = e;
+                    self.current = (self.current + 1) % self.endpoints.len();
+                }
+            }
+        }
+        Err(format!(
+            "gave up after {} attempts: {}",
+            self.policy.max_attempts, last_error
+        ))
+    }
+
+    fn open(&self) -> Result<Connection, String> {
+        let mut connection = Connection::open(self.endpoint())?;
+        if let Some(hello) = &self.hello {
+            match connection.call(hello)? {
+                Reply::Map(_) => {}
+                other => return Err(format!("HELLO rejected: {:?}", other)),
+            }
+        }
+        Ok(connection)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::client::{Client, ReconnectPolicy, Reply};
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+    use std::time::Duration;
+
+    fn read_frame(stream: &mut TcpStream) -> String {
+        let mut buffer = [0u8; 1024];
+        let len = stream.read(&mut buffer).unwrap();
+        String::from_utf8_lossy(&buffer[..len]).into_owned()
+    }
+
+    fn quick_policy() -> ReconnectPolicy {
+        ReconnectPolicy {
+            initial_backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(5),
+            max_attempts: 4,
+        }
+    }
+
+    #[test]
+    fn test_reconnect_replays_hello() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = listener.local_addr().unwrap().to_string();
+        let server = thread::spawn(move || {
+            let mut received = Vec::new();
+            // The first connection takes HELLO, then goes away without answering PUSH.
+            let (mut first, _) = listener.accept().unwrap();
+            received.push(read_frame(&mut first));
+            first.write_all(b"%0\r\n").unwrap();
+            received.push(read_frame(&mut first));
+            drop(first);
+            let (mut second, _) = listener.accept().unwrap();
+            received.push(read_frame(&mut second));
+            second.write_all(b"%0\r\n").unwrap();
+            received.push(read_frame(&mut second));
+            second.write_all(b"$2\r\nid\r\n").unwrap();
+            received
+        });
+
+        let mut client = Client::with_failover(vec![endpoint], quick_policy()).unwrap();
+        assert_eq!(client.call(&["HELLO", "3"]).unwrap(), Reply::Map(vec![]));
+        let reply = client.call(&["PUSH", "jobs", "a"]).unwrap();
+        assert_eq!(reply, Reply::Bulk(b"id".to_vec()));
+        assert_eq!(client.reconnects(), 1);
+
+        let hello = "*2\r\n$5\r\nHELLO\r\n$1\r\n3\r\n";
+        let push = "*3\r\n$4\r\nPUSH\r\n$4\r\njobs\r\n$1\r\na\r\n";
+        assert_eq!(server.join().unwrap(), [hello, push, hello, push]);
+    }
+
+    #[test]
+    fn test_failover_to_next_endpoint() {
+        let down = TcpListener::bind("127.0.0.1:0").unwrap();
+        let down_endpoint = down.local_addr().unwrap().to_string();
+        drop(down);
+        let up = TcpListener::bind("127.0.0.1:0").unwrap();
+        let up_endpoint = up.local_addr().unwrap().to_string();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = up.accept().unwrap();
+            read_frame(&mut stream);
+            stream.write_all(b"+PONG\r\n").unwrap();
+        });
+
+        let endpoints = vec![down_endpoint, up_endpoint.clone()];
+        let mut client = Client::with_failover(endpoints, quick_policy()).unwrap();
+        assert_eq!(client.endpoint(), up_endpoint);
+        assert_eq!(
+            client.call(&["PING"]).unwrap(),
+            Reply::Simple("PONG".to_string())
+        );
+        server.join().unwrap();
+
+        let nowhere = TcpListener::bind("127.0.0.1:0").unwrap();
+        let nowhere_endpoint = nowhere.local_addr().unwrap().to_string();
+        drop(nowhere);
+        assert!(Client::with_failover(vec![nowhere_endpoint], quick_policy()).is_err());
+    }
+
+    #[test]
+    fn test_backoff_jitter() {
+        let backoff = Duration::from_millis(100);
+        for _ in 0..100 {
+            let wait = ReconnectPolicy::jittered(backoff);
+            assert!(wait >= backoff / 2 && wait <= backoff, "{:?}", wait);
+        }
+    }
+}
@@ -0,0 +1,2699 @@
+use crate::config::ChecksumMode;
+use crate::constants::DEFAULT_PROTOCOL;
+use crate::deadline::Deadline;
+use crate::dedup::DedupKey;
+use crate::error_code::ErrorCode;
+use crate::events::ServerEvent;
+use crate::jobs::{Job, JOB_BATCH};
+use crate::metrics;
+use crate::profiler;
+use crate::queue::{
+    body_checksum, now_ms, receipt_id, ConsumerId, FullPolicy, Message, Progress, Queue, QueueOrder,
+};
+use crate::registry::shard_name;
+use crate::resp::{
+    soft_limit_push, Cmd, CommandCmd, CommandSet, DebugCmd, EmptyPop, JobCmd, QueueCmd, QueueParam,
+    QueueSetting, RespError, ScheduleCmd, ServerCmd,
+};
+use crate::resp_value::RespValue;
+use crate::rules::RuleContext;
+use crate::server::{ServerState, Shutdown};
+use crate::trace_sampling::TraceScope;
+use bytes::Bytes;
+use chrono::Utc;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+use strum::IntoEnumIterator;
+use tracing::{debug, error, info, warn};
+
+/// Handshake map returned by HELLO for a connection that now speaks `protocol`.
+/// There is no clustering or replication, so every server is a standalone master.
+pub fn hello_reply(state: &ServerState, protocol: u8) -> RespValue {
+    RespValue::map()
+        .field("server", "infinity_q")
+        .field("version", RespValue::bulk(env!("CARGO_PKG_VERSION")))
+        .field("proto", protocol as i64)
+        .field("id", RespValue::bulk(&state.run_id))
+        .field("mode", RespValue::bulk("standalone"))
+        .field("role", RespValue::bulk("master"))
+        .field("modules", RespValue::array())
+        .build()
+}
+
+/// Messages a POP leases between checks of its deadline.
+const LEASE_BATCH: usize = 256;
+
+fn unknown_queue(queue: &str) -> RespValue {
+    RespError::UnknownQueue(queue.to_string()).into()
+}
+
+pub fn new_queue(name: &str, state: &ServerState) -> Queue {
+    let mut q =
+        Queue::create_with_expiration(name.to_string(), state.config.in_flight_expiration_ms);
+    q.set_ack_cache_size(state.config.ack_cache_size);
+    q.set_max_in_flight_bytes(state.config.max_in_flight_bytes);
+    q.set_max_in_flight(state.config.max_in_flight);
+    q.set_dead_letter_expired(state.config.dead_letter_expired);
+    q.set_dedup_window(state.config.dedup_window.as_millis() as i64);
+    q
+}
+
+fn unknown_job(id: u64) -> RespValue {
+    RespError::UnknownJob(id).into()
+}
+
+/// Runs `work` as a background job and replies with its id.
+fn start_job<F>(state: &ServerState, kind: &'static str, target: &str, work: F) -> RespValue
+where
+    F: FnOnce(&Job) -> Result<(), String> + Send + 'static,
+{
+    match state.jobs.spawn(kind, target, work) {
+        Ok(id) => id.into(),
+        Err(e) => RespValue::error(
+            ErrorCode::ERR,
+            &format!("couldn't start {} job: {}", kind, e),
+        ),
+    }
+}
+
+/// Writes `msgs` to `path` as JSON lines, next to it first and renamed into place once
+/// complete, the way snapshots are written. Nothing is left behind when cancelled.
+fn export(job: &Job, msgs: &[Message], path: &PathBuf) -> Result<(), String> {
+    let tmp_path = path.with_extension("tmp");
+    let write = || -> std::io::Result<bool> {
+        let mut writer = BufWriter::new(File::create(&tmp_path)?);
+        for batch in msgs.chunks(JOB_BATCH) {
+            if job.cancelled() {
+                return Ok(false);
+            }
+            for msg in batch {
+                serde_json::to_writer(&mut writer, msg)?;
+                writer.write_all(b"\n")?;
+            }
+            job.advance(batch.len());
+        }
+        writer.flush()?;
+        Ok(true)
+    };
+    match write() {
+        Ok(true) => fs::rename(&tmp_path, path).map_err(|e| e.to_string()),
+        Ok(false) => {
+            let _ = fs::remove_file(&tmp_path);
+            Ok(())
+        }
+        Err(e) => {
+            let _ = fs::remove_file(&tmp_path);
+            Err(e.to_string())
+        }
+    }
+}
+
+/// Finds `name`, creating it first when the server runs with `auto_create_queues`.
+fn lookup_queue<'a>(
+    queues: &'a mut HashMap<String, Queue>,
+    name: &str,
+    state: &ServerState,
+) -> Option<&'a mut Queue> {
+    if state.config.auto_create_queues && !queues.contains_key(name) {
+        queues.insert(name.to_string(), new_queue(name, state));
+        info!(queue = name, "queue auto-created");
+    }
+    queues.get_mut(name)
+}
+
+/// How many more pushes `q` can take, in its backlog and then its overflow buffer.
+fn room(q: &Queue, state: &ServerState) -> usize {
+    room_below(q, state.capacity(q))
+}
+
+/// Like `room`, with the backlog holding at most `capacity` messages; for jobs, which
+/// run without the `ServerState`, and for the sweep.
+pub fn room_below(q: &Queue, capacity: Option<usize>) -> usize {
+    let backlog = capacity.map_or(usize::MAX, |capacity| capacity.saturating_sub(q.depth()));
+    backlog.saturating_add(q.overflow_limit().saturating_sub(q.overflow_depth()))
+}
+
+fn has_room(q: &Queue, state: &ServerState) -> bool {
+    room(q, state) > 0
+}
+
+/// The id of the message `receipt` settles, unless the message has been leased out
+/// again since, so a consumer that lost its lease can't settle someone else's delivery.
+fn settled_id(q: &Queue, queue: &str, receipt: &str) -> Option<String> {
+    if q.stale_receipt(receipt) {
+        debug!(queue = %queue, receipt = %receipt, "stale receipt ignored");
+        return None;
+    }
+    Some(receipt_id(receipt).to_string())
+}
+
+/// Fails unless `q` takes bodies as big as `body`, see `QUEUE CREATE ... MAXSIZE`.
+fn check_size(q: &Queue, queue: &str, body: &[u8]) -> Result<(), RespError> {
+    match q.max_message_bytes() {
+        Some(max) if body.len() > max => {
+            warn!(queue = %queue, size = body.len(), max, "body too large, rejecting push");
+            Err(RespError::MessageTooLarge(queue.to_string(), max))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Fails when a PUSH or MPUSH names a GROUP but `q` doesn't deliver in order.
+fn check_group(q: &Queue, queue: &str, group: &Option<String>) -> Result<(), RespError> {
+    if group.is_some() && q.order() != QueueOrder::Fifo {
+        return Err(RespError::InvalidArgument(format!(
+            "GROUP, '{}' isn't FIFO",
+            queue
+        )));
+    }
+    Ok(())
+}
+
+/// Makes room in a full `q` if its policy allows, see `FullPolicy::DropOldest`.
+fn make_room(q: &mut Queue, queue: &str) -> bool {
+    if q.full_policy() != FullPolicy::DropOldest {
+        return false;
+    }
+    match q.drop_oldest() {
+        Some(msg) => {
+            debug!(queue = %queue, id = msg.id(), "queue full, dropped its oldest message");
+            true
+        }
+        None => false,
+    }
+}
+
+fn new_message(
+    queue: &str,
+    body: Bytes,
+    priority: u8,
+    ttl: Option<Duration>,
+    state: &ServerState,
+) -> Message {
+    let mut msg = Message::new(queue.to_string(), body).with_priority(priority);
+    if let Some(ttl) = ttl {
+        msg = msg.with_ttl(ttl.as_millis() as i64);
+    }
+    if state.config.checksums != ChecksumMode::Off {
+        msg = msg.with_checksum();
+    }
+    if state
+        .config
+        .compress_bodies_over
+        .is_some_and(|size| msg.body().len() > size)
+    {
+        msg = msg.with_compression();
+    }
+    msg
+}
+
+/// `msg` with the GROUP and ATTR options of a PUSH or MPUSH.
+fn with_options(
+    mut msg: Message,
+    group: Option<String>,
+    attributes: BTreeMap<String, String>,
+) -> Message {
+    if let Some(group) = group {
+        msg = msg.with_group(group);
+    }
+    if !attributes.is_empty() {
+        msg = msg.with_attributes(attributes);
+    }
+    msg
+}
+
+/// Adds `msg` to `q`, which `has_room`: to the backlog, or to the overflow buffer while
+/// the backlog is full. Producers past the soft limit are warned through `pushes`.
+/// False, with `msg` dropped, when the overflow buffer turned out to be full too, as
+/// happens once a lowered MAXDEPTH leaves the backlog over it.
+fn enqueue(
+    q: &mut Queue,
+    queue: &str,
+    msg: Message,
+    state: &ServerState,
+    pushes: &mut Vec<RespValue>,
+) -> bool {
+    let depth = q.depth();
+    let capacity = state.capacity(q);
+    if let Some(capacity) = capacity {
+        let soft_limit = state.config.soft_limit(capacity);
+        if depth + 1 >= soft_limit {
+            if depth < soft_limit {
+                warn!(queue = %queue, soft_limit, capacity, "queue passed its soft limit");
+            }
+            pushes.push(soft_limit_push(queue, depth + 1, capacity));
+        }
+    }
+    if capacity.is_some_and(|capacity| depth >= capacity) {
+        let id = msg.id().clone();
+        let absorbed = q.absorb(msg);
+        if absorbed {
+            debug!(queue = %queue, id = %id, "queue full, push absorbed by its overflow buffer");
+        } else {
+            warn!(queue = %queue, id = %id, "queue and its overflow buffer full, push dropped");
+        }
+        return absorbed;
+    }
+    debug!(queue = %queue, id = msg.id(), "message pushed");
+    q.add(msg);
+    state.pushed.notify_waiters();
+    if depth == 0 {
+        state.announce(ServerEvent::MessagesAvailable(queue.to_string()));
+    }
+    true
+}
+
+/// `enqueue` for messages that all fit in the backlog, added with one `Queue::add_batch`
+/// and announced once.
+fn enqueue_batch(
+    q: &mut Queue,
+    queue: &str,
+    msgs: Vec<Message>,
+    state: &ServerState,
+    pushes: &mut Vec<RespValue>,
+) {
+    let depth = q.depth();
+    if let Some(capacity) = state.capacity(q) {
+        let soft_limit = state.config.soft_limit(capacity);
+        let after = depth + msgs.len();
+        if after >= soft_limit {
+            if depth < soft_limit {
+                warn!(queue = %queue, soft_limit, capacity, "queue passed its soft limit");
+            }
+            pushes.push(soft_limit_push(queue, after, capacity));
+        }
+    }
+    debug!(queue = %queue, count = msgs.len(), "messages pushed");
+    q.add_batch(msgs);
+    state.pushed.notify_waiters();
+    if depth == 0 {
+        state.announce(ServerEvent::MessagesAvailable(queue.to_string()));
+    }
+}
+
+/// Runs a parsed command against the shared state and returns the RESP3 encoded reply,
+/// preceded by any push frames the command raised.
+pub fn execute(cmd: Cmd, client_id: ConsumerId, state: &ServerState) -> Vec<u8> {
+    execute_for(cmd, client_id, state, DEFAULT_PROTOCOL)
+}
+
+/// Like `execute`, encoded for the protocol the client negotiated. RESP2 has no push
+/// frames, so those are dropped.
+pub fn execute_for(cmd: Cmd, client_id: ConsumerId, state: &ServerState, protocol: u8) -> Vec<u8> {
+    let mut pushes = Vec::new();
+    let name = cmd.name();
+    let deadline = Deadline::after(state.config.command_timeout);
+    let traced = state
+        .trace_sampling
+        .sample(name, cmd.queue())
+        .then(|| (cmd.queue().map(str::to_string), format!("{:?}", cmd)));
+    let started = Instant::now();
+    let cmd = state.queues.route(cmd);
+    let reply = run(cmd, client_id, state, protocol, &deadline, &mut pushes);
+    let failed = matches!(reply, RespValue::Error(_));
+    state.command_stats.record(name, started.elapsed(), failed);
+    if let Some((queue, cmd)) = traced {
+        info!(
+            command = name,
+            queue,
+            cmd,
+            elapsed = ?started.elapsed(),
+            reply = ?reply,
+            "sampled command trace"
+        );
+    }
+    if deadline.expired() {
+        warn!(command = name, budget = ?state.config.command_timeout, "command ran past its time budget");
+    }
+    if protocol < 3 {
+        return reply.encode_for(protocol);
+    }
+    let mut encoded = Vec::new();
+    for push in pushes.iter().chain(std::iter::once(&reply)) {
+        encoded.extend(push.encode());
+    }
+    encoded
+}
+
+/// What `POP ... NULL` finding nothing, and a `POP ... BLOCK` timing out, reply: null,
+/// sent to RESP2 clients as a null bulk string for one message and a null array for
+/// several, as Redis' LPOP does.
+pub fn null_pop(count: usize, protocol: u8) -> RespValue {
+    if count > 1 {
+        RespValue::null_array(protocol)
+    } else {
+        RespValue::Null
+    }
+}
+
+/// Whether a PUSH to `queue` has to wait for room, see `FullPolicy::Block`.
+pub fn push_blocks(cmd: &Cmd, state: &ServerState) -> bool {
+    let (queue, count) = match cmd {
+        Cmd::PUSH { queue, .. } => (queue, 1),
+        Cmd::MPUSH { queue, bodies, .. } => (queue, bodies.len()),
+        _ => return false,
+    };
+    let queues = state.queues.read().unwrap();
+    queues.get(queue).is_some_and(|q| {
+        // A batch bigger than the queue could ever hold is rejected rather than left
+        // waiting forever.
+        let most = state.capacity(q).map_or(usize::MAX, |capacity| {
+            capacity.saturating_add(q.overflow_limit())
+        });
+        q.full_policy() == FullPolicy::Block && room(q, state) < count && count <= most
+    })
+}
+
+/// One attempt at a `POP ... BLOCK`: `None` while there is nothing to hand out yet.
+pub fn try_pop(
+    queue: &str,
+    count: usize,
+    client_id: ConsumerId,
+    visibility: Option<Duration>,
+    state: &ServerState,
+    protocol: u8,
+) -> Option<Vec<u8>> {
+    let deadline = Deadline::after(state.config.command_timeout);
+    match lease(queue, count, client_id, visibility, state, &deadline) {
+        Ok(msgs) if msgs.is_empty() => None,
+        Ok(msgs) => Some(RespValue::array().items(msgs).build().encode_for(protocol)),
+        Err(err) => Some(err.encode_for(protocol)),
+    }
+}
+
+/// Leases up to `count` messages as `[id, body]` pairs, for `visibility` if given and the
+/// queue's lease time otherwise. Nothing is handed out while draining or while the queue
+/// is paused, and an exclusive queue claimed by another consumer fails the POP. Once
+/// `deadline` passes, the messages leased so far are returned.
+fn lease(
+    queue: &str,
+    count: usize,
+    client_id: ConsumerId,
+    visibility: Option<Duration>,
+    state: &ServerState,
+    deadline: &Deadline,
+) -> Result<Vec<RespValue>, RespValue> {
+    let mut queues = state.queues.lock().unwrap();
+    let Some(q) = lookup_queue(&mut queues, queue, state) else {
+        return Err(unknown_queue(queue));
+    };
+    if !q.claim(client_id) {
+        return Err(RespError::ExclusiveQueue(queue.to_string()).into());
+    }
+    if state.draining.load(Ordering::Relaxed) || q.paused() {
+        return Ok(vec![]);
+    }
+    let mut msgs = Vec::new();
+    while msgs.len() < count {
+        let batch = LEASE_BATCH.min(count - msgs.len());
+        let leased = match visibility {
+            Some(visibility) => {
+                q.pop_for_with_visibility(client_id, batch, visibility.as_millis() as i64)
+            }
+            None => q.pop_for(client_id, batch),
+        };
+        let exhausted = leased.len() < batch;
+        msgs.extend(leased);
+        if exhausted {
+            break;
+        }
+        if msgs.len() < count && deadline.expired() {
+            warn!(queue = %queue, requested = count, leased = msgs.len(), "POP cut short by its time budget");
+            break;
+        }
+    }
+    q.drain_overflow(state.capacity(q));
+    if !msgs.is_empty() && q.full_policy() == FullPolicy::Block {
+        // Room for the PUSHes waiting on the queue.
+        state.pushed.notify_waiters();
+    }
+    debug!(queue = %queue, requested = count, leased = msgs.len(), "messages popped");
+    let chunk_size = state.config.stream_chunk_size;
+    Ok(msgs.iter().map(|msg| delivery(msg, chunk_size)).collect())
+}
+
+/// `[id, body]`, plus the body's CRC32 when one was stored at push. Bodies longer than
+/// `chunk_size` are streamed, and so is the array around them, closed by `.`. RESP3
+/// clients also get the delivery attempt, push time, the receipt to ACK with and the
+/// message group, if any, as attributes; RESP2 encoding drops them.
+fn delivery(msg: &Message, chunk_size: Option<usize>) -> RespValue {
+    let streamed = chunk_size.is_some_and(|size| msg.body().len() > size);
+    let body = match chunk_size {
+        Some(size) if streamed => RespValue::chunked(msg.body(), size),
+        _ => RespValue::bulk(msg.body()),
+    };
+    let mut fields = RespValue::array()
+        .item(RespValue::bulk(msg.id()))
+        .item(body);
+    if let Some(checksum) = msg.checksum() {
+        if !msg.body_intact() {
+            error!(id = msg.id(), "message body no longer matches its checksum");
+        }
+        fields = fields.item(checksum);
+    }
+    let mut attributes = RespValue::map()
+        .field("attempt", i64::from(msg.attempt()))
+        .field("enqueued-at", msg.enqueued_at())
+        .field("receipt", RespValue::bulk(msg.receipt()));
+    if let Some(group) = msg.group() {
+        attributes = attributes.field("group", RespValue::bulk(group));
+    }
+    if !msg.attributes().is_empty() {
+        let set = msg
+            .attributes()
+            .iter()
+            .map(|(key, value)| (key, RespValue::bulk(value)));
+        attributes = attributes.field("attributes", RespValue::map().fields(set));
+    }
+    let fields = fields.build();
+    if streamed {
+        return attributes.annotate(RespValue::Streamed(Box::new(fields)));
+    }
+    attributes.annotate(fields)
+}
+
+fn run(
+    cmd: Cmd,
+    client_id: ConsumerId,
+    state: &ServerState,
+    protocol: u8,
+    deadline: &Deadline,
+    pushes: &mut Vec<RespValue>,
+) -> RespValue {
+    match cmd {
+        Cmd::HELLO {
+            protocol_version, ..
+        } => hello_reply(state, protocol_version),
+        // The default queue is kept by the connection; see `Session::default_queue`.
+        Cmd::USE { .. } => RespValue::ok(),
+        // Enforced by the connection too; see `Session::within_prefetch`.
+        Cmd::PREFETCH { .. } => RespValue::ok(),
+        Cmd::QUEUE(QueueCmd::CREATE {
+            name,
+            order,
+            overflow,
+            concurrency,
+            max_depth,
+            on_full,
+            dedup,
+            shards,
+            max_size,
+            rate,
+            ephemeral,
+            exclusive,
+        }) => {
+            let mut queues = state.queues.lock().unwrap();
+            if queues.contains_key(&name) || state.queues.shard_count(&name).is_some() {
+                return RespError::QueueExists(name).into();
+            }
+            // Shards already in the registry, say restored from a snapshot, keep their
+            // messages and take the new settings.
+            let names = match shards {
+                Some(count) => (0..count).map(|i| shard_name(&name, i)).collect(),
+                None => vec![name.clone()],
+            };
+            for queue in names {
+                let q = queues
+                    .entry(queue.clone())
+                    .or_insert_with(|| new_queue(&queue, state));
+                q.set_order(order);
+                q.set_overflow_limit(overflow.unwrap_or(0));
+                if concurrency.is_some() {
+                    q.set_max_in_flight(concurrency);
+                }
+                q.set_max_depth(max_depth);
+                q.set_full_policy(on_full);
+                q.set_max_message_bytes(max_size);
+                q.set_rate_limit(rate);
+                if ephemeral {
+                    q.set_owner(Some(client_id));
+                }
+                q.set_exclusive(exclusive);
+                if let Some(window) = dedup {
+                    q.set_dedup_window(window.as_millis() as i64);
+                    q.set_dedup_by_content(true);
+                }
+            }
+            if let Some(count) = shards {
+                let router = state.config.routing_for(&name).router();
+                state.queues.add_shards(&name, count, router);
+            }
+            info!(queue = %name, ?order, ?overflow, ?concurrency, ?max_depth, ?on_full, ?dedup, ?max_size, ?rate, ?shards, ephemeral, exclusive, "queue created");
+            RespValue::ok()
+        }
+        Cmd::QUEUE(QueueCmd::DELETE { name, force }) => {
+            let mut queues = state.queues.lock().unwrap();
+            if let Some(count) = state.queues.shard_count(&name) {
+                let names: Vec<String> = (0..count).map(|i| shard_name(&name, i)).collect();
+                let leased = names
+                    .iter()
+                    .filter_map(|shard| queues.get(shard))
+                    .map(Queue::in_flight_count)
+                    .sum();
+                if leased > 0 && !force {
+                    return RespError::QueueLeased(name, leased).into();
+                }
+                names.iter().for_each(|shard| {
+                    queues.remove(shard);
+                });
+                state.queues.remove_shards(&name);
+                info!(queue = %name, shards = count, leased, "sharded queue deleted");
+                state.pushed.notify_waiters();
+                return RespValue::ok();
+            }
+            let Some(q) = queues.get(&name) else {
+                return unknown_queue(&name);
+            };
+            let leased = q.in_flight_count();
+            if leased > 0 && !force {
+                return RespError::QueueLeased(name, leased).into();
+            }
+            info!(queue = %name, pending = q.depth(), leased, "queue deleted");
+            queues.remove(&name);
+            // Blocked POPs on the queue wake up to `NOQUEUE` rather than waiting it out.
+            state.pushed.notify_waiters();
+            RespValue::ok()
+        }
+        Cmd::QUEUE(QueueCmd::LIST) => {
+            let mut names: Vec<String> = state.queues.read().unwrap().keys().cloned().collect();
+            names.sort();
+            RespValue::array()
+                .items(names.into_iter().map(RespValue::bulk))
+                .build()
+        }
+        Cmd::QUEUE(QueueCmd::STATS { name }) => {
+            // Expired leases count as in flight until the next sweep requeues them.
+            let mut queues = state.queues.lock().unwrap();
+            let Some(q) = queues.get_mut(&name) else {
+                return unknown_queue(&name);
+            };
+            let delayed = q.delayed_count();
+            let oldest_age_ms = q
+                .oldest_enqueued_at()
+                .map_or(0, |enqueued_at| (now_ms() - enqueued_at).max(0));
+            RespValue::map()
+                .field("depth", q.depth() as i64)
+                .field("in_flight", (q.in_flight_count() - delayed) as i64)
+                .field("in_flight_bytes", q.in_flight_bytes() as i64)
+                .field(
+                    "max_in_flight_bytes",
+                    q.max_in_flight_bytes()
+                        .map_or(RespValue::Null, RespValue::from),
+                )
+                .field("delayed", delayed as i64)
+                .field("overflow", q.overflow_depth() as i64)
+                .field("dead_letters", q.dead_letter_count() as i64)
+                .field("oldest_age_ms", oldest_age_ms)
+                .field("enqueue_rate", RespValue::Double(q.enqueue_rate()))
+                .field("dequeue_rate", RespValue::Double(q.dequeue_rate()))
+                .field("redeliveries", q.redelivered() as i64)
+                .field("ack_cache_hits", q.ack_cache_hits() as i64)
+                .field("paused", q.paused())
+                .build()
+        }
+        Cmd::QUEUE(QueueCmd::INFLIGHT { name, count }) => {
+            let queues = state.queues.lock().unwrap();
+            let Some(q) = queues.get(&name) else {
+                return unknown_queue(&name);
+            };
+            let leases = q.show_in_flight(count.unwrap_or(usize::MAX));
+            RespValue::array()
+                .items(leases.into_iter().map(|lease| {
+                    let progress = match lease.progress() {
+                        Some(Progress::Percent(percent)) => RespValue::Integer(*percent as i64),
+                        Some(Progress::Status(status)) => RespValue::bulk(status),
+                        None => RespValue::Null,
+                    };
+                    RespValue::map()
+                        .field("id", RespValue::bulk(lease.message().id()))
+                        .field(
+                            "consumer",
+                            lease.consumer().map_or(RespValue::Null, RespValue::from),
+                        )
+                        .field("leased_at", lease.leased_at())
+                        .field("expires_at", lease.expires_at())
+                        .field("attempt", lease.message().attempt() as i64)
+                        .field("progress", progress)
+                        .field(
+                            "progress_updated_at",
+                            lease
+                                .progress_updated_at()
+                                .map_or(RespValue::Null, RespValue::from),
+                        )
+                        .build()
+                }))
+                .build()
+        }
+        Cmd::QUEUE(QueueCmd::PAUSE { name }) => {
+            let mut queues = state.queues.lock().unwrap();
+            let Some(q) = queues.get_mut(&name) else {
+                return unknown_queue(&name);
+            };
+            q.set_paused(true);
+            info!(queue = %name, "queue paused, POP will hand out no messages");
+            RespValue::ok()
+        }
+        Cmd::QUEUE(QueueCmd::RESUME { name }) => {
+            let mut queues = state.queues.lock().unwrap();
+            let Some(q) = queues.get_mut(&name) else {
+                return unknown_queue(&name);
+            };
+            q.set_paused(false);
+            info!(queue = %name, "queue resumed");
+            // Blocked POPs get what was pushed while paused.
+            state.pushed.notify_waiters();
+            RespValue::ok()
+        }
+        Cmd::QUEUE(QueueCmd::GETCONFIG { name, param }) => {
+            let queues = state.queues.lock().unwrap();
+            let Some(q) = queues.get(&name) else {
+                return unknown_queue(&name);
+            };
+            let limit = |limit: Option<usize>| limit.map_or(RespValue::Null, RespValue::from);
+            match param {
+                QueueParam::VISIBILITY => q.in_flight_expiration_ms().into(),
+                QueueParam::MAXATTEMPTS => (q.max_attempts() as i64).into(),
+                QueueParam::CONCURRENCY => limit(q.max_in_flight()),
+                QueueParam::MAXDEPTH => limit(q.max_depth()),
+                QueueParam::ONFULL => RespValue::simple(match q.full_policy() {
+                    FullPolicy::Reject => "REJECT",
+                    FullPolicy::Block => "BLOCK",
+                    FullPolicy::DropOldest => "DROPOLDEST",
+                }),
+                QueueParam::MAXSIZE => limit(q.max_message_bytes()),
+                QueueParam::RATE => limit(q.rate_limit()),
+                QueueParam::DEADLETTER => q
+                    .dead_letter_queue()
+                    .map_or(RespValue::Null, RespValue::bulk),
+                QueueParam::RETENTION => q.retention_ms().map_or(RespValue::Null, RespValue::from),
+            }
+        }
+        Cmd::QUEUE(QueueCmd::SETCONFIG { name, setting }) => {
+            let mut queues = state.queues.lock().unwrap();
+            if let QueueSetting::DeadLetter(Some(target)) = &setting {
+                if !queues.contains_key(target) {
+                    return unknown_queue(target);
+                }
+            }
+            let Some(q) = queues.get_mut(&name) else {
+                return unknown_queue(&name);
+            };
+            match setting.clone() {
+                QueueSetting::Visibility(visibility) => {
+                    q.set_in_flight_expiration_ms(visibility.as_millis() as i64)
+                }
+                QueueSetting::MaxAttempts(attempts) => q.set_max_attempts(attempts),
+                QueueSetting::Concurrency(max) => {
+                    q.set_max_in_flight(max.or(state.config.max_in_flight))
+                }
+                QueueSetting::MaxDepth(max) => q.set_max_depth(max),
+                QueueSetting::OnFull(policy) => q.set_full_policy(policy),
+                QueueSetting::MaxSize(max) => q.set_max_message_bytes(max),
+                QueueSetting::Rate(per_second) => q.set_rate_limit(per_second),
+                QueueSetting::DeadLetter(target) => q.set_dead_letter_queue(target),
+                QueueSetting::Retention(retention) => {
+                    q.set_retention_ms(retention.map(|retention| retention.as_millis() as i64))
+                }
+            }
+            info!(queue = %name, ?setting, "queue reconfigured");
+            // Raised limits may let blocked POPs lease and blocked pushes in.
+            state.pushed.notify_waiters();
+            RespValue::ok()
+        }
+        Cmd::QUEUE(QueueCmd::DIGEST { name }) => {
+            let queues = state.queues.read().unwrap();
+            let Some(q) = queues.get(&name) else {
+                return unknown_queue(&name);
+            };
+            let (pending, digest) = q.digest();
+            RespValue::map()
+                .field("pending", pending)
+                .field("digest", RespValue::bulk(format!("{:016x}", digest)))
+                .build()
+        }
+        Cmd::QUEUE(QueueCmd::PURGE { name }) => {
+            let Some(total) = state.queues.read().unwrap().get(&name).map(Queue::depth) else {
+                return unknown_queue(&name);
+            };
+            let queues = state.queues.clone();
+            start_job(state, "purge", &name.clone(), move |job| {
+                job.set_total(total);
+                // Stops at what was waiting when the job started; later pushes are kept.
+                while job.done() < total && !job.cancelled() {
+                    let mut queues = queues.lock().unwrap();
+                    let q = queues.get_mut(&name).ok_or("queue is gone")?;
+                    let purged = q.purge(JOB_BATCH.min(total - job.done()));
+                    if purged == 0 {
+                        break;
+                    }
+                    job.advance(purged);
+                }
+                Ok(())
+            })
+        }
+        Cmd::QUEUE(QueueCmd::CLONE {
+            source,
+            destination,
+        }) => {
+            let mut queues = state.queues.lock().unwrap();
+            let Some(q) = queues.get(&source) else {
+                return unknown_queue(&source);
+            };
+            if queues.contains_key(&destination) {
+                return RespError::QueueExists(destination).into();
+            }
+            let msgs: Vec<Message> = q.waiting().into_iter().cloned().collect();
+            let mut copy = new_queue(&destination, state);
+            copy.set_order(q.order());
+            queues.insert(destination.clone(), copy);
+            info!(queue = %destination, from = %source, "queue created as a clone");
+            let (shared, pushed) = (state.queues.clone(), state.pushed.clone());
+            start_job(state, "clone", &source, move |job| {
+                job.set_total(msgs.len());
+                for batch in msgs.chunks(JOB_BATCH) {
+                    if job.cancelled() {
+                        break;
+                    }
+                    let copies = batch.iter().map(|msg| msg.copy_to(destination.clone()));
+                    let mut queues = shared.lock().unwrap();
+                    let q = queues.get_mut(&destination).ok_or("queue is gone")?;
+                    copies.for_each(|msg| q.append(msg));
+                    job.advance(batch.len());
+                    pushed.notify_waiters();
+                }
+                Ok(())
+            })
+        }
+        Cmd::QUEUE(QueueCmd::EXPORT { name, path }) => {
+            let queues = state.queues.read().unwrap();
+            let Some(q) = queues.get(&name) else {
+                return unknown_queue(&name);
+            };
+            let msgs: Vec<Message> = q.snapshot().into_iter().map(Message::inflated).collect();
+            let path = PathBuf::from(path);
+            start_job(state, "export", &name, move |job| {
+                job.set_total(msgs.len());
+                export(job, &msgs, &path)
+            })
+        }
+        Cmd::QUEUE(QueueCmd::REDRIVE {
+            name,
+            count,
+            priority,
+        }) => {
+            let Some(dead) = state
+                .queues
+                .lock()
+                .unwrap()
+                .get(&name)
+                .map(Queue::dead_letter_count)
+            else {
+                return unknown_queue(&name);
+            };
+            let total = count.map_or(dead, |count| count.min(dead));
+            let (queues, pushed) = (state.queues.clone(), state.pushed.clone());
+            start_job(state, "redrive", &name.clone(), move |job| {
+                job.set_total(total);
+                while job.done() < total && !job.cancelled() {
+                    let mut queues = queues.lock().unwrap();
+                    let q = queues.get_mut(&name).ok_or("queue is gone")?;
+                    let moved = q.redrive(JOB_BATCH.min(total - job.done()), priority);
+                    if moved == 0 {
+                        break;
+                    }
+                    job.advance(moved);
+                    pushed.notify_waiters();
+                }
+                Ok(())
+            })
+        }
+        Cmd::QUEUE(QueueCmd::REDRIVETO {
+            name,
+            target,
+            count,
+        }) => {
+            let queues = state.queues.read().unwrap();
+            let Some(waiting) = queues.get(&name).map(Queue::depth) else {
+                return unknown_queue(&name);
+            };
+            if !queues.contains_key(&target) {
+                return unknown_queue(&target);
+            }
+            drop(queues);
+            let total = count.map_or(waiting, |count| count.min(waiting));
+            let (queues, pushed) = (state.queues.clone(), state.pushed.clone());
+            let queue_capacity = state.config.queue_capacity;
+            start_job(state, "redrive", &name.clone(), move |job| {
+                job.set_total(total);
+                while job.done() < total && !job.cancelled() {
+                    let mut queues = queues.lock().unwrap();
+                    // Taken in batches that fit, the way PUSH would take them.
+                    let q = queues.get_mut(&target).ok_or("target queue is gone")?;
+                    let capacity = q.max_depth().or(queue_capacity);
+                    let mut batch = room_below(q, capacity).min(JOB_BATCH);
+                    // Dropping one from a backlog left over a lowered MAXDEPTH wouldn't
+                    // make room, so that one is left alone.
+                    let at_capacity = capacity.is_some_and(|capacity| q.depth() == capacity);
+                    if batch == 0 && at_capacity && make_room(q, &target) {
+                        batch = 1;
+                    }
+                    if batch == 0 {
+                        return Err(format!(
+                            "'{}' is full, {} messages left in '{}'",
+                            target,
+                            total - job.done(),
+                            name
+                        ));
+                    }
+                    let dlq = queues.get_mut(&name).ok_or("queue is gone")?;
+                    let msgs = dlq.take_waiting(batch.min(total - job.done()));
+                    if msgs.is_empty() {
+                        break;
+                    }
+                    job.advance(msgs.len());
+                    let q = queues.get_mut(&target).ok_or("target queue is gone")?;
+                    for msg in msgs {
+                        let msg = msg.move_to(target.clone());
+                        // Past the backlog they fit the overflow buffer, which the batch
+                        // size counted.
+                        if capacity.is_some_and(|capacity| q.depth() >= capacity) {
+                            q.absorb(msg);
+                        } else {
+                            q.add(msg);
+                        }
+                    }
+                    pushed.notify_waiters();
+                }
+                Ok(())
+            })
+        }
+        Cmd::JOB(JobCmd::STATUS(id)) => state
+            .jobs
+            .get(id)
+            .map_or_else(|| unknown_job(id), |job| job.status()),
+        Cmd::JOB(JobCmd::CANCEL(id)) => match state.jobs.cancel(id) {
+            Some(running) => RespValue::Integer(running as i64),
+            None => unknown_job(id),
+        },
+        Cmd::JOB(JobCmd::LIST) => RespValue::array()
+            .items(state.jobs.list().iter().map(|job| job.status()))
+            .build(),
+        Cmd::SCHEDULE(ScheduleCmd::ADD {
+            name,
+            queue,
+            cron,
+            body,
+        }) => {
+            let expr = cron.to_string();
+            let Some(next_run) = state
+                .schedules
+                .add(name.clone(), queue, cron, body, Utc::now())
+            else {
+                return RespError::InvalidArgument(format!("'{}', which never runs", expr)).into();
+            };
+            info!(schedule = %name, cron = %expr, %next_run, "schedule added");
+            next_run.timestamp_millis().into()
+        }
+        Cmd::SCHEDULE(ScheduleCmd::DEL { name }) => (state.schedules.remove(&name) as i64).into(),
+        Cmd::SCHEDULE(ScheduleCmd::LIST) => RespValue::array()
+            .items(state.schedules.list().into_iter().map(|(name, schedule)| {
+                RespValue::map()
+                    .field("name", RespValue::bulk(&name))
+                    .field("queue", RespValue::bulk(&schedule.queue))
+                    .field("cron", RespValue::bulk(schedule.cron.to_string()))
+                    .field("next_run", schedule.next_run.timestamp_millis())
+                    .field("runs", schedule.runs)
+                    .build()
+            }))
+            .build(),
+        Cmd::PUSH {
+            queue,
+            body,
+            checksum,
+            priority,
+            ttl,
+            dedup,
+            group,
+            attributes,
+        } => {
+            let mut queues = state.queues.lock().unwrap();
+            let Some(q) = lookup_queue(&mut queues, &queue, state) else {
+                return unknown_queue(&queue);
+            };
+            let now = now_ms();
+            let dedup = match dedup {
+                Some(key) => Some(DedupKey::Producer(key)),
+                None => q.dedups_by_content().then(|| DedupKey::content(&body)),
+            };
+            if let Some(id) = dedup.as_ref().and_then(|key| q.duplicate_of(key, now)) {
+                debug!(queue = %queue, id = %id, "duplicate push acknowledged");
+                return RespValue::bulk(id);
+            }
+            if let Err(err) = check_size(q, &queue, &body) {
+                return err.into();
+            }
+            if checksum.is_some_and(|checksum| checksum != body_checksum(&body)) {
+                warn!(queue = %queue, "pushed body doesn't match its checksum");
+                return RespError::ChecksumMismatch(format!("PUSH to '{}'", queue)).into();
+            }
+            if let Err(err) = check_group(q, &queue, &group) {
+                return err.into();
+            }
+            // Last, as making room drops a message that a rejected push mustn't cost.
+            if !has_room(q, state) && !make_room(q, &queue) {
+                warn!(queue = %queue, depth = q.depth(), "queue full, rejecting push");
+                return RespError::QueueFull(queue).into();
+            }
+            let msg = new_message(&queue, body, priority, ttl, state);
+            let msg = with_options(msg, group, attributes);
+            let id = msg.id().clone();
+            if !enqueue(q, &queue, msg, state, pushes) {
+                return RespError::QueueFull(queue).into();
+            }
+            if let Some(key) = dedup {
+                q.remember_push(key, id.clone(), now);
+            }
+            RespValue::bulk(id)
+        }
+        Cmd::MPUSH {
+            queue,
+            bodies,
+            priority,
+            ttl,
+            group,
+            attributes,
+        } => {
+            let mut queues = state.queues.lock().unwrap();
+            let Some(q) = lookup_queue(&mut queues, &queue, state) else {
+                return unknown_queue(&queue);
+            };
+            // All or nothing, so a producer retrying a rejected batch doesn't duplicate
+            // the part that got in.
+            if let Err(err) = bodies
+                .iter()
+                .try_for_each(|body| check_size(q, &queue, body))
+            {
+                return err.into();
+            }
+            if let Err(err) = check_group(q, &queue, &group) {
+                return err.into();
+            }
+            if room(q, state) < bodies.len() && q.full_policy() != FullPolicy::DropOldest {
+                warn!(queue = %queue, depth = q.depth(), batch = bodies.len(), "queue full, rejecting batch");
+                return RespError::QueueFull(queue).into();
+            }
+            let msgs: Vec<Message> = bodies
+                .into_iter()
+                .map(|body| {
+                    let msg = new_message(&queue, body, priority, ttl, state);
+                    with_options(msg, group.clone(), attributes.clone())
+                })
+                .collect();
+            let backlog_room = state
+                .capacity(q)
+                .map_or(usize::MAX, |capacity| capacity.saturating_sub(q.depth()));
+            if msgs.len() <= backlog_room {
+                let ids: Vec<RespValue> =
+                    msgs.iter().map(|msg| RespValue::bulk(msg.id())).collect();
+                enqueue_batch(q, &queue, msgs, state, pushes);
+                return RespValue::array().items(ids).build();
+            }
+            // Past the backlog each message may need room made, or go to the overflow
+            // buffer. One that still finds no room is dropped, and gets a null id.
+            let mut ids = Vec::with_capacity(msgs.len());
+            for msg in msgs {
+                if !has_room(q, state) {
+                    make_room(q, &queue);
+                }
+                let id = RespValue::bulk(msg.id());
+                let pushed = enqueue(q, &queue, msg, state, pushes);
+                ids.push(if pushed { id } else { RespValue::Null });
+            }
+            RespValue::array().items(ids).build()
+        }
+        Cmd::FANOUT {
+            body,
+            queues: names,
+            rules,
+            attributes,
+        } => {
+            let ctx = RuleContext {
+                attributes: &attributes,
+                body: &String::from_utf8_lossy(&body),
+            };
+            let mut routed = Vec::with_capacity(names.len());
+            for name in &names {
+                match rules.get(name).map(|rule| (rule, rule.matches(&ctx))) {
+                    Some((_, Err(err))) => return RespError::InvalidRule(err).into(),
+                    Some((rule, Ok(false))) => {
+                        debug!(queue = %name, rule = rule.source(), "fanout skipped by rule")
+                    }
+                    Some((_, Ok(true))) | None => routed.push(name),
+                }
+            }
+            let mut queues = state.queues.lock().unwrap();
+            // Everything is checked before anything is created or pushed, under the one
+            // lock, so no consumer ever sees the message on some of the queues only.
+            for &name in &routed {
+                let created;
+                let q = match queues.get(name) {
+                    Some(q) => q,
+                    None if state.config.auto_create_queues => {
+                        created = new_queue(name, state);
+                        &created
+                    }
+                    None => return unknown_queue(name),
+                };
+                if let Err(err) = check_size(q, name, &body) {
+                    return err.into();
+                }
+                if !has_room(q, state) && q.full_policy() != FullPolicy::DropOldest {
+                    warn!(queue = %name, depth = q.depth(), "queue full, rejecting fanout");
+                    return RespError::QueueFull(name.clone()).into();
+                }
+            }
+            let mut ids = Vec::with_capacity(names.len());
+            for name in &names {
+                if !routed.contains(&name) {
+                    ids.push(RespValue::Null);
+                    continue;
+                }
+                let q = lookup_queue(&mut queues, name, state).expect("checked above");
+                if !has_room(q, state) {
+                    make_room(q, name);
+                }
+                let msg = new_message(name, body.clone(), 0, None, state)
+                    .with_attributes(attributes.clone());
+                let id = RespValue::bulk(msg.id());
+                let pushed = enqueue(q, name, msg, state, pushes);
+                ids.push(if pushed { id } else { RespValue::Null });
+            }
+            RespValue::array().items(ids).build()
+        }
+        Cmd::PEEK { queue, count } => {
+            let queues = state.queues.read().unwrap();
+            let Some(q) = queues.get(&queue) else {
+                return unknown_queue(&queue);
+            };
+            let chunk_size = state.config.stream_chunk_size;
+            RespValue::array()
+                .items(q.peek(count).iter().map(|msg| delivery(msg, chunk_size)))
+                .build()
+        }
+        Cmd::POP {
+            queue,
+            count,
+            on_empty,
+            visibility,
+        } => match lease(&queue, count, client_id, visibility, state, deadline) {
+            Ok(msgs) if msgs.is_empty() && on_empty == EmptyPop::Null => null_pop(count, protocol),
+            Ok(msgs) => RespValue::array().items(msgs).build(),
+            Err(err) => err,
+        },
+        Cmd::ACK {
+            queue,
+            ids,
+            checksum,
+        } => {
+            let mut queues = state.queues.lock().unwrap();
+            let Some(q) = queues.get_mut(&queue) else {
+                return unknown_queue(&queue);
+            };
+            let ids: Vec<String> = ids
+                .iter()
+                .filter_map(|receipt| settled_id(q, &queue, receipt))
+                .collect();
+            // Checked before any is acked, so a mismatch settles none of them.
+            for id in &ids {
+                let Some(expected) = q.in_flight_checksum(id) else {
+                    continue;
+                };
+                let verified = match checksum {
+                    Some(checksum) => checksum == expected,
+                    None => state.config.checksums != ChecksumMode::Strict,
+                };
+                if !verified {
+                    warn!(queue = %queue, id = %id, "ack doesn't match the delivered checksum");
+                    return RespError::ChecksumMismatch(id.clone()).into();
+                }
+            }
+            let acked = q
+                .complete_batch(&ids)
+                .into_iter()
+                .filter(|acked| *acked)
+                .count();
+            debug!(queue = %queue, ?ids, acked, "messages acked");
+            if acked > 0 && q.max_in_flight().is_some() {
+                // Frees slots that POPs blocked on the concurrency cap are waiting for.
+                state.pushed.notify_waiters();
+            }
+            RespValue::Integer(acked as i64)
+        }
+        Cmd::TOUCH {
+            queue,
+            id,
+            visibility,
+        } => {
+            let mut queues = state.queues.lock().unwrap();
+            let Some(q) = queues.get_mut(&queue) else {
+                return unknown_queue(&queue);
+            };
+            let Some(id) = settled_id(q, &queue, &id) else {
+                return RespValue::Integer(0);
+            };
+            let touched = q.touch(&id, visibility.map(|v| v.as_millis() as i64));
+            debug!(queue = %queue, id = %id, touched, ?visibility, "lease extended");
+            RespValue::Integer(touched as i64)
+        }
+        Cmd::PROGRESS {
+            queue,
+            id,
+            progress,
+        } => {
+            let mut queues = state.queues.lock().unwrap();
+            let Some(q) = queues.get_mut(&queue) else {
+                return unknown_queue(&queue);
+            };
+            let Some(id) = settled_id(q, &queue, &id) else {
+                return RespValue::Integer(0);
+            };
+            let reported = q.report_progress(&id, progress);
+            debug!(queue = %queue, id = %id, reported, "progress reported");
+            RespValue::Integer(reported as i64)
+        }
+        Cmd::CANCEL { queue, id } => {
+            let mut queues = state.queues.lock().unwrap();
+            let Some(q) = queues.get_mut(&queue) else {
+                return unknown_queue(&queue);
+            };
+            let id = receipt_id(&id).to_string();
+            let Some(consumer) = q.cancel(&id) else {
+                return RespValue::Integer(0);
+            };
+            info!(queue = %queue, id = %id, consumer, "lease cancelled");
+            state.announce(ServerEvent::Cancelled {
+                consumer,
+                queue: queue.clone(),
+                id,
+            });
+            RespValue::Integer(1)
+        }
+        Cmd::NACK { queue, id, delay } => {
+            let mut queues = state.queues.lock().unwrap();
+            let Some(q) = queues.get_mut(&queue) else {
+                return unknown_queue(&queue);
+            };
+            let Some(id) = settled_id(q, &queue, &id) else {
+                return RespValue::Integer(0);
+            };
+            let nacked = q.nack(&id, delay.as_millis() as i64);
+            debug!(queue = %queue, id = %id, nacked, ?delay, "message nacked");
+            if nacked && delay.is_zero() {
+                state.pushed.notify_waiters();
+            }
+            RespValue::Integer(nacked as i64)
+        }
+        Cmd::SERVER(ServerCmd::DRAIN) => {
+            state.draining.store(true, Ordering::Relaxed);
+            info!("draining, POP will hand out no messages");
+            state.announce(ServerEvent::Paused);
+            RespValue::ok()
+        }
+        Cmd::SERVER(ServerCmd::RESUME) => {
+            state.draining.store(false, Ordering::Relaxed);
+            state.pushed.notify_waiters();
+            info!("resumed handing out messages");
+            state.announce(ServerEvent::Resumed);
+            RespValue::ok()
+        }
+        Cmd::SERVER(ServerCmd::TELEMETRY) => state.telemetry.reply(),
+        Cmd::SERVER(ServerCmd::RESETSTATS) => {
+            state.command_stats.reset();
+            RespValue::ok()
+        }
+        // Commandstats is the only section so far; unknown ones are empty, as in Redis.
+        Cmd::INFO { section } => match section.as_deref() {
+            None | Some("commandstats" | "all" | "everything") => {
+                RespValue::bulk(state.command_stats.info())
+            }
+            Some(_) => RespValue::bulk(""),
+        },
+        Cmd::SERVER(ServerCmd::METRICS) => {
+            let queues = state.queues.read().unwrap();
+            match metrics::render(&queues, state.config.metrics_max_queue_labels, deadline) {
+                Ok(text) => RespValue::bulk(text),
+                Err(err) => err.into(),
+            }
+        }
+        Cmd::SERVER(ServerCmd::SNAPSHOT) => {
+            let (id, len) = state.begin_snapshot();
+            RespValue::array()
+                .item(RespValue::bulk(id))
+                .item(len)
+                .build()
+        }
+        Cmd::SERVER(ServerCmd::SNAPSHOTREAD { id, offset, count }) => {
+            match state.snapshot_chunk(&id, offset, count) {
+                Some(chunk) => RespValue::bulk(chunk),
+                None => RespError::InvalidArgument(format!("snapshot {}", id)).into(),
+            }
+        }
+        Cmd::COMMAND(CommandCmd::LIST) => RespValue::array()
+            .items(CommandSet::iter().map(|command| command.spec().info()))
+            .build(),
+        Cmd::COMMAND(CommandCmd::COUNT) => CommandSet::iter().count().into(),
+        Cmd::COMMAND(CommandCmd::INFO(names)) => RespValue::array()
+            .items(names.iter().map(|name| match CommandSet::from_str(name) {
+                Ok(command) => command.spec().info(),
+                Err(_) => RespValue::Null,
+            }))
+            .build(),
+        Cmd::COMMAND(CommandCmd::DOCS(names)) => {
+            let commands: Vec<CommandSet> = if names.is_empty() {
+                CommandSet::iter().collect()
+            } else {
+                names
+                    .iter()
+                    .filter_map(|name| CommandSet::from_str(name).ok())
+                    .collect()
+            };
+            RespValue::map()
+                .fields(commands.iter().map(|command| {
+                    let spec = command.spec();
+                    (spec.name.to_lowercase(), spec.docs())
+                }))
+                .build()
+        }
+        Cmd::DEBUG(DebugCmd::PROFILE { seconds, path }) => {
+            let path = path.map_or_else(profiler::default_path, PathBuf::from);
+            match profiler::start(Duration::from_secs(seconds), path.clone()) {
+                // Answered right away so the connection isn't held for the capture.
+                Ok(_) => RespValue::bulk(path.display().to_string()),
+                Err(e) => RespValue::error(ErrorCode::ERR, &e),
+            }
+        }
+        Cmd::DEBUG(DebugCmd::TRACE { scope, name, every }) => {
+            info!(?scope, name = %name, every, "trace sampling set");
+            state.trace_sampling.set(scope, &name, every);
+            RespValue::ok()
+        }
+        Cmd::DEBUG(DebugCmd::TRACES) => {
+            let rates = state.trace_sampling.rates();
+            let scoped = |scope| {
+                RespValue::map()
+                    .fields(
+                        rates
+                            .iter()
+                            .filter(|(s, ..)| *s == scope)
+                            .map(|(_, name, every)| (name, *every as i64)),
+                    )
+                    .build()
+            };
+            RespValue::map()
+                .field("commands", scoped(TraceScope::Command))
+                .field("queues", scoped(TraceScope::Queue))
+                .build()
+        }
+        Cmd::SHUTDOWN { save } => {
+            let mode = if save {
+                Shutdown::Save
+            } else {
+                Shutdown::NoSave
+            };
+            state.request_shutdown(mode);
+            RespValue::ok()
+        }
+        _ => RespValue::ok(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::commands::{execute, execute_for, hello_reply};
+    use crate::config::{ChecksumMode, ServerConfig};
+    use crate::jobs::JobState;
+    use crate::queue::{Message, Queue, QueueOrder, RedrivePriority};
+    use crate::resp::{
+        parse_cmd, Cmd, CommandCmd, DebugCmd, EmptyPop, JobCmd, QueueCmd, ServerCmd,
+    };
+    use crate::routing::RoutingStrategy;
+    use crate::server::{ServerState, Shutdown};
+    use crate::session::Session;
+    use crate::test_utils::{frame, send, wait_for_job};
+    use crate::trace_sampling::TraceScope;
+    use crate::wire;
+    use bytes::Bytes;
+    use std::collections::BTreeMap;
+    use std::fs;
+    use std::time::Duration;
+    use uuid::Uuid;
+
+    /// Parses `args` as a client would have sent them.
+    fn cmd(args: &[&str]) -> Cmd {
+        parse_cmd(&frame(args)).unwrap()
+    }
+
+    /// `PUSH <queue> <body>` without options.
+    fn push(queue: &str, body: impl AsRef<[u8]>) -> Cmd {
+        Cmd::PUSH {
+            queue: queue.to_string(),
+            body: Bytes::copy_from_slice(body.as_ref()),
+            checksum: None,
+            priority: 0,
+            ttl: None,
+            dedup: None,
+            group: None,
+            attributes: BTreeMap::new(),
+        }
+    }
+
+    /// `QUEUE CREATE <name>` without options.
+    fn create(name: &str) -> Cmd {
+        cmd(&["QUEUE", "CREATE", name])
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_save_writes_snapshot() {
+        let config = ServerConfig {
+            snapshot_path: std::env::temp_dir().join("infinity_q_test_shutdown_save.json"),
+            ..ServerConfig::default()
+        };
+        let state = ServerState::new(config);
+        let mut q = Queue::create("jobs".to_string());
+        q.add(Message::new("jobs".to_string(), "hello".to_string()));
+        q.set_max_attempts(5);
+        state.queues.lock().unwrap().insert("jobs".to_string(), q);
+
+        execute(Cmd::SHUTDOWN { save: true }, 1, &state);
+        let mode = state.wait_for_shutdown().await;
+        assert_eq!(mode, Shutdown::Save);
+
+        state.finish_shutdown(mode).unwrap();
+        let snapshot = fs::read_to_string(&state.config.snapshot_path).unwrap();
+        assert!(snapshot.contains("hello"));
+
+        // the next start picks up where this one left off
+        let restarted = ServerState::new(state.config.clone());
+        restarted.restore_snapshot().unwrap();
+        fs::remove_file(&state.config.snapshot_path).unwrap();
+        let queues = restarted.queues.lock().unwrap();
+        assert_eq!(queues["jobs"].depth(), 1);
+        assert_eq!(queues["jobs"].max_attempts(), 5);
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_nosave() {
+        let state = ServerState::new(ServerConfig::default());
+        execute(Cmd::SHUTDOWN { save: false }, 1, &state);
+        assert_eq!(state.wait_for_shutdown().await, Shutdown::NoSave);
+    }
+
+    #[test]
+    fn test_hello_reply() {
+        let state = ServerState::new(ServerConfig::default());
+        let reply = String::from_utf8(hello_reply(&state, 2).encode_for(2)).unwrap();
+        let version = env!("CARGO_PKG_VERSION");
+        let version = format!("+version\r\n${}\r\n{}\r\n", version.len(), version);
+        assert!(reply.contains(&version));
+        assert!(reply.contains(":2\r\n"));
+        assert!(reply.contains(&format!("+id\r\n$32\r\n{}\r\n", state.run_id)));
+
+        let restarted = ServerState::new(ServerConfig::default());
+        assert_ne!(restarted.run_id, state.run_id);
+    }
+
+    #[test]
+    fn test_push_pop_ack() {
+        let state = ServerState::new(ServerConfig::default());
+        assert_eq!(execute(create("jobs"), 1, &state), b"+OK\r\n");
+        let id_reply = String::from_utf8(execute(push("jobs", "hello"), 1, &state)).unwrap();
+        let id = id_reply.split("\r\n").nth(1).unwrap().to_string();
+
+        let pop = Cmd::POP {
+            queue: "jobs".to_string(),
+            count: 5,
+            on_empty: EmptyPop::Array,
+            visibility: None,
+        };
+        let reply = String::from_utf8(execute(pop, 1, &state)).unwrap();
+        assert!(reply.starts_with("*1\r\n|3\r\n+attempt\r\n:1\r\n+enqueued-at\r\n:"));
+        assert!(reply.ends_with(&format!(
+            "+receipt\r\n{}*2\r\n{}$5\r\nhello\r\n",
+            id_reply, id_reply
+        )));
+
+        // RESP2 clients keep the plain `[id, body]` shape.
+        let second = String::from_utf8(execute(push("jobs", "again"), 1, &state)).unwrap();
+        let pop = Cmd::POP {
+            queue: "jobs".to_string(),
+            count: 5,
+            on_empty: EmptyPop::Array,
+            visibility: None,
+        };
+        assert_eq!(
+            execute_for(pop, 1, &state, 2),
+            format!("*1\r\n*2\r\n{}$5\r\nagain\r\n", second).into_bytes()
+        );
+
+        let ack = |id: &str| Cmd::ACK {
+            queue: "jobs".to_string(),
+            ids: vec![id.to_string()],
+            checksum: None,
+        };
+        assert_eq!(execute(ack(&id), 1, &state), b":1\r\n");
+        // Retried acks are answered from the ack cache.
+        assert_eq!(execute(ack(&id), 1, &state), b":1\r\n");
+        assert_eq!(execute(ack("unknown"), 1, &state), b":0\r\n");
+
+        // One ACK settles several receipts and counts those it acked.
+        execute(cmd(&["MPUSH", "jobs", "a", "b"]), 1, &state);
+        let reply = String::from_utf8(execute(cmd(&["POP", "jobs", "2"]), 1, &state)).unwrap();
+        let receipts: Vec<&str> = reply
+            .split("+receipt\r\n$36\r\n")
+            .skip(1)
+            .map(|rest| &rest[..36])
+            .collect();
+        let ack_all = cmd(&["ACK", "jobs", receipts[0], receipts[1], "unknown"]);
+        assert_eq!(execute(ack_all, 1, &state), b":2\r\n");
+        // Only the RESP2 delivery of `again` is still held.
+        assert_eq!(state.queues.lock().unwrap()["jobs"].in_flight_count(), 1);
+    }
+
+    #[test]
+    fn test_nack_and_touch() {
+        let state = ServerState::new(ServerConfig::dev());
+        let id_reply = String::from_utf8(execute(push("jobs", "hello"), 1, &state)).unwrap();
+        let id = id_reply.split("\r\n").nth(1).unwrap().to_string();
+        let pop = || Cmd::POP {
+            queue: "jobs".to_string(),
+            count: 1,
+            on_empty: EmptyPop::Array,
+            visibility: None,
+        };
+        let nack = |queue: &str, receipt: &str| Cmd::NACK {
+            queue: queue.to_string(),
+            id: receipt.to_string(),
+            delay: Duration::ZERO,
+        };
+        execute(pop(), 1, &state);
+        assert_eq!(execute(nack("jobs", &id), 1, &state), b":1\r\n");
+        assert_eq!(execute(nack("jobs", &id), 1, &state), b":0\r\n");
+        assert!(execute(nack("missing", &id), 1, &state).starts_with(b"-NOQUEUE"));
+
+        // Redelivered right away, as the second attempt, under a receipt of its own.
+        let reply = String::from_utf8(execute(pop(), 1, &state)).unwrap();
+        assert!(reply.starts_with("*1\r\n|3\r\n+attempt\r\n:2\r\n"));
+        let receipt = format!("{}:2", id);
+        assert!(reply.contains(&format!("+receipt\r\n$38\r\n{}\r\n", receipt)));
+
+        let touch = |receipt: &str| Cmd::TOUCH {
+            queue: "jobs".to_string(),
+            id: receipt.to_string(),
+            visibility: Some(Duration::from_secs(60)),
+        };
+        // The first delivery's receipt no longer settles anything.
+        assert_eq!(execute(touch(&id), 1, &state), b":0\r\n");
+        assert_eq!(execute(nack("jobs", &id), 1, &state), b":0\r\n");
+        assert_eq!(execute(touch(&receipt), 1, &state), b":1\r\n");
+        assert_eq!(execute(nack("jobs", &receipt), 1, &state), b":1\r\n");
+    }
+
+    #[test]
+    fn test_pop_unknown_queue() {
+        let state = ServerState::new(ServerConfig::default());
+        let pop = Cmd::POP {
+            queue: "missing".to_string(),
+            count: 1,
+            on_empty: EmptyPop::Array,
+            visibility: None,
+        };
+        assert_eq!(
+            execute(pop, 1, &state),
+            b"-NOQUEUE unknown queue 'missing'\r\n"
+        );
+    }
+
+    #[test]
+    fn test_sharded_queue() {
+        let mut config = ServerConfig::dev();
+        config
+            .queue_routing
+            .insert("hot".to_string(), RoutingStrategy::RoundRobin);
+        let state = ServerState::new(config);
+        let mut session = Session::new("0.0.0.0".to_string());
+        let create = ["QUEUE", "CREATE", "hot", "FIFO", "SHARDS", "3"];
+        assert_eq!(send(&mut session, &state, &create), "+OK\r\n");
+        assert!(send(&mut session, &state, &create).starts_with("-BUSYQUEUE"));
+        for body in ["a", "b", "c", "d"] {
+            send(&mut session, &state, &["PUSH", "hot", body]);
+        }
+        let depths = |state: &ServerState| -> Vec<usize> {
+            let queues = state.queues.read().unwrap();
+            (0..3)
+                .map(|i| queues[&format!("hot:{}", i)].depth())
+                .collect()
+        };
+        assert_eq!(depths(&state), [2, 1, 1]);
+        assert!(!state.queues.read().unwrap().contains_key("hot"));
+
+        // Consumers take from the shards in turn, and ack through the queue's name.
+        let mut bodies = Vec::new();
+        for _ in 0..4 {
+            let reply = send(&mut session, &state, &["POP", "hot"]);
+            // Ends in `[id, body]`.
+            let parts: Vec<&str> = reply.rsplit("\r\n").collect();
+            bodies.push(parts[1].to_string());
+            assert_eq!(
+                send(&mut session, &state, &["ACK", "hot", parts[3]]),
+                ":1\r\n"
+            );
+        }
+        assert_eq!(bodies, ["a", "b", "c", "d"]);
+        assert_eq!(depths(&state), [0, 0, 0]);
+        assert_eq!(send(&mut session, &state, &["POP", "hot", "NULL"]), "_\r\n");
+
+        assert_eq!(
+            send(&mut session, &state, &["QUEUE", "DELETE", "hot"]),
+            "+OK\r\n"
+        );
+        assert!(state.queues.read().unwrap().is_empty());
+        assert_eq!(state.queues.shard_count("hot"), None);
+    }
+
+    #[test]
+    fn test_queue_create_twice() {
+        let state = ServerState::new(ServerConfig::default());
+        let create = || cmd(&["QUEUE", "CREATE", "jobs", "LIFO"]);
+        assert_eq!(execute(create(), 1, &state), b"+OK\r\n");
+        assert!(execute(create(), 1, &state).starts_with(b"-BUSYQUEUE"));
+        assert_eq!(
+            state.queues.lock().unwrap()["jobs"].order(),
+            QueueOrder::Lifo
+        );
+    }
+
+    #[test]
+    fn test_push_priority() {
+        let state = ServerState::new(ServerConfig::default());
+        let create = cmd(&["QUEUE", "CREATE", "jobs", "PRIORITY"]);
+        assert_eq!(execute(create, 1, &state), b"+OK\r\n");
+        execute(push("jobs", "bulk"), 1, &state);
+        execute(
+            cmd(&["PUSH", "jobs", "urgent", "PRIORITY", "200"]),
+            1,
+            &state,
+        );
+        let pop = Cmd::POP {
+            queue: "jobs".to_string(),
+            count: 1,
+            on_empty: EmptyPop::Array,
+            visibility: None,
+        };
+        assert!(execute_for(pop, 1, &state, 2).ends_with(b"$6\r\nurgent\r\n"));
+    }
+
+    #[test]
+    fn test_auto_create_queues() {
+        let state = ServerState::new(ServerConfig::default());
+        assert!(execute(push("jobs", "hello"), 1, &state).starts_with(b"-NOQUEUE unknown queue"));
+
+        let state = ServerState::new(ServerConfig::dev());
+        assert!(execute(push("jobs", "hello"), 1, &state).starts_with(b"$"));
+    }
+
+    #[test]
+    fn test_soft_and_hard_limits() {
+        let config = ServerConfig {
+            queue_capacity: Some(5),
+            soft_limit_percent: 80,
+            ..ServerConfig::dev()
+        };
+        let state = ServerState::new(config);
+        for _ in 0..3 {
+            assert!(execute(push("jobs", "hello"), 1, &state).starts_with(b"$"));
+        }
+        assert!(execute(push("jobs", "hello"), 1, &state)
+            .starts_with(b">4\r\n+soft-limit\r\n$4\r\njobs\r\n:4\r\n:5\r\n$"));
+        assert!(execute(push("jobs", "hello"), 1, &state).starts_with(b">4"));
+        assert!(execute(push("jobs", "hello"), 1, &state).starts_with(b"-QUEUEFULL"));
+    }
+
+    #[test]
+    fn test_fanout_is_all_or_nothing() {
+        let config = ServerConfig {
+            queue_capacity: Some(1),
+            ..ServerConfig::dev()
+        };
+        let state = ServerState::new(config);
+        let fanout = |queues: &[&str]| Cmd::FANOUT {
+            body: Bytes::from_static(b"hello"),
+            queues: queues.iter().map(|queue| queue.to_string()).collect(),
+            rules: BTreeMap::new(),
+            attributes: BTreeMap::new(),
+        };
+        let reply = String::from_utf8(execute(fanout(&["jobs", "audit"]), 1, &state)).unwrap();
+        // Both queues are past their soft limit, so soft-limit pushes come first.
+        let ids = &reply[reply.find("*2\r\n").unwrap()..];
+        let ids: Vec<&str> = ids.split("\r\n").skip(2).step_by(2).collect();
+        assert_eq!(ids[0].len(), 36);
+        assert_ne!(ids[0], ids[1]);
+
+        // `jobs` is full now, so `emails` isn't even created.
+        assert!(execute(fanout(&["emails", "jobs"]), 1, &state).starts_with(b"-QUEUEFULL"));
+        let queues = state.queues.lock().unwrap();
+        assert_eq!((queues["jobs"].depth(), queues["audit"].depth()), (1, 1));
+        assert!(!queues.contains_key("emails"));
+    }
+
+    #[test]
+    fn test_mpush() {
+        let config = ServerConfig {
+            queue_capacity: Some(3),
+            soft_limit_percent: 100,
+            ..ServerConfig::dev()
+        };
+        let state = ServerState::new(config);
+        let mpush = |bodies: &[&str]| cmd(&[&["MPUSH", "jobs"], bodies].concat());
+        let reply = String::from_utf8(execute(mpush(&["a", "b"]), 1, &state)).unwrap();
+        assert!(reply.starts_with("*2\r\n$36\r\n"));
+        assert!(execute(mpush(&["c", "d"]), 1, &state).starts_with(b"-QUEUEFULL"));
+
+        let pop = Cmd::POP {
+            queue: "jobs".to_string(),
+            count: 5,
+            on_empty: EmptyPop::Array,
+            visibility: None,
+        };
+        let reply = String::from_utf8(execute(pop, 1, &state)).unwrap();
+        assert!(reply.starts_with("*2\r\n"));
+        assert!(reply.find("\r\na\r\n").unwrap() < reply.find("\r\nb\r\n").unwrap());
+
+        // The PUSH options apply to every message; BODIES lets a body look like one.
+        let mpush = cmd(&[
+            "MPUSH", "tagged", "PRIORITY", "3", "ATTR", "k", "v", "BODIES", "TTL", "x",
+        ]);
+        assert!(execute(mpush, 1, &state).starts_with(b"*2\r\n"));
+        let queues = state.queues.lock().unwrap();
+        assert!(queues["tagged"]
+            .waiting()
+            .iter()
+            .all(|msg| msg.priority() == 3));
+        drop(queues);
+        let pop = cmd(&["POP", "tagged", "2"]);
+        let reply = String::from_utf8(execute(pop, 1, &state)).unwrap();
+        assert_eq!(
+            reply.matches("+attributes\r\n%1\r\n+k\r\n$1\r\nv").count(),
+            2
+        );
+        assert!(reply.contains("$3\r\nTTL\r\n"));
+    }
+
+    #[test]
+    fn test_max_size() {
+        let state = ServerState::new(ServerConfig::dev());
+        let run = |args: &[&str]| execute(parse_cmd(&frame(args)).unwrap(), 1, &state);
+        let reply = run(&["QUEUE", "CREATE", "small", "MAXSIZE", "4"]);
+        assert_eq!(reply, b"+OK\r\n");
+        let too_large = b"-TOOLARGE queue 'small' takes bodies of at most 4 bytes\r\n";
+        assert_eq!(run(&["PUSH", "small", "hello"]), too_large);
+        assert_eq!(run(&["MPUSH", "small", "a", "hello"]), too_large);
+        assert_eq!(run(&["FANOUT", "hello", "jobs", "small"]), too_large);
+        assert!(run(&["PUSH", "small", "hell"]).starts_with(b"$36"));
+
+        let queues = state.queues.lock().unwrap();
+        assert_eq!(queues["small"].depth(), 1);
+        assert!(!queues.contains_key("jobs"));
+    }
+
+    #[test]
+    fn test_peek() {
+        let state = ServerState::new(ServerConfig::dev());
+        execute(cmd(&["MPUSH", "jobs", "first", "second"]), 1, &state);
+        let peek = |count| Cmd::PEEK {
+            queue: "jobs".to_string(),
+            count,
+        };
+        let reply = String::from_utf8(execute(peek(1), 1, &state)).unwrap();
+        assert!(reply.starts_with("*1\r\n"));
+        assert!(reply.ends_with("$5\r\nfirst\r\n"));
+        // Nothing was leased, so both are still there to peek at and pop.
+        let reply = String::from_utf8(execute(peek(10), 1, &state)).unwrap();
+        assert!(reply.starts_with("*2\r\n"));
+        let queues = state.queues.lock().unwrap();
+        assert_eq!(
+            (queues["jobs"].depth(), queues["jobs"].in_flight_count()),
+            (2, 0)
+        );
+        drop(queues);
+
+        let missing = Cmd::PEEK {
+            queue: "missing".to_string(),
+            count: 1,
+        };
+        assert!(execute(missing, 1, &state).starts_with(b"-NOQUEUE"));
+    }
+
+    #[test]
+    fn test_overflow_absorbs_bursts() {
+        let config = ServerConfig {
+            queue_capacity: Some(1),
+            ..ServerConfig::dev()
+        };
+        let state = ServerState::new(config);
+        execute(
+            cmd(&["QUEUE", "CREATE", "jobs", "OVERFLOW", "1"]),
+            1,
+            &state,
+        );
+        let pop = || Cmd::POP {
+            queue: "jobs".to_string(),
+            count: 1,
+            on_empty: EmptyPop::Array,
+            visibility: None,
+        };
+        // Both past the soft limit, so replies start with its push frame.
+        assert!(execute(push("jobs", "first"), 1, &state).starts_with(b">"));
+        assert!(execute(push("jobs", "burst"), 1, &state).starts_with(b">"));
+        assert!(execute(push("jobs", "more"), 1, &state).starts_with(b"-QUEUEFULL"));
+
+        assert!(execute(pop(), 1, &state).ends_with(b"$5\r\nfirst\r\n"));
+        assert!(execute(pop(), 1, &state).ends_with(b"$5\r\nburst\r\n"));
+        let queues = state.queues.lock().unwrap();
+        assert_eq!(queues["jobs"].overflow_depth(), 0);
+        assert_eq!(queues["jobs"].overflowed(), 1);
+    }
+
+    #[test]
+    fn test_push_past_a_full_overflow_is_refused() {
+        let state = ServerState::new(ServerConfig::dev());
+        let create = [
+            "QUEUE",
+            "CREATE",
+            "jobs",
+            "MAXDEPTH",
+            "3",
+            "ONFULL",
+            "DROPOLDEST",
+        ];
+        execute(cmd(&[&create[..], &["OVERFLOW", "1"]].concat()), 1, &state);
+        for body in ["a", "b", "c", "d"] {
+            execute(push("jobs", body), 1, &state);
+        }
+        // Left over the lowered MAXDEPTH, dropping the oldest doesn't make room.
+        execute(
+            cmd(&["QUEUE", "CONFIG", "jobs", "SET", "MAXDEPTH", "1"]),
+            1,
+            &state,
+        );
+
+        let reply = String::from_utf8(execute(push("jobs", "e"), 1, &state)).unwrap();
+        assert!(reply.contains("-QUEUEFULL"), "{}", reply);
+        // "f" finds no room either, then dropping "c" leaves the backlog room for "g".
+        let mpush = cmd(&["MPUSH", "jobs", "f", "g"]);
+        let reply = String::from_utf8(execute(mpush, 1, &state)).unwrap();
+        assert!(reply.contains("*2\r\n_\r\n$"), "{}", reply);
+        let queues = state.queues.lock().unwrap();
+        let waiting = queues["jobs"].peek(10);
+        let bodies: Vec<&[u8]> = waiting.iter().map(Message::body).collect();
+        assert_eq!(bodies, [b"g"]);
+        assert_eq!(queues["jobs"].overflow_depth(), 1);
+    }
+
+    #[test]
+    fn test_max_depth_drops_oldest() {
+        let state = ServerState::new(ServerConfig::dev());
+        let create = |name: &str, on_full: &str| {
+            cmd(&["QUEUE", "CREATE", name, "MAXDEPTH", "2", "ONFULL", on_full])
+        };
+        execute(create("jobs", "DROPOLDEST"), 1, &state);
+        execute(create("strict", "REJECT"), 1, &state);
+        for body in ["first", "second", "third"] {
+            assert!(!execute(push("jobs", body), 1, &state).starts_with(b"-"));
+            execute(push("strict", body), 1, &state);
+        }
+        let fanout = |queues: &[&str]| Cmd::FANOUT {
+            body: Bytes::from_static(b"fourth"),
+            queues: queues.iter().map(|queue| queue.to_string()).collect(),
+            rules: BTreeMap::new(),
+            attributes: BTreeMap::new(),
+        };
+        assert!(execute(fanout(&["jobs", "strict"]), 1, &state).starts_with(b"-QUEUEFULL"));
+        assert!(!execute(fanout(&["jobs"]), 1, &state).starts_with(b"-"));
+
+        let queues = state.queues.lock().unwrap();
+        let bodies = |queue: &str| -> Vec<String> {
+            let waiting = queues[queue].waiting();
+            waiting
+                .iter()
+                .map(|msg| String::from_utf8(msg.body().to_vec()).unwrap())
+                .collect()
+        };
+        assert_eq!(bodies("jobs"), ["third", "fourth"]);
+        assert_eq!(bodies("strict"), ["first", "second"]);
+        assert_eq!(queues["jobs"].dropped(), 2);
+    }
+
+    #[test]
+    fn test_dedup() {
+        let state = ServerState::new(ServerConfig::dev());
+        execute(
+            cmd(&["QUEUE", "CREATE", "events", "DEDUP", "60s"]),
+            1,
+            &state,
+        );
+        let dedup = |body: &str, key: &str| cmd(&["PUSH", "jobs", body, "DEDUP", key]);
+        let first = execute(dedup("a", "order-1"), 1, &state);
+        assert_eq!(execute(dedup("b", "order-1"), 1, &state), first);
+        assert_ne!(execute(dedup("a", "order-2"), 1, &state), first);
+        // Without a key, only queues with a dedup window of their own compare bodies.
+        assert_ne!(execute(push("jobs", "a"), 1, &state), first);
+        let event = execute(push("events", "a"), 1, &state);
+        assert_eq!(execute(push("events", "a"), 1, &state), event);
+
+        let queues = state.queues.lock().unwrap();
+        assert_eq!(
+            (queues["jobs"].depth(), queues["jobs"].deduplicated()),
+            (3, 1)
+        );
+        assert_eq!(
+            (queues["events"].depth(), queues["events"].deduplicated()),
+            (1, 1)
+        );
+    }
+
+    #[test]
+    fn test_push_group() {
+        let state = ServerState::new(ServerConfig::dev());
+        let create = cmd(&["QUEUE", "CREATE", "stack", "LIFO"]);
+        execute(create, 1, &state);
+        let grouped = |queue: &str| cmd(&["PUSH", queue, "hello", "GROUP", "customer-1"]);
+        assert_eq!(
+            execute(grouped("stack"), 1, &state),
+            b"-ERR invalid arg for GROUP, 'stack' isn't FIFO\r\n"
+        );
+        execute(grouped("jobs"), 1, &state);
+
+        // Rejected before a full DROPOLDEST queue makes room, so nothing is dropped.
+        let create = cmd(&[
+            "QUEUE",
+            "CREATE",
+            "full",
+            "LIFO",
+            "MAXDEPTH",
+            "1",
+            "ONFULL",
+            "DROPOLDEST",
+        ]);
+        execute(create, 1, &state);
+        execute(push("full", "kept"), 1, &state);
+        assert!(execute(grouped("full"), 1, &state).starts_with(b"-ERR invalid arg for GROUP"));
+        let queues = state.queues.lock().unwrap();
+        assert_eq!((queues["full"].depth(), queues["full"].dropped()), (1, 0));
+        drop(queues);
+
+        let pop = Cmd::POP {
+            queue: "jobs".to_string(),
+            count: 1,
+            on_empty: EmptyPop::Array,
+            visibility: None,
+        };
+        let reply = String::from_utf8(execute(pop, 1, &state)).unwrap();
+        assert!(reply.contains("+group\r\n$10\r\ncustomer-1\r\n"));
+    }
+
+    #[test]
+    fn test_push_attributes() {
+        let state = ServerState::new(ServerConfig::dev());
+        let push = cmd(&[
+            "PUSH",
+            "jobs",
+            "{}",
+            "ATTR",
+            "content-type",
+            "application/json",
+            "ATTR",
+            "trace-id",
+            "abc",
+        ]);
+        execute(push, 1, &state);
+        let pop = Cmd::POP {
+            queue: "jobs".to_string(),
+            count: 1,
+            on_empty: EmptyPop::Array,
+            visibility: None,
+        };
+        let reply = String::from_utf8(execute(pop, 1, &state)).unwrap();
+        assert!(reply.contains(
+            "+attributes\r\n%2\r\n+content-type\r\n$16\r\napplication/json\r\n\
+             +trace-id\r\n$3\r\nabc\r\n"
+        ));
+    }
+
+    #[test]
+    fn test_drain() {
+        let state = ServerState::new(ServerConfig::dev());
+        let pop = || Cmd::POP {
+            queue: "jobs".to_string(),
+            count: 10,
+            on_empty: EmptyPop::Array,
+            visibility: None,
+        };
+        execute(push("jobs", "leased"), 1, &state);
+        let leased = String::from_utf8(execute(pop(), 1, &state)).unwrap();
+        let id = leased.split("\r\n").nth(8).unwrap().to_string();
+
+        assert_eq!(
+            execute(Cmd::SERVER(ServerCmd::DRAIN), 1, &state),
+            b"+OK\r\n"
+        );
+        assert!(execute(push("jobs", "waiting"), 1, &state).starts_with(b"$"));
+        assert_eq!(execute(pop(), 1, &state), b"*0\r\n");
+        let ack = Cmd::ACK {
+            queue: "jobs".to_string(),
+            ids: vec![id],
+            checksum: None,
+        };
+        assert_eq!(execute(ack, 1, &state), b":1\r\n");
+
+        execute(Cmd::SERVER(ServerCmd::RESUME), 1, &state);
+        assert!(execute(pop(), 1, &state).ends_with(b"$7\r\nwaiting\r\n"));
+    }
+
+    #[test]
+    fn test_pause() {
+        let state = ServerState::new(ServerConfig::dev());
+        let run = |args: &[&str]| execute(parse_cmd(&frame(args)).unwrap(), 1, &state);
+        run(&["PUSH", "jobs", "before"]);
+        assert_eq!(run(&["QUEUE", "PAUSE", "jobs"]), b"+OK\r\n");
+        assert!(run(&["PUSH", "jobs", "during"]).starts_with(b"$36"));
+        assert_eq!(run(&["POP", "jobs", "10"]), b"*0\r\n");
+        assert!(run(&["PEEK", "jobs", "10"]).starts_with(b"*2\r\n"));
+        assert!(String::from_utf8(run(&["QUEUE", "STATS", "jobs"]))
+            .unwrap()
+            .ends_with("+paused\r\n#t\r\n"));
+
+        assert_eq!(run(&["QUEUE", "RESUME", "jobs"]), b"+OK\r\n");
+        assert!(run(&["POP", "jobs", "10"]).starts_with(b"*2\r\n"));
+        assert!(run(&["QUEUE", "PAUSE", "missing"]).starts_with(b"-NOQUEUE"));
+    }
+
+    #[test]
+    fn test_exclusive_queue() {
+        let state = ServerState::new(ServerConfig::dev());
+        let run = |args: &[&str], client| execute(parse_cmd(&frame(args)).unwrap(), client, &state);
+        run(&["QUEUE", "CREATE", "jobs", "EXCLUSIVE"], 1);
+        run(&["PUSH", "jobs", "a"], 3);
+        run(&["PUSH", "jobs", "b"], 3);
+        assert!(run(&["POP", "jobs"], 1).starts_with(b"*1\r\n"));
+        assert_eq!(
+            run(&["POP", "jobs"], 2),
+            b"-EXCLUSIVE queue 'jobs' is exclusive to another consumer\r\n"
+        );
+
+        state.connection_closed(&[1]);
+        assert!(run(&["POP", "jobs", "10"], 2).starts_with(b"*2\r\n"));
+        assert!(run(&["POP", "jobs"], 1).starts_with(b"-EXCLUSIVE"));
+    }
+
+    #[test]
+    fn test_rate_limit() {
+        let state = ServerState::new(ServerConfig::dev());
+        let run = |args: &[&str]| execute(parse_cmd(&frame(args)).unwrap(), 1, &state);
+        assert_eq!(run(&["QUEUE", "CREATE", "jobs", "RATE", "2"]), b"+OK\r\n");
+        for body in ["a", "b", "c"] {
+            run(&["PUSH", "jobs", body]);
+        }
+        assert!(run(&["POP", "jobs", "10"]).starts_with(b"*2\r\n"));
+        assert_eq!(run(&["POP", "jobs", "10"]), b"*0\r\n");
+        assert!(state.queues.lock().unwrap()["jobs"].throttled());
+        assert_eq!(run(&["QUEUE", "CONFIG", "jobs", "GET", "RATE"]), b":2\r\n");
+
+        run(&["QUEUE", "CONFIG", "jobs", "SET", "RATE", "NONE"]);
+        assert!(run(&["POP", "jobs", "10"]).starts_with(b"*1\r\n"));
+        assert!(!state.queues.lock().unwrap()["jobs"].throttled());
+    }
+
+    #[test]
+    fn test_queue_config() {
+        let state = ServerState::new(ServerConfig::dev());
+        let run = |args: &[&str]| execute(parse_cmd(&frame(args)).unwrap(), 1, &state);
+        run(&["QUEUE", "CREATE", "jobs", "MAXDEPTH", "1"]);
+        assert_eq!(
+            run(&["QUEUE", "CONFIG", "jobs", "GET", "maxdepth"]),
+            b":1\r\n"
+        );
+        assert_eq!(
+            run(&["QUEUE", "CONFIG", "jobs", "GET", "MAXSIZE"]),
+            b"_\r\n"
+        );
+        assert_eq!(
+            run(&["QUEUE", "CONFIG", "jobs", "GET", "ONFULL"]),
+            b"+REJECT\r\n"
+        );
+        run(&["PUSH", "jobs", "first"]);
+        assert!(run(&["PUSH", "jobs", "second"]).starts_with(b"-QUEUEFULL"));
+
+        assert_eq!(
+            run(&["QUEUE", "CONFIG", "jobs", "SET", "MAXDEPTH", "NONE"]),
+            b"+OK\r\n"
+        );
+        assert!(run(&["PUSH", "jobs", "second"]).starts_with(b"$36"));
+        run(&["QUEUE", "CONFIG", "jobs", "SET", "VISIBILITY", "30s"]);
+        assert_eq!(
+            run(&["QUEUE", "CONFIG", "jobs", "GET", "VISIBILITY"]),
+            b":30000\r\n"
+        );
+        run(&["QUEUE", "CONFIG", "jobs", "SET", "MAXSIZE", "4b"]);
+        assert!(run(&["PUSH", "jobs", "too long"]).starts_with(b"-TOOLARGE"));
+        run(&["QUEUE", "CONFIG", "jobs", "SET", "MAXATTEMPTS", "5"]);
+        assert_eq!(
+            run(&["QUEUE", "CONFIG", "jobs", "GET", "MAXATTEMPTS"]),
+            b":5\r\n"
+        );
+        assert!(run(&["QUEUE", "CONFIG", "missing", "GET", "MAXDEPTH"]).starts_with(b"-NOQUEUE"));
+    }
+
+    #[test]
+    fn test_fanout_rules() {
+        let state = ServerState::new(ServerConfig::dev());
+        let run = |args: &[&str]| execute(parse_cmd(&frame(args)).unwrap(), 1, &state);
+        let fanout = |region: &str| {
+            run(&[
+                "FANOUT",
+                "order",
+                "eu-orders",
+                "IF",
+                r#"attributes.region == "eu""#,
+                "us-orders",
+                "IF",
+                r#"attributes.region == "us" && body == "order""#,
+                "audit",
+                "ATTR",
+                "region",
+                region,
+            ])
+        };
+        let reply = String::from_utf8(fanout("eu")).unwrap();
+        assert!(reply.starts_with("*3\r\n$36\r\n"));
+        assert!(reply.contains("\r\n_\r\n$36\r\n"));
+        fanout("us");
+        let queues = state.queues.lock().unwrap();
+        assert_eq!(queues["eu-orders"].depth(), 1);
+        assert_eq!(queues["us-orders"].depth(), 1);
+        assert_eq!(queues["audit"].depth(), 2);
+        assert_eq!(queues["us-orders"].peek(1)[0].attributes()["region"], "us");
+        drop(queues);
+
+        // a rule is cut off once it has used up its budget
+        let greedy = vec![r#"attributes.tier == "gold""#; 100].join(" || ");
+        let reply = run(&["FANOUT", "order", "vip", "IF", &greedy]);
+        assert!(reply.starts_with(b"-ERR rule exceeded its execution budget"));
+        assert!(!state.queues.lock().unwrap().contains_key("vip"));
+    }
+
+    #[test]
+    fn test_progress_and_inflight() {
+        let state = ServerState::new(ServerConfig::dev());
+        let run = |args: &[&str]| execute(parse_cmd(&frame(args)).unwrap(), 1, &state);
+        run(&["QUEUE", "CREATE", "jobs"]);
+        run(&["PUSH", "jobs", "resize"]);
+        let receipt = {
+            let mut queues = state.queues.lock().unwrap();
+            queues.get_mut("jobs").unwrap().pop_for(7, 1)[0].receipt()
+        };
+        let listed = |state: &ServerState| {
+            String::from_utf8(execute(
+                parse_cmd(&frame(&["QUEUE", "INFLIGHT", "jobs"])).unwrap(),
+                1,
+                state,
+            ))
+            .unwrap()
+        };
+        let before = listed(&state);
+        assert!(before.starts_with("*1\r\n%7\r\n+id\r\n"));
+        assert!(before.contains("+consumer\r\n:7\r\n"));
+        assert!(before.contains("+progress\r\n_\r\n+progress_updated_at\r\n_\r\n"));
+
+        assert_eq!(run(&["PROGRESS", "jobs", &receipt, "40%"]), b":1\r\n");
+        assert!(listed(&state).contains("+progress\r\n:40\r\n+progress_updated_at\r\n:"));
+        assert_eq!(run(&["PROGRESS", "jobs", &receipt, "uploading"]), b":1\r\n");
+        assert!(listed(&state).contains("+progress\r\n$9\r\nuploading\r\n"));
+
+        run(&["ACK", "jobs", &receipt]);
+        assert_eq!(run(&["PROGRESS", "jobs", &receipt, "100"]), b":0\r\n");
+        assert_eq!(listed(&state), "*0\r\n");
+        assert!(run(&["QUEUE", "INFLIGHT", "missing"]).starts_with(b"-NOQUEUE"));
+    }
+
+    #[test]
+    fn test_queue_config_dead_letters_and_retention() {
+        let state = ServerState::new(ServerConfig::dev());
+        let run = |args: &[&str]| execute(parse_cmd(&frame(args)).unwrap(), 1, &state);
+        run(&["QUEUE", "CREATE", "jobs"]);
+        let set = |param: &str, value: &str| run(&["QUEUE", "CONFIG", "jobs", "SET", param, value]);
+        assert!(set("DEADLETTER", "failed").starts_with(b"-NOQUEUE"));
+        // a queue can't be its own dead letter queue
+        assert!(parse_cmd(&frame(&[
+            "QUEUE",
+            "CONFIG",
+            "jobs",
+            "SET",
+            "DEADLETTER",
+            "jobs"
+        ]))
+        .is_err());
+        run(&["QUEUE", "CREATE", "failed"]);
+        assert_eq!(set("DEADLETTER", "failed"), b"+OK\r\n");
+        assert_eq!(
+            run(&["QUEUE", "CONFIG", "jobs", "GET", "DEADLETTER"]),
+            b"$6\r\nfailed\r\n"
+        );
+        assert_eq!(set("RETENTION", "1h"), b"+OK\r\n");
+        assert_eq!(
+            run(&["QUEUE", "CONFIG", "jobs", "GET", "RETENTION"]),
+            b":3600000\r\n"
+        );
+        set("MAXATTEMPTS", "1");
+
+        run(&["PUSH", "jobs", "doomed"]);
+        {
+            let mut queues = state.queues.lock().unwrap();
+            let q = queues.get_mut("jobs").unwrap();
+            let msg = q.pop_for(1, 1).remove(0);
+            assert!(msg.expires_at().is_some());
+            q.nack(msg.id(), 0);
+            assert_eq!(q.dead_letter_count(), 1);
+        }
+        state.sweep();
+        let queues = state.queues.lock().unwrap();
+        assert_eq!(queues["jobs"].dead_letter_count(), 0);
+        let moved = queues["failed"].peek(1);
+        assert_eq!(moved[0].body(), b"doomed");
+        assert_eq!(moved[0].attempt(), 1);
+        drop(queues);
+
+        assert_eq!(set("DEADLETTER", "NONE"), b"+OK\r\n");
+        assert_eq!(
+            run(&["QUEUE", "CONFIG", "jobs", "GET", "DEADLETTER"]),
+            b"_\r\n"
+        );
+    }
+
+    #[test]
+    fn test_dead_letters_forwarded_up_to_capacity() {
+        let state = ServerState::new(ServerConfig::dev());
+        let run = |args: &[&str]| execute(cmd(args), 1, &state);
+        run(&[
+            "QUEUE", "CREATE", "failed", "MAXDEPTH", "1", "OVERFLOW", "1",
+        ]);
+        run(&["QUEUE", "CREATE", "jobs"]);
+        run(&["QUEUE", "CONFIG", "jobs", "SET", "DEADLETTER", "failed"]);
+        run(&["QUEUE", "CONFIG", "jobs", "SET", "MAXATTEMPTS", "1"]);
+        run(&["MPUSH", "jobs", "a", "b", "c"]);
+        {
+            let mut queues = state.queues.lock().unwrap();
+            let q = queues.get_mut("jobs").unwrap();
+            for msg in q.pop_for(1, 3) {
+                q.nack(msg.id(), 0);
+            }
+            assert_eq!(q.dead_letter_count(), 3);
+        }
+
+        state.sweep();
+        let queues = state.queues.lock().unwrap();
+        assert_eq!(queues["failed"].depth(), 1);
+        assert_eq!(queues["failed"].overflow_depth(), 1);
+        assert_eq!(queues["jobs"].dead_letter_count(), 1);
+        drop(queues);
+
+        // Popping makes room in the backlog, the overflow buffer drains into it and
+        // the last dead letter takes its place.
+        run(&["POP", "failed"]);
+        state.sweep();
+        let queues = state.queues.lock().unwrap();
+        assert_eq!(queues["failed"].overflow_depth(), 1);
+        assert_eq!(queues["jobs"].dead_letter_count(), 0);
+    }
+
+    #[test]
+    fn test_info_commandstats() {
+        let state = ServerState::new(ServerConfig::default());
+        let pop = || Cmd::POP {
+            queue: "missing".to_string(),
+            count: 1,
+            on_empty: EmptyPop::Array,
+            visibility: None,
+        };
+        execute(pop(), 1, &state);
+        execute(pop(), 1, &state);
+        let info = |section: Option<&str>| {
+            let section = section.map(str::to_string);
+            String::from_utf8(execute(Cmd::INFO { section }, 1, &state)).unwrap()
+        };
+        let stats = info(Some("commandstats"));
+        assert!(stats.contains("\r\n# Commandstats\r\ncmdstat_pop:calls=2,usec="));
+        assert!(stats.contains(",failed_calls=2\r\n"));
+        assert_eq!(info(Some("keyspace")), "$0\r\n\r\n");
+
+        execute(Cmd::SERVER(ServerCmd::RESETSTATS), 1, &state);
+        // The reset itself is counted once it has run.
+        let stats = info(None);
+        assert!(stats.contains("\r\n# Commandstats\r\ncmdstat_server:calls=1,"));
+        assert!(!stats.contains("cmdstat_pop"));
+    }
+
+    #[test]
+    fn test_command_timeout() {
+        let state = ServerState::new(ServerConfig {
+            command_timeout: Some(Duration::ZERO),
+            ..ServerConfig::dev()
+        });
+        for _ in 0..300 {
+            execute(push("jobs", "x"), 1, &state);
+        }
+        let pop = || Cmd::POP {
+            queue: "jobs".to_string(),
+            count: 1000,
+            on_empty: EmptyPop::Array,
+            visibility: None,
+        };
+        // Cut short after the first batch rather than failing outright.
+        assert!(execute(pop(), 1, &state).starts_with(b"*256\r\n"));
+        assert!(execute(pop(), 1, &state).starts_with(b"*44\r\n"));
+
+        let reply = execute(Cmd::SERVER(ServerCmd::METRICS), 1, &state);
+        assert!(reply.starts_with(b"-TIMEOUT "));
+    }
+
+    #[test]
+    fn test_queue_jobs() {
+        let state = ServerState::new(ServerConfig::dev());
+        let mut q = Queue::create("jobs".to_string());
+        for i in 0..3000 {
+            q.add(Message::new("jobs".to_string(), format!("m{}", i)));
+        }
+        state.queues.lock().unwrap().insert("jobs".to_string(), q);
+        let run_job = |cmd| {
+            let reply = String::from_utf8(execute(cmd, 1, &state)).unwrap();
+            let id = reply[1..].trim_end().parse().unwrap();
+            assert_eq!(wait_for_job(&state.jobs, id), JobState::Done);
+            id
+        };
+        let depth = |name: &str| state.queues.lock().unwrap()[name].depth();
+
+        run_job(Cmd::QUEUE(QueueCmd::CLONE {
+            source: "jobs".to_string(),
+            destination: "jobs.copy".to_string(),
+        }));
+        assert_eq!(depth("jobs.copy"), 3000);
+        let path = std::env::temp_dir().join(format!("infinity_q_export_{}.jsonl", Uuid::new_v4()));
+        run_job(Cmd::QUEUE(QueueCmd::EXPORT {
+            name: "jobs".to_string(),
+            path: path.display().to_string(),
+        }));
+        let exported = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(exported.lines().count(), 3000);
+        assert!(exported.starts_with("{\"messageBody\":\"m0\""));
+
+        let purge = run_job(Cmd::QUEUE(QueueCmd::PURGE {
+            name: "jobs".to_string(),
+        }));
+        assert_eq!(depth("jobs"), 0);
+        assert_eq!(depth("jobs.copy"), 3000);
+        let status =
+            String::from_utf8(execute(Cmd::JOB(JobCmd::STATUS(purge)), 1, &state)).unwrap();
+        assert!(status.contains("+state\r\n+done\r\n+done\r\n:3000\r\n"));
+        run_job(Cmd::QUEUE(QueueCmd::REDRIVE {
+            name: "jobs".to_string(),
+            count: None,
+            priority: RedrivePriority::Backlog,
+        }));
+
+        assert!(execute(Cmd::JOB(JobCmd::LIST), 1, &state).starts_with(b"*4\r\n"));
+        let digest = |name: &str| {
+            execute(
+                Cmd::QUEUE(QueueCmd::DIGEST {
+                    name: name.to_string(),
+                }),
+                1,
+                &state,
+            )
+        };
+        assert_eq!(
+            digest("jobs"),
+            b"%2\r\n+pending\r\n:0\r\n+digest\r\n$16\r\n0000000000000000\r\n"
+        );
+        assert!(digest("jobs.copy").starts_with(b"%2\r\n+pending\r\n:3000\r\n"));
+        assert_eq!(
+            execute(Cmd::JOB(JobCmd::CANCEL(purge)), 1, &state),
+            b":0\r\n"
+        );
+        assert!(execute(Cmd::JOB(JobCmd::STATUS(99)), 1, &state).starts_with(b"-NOJOB unknown job"));
+        let purge_missing = Cmd::QUEUE(QueueCmd::PURGE {
+            name: "nope".to_string(),
+        });
+        assert!(execute(purge_missing, 1, &state).starts_with(b"-NOQUEUE"));
+    }
+
+    #[test]
+    fn test_redrive_to() {
+        let state = ServerState::new(ServerConfig::dev());
+        let mut session = Session::new("0.0.0.0".to_string());
+        let mut send = |args: &[&str]| send(&mut session, &state, args);
+        for body in ["a", "b", "c"] {
+            send(&["PUSH", "jobs.failed", body]);
+        }
+        // Delivered once more before it is replayed.
+        let popped = send(&["POP", "jobs.failed"]);
+        let receipt = popped.rsplit("\r\n").nth(3).unwrap();
+        assert_eq!(send(&["NACK", "jobs.failed", receipt]), ":1\r\n");
+        assert_eq!(
+            state.queues.read().unwrap()["jobs.failed"].peek(3)[0].attempt(),
+            2
+        );
+        assert_eq!(
+            send(&["QUEUE", "CREATE", "jobs", "MAXDEPTH", "2"]),
+            "+OK\r\n"
+        );
+        send(&["PUSH", "jobs", "first"]);
+
+        let redrive = |send: &mut dyn FnMut(&[&str]) -> String| {
+            let reply = send(&["QUEUE", "REDRIVE", "jobs.failed", "TO", "jobs"]);
+            let id = reply[1..].trim_end().parse().unwrap();
+            (wait_for_job(&state.jobs, id), id)
+        };
+        let (outcome, id) = redrive(&mut send);
+        assert_eq!(outcome, JobState::Failed);
+        let status = String::from_utf8(execute(Cmd::JOB(JobCmd::STATUS(id)), 1, &state)).unwrap();
+        assert!(status.contains("'jobs' is full, 2 messages left in 'jobs.failed'"));
+        {
+            let queues = state.queues.read().unwrap();
+            assert_eq!(queues["jobs"].depth(), 2);
+            let replayed = &queues["jobs"].peek(2)[1];
+            assert_eq!((replayed.body(), replayed.attempt()), (&b"a"[..], 1));
+            assert_eq!(queues["jobs.failed"].depth(), 2);
+        }
+        // Over a lowered MAXDEPTH, dropping the oldest wouldn't make room.
+        for (param, value) in [("ONFULL", "DROPOLDEST"), ("MAXDEPTH", "1")] {
+            let reply = send(&["QUEUE", "CONFIG", "jobs", "SET", param, value]);
+            assert_eq!(reply, "+OK\r\n");
+        }
+        assert_eq!(redrive(&mut send).0, JobState::Failed);
+        {
+            let queues = state.queues.read().unwrap();
+            assert_eq!(queues["jobs"].depth(), 2);
+            assert_eq!(queues["jobs.failed"].depth(), 2);
+        }
+
+        send(&["QUEUE", "DELETE", "jobs"]);
+        send(&["QUEUE", "CREATE", "jobs"]);
+        assert_eq!(redrive(&mut send).0, JobState::Done);
+        let queues = state.queues.read().unwrap();
+        assert_eq!(queues["jobs.failed"].depth(), 0);
+        assert_eq!(queues["jobs"].depth(), 2);
+        drop(queues);
+        assert!(send(&["QUEUE", "REDRIVE", "jobs.failed", "TO", "missing"]).starts_with("-NOQUEUE"));
+    }
+
+    #[test]
+    fn test_debug_trace() {
+        let state = ServerState::new(ServerConfig::dev());
+        let trace = Cmd::DEBUG(DebugCmd::TRACE {
+            scope: TraceScope::Command,
+            name: "push".to_string(),
+            every: 2,
+        });
+        assert_eq!(execute(trace, 1, &state), b"+OK\r\n");
+        assert_eq!(
+            execute(Cmd::DEBUG(DebugCmd::TRACES), 1, &state),
+            b"%2\r\n+commands\r\n%1\r\n+PUSH\r\n:2\r\n+queues\r\n%0\r\n"
+        );
+    }
+
+    #[test]
+    fn test_queue_stats() {
+        let state = ServerState::new(ServerConfig::dev());
+        execute(push("jobs", "hello"), 1, &state);
+        execute(push("jobs", "hello"), 1, &state);
+        let pop = Cmd::POP {
+            queue: "jobs".to_string(),
+            count: 1,
+            on_empty: EmptyPop::Array,
+            visibility: None,
+        };
+        execute(pop, 1, &state);
+
+        let stats = Cmd::QUEUE(QueueCmd::STATS {
+            name: "jobs".to_string(),
+        });
+        let reply = String::from_utf8(execute(stats, 1, &state)).unwrap();
+        assert!(reply.starts_with(
+            "%13\r\n+depth\r\n:1\r\n+in_flight\r\n:1\r\n+in_flight_bytes\r\n:5\r\n\
+             +max_in_flight_bytes\r\n_\r\n+delayed\r\n:0\r\n\
+             +overflow\r\n:0\r\n+dead_letters\r\n:0\r\n+oldest_age_ms\r\n:"
+        ));
+        assert!(reply.contains("+enqueue_rate\r\n,0.0333"));
+        assert!(
+            reply.ends_with("+redeliveries\r\n:0\r\n+ack_cache_hits\r\n:0\r\n+paused\r\n#f\r\n")
+        );
+        let missing = Cmd::QUEUE(QueueCmd::STATS {
+            name: "nope".to_string(),
+        });
+        assert!(execute(missing, 1, &state).starts_with(b"-NOQUEUE"));
+    }
+
+    #[test]
+    fn test_queue_stats_leaves_expired_leases() {
+        let state = ServerState::new(ServerConfig::dev());
+        execute(push("jobs", "hello"), 1, &state);
+        let pop = Cmd::POP {
+            queue: "jobs".to_string(),
+            count: 1,
+            on_empty: EmptyPop::Array,
+            visibility: Some(Duration::from_millis(1)),
+        };
+        execute(pop, 1, &state);
+        std::thread::sleep(Duration::from_millis(5));
+
+        let stats = || cmd(&["QUEUE", "STATS", "jobs"]);
+        let reply = execute(stats(), 1, &state);
+        assert!(reply.starts_with(b"%13\r\n+depth\r\n:0\r\n+in_flight\r\n:1\r\n"));
+        state.sweep();
+        let reply = execute(stats(), 1, &state);
+        assert!(reply.starts_with(b"%13\r\n+depth\r\n:1\r\n+in_flight\r\n:0\r\n"));
+    }
+
+    #[test]
+    fn test_queue_delete_and_list() {
+        let state = ServerState::new(ServerConfig::dev());
+        for name in ["jobs", "emails"] {
+            execute(create(name), 1, &state);
+        }
+        assert_eq!(
+            execute(Cmd::QUEUE(QueueCmd::LIST), 1, &state),
+            b"*2\r\n$6\r\nemails\r\n$4\r\njobs\r\n"
+        );
+        execute(push("jobs", "hello"), 1, &state);
+        let pop = Cmd::POP {
+            queue: "jobs".to_string(),
+            count: 1,
+            on_empty: EmptyPop::Array,
+            visibility: None,
+        };
+        execute(pop, 1, &state);
+        let delete = |name: &str, force: bool| {
+            let delete = Cmd::QUEUE(QueueCmd::DELETE {
+                name: name.to_string(),
+                force,
+            });
+            execute(delete, 1, &state)
+        };
+        assert!(delete("jobs", false).starts_with(b"-BUSYQUEUE queue 'jobs' has 1 leased"));
+        assert_eq!(delete("emails", false), b"+OK\r\n");
+        assert_eq!(delete("jobs", true), b"+OK\r\n");
+        assert!(delete("jobs", true).starts_with(b"-NOQUEUE"));
+        assert_eq!(execute(Cmd::QUEUE(QueueCmd::LIST), 1, &state), b"*0\r\n");
+    }
+
+    #[test]
+    fn test_command_introspection() {
+        let state = ServerState::new(ServerConfig::dev());
+        let reply = execute(Cmd::COMMAND(CommandCmd::COUNT), 1, &state);
+        assert_eq!(reply, format!(":{}\r\n", wire::COMMANDS.len()).into_bytes());
+
+        let reply = execute(Cmd::COMMAND(CommandCmd::LIST), 1, &state);
+        assert!(reply.starts_with(format!("*{}\r\n", wire::COMMANDS.len()).as_bytes()));
+
+        let info = Cmd::COMMAND(CommandCmd::INFO(vec![
+            "push".to_string(),
+            "nope".to_string(),
+        ]));
+        assert_eq!(
+            execute(info, 1, &state),
+            b"*2\r\n*6\r\n$4\r\npush\r\n:-3\r\n~2\r\n+write\r\n+fast\r\n:1\r\n:1\r\n:1\r\n_\r\n"
+        );
+
+        let docs = Cmd::COMMAND(CommandCmd::DOCS(vec!["ACK".to_string()]));
+        let reply = String::from_utf8(execute(docs, 1, &state)).unwrap();
+        assert!(reply.starts_with("%1\r\n+ack\r\n%3\r\n+summary\r\n"));
+        assert!(reply.contains("$7\r\nreceipt\r\n+type\r\n$6\r\nstring\r\n"));
+    }
+
+    #[test]
+    fn test_resp2_drops_push_frames() {
+        let config = ServerConfig {
+            queue_capacity: Some(1),
+            ..ServerConfig::dev()
+        };
+        let state = ServerState::new(config);
+        assert!(execute_for(push("jobs", "hello"), 1, &state, 2).starts_with(b"$"));
+    }
+
+    #[test]
+    fn test_pop_on_empty() {
+        let state = ServerState::new(ServerConfig::dev());
+        let pop = |on_empty| Cmd::POP {
+            queue: "jobs".to_string(),
+            count: 1,
+            on_empty,
+            visibility: None,
+        };
+        assert_eq!(execute(pop(EmptyPop::Array), 1, &state), b"*0\r\n");
+        assert_eq!(execute(pop(EmptyPop::Null), 1, &state), b"_\r\n");
+        assert_eq!(execute_for(pop(EmptyPop::Null), 1, &state, 2), b"$-1\r\n");
+
+        let pop_many = Cmd::POP {
+            queue: "jobs".to_string(),
+            count: 5,
+            on_empty: EmptyPop::Null,
+            visibility: None,
+        };
+        assert_eq!(execute_for(pop_many, 1, &state, 2), b"*-1\r\n");
+    }
+
+    #[test]
+    fn test_pop_visibility() {
+        let state = ServerState::new(ServerConfig::dev());
+        for _ in 0..2 {
+            execute(push("jobs", "hello"), 1, &state);
+        }
+        let pop = |visibility| Cmd::POP {
+            queue: "jobs".to_string(),
+            count: 1,
+            on_empty: EmptyPop::Array,
+            visibility,
+        };
+        execute(pop(None), 1, &state);
+        execute(pop(Some(Duration::ZERO)), 1, &state);
+        std::thread::sleep(Duration::from_millis(2));
+
+        let mut queues = state.queues.lock().unwrap();
+        let q = queues.get_mut("jobs").unwrap();
+        assert!(q.sweep_in_flight());
+        assert_eq!(q.in_flight_count(), 1);
+        assert_eq!(q.depth(), 1);
+    }
+
+    #[test]
+    fn test_checksums() {
+        let config = ServerConfig {
+            checksums: ChecksumMode::Strict,
+            ..ServerConfig::dev()
+        };
+        let state = ServerState::new(config);
+        let push = |checksum| cmd(&["PUSH", "jobs", "hello", "CHECKSUM", checksum]);
+        assert!(execute(push("1"), 1, &state).starts_with(b"-BADCHECKSUM"));
+        execute(push("907060870"), 1, &state);
+
+        let pop = Cmd::POP {
+            queue: "jobs".to_string(),
+            count: 1,
+            on_empty: EmptyPop::Array,
+            visibility: None,
+        };
+        let reply = String::from_utf8(execute(pop, 1, &state)).unwrap();
+        assert!(reply.ends_with("$5\r\nhello\r\n:907060870\r\n"));
+        let id = reply.split("\r\n").nth(8).unwrap().to_string();
+
+        let ack = |checksum| Cmd::ACK {
+            queue: "jobs".to_string(),
+            ids: vec![id.clone()],
+            checksum,
+        };
+        assert!(execute(ack(None), 1, &state).starts_with(b"-BADCHECKSUM"));
+        assert!(execute(ack(Some(1)), 1, &state).starts_with(b"-BADCHECKSUM"));
+        assert_eq!(execute(ack(Some(907060870)), 1, &state), b":1\r\n");
+    }
+
+    #[test]
+    fn test_binary_body_round_trip() {
+        let state = ServerState::new(ServerConfig::dev());
+        execute(push("jobs", b"a\r\n\xff"), 1, &state);
+        let pop = Cmd::POP {
+            queue: "jobs".to_string(),
+            count: 1,
+            on_empty: EmptyPop::Array,
+            visibility: None,
+        };
+        assert!(execute(pop, 1, &state).ends_with(b"$4\r\na\r\n\xff\r\n"));
+    }
+
+    #[test]
+    fn test_compressed_bodies() {
+        let config = ServerConfig {
+            compress_bodies_over: Some(16),
+            ..ServerConfig::dev()
+        };
+        let state = ServerState::new(config);
+        let body = "ab".repeat(100);
+        execute(push("jobs", &body), 1, &state);
+        let queues = state.queues.lock().unwrap();
+        let stored = queues["jobs"].waiting()[0];
+        assert!(stored.compressed() && stored.body().len() < body.len());
+        drop(queues);
+
+        let delivered = format!("$200\r\n{}\r\n", body);
+        let peek = Cmd::PEEK {
+            queue: "jobs".to_string(),
+            count: 1,
+        };
+        assert!(execute(peek, 1, &state).ends_with(delivered.as_bytes()));
+        let pop = Cmd::POP {
+            queue: "jobs".to_string(),
+            count: 1,
+            on_empty: EmptyPop::Array,
+            visibility: None,
+        };
+        assert!(execute(pop, 1, &state).ends_with(delivered.as_bytes()));
+    }
+
+    #[test]
+    fn test_streamed_bodies() {
+        let config = ServerConfig {
+            stream_chunk_size: Some(4),
+            ..ServerConfig::dev()
+        };
+        let state = ServerState::new(config);
+        let pop = || Cmd::POP {
+            queue: "jobs".to_string(),
+            count: 1,
+            on_empty: EmptyPop::Array,
+            visibility: None,
+        };
+        execute(push("jobs", "tiny"), 1, &state);
+        assert!(execute(pop(), 1, &state).ends_with(b"$4\r\ntiny\r\n"));
+
+        execute(push("jobs", "larger body"), 1, &state);
+        assert!(execute(pop(), 1, &state)
+            .ends_with(b"$?\r\n;4\r\nlarg\r\n;4\r\ner b\r\n;3\r\nody\r\n;0\r\n.\r\n"));
+
+        execute(push("jobs", "larger body"), 1, &state);
+        assert!(execute_for(pop(), 1, &state, 2).ends_with(b"$11\r\nlarger body\r\n"));
+    }
+}
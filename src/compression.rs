@@ -0,0 +1,123 @@
+use crate::resp_value::RespValue;
+use std::str::FromStr;
+use strum_macros::EnumString;
+
+/// Reply compression a client can opt into with `HELLO 3 COMPRESS <algorithm>`.
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, Clone, Copy, PartialEq, EnumString)]
+#[strum(ascii_case_insensitive)]
+pub enum Compression {
+    LZ4,
+}
+
+impl Compression {
+    pub fn parse(name: &str) -> Option<Compression> {
+        Compression::from_str(name).ok()
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Compression::LZ4 => "lz4",
+        }
+    }
+
+    /// Output is prefixed with the uncompressed length as a little endian u32.
+    fn compress(&self, data: &[u8]) -> Vec<u8> {
+        match self {
+            Compression::LZ4 => lz4_flex::compress_prepend_size(data),
+        }
+    }
+}
+
+/// Rewrites every bulk string of at least `threshold` bytes in an encoded reply as
+/// `|1\r\n+compression\r\n+<algorithm>\r\n$<len>\r\n<compressed>\r\n`, i.e. a RESP3
+/// attribute naming the algorithm followed by the compressed payload. Everything else,
+/// streamed string chunks included, is copied through untouched.
+pub fn compress_bulk_strings(reply: &[u8], threshold: usize, compression: Compression) -> Vec<u8> {
+    let mut out = Vec::with_capacity(reply.len());
+    let mut i = 0;
+    while i < reply.len() {
+        let Some(eol) = find_crlf(reply, i) else {
+            out.extend_from_slice(&reply[i..]);
+            break;
+        };
+        let line = &reply[i..eol];
+        let blob = line
+            .split_first()
+            .filter(|(prefix, _)| matches!(prefix, b'$' | b';'))
+            .and_then(|(&prefix, len)| Some((prefix, std::str::from_utf8(len).ok()?)))
+            .and_then(|(prefix, len)| Some((prefix, len.parse::<usize>().ok()?)));
+        // `;0` closes a streamed string and has no payload of its own.
+        let Some((prefix, len)) = blob.filter(|&(prefix, len)| {
+            eol + 2 + len + 2 <= reply.len() && (prefix == b'$' || len > 0)
+        }) else {
+            out.extend_from_slice(&reply[i..eol + 2]);
+            i = eol + 2;
+            continue;
+        };
+        let body = &reply[eol + 2..eol + 2 + len];
+        // Chunks of a streamed string are passed through as they are.
+        if prefix == b'$' && len >= threshold {
+            let compressed = RespValue::map()
+                .field("compression", compression.name())
+                .annotate(RespValue::BulkString(compression.compress(body)));
+            out.extend_from_slice(&compressed.encode());
+        } else {
+            out.extend_from_slice(&reply[i..eol + 2 + len + 2]);
+        }
+        i = eol + 2 + len + 2;
+    }
+    out
+}
+
+fn find_crlf(buf: &[u8], start: usize) -> Option<usize> {
+    buf[start..]
+        .windows(2)
+        .position(|w| w == b"\r\n")
+        .map(|pos| start + pos)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::compression::*;
+
+    #[test]
+    fn test_small_bulk_strings_untouched() {
+        let reply = b"*1\r\n*2\r\n$2\r\nid\r\n$5\r\nhello\r\n";
+        assert_eq!(compress_bulk_strings(reply, 64, Compression::LZ4), reply);
+    }
+
+    #[test]
+    fn test_large_bulk_string_compressed() {
+        let body = "a\r\nb".repeat(100);
+        let reply = format!("*2\r\n$2\r\nid\r\n${}\r\n{}\r\n", body.len(), body);
+        let out = compress_bulk_strings(reply.as_bytes(), 64, Compression::LZ4);
+        assert!(out.len() < reply.len());
+
+        let prefix = b"*2\r\n$2\r\nid\r\n|1\r\n+compression\r\n+lz4\r\n$";
+        assert!(out.starts_with(prefix));
+        let rest = &out[prefix.len()..];
+        let eol = find_crlf(rest, 0).unwrap();
+        let len: usize = std::str::from_utf8(&rest[..eol]).unwrap().parse().unwrap();
+        let compressed = &rest[eol + 2..eol + 2 + len];
+        let decompressed = lz4_flex::decompress_size_prepended(compressed).unwrap();
+        assert_eq!(decompressed, body.as_bytes());
+        assert_eq!(&rest[eol + 2 + len..], b"\r\n");
+    }
+
+    #[test]
+    fn test_streamed_chunks_untouched() {
+        let chunk = "$5\r\nhello\r\n".repeat(10);
+        let reply = format!("*1\r\n$?\r\n;{}\r\n{}\r\n;0\r\n", chunk.len(), chunk);
+        assert_eq!(
+            compress_bulk_strings(reply.as_bytes(), 4, Compression::LZ4),
+            reply.as_bytes()
+        );
+    }
+
+    #[test]
+    fn test_parse_algorithm() {
+        assert_eq!(Compression::parse("lz4"), Some(Compression::LZ4));
+        assert_eq!(Compression::parse("zip"), None);
+    }
+}
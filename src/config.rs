@@ -0,0 +1,531 @@
+use crate::auth::{AuthProvider, StaticAuth, ADMIN, ADMIN_PW};
+use crate::routing::RoutingStrategy;
+use std::collections::HashMap;
+use std::fmt;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use strum_macros::EnumString;
+use tracing::Level;
+
+pub const DEFAULT_BIND_ADDRESS: &str = "127.0.0.1:6379";
+pub const DEFAULT_SNAPSHOT_PATH: &str = "infinity_q.snapshot.json";
+/// Sweeping more often than this costs more in queue locking than it gains in latency.
+pub const MIN_SWEEP_INTERVAL: Duration = Duration::from_millis(10);
+
+/// One problem found in the configuration, e.g.
+/// `connection_limits.max_queued_commands: must be at least 1`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConfigError {
+    /// Path of the offending setting, or the command line flag it was given with.
+    pub field: String,
+    pub message: String,
+}
+
+impl ConfigError {
+    pub fn new(field: impl Into<String>, message: impl Into<String>) -> ConfigError {
+        ConfigError {
+            field: field.into(),
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.field, self.message)
+    }
+}
+
+/// Options applied to every accepted TcpStream.
+#[derive(Debug, Clone)]
+pub struct SocketConfig {
+    /// Disables Nagle's algorithm so small replies are flushed immediately.
+    pub nodelay: bool,
+    /// Idle time before keepalive probes are sent. `None` leaves keepalive off.
+    pub keepalive: Option<Duration>,
+    /// Time between keepalive probes once probing has started.
+    pub keepalive_interval: Option<Duration>,
+    /// SO_RCVBUF in bytes. `None` keeps the OS default.
+    pub recv_buffer_size: Option<usize>,
+    /// SO_SNDBUF in bytes. `None` keeps the OS default.
+    pub send_buffer_size: Option<usize>,
+}
+
+impl Default for SocketConfig {
+    fn default() -> Self {
+        SocketConfig {
+            nodelay: true,
+            keepalive: Some(Duration::from_secs(300)),
+            keepalive_interval: Some(Duration::from_secs(60)),
+            recv_buffer_size: None,
+            send_buffer_size: None,
+        }
+    }
+}
+
+/// Runtime used for the accept/read/write path.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum NetworkBackend {
+    #[default]
+    Tokio,
+    /// Requires the `io-uring` feature and a Linux kernel with io_uring enabled.
+    IoUring,
+}
+
+/// End-to-end integrity checks on message bodies.
+#[derive(Debug, Clone, Copy, PartialEq, Default, EnumString)]
+#[strum(serialize_all = "lowercase", ascii_case_insensitive)]
+pub enum ChecksumMode {
+    #[default]
+    Off,
+    /// CRC32 bodies at push and hand the checksum out with every delivery. ACKs that
+    /// echo a checksum are checked against it.
+    On,
+    /// Like `On`, but every ACK must echo the checksum of what the consumer received.
+    Strict,
+}
+
+/// Caps on what the frame decoder accepts from a client, so a hostile header such as
+/// `*99999999` can't make it buffer without bound. Breaking one closes the connection.
+#[derive(Debug, Clone, Copy)]
+pub struct FrameLimits {
+    /// Elements, or entries for maps, in a single aggregate.
+    pub max_elements: usize,
+    /// Bytes in one bulk string or streamed string chunk, and so the largest body a
+    /// plain PUSH can bring; `--max-message-size` sets it. Checked against the length
+    /// header, before any of the body is buffered.
+    pub max_bulk_len: usize,
+    /// Bytes in one whole frame.
+    pub max_frame_bytes: usize,
+}
+
+impl Default for FrameLimits {
+    fn default() -> Self {
+        FrameLimits {
+            max_elements: 1024 * 1024,
+            max_bulk_len: 64 * 1024 * 1024,
+            max_frame_bytes: 128 * 1024 * 1024,
+        }
+    }
+}
+
+/// Caps on what one connection may have read but not yet run. Once a cap is reached
+/// nothing more is read from the socket until queued commands have run, so a client
+/// pipelining faster than it is served is slowed down by TCP instead of growing memory.
+#[derive(Debug, Clone, Copy)]
+pub struct ConnectionLimits {
+    /// Complete commands waiting to run.
+    pub max_queued_commands: usize,
+    /// Bytes of waiting commands plus any partly received one. More than
+    /// `FrameLimits::max_frame_bytes`, so the largest allowed frame can always arrive.
+    pub max_buffered_bytes: usize,
+}
+
+impl Default for ConnectionLimits {
+    fn default() -> Self {
+        ConnectionLimits {
+            max_queued_commands: 1024,
+            max_buffered_bytes: 256 * 1024 * 1024,
+        }
+    }
+}
+
+/// Resource thresholds past which low priority commands are rejected with `BUSY`.
+/// Nothing is shed while both are `None`.
+#[derive(Debug, Clone)]
+pub struct OverloadConfig {
+    /// Resident memory of the broker, in bytes.
+    pub max_memory_bytes: Option<usize>,
+    /// 1 minute load average per CPU, e.g. `0.9`.
+    pub max_cpu_load: Option<f64>,
+    /// How often memory and load are sampled.
+    pub sample_interval: Duration,
+}
+
+impl OverloadConfig {
+    pub fn enabled(&self) -> bool {
+        self.max_memory_bytes.is_some() || self.max_cpu_load.is_some()
+    }
+}
+
+impl Default for OverloadConfig {
+    fn default() -> Self {
+        OverloadConfig {
+            max_memory_bytes: None,
+            max_cpu_load: None,
+            sample_interval: Duration::from_secs(1),
+        }
+    }
+}
+
+/// Primary a new replica copies its queues from before it starts serving; see
+/// `bootstrap::fetch_snapshot`.
+#[derive(Debug, Clone)]
+pub struct BootstrapSource {
+    /// `host:port` of the primary.
+    pub address: String,
+    /// An admin on the primary, as `SERVER SNAPSHOT` is an admin command.
+    pub user: String,
+    pub password: String,
+}
+
+impl BootstrapSource {
+    /// Logs in with the credentials `StaticAuth::default()` accepts.
+    pub fn new(address: impl Into<String>) -> BootstrapSource {
+        BootstrapSource {
+            address: address.into(),
+            user: ADMIN.to_string(),
+            password: ADMIN_PW.to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    /// Every address gets its own listener; all of them serve the same queues.
+    pub bind_addresses: Vec<String>,
+    pub socket: SocketConfig,
+    pub backend: NetworkBackend,
+    /// Visibility timeout given to queues created on demand. Sub-second values are
+    /// fine as long as `sweep_interval` is at most as long.
+    pub in_flight_expiration_ms: i64,
+    /// How often expired leases are put back in their queues, and messages whose TTL
+    /// ran out taken out of them, independently of POPs.
+    pub sweep_interval: Duration,
+    /// Messages whose TTL runs out go to the dead letters, where `QUEUE REDRIVE` can
+    /// find them, instead of being dropped.
+    pub dead_letter_expired: bool,
+    /// How long a `PUSH ... DEDUP <key>` is remembered, so a retry within it is
+    /// acknowledged without queueing the message twice. `QUEUE CREATE ... DEDUP
+    /// <window>` overrides it for one queue.
+    pub dedup_window: Duration,
+    /// Where `SHUTDOWN SAVE` writes the queue contents.
+    pub snapshot_path: PathBuf,
+    /// Keep everything in memory; `SHUTDOWN SAVE` behaves like `NOSAVE`.
+    pub in_memory: bool,
+    /// Start as a warm standby: copy every queue from this primary before serving.
+    pub bootstrap_from: Option<BootstrapSource>,
+    /// Create queues on the first PUSH/POP instead of requiring `QUEUE CREATE`.
+    pub auto_create_queues: bool,
+    /// Reject every command except HELLO until the client has authenticated.
+    pub auth_required: bool,
+    /// Checks HELLO AUTH credentials and resolves what each user may do.
+    pub auth: Arc<dyn AuthProvider>,
+    /// Expect a PROXY protocol v1/v2 header on every connection, as sent by HAProxy and
+    /// most TCP load balancers, and report the original peer instead of the proxy.
+    pub proxy_protocol: bool,
+    /// Replies at least this long have their large bulk strings compressed for clients
+    /// that negotiated compression in HELLO.
+    pub compression_threshold: usize,
+    /// Bodies longer than this are kept compressed while they wait in a queue or its
+    /// snapshot, and delivered as they were pushed. `None` stores every body as is.
+    pub compress_bodies_over: Option<usize>,
+    /// RESP3 clients get message bodies longer than this as streamed strings, sent in
+    /// chunks of this many bytes. `None` always sends plain bulk strings.
+    pub stream_chunk_size: Option<usize>,
+    /// Acked ids each queue remembers so a retried ACK still reports success.
+    pub ack_cache_size: usize,
+    /// Shard routing per queue name; queues not listed use `RoutingStrategy::default()`.
+    pub queue_routing: HashMap<String, RoutingStrategy>,
+    /// Per-queue cap on the body bytes leased out and not yet acked.
+    pub max_in_flight_bytes: Option<usize>,
+    /// Per-queue cap on the messages leased out and not yet acked, across every
+    /// consumer; `QUEUE CREATE ... CONCURRENCY <n>` overrides it for one queue.
+    pub max_in_flight: Option<usize>,
+    /// Hard cap on waiting messages per queue; PUSH is rejected once it is reached.
+    /// `QUEUE CREATE ... MAXDEPTH <n>` overrides it for one queue, and `ONFULL` picks
+    /// what a push to the full queue does.
+    pub queue_capacity: Option<usize>,
+    /// Share of `queue_capacity`, in percent, past which producers are warned.
+    pub soft_limit_percent: u8,
+    pub checksums: ChecksumMode,
+    pub frame_limits: FrameLimits,
+    pub connection_limits: ConnectionLimits,
+    pub overload: OverloadConfig,
+    /// How long one command may run. POP hands out what it leased so far once this is
+    /// used up; `SERVER METRICS` is cancelled with `TIMEOUT`. `None` lets commands run
+    /// to completion.
+    pub command_timeout: Option<Duration>,
+    /// Queues exported with their own `queue` label by `SERVER METRICS`; the rest are
+    /// summed under `queue="other"` to keep the number of series bounded.
+    pub metrics_max_queue_labels: usize,
+    /// Most verbose level that gets logged. Commands are logged at `DEBUG`.
+    pub log_level: Level,
+}
+
+impl ServerConfig {
+    pub fn routing_for(&self, queue: &str) -> RoutingStrategy {
+        self.queue_routing.get(queue).copied().unwrap_or_default()
+    }
+
+    /// Depth at which a queue holding at most `capacity` counts as nearly full.
+    pub fn soft_limit(&self, capacity: usize) -> usize {
+        capacity * self.soft_limit_percent.min(100) as usize / 100
+    }
+
+    /// Checks every setting and reports all the problems found, not just the first.
+    /// Besides values out of range, it rejects combinations that can't be honoured: a
+    /// lease can only come back as quickly as the sweep that finds it expired, and a
+    /// connection must be able to buffer the largest frame it is allowed to send.
+    pub fn validate(&self) -> Result<(), Vec<ConfigError>> {
+        let mut errors = Vec::new();
+        if self.bind_addresses.is_empty() {
+            errors.push(ConfigError::new(
+                "bind_addresses",
+                "at least one address is needed",
+            ));
+        }
+        for (i, address) in self.bind_addresses.iter().enumerate() {
+            if let Err(message) = check_address(address) {
+                errors.push(ConfigError::new(format!("bind_addresses[{}]", i), message));
+            }
+        }
+        if let Some(source) = &self.bootstrap_from {
+            if let Err(message) = check_address(&source.address) {
+                errors.push(ConfigError::new("bootstrap_from.address", message));
+            }
+        }
+        if self.in_flight_expiration_ms <= 0 {
+            errors.push(ConfigError::new(
+                "in_flight_expiration_ms",
+                format!("must be positive, got {}", self.in_flight_expiration_ms),
+            ));
+        }
+        if self.sweep_interval < MIN_SWEEP_INTERVAL {
+            errors.push(ConfigError::new(
+                "sweep_interval",
+                format!(
+                    "must be at least {} ms, got {} ms",
+                    MIN_SWEEP_INTERVAL.as_millis(),
+                    self.sweep_interval.as_millis()
+                ),
+            ));
+        } else if self.in_flight_expiration_ms > 0
+            && self.sweep_interval.as_millis() > self.in_flight_expiration_ms as u128
+        {
+            errors.push(ConfigError::new(
+                "sweep_interval",
+                format!(
+                    "{} ms is longer than the {} ms in_flight_expiration_ms",
+                    self.sweep_interval.as_millis(),
+                    self.in_flight_expiration_ms
+                ),
+            ));
+        }
+        if self.connection_limits.max_buffered_bytes <= self.frame_limits.max_frame_bytes {
+            errors.push(ConfigError::new(
+                "connection_limits.max_buffered_bytes",
+                format!(
+                    "{} bytes can't hold a frame of frame_limits.max_frame_bytes = {}",
+                    self.connection_limits.max_buffered_bytes, self.frame_limits.max_frame_bytes
+                ),
+            ));
+        }
+        if self.connection_limits.max_queued_commands == 0 {
+            errors.push(ConfigError::new(
+                "connection_limits.max_queued_commands",
+                "must be at least 1",
+            ));
+        }
+        if self.frame_limits.max_elements == 0 {
+            errors.push(ConfigError::new(
+                "frame_limits.max_elements",
+                "must be at least 1",
+            ));
+        }
+        if self.soft_limit_percent > 100 {
+            errors.push(ConfigError::new(
+                "soft_limit_percent",
+                format!("must be at most 100, got {}", self.soft_limit_percent),
+            ));
+        }
+        let positive = [
+            ("queue_capacity", self.queue_capacity),
+            ("max_in_flight_bytes", self.max_in_flight_bytes),
+            ("max_in_flight", self.max_in_flight),
+            ("stream_chunk_size", self.stream_chunk_size),
+            ("compress_bodies_over", self.compress_bodies_over),
+        ];
+        for (field, value) in positive {
+            if value == Some(0) {
+                errors.push(ConfigError::new(
+                    field,
+                    "must be at least 1; leave it unset for no limit",
+                ));
+            }
+        }
+        if self.command_timeout == Some(Duration::ZERO) {
+            errors.push(ConfigError::new(
+                "command_timeout",
+                "must be positive; leave it unset for no limit",
+            ));
+        }
+        if self
+            .overload
+            .max_cpu_load
+            .is_some_and(|load| load.is_nan() || load <= 0.0)
+        {
+            errors.push(ConfigError::new(
+                "overload.max_cpu_load",
+                "must be a positive load per CPU, e.g. 0.9",
+            ));
+        }
+        if self.overload.enabled() && self.overload.sample_interval.is_zero() {
+            errors.push(ConfigError::new(
+                "overload.sample_interval",
+                "must be positive",
+            ));
+        }
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// Relaxed settings for trying the broker out locally: queues are created on
+    /// demand, nothing is persisted and no authentication is needed.
+    pub fn dev() -> Self {
+        ServerConfig {
+            in_memory: true,
+            auto_create_queues: true,
+            auth_required: false,
+            log_level: Level::DEBUG,
+            ..ServerConfig::default()
+        }
+    }
+}
+
+/// `host:port` with a port that fits in 16 bits. The host is resolved when binding.
+fn check_address(address: &str) -> Result<(), String> {
+    let Some((host, port)) = address.rsplit_once(':') else {
+        return Err(format!("'{}' should be host:port", address));
+    };
+    if host.is_empty() {
+        return Err(format!("'{}' has no host", address));
+    }
+    match port.parse::<u16>() {
+        Ok(_) => Ok(()),
+        Err(_) => Err(format!("port '{}' isn't in 0-65535", port)),
+    }
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            bind_addresses: vec![DEFAULT_BIND_ADDRESS.to_string()],
+            socket: SocketConfig::default(),
+            backend: NetworkBackend::default(),
+            in_flight_expiration_ms: 1000,
+            sweep_interval: Duration::from_millis(100),
+            dead_letter_expired: false,
+            dedup_window: Duration::from_secs(5 * 60),
+            snapshot_path: PathBuf::from(DEFAULT_SNAPSHOT_PATH),
+            in_memory: false,
+            bootstrap_from: None,
+            auto_create_queues: false,
+            auth_required: true,
+            auth: Arc::new(StaticAuth::default()),
+            proxy_protocol: false,
+            compression_threshold: 1024,
+            compress_bodies_over: None,
+            stream_chunk_size: None,
+            ack_cache_size: 1024,
+            queue_routing: HashMap::new(),
+            max_in_flight_bytes: None,
+            max_in_flight: None,
+            queue_capacity: None,
+            soft_limit_percent: 80,
+            checksums: ChecksumMode::default(),
+            frame_limits: FrameLimits::default(),
+            connection_limits: ConnectionLimits::default(),
+            overload: OverloadConfig::default(),
+            command_timeout: Some(Duration::from_secs(1)),
+            metrics_max_queue_labels: 100,
+            log_level: Level::INFO,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::*;
+
+    #[test]
+    fn test_validate_timings() {
+        assert!(ServerConfig::default().validate().is_ok());
+
+        let config = |expiration_ms, sweep_ms| ServerConfig {
+            in_flight_expiration_ms: expiration_ms,
+            sweep_interval: Duration::from_millis(sweep_ms),
+            ..ServerConfig::default()
+        };
+        assert!(config(150, 50).validate().is_ok());
+        assert!(config(100, 100).validate().is_ok());
+        assert!(config(0, 50).validate().is_err());
+        assert!(config(150, 5).validate().is_err());
+        assert!(config(150, 500).validate().is_err());
+    }
+
+    #[test]
+    fn test_validate_connection_limits() {
+        let config = |max_queued_commands, max_buffered_bytes| ServerConfig {
+            connection_limits: ConnectionLimits {
+                max_queued_commands,
+                max_buffered_bytes,
+            },
+            frame_limits: FrameLimits {
+                max_frame_bytes: 1024,
+                ..FrameLimits::default()
+            },
+            ..ServerConfig::default()
+        };
+        assert!(config(1, 1025).validate().is_ok());
+        assert!(config(0, 1025).validate().is_err());
+        assert!(config(1, 1024).validate().is_err());
+    }
+
+    #[test]
+    fn test_validate_reports_every_problem() {
+        let config = ServerConfig {
+            bind_addresses: vec![
+                "127.0.0.1:6379".to_string(),
+                "[::1]:70000".to_string(),
+                "localhost".to_string(),
+            ],
+            bootstrap_from: Some(BootstrapSource::new("primary")),
+            soft_limit_percent: 120,
+            queue_capacity: Some(0),
+            command_timeout: Some(Duration::ZERO),
+            connection_limits: ConnectionLimits {
+                max_queued_commands: 0,
+                ..ConnectionLimits::default()
+            },
+            ..ServerConfig::default()
+        };
+        let fields: Vec<String> = config
+            .validate()
+            .unwrap_err()
+            .into_iter()
+            .map(|e| e.field)
+            .collect();
+        assert_eq!(
+            fields,
+            [
+                "bind_addresses[1]",
+                "bind_addresses[2]",
+                "bootstrap_from.address",
+                "connection_limits.max_queued_commands",
+                "soft_limit_percent",
+                "queue_capacity",
+                "command_timeout",
+            ]
+        );
+        assert_eq!(
+            ConfigError::new("soft_limit_percent", "must be at most 100").to_string(),
+            "soft_limit_percent: must be at most 100"
+        );
+        assert!(ServerConfig::dev().validate().is_ok());
+    }
+}
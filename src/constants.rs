@@ -5,21 +5,6 @@ pub const ASCII_ASTERISK: u8 = 42;
 pub const ASCII_BULK_STRING: u8 = 36;
 pub const RESP_BUFFER_SIZE: usize = 4096;
 
-pub const OKAY_RESPONSE: &str = "%7\r\n\
-+server\r\n\
-+infinity_q\r\n\
-+version\r\n\
-:1\r\n\
-+proto\r\n\
-:3\r\n\
-+id\r\n\
-$1\r\n\
-a\r\n\
-+mode\r\n\
-$10\r\n\
-standalone\r\n\
-+role\r\n\
-$6\r\n\
-master\r\n\
-+modules\r\n\
-*-1\r\n";
+/// Protocol a connection speaks until HELLO picks another one.
+pub const DEFAULT_PROTOCOL: u8 = 3;
+pub const SUPPORTED_PROTOCOLS: [u8; 2] = [2, 3];
@@ -0,0 +1,55 @@
+use crate::resp::RespError;
+use std::time::{Duration, Instant};
+
+/// When the command being run has used up `ServerConfig::command_timeout`. Handlers
+/// that loop over many messages or queues check it between steps, so one command can't
+/// keep its connection, and the queue locks it holds, busy for long.
+#[derive(Debug, Clone, Copy)]
+pub struct Deadline {
+    at: Option<Instant>,
+}
+
+impl Deadline {
+    /// `budget` from now; `None` never expires.
+    pub fn after(budget: Option<Duration>) -> Deadline {
+        Deadline {
+            at: budget.map(|budget| Instant::now() + budget),
+        }
+    }
+
+    pub fn never() -> Deadline {
+        Deadline { at: None }
+    }
+
+    pub fn expired(&self) -> bool {
+        self.at.is_some_and(|at| Instant::now() >= at)
+    }
+
+    /// `TIMEOUT` for `command` once the budget is spent.
+    pub fn check(&self, command: &str) -> Result<(), RespError> {
+        if self.expired() {
+            return Err(RespError::Timeout(command.to_string()));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::deadline::*;
+    use crate::error_code::ErrorCode;
+
+    #[test]
+    fn test_deadline() {
+        assert!(!Deadline::never().expired());
+        assert!(Deadline::after(None).check("METRICS").is_ok());
+        assert!(!Deadline::after(Some(Duration::from_secs(60))).expired());
+
+        let spent = Deadline::after(Some(Duration::ZERO));
+        assert!(spent.expired());
+        assert_eq!(
+            spent.check("METRICS").unwrap_err().code(),
+            ErrorCode::TIMEOUT
+        );
+    }
+}
@@ -0,0 +1,88 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, VecDeque};
+use std::hash::{Hash, Hasher};
+
+/// What makes two pushes the same message.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum DedupKey {
+    /// Given by the producer with `PUSH ... DEDUP <key>`.
+    Producer(String),
+    /// Hash of the body, for queues created with `DEDUP <window>`.
+    Content(u64),
+}
+
+impl DedupKey {
+    pub fn content(body: &[u8]) -> DedupKey {
+        let mut hasher = DefaultHasher::new();
+        body.hash(&mut hasher);
+        DedupKey::Content(hasher.finish())
+    }
+}
+
+/// Keys of recent pushes with the id each was given, so a producer retrying a push whose
+/// reply it never saw gets the first push's id back instead of a second copy.
+#[derive(Debug, Default)]
+pub struct DedupWindow {
+    /// Id pushed under each key, and when the key is forgotten, in ms since the epoch.
+    ids: HashMap<DedupKey, (String, i64)>,
+    /// Keys in the order they were remembered, which is the order they are forgotten in
+    /// as long as the window stays the same.
+    remembered: VecDeque<(i64, DedupKey)>,
+}
+
+impl DedupWindow {
+    /// Id of the push made under `key` that is still remembered at `now`.
+    pub fn seen(&mut self, key: &DedupKey, now: i64) -> Option<&str> {
+        self.forget(now);
+        self.ids
+            .get(key)
+            .filter(|(_, until)| *until > now)
+            .map(|(id, _)| id.as_str())
+    }
+
+    pub fn remember(&mut self, key: DedupKey, id: String, until: i64) {
+        self.remembered.push_back((until, key.clone()));
+        self.ids.insert(key, (id, until));
+    }
+
+    pub fn len(&self) -> usize {
+        self.ids.len()
+    }
+
+    fn forget(&mut self, now: i64) {
+        while let Some((until, _)) = self.remembered.front() {
+            if *until > now {
+                break;
+            }
+            let (_, key) = self.remembered.pop_front().unwrap();
+            if self.ids.get(&key).is_some_and(|(_, until)| *until <= now) {
+                self.ids.remove(&key);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::dedup::*;
+
+    #[test]
+    fn test_dedup_window() {
+        let mut window = DedupWindow::default();
+        let key = DedupKey::Producer("order-1".to_string());
+        assert_eq!(window.seen(&key, 0), None);
+        window.remember(key.clone(), "first".to_string(), 100);
+        window.remember(DedupKey::content(b"hello"), "second".to_string(), 150);
+        assert_eq!(window.seen(&key, 99), Some("first"));
+        assert_eq!(
+            window.seen(&DedupKey::content(b"hello"), 99),
+            Some("second")
+        );
+        assert_eq!(window.seen(&DedupKey::content(b"other"), 99), None);
+
+        assert_eq!(window.seen(&key, 100), None);
+        assert_eq!(window.len(), 1);
+        assert_eq!(window.seen(&DedupKey::content(b"hello"), 150), None);
+        assert_eq!(window.len(), 0);
+    }
+}
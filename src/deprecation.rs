@@ -0,0 +1,42 @@
+use crate::resp_value::RespValue;
+
+/// A command or option that keeps working for existing clients but is on its way out.
+/// Each connection is warned once per deprecation it uses, see `Session::warn_deprecated`,
+/// and every use is counted in `SERVER TELEMETRY`.
+#[derive(Debug, PartialEq)]
+pub struct Deprecation {
+    /// What the use is counted under.
+    pub name: &'static str,
+    /// What clients should send instead.
+    pub replacement: &'static str,
+}
+
+/// HELLO's `PASSWORD <pw>` without `AUTH`, which never authenticates anything.
+pub const HELLO_PASSWORD: Deprecation = Deprecation {
+    name: "hello-password",
+    replacement: "HELLO <protover> AUTH <username> <password>",
+};
+
+impl Deprecation {
+    /// RESP3 push frame warning the client, written ahead of the reply.
+    pub fn push(&self) -> RespValue {
+        RespValue::push("deprecated")
+            .item(RespValue::bulk(self.name))
+            .item(RespValue::bulk(self.replacement))
+            .build()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::deprecation::*;
+
+    #[test]
+    fn test_deprecation_push() {
+        assert_eq!(
+            HELLO_PASSWORD.push().encode(),
+            b">3\r\n+deprecated\r\n$14\r\nhello-password\r\n\
+              $43\r\nHELLO <protover> AUTH <username> <password>\r\n"
+        );
+    }
+}
@@ -0,0 +1,245 @@
+use crate::queue::{ConsumerId, Message, Queue, QueueOrder};
+use crate::resp::RespError;
+use bytes::Bytes;
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How often pops left waiting look again, so leases that expired in the meantime
+/// are redelivered to them.
+const RETRY_WAITING_EVERY: Duration = Duration::from_millis(100);
+/// Leases taken through an `Embedded` handle all belong to this consumer.
+const EMBEDDED_CONSUMER: ConsumerId = 0;
+
+type Reply<T> = Sender<Result<T, RespError>>;
+
+enum Request {
+    Create {
+        name: String,
+        order: QueueOrder,
+        reply: Reply<()>,
+    },
+    Push {
+        queue: String,
+        body: Bytes,
+        reply: Reply<String>,
+    },
+    Pop {
+        queue: String,
+        count: usize,
+        until: Instant,
+        reply: Reply<Vec<Message>>,
+    },
+    Ack {
+        queue: String,
+        id: String,
+        reply: Reply<bool>,
+    },
+}
+
+/// A POP that found nothing and waits for a push or an expired lease.
+struct WaitingPop {
+    queue: String,
+    count: usize,
+    until: Instant,
+    reply: Reply<Vec<Message>>,
+}
+
+/// The queue engine without a server or an async runtime, for CLI tools and tests.
+/// One thread owns the queues and every handle sends it requests over a channel, so
+/// handles can be cloned into as many threads as needed. The thread exits once the
+/// last handle is dropped.
+#[derive(Clone)]
+pub struct Embedded {
+    requests: Sender<Request>,
+}
+
+impl Embedded {
+    /// Starts the engine thread; leases not acked within `in_flight_expiration_ms` are
+    /// redelivered.
+    pub fn start(in_flight_expiration_ms: i64) -> std::io::Result<Embedded> {
+        let (requests, received) = mpsc::channel();
+        thread::Builder::new()
+            .name("embedded-queues".to_string())
+            .spawn(move || run(received, in_flight_expiration_ms))?;
+        Ok(Embedded { requests })
+    }
+
+    pub fn create(&self, name: &str, order: QueueOrder) -> Result<(), RespError> {
+        self.call(|reply| Request::Create {
+            name: name.to_string(),
+            order,
+            reply,
+        })
+    }
+
+    /// Returns the id of the new message.
+    pub fn push(&self, queue: &str, body: impl Into<Bytes>) -> Result<String, RespError> {
+        self.call(|reply| Request::Push {
+            queue: queue.to_string(),
+            body: body.into(),
+            reply,
+        })
+    }
+
+    /// Leases up to `count` messages, waiting up to `timeout` for the first one.
+    /// Returns nothing if none came in time.
+    pub fn pop(
+        &self,
+        queue: &str,
+        count: usize,
+        timeout: Duration,
+    ) -> Result<Vec<Message>, RespError> {
+        self.call(|reply| Request::Pop {
+            queue: queue.to_string(),
+            count,
+            until: Instant::now() + timeout,
+            reply,
+        })
+    }
+
+    /// Whether `id` was leased and is now acked.
+    pub fn ack(&self, queue: &str, id: &str) -> Result<bool, RespError> {
+        self.call(|reply| Request::Ack {
+            queue: queue.to_string(),
+            id: id.to_string(),
+            reply,
+        })
+    }
+
+    fn call<T>(&self, request: impl FnOnce(Reply<T>) -> Request) -> Result<T, RespError> {
+        let (reply, response) = mpsc::channel();
+        // The engine only stops once every handle is gone, and this one isn't.
+        self.requests
+            .send(request(reply))
+            .expect("embedded engine is running");
+        response.recv().expect("embedded engine replies")
+    }
+}
+
+fn run(requests: Receiver<Request>, in_flight_expiration_ms: i64) {
+    let mut queues: HashMap<String, Queue> = HashMap::new();
+    let mut waiting: Vec<WaitingPop> = Vec::new();
+    loop {
+        let next_deadline = waiting.iter().map(|pop| pop.until).min();
+        let wait = next_deadline.map_or(RETRY_WAITING_EVERY, |until| {
+            until
+                .saturating_duration_since(Instant::now())
+                .min(RETRY_WAITING_EVERY)
+        });
+        match requests.recv_timeout(wait) {
+            Ok(request) => handle(request, &mut queues, &mut waiting, in_flight_expiration_ms),
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => return,
+        }
+        waiting.retain(|pop| {
+            let Some(q) = queues.get_mut(&pop.queue) else {
+                let _ = pop
+                    .reply
+                    .send(Err(RespError::UnknownQueue(pop.queue.clone())));
+                return false;
+            };
+            let msgs = q.pop_for(EMBEDDED_CONSUMER, pop.count);
+            if msgs.is_empty() && Instant::now() < pop.until {
+                return true;
+            }
+            let _ = pop.reply.send(Ok(msgs));
+            false
+        });
+    }
+}
+
+fn handle(
+    request: Request,
+    queues: &mut HashMap<String, Queue>,
+    waiting: &mut Vec<WaitingPop>,
+    in_flight_expiration_ms: i64,
+) {
+    match request {
+        Request::Create { name, order, reply } => {
+            let result = match queues.entry(name) {
+                Entry::Occupied(entry) => Err(RespError::QueueExists(entry.key().clone())),
+                Entry::Vacant(entry) => {
+                    let mut q =
+                        Queue::create_with_expiration(entry.key().clone(), in_flight_expiration_ms);
+                    q.set_order(order);
+                    entry.insert(q);
+                    Ok(())
+                }
+            };
+            let _ = reply.send(result);
+        }
+        Request::Push { queue, body, reply } => {
+            let result = match queues.get_mut(&queue) {
+                Some(q) => {
+                    let msg = Message::new(queue, body);
+                    let id = msg.id().clone();
+                    q.add(msg);
+                    Ok(id)
+                }
+                None => Err(RespError::UnknownQueue(queue)),
+            };
+            let _ = reply.send(result);
+        }
+        // Tried along with the other waiting pops right after this.
+        Request::Pop {
+            queue,
+            count,
+            until,
+            reply,
+        } => waiting.push(WaitingPop {
+            queue,
+            count,
+            until,
+            reply,
+        }),
+        Request::Ack { queue, id, reply } => {
+            let result = match queues.get_mut(&queue) {
+                Some(q) => Ok(q.complete(&id)),
+                None => Err(RespError::UnknownQueue(queue)),
+            };
+            let _ = reply.send(result);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::embedded::*;
+    use crate::error_code::ErrorCode;
+
+    #[test]
+    fn test_push_pop_ack() {
+        let queues = Embedded::start(60_000).unwrap();
+        queues.create("jobs", QueueOrder::Lifo).unwrap();
+        assert_eq!(
+            queues.create("jobs", QueueOrder::Fifo).unwrap_err().code(),
+            ErrorCode::BUSYQUEUE
+        );
+        queues.push("jobs", "first").unwrap();
+        let id = queues.push("jobs", "second").unwrap();
+
+        let msgs = queues.pop("jobs", 1, Duration::ZERO).unwrap();
+        assert_eq!(msgs[0].id(), &id);
+        assert!(queues.ack("jobs", &id).unwrap());
+        assert!(queues.pop("missing", 1, Duration::ZERO).is_err());
+    }
+
+    #[test]
+    fn test_pop_waits_for_push() {
+        let queues = Embedded::start(60_000).unwrap();
+        queues.create("jobs", QueueOrder::Fifo).unwrap();
+        assert!(queues.pop("jobs", 1, Duration::ZERO).unwrap().is_empty());
+
+        let producer = queues.clone();
+        let pushed = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            producer.push("jobs", "late").unwrap()
+        });
+        let msgs = queues.pop("jobs", 1, Duration::from_secs(5)).unwrap();
+        assert_eq!(msgs[0].id(), &pushed.join().unwrap());
+        assert_eq!(msgs[0].body(), b"late");
+    }
+}
@@ -0,0 +1,38 @@
+use std::fmt;
+use strum_macros::{EnumIter, IntoStaticStr};
+
+/// Class of an error reply, sent as its first word, e.g. `-NOQUEUE unknown queue 'jobs'`.
+/// Clients branch on it rather than on the message, which is free to change. Every
+/// code is described in `wire::ERRORS`.
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, EnumIter, IntoStaticStr)]
+pub enum ErrorCode {
+    /// Anything without a class of its own; protocol errors included, as in Redis.
+    ERR,
+    WRONGPASS,
+    NOAUTH,
+    NOPERM,
+    NOPROTO,
+    NOQUEUE,
+    BUSYQUEUE,
+    QUEUEFULL,
+    TOOLARGE,
+    PREFETCH,
+    EXCLUSIVE,
+    NOJOB,
+    BADCHECKSUM,
+    BUSY,
+    TIMEOUT,
+}
+
+impl ErrorCode {
+    pub fn as_str(&self) -> &'static str {
+        self.into()
+    }
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
@@ -0,0 +1,60 @@
+use crate::queue::ConsumerId;
+use crate::resp::cancel_push;
+use crate::resp_value::RespValue;
+
+/// Events a connection hasn't written yet before the oldest are dropped.
+pub const EVENT_BACKLOG: usize = 1024;
+
+/// Something RESP3 clients hear about without asking, as a `>` push frame written
+/// between replies. RESP2 connections never get them.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ServerEvent {
+    /// A queue went from empty to holding messages. Only sent to connections that have
+    /// popped from it, so idle consumers know when to POP again.
+    MessagesAvailable(String),
+    /// `SERVER DRAIN`: POP hands out nothing until `Resumed`.
+    Paused,
+    Resumed,
+    /// The server is about to stop; clients should finish up and reconnect elsewhere.
+    ShuttingDown,
+    /// `CANCEL`: message `id` of `queue` won't be redelivered. Only sent to the
+    /// connection whose `consumer` holds the lease, so it can abandon the work.
+    Cancelled {
+        consumer: ConsumerId,
+        queue: String,
+        id: String,
+    },
+}
+
+impl ServerEvent {
+    pub fn push(&self) -> RespValue {
+        match self {
+            ServerEvent::MessagesAvailable(queue) => RespValue::push("messages-available")
+                .item(RespValue::bulk(queue))
+                .build(),
+            ServerEvent::Paused => RespValue::push("paused").build(),
+            ServerEvent::Resumed => RespValue::push("resumed").build(),
+            ServerEvent::ShuttingDown => RespValue::push("shutting-down").build(),
+            ServerEvent::Cancelled { queue, id, .. } => cancel_push(queue, id),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::events::*;
+
+    #[test]
+    fn test_event_pushes() {
+        assert_eq!(
+            ServerEvent::MessagesAvailable("jobs".to_string())
+                .push()
+                .encode(),
+            b">2\r\n+messages-available\r\n$4\r\njobs\r\n"
+        );
+        assert_eq!(
+            ServerEvent::ShuttingDown.push().encode(),
+            b">1\r\n+shutting-down\r\n"
+        );
+    }
+}
@@ -0,0 +1,106 @@
+/// Upper bounds, in milliseconds, of the buckets latencies are counted in.
+pub const LATENCY_BUCKETS_MS: &[u64] = &[
+    1, 5, 10, 25, 50, 100, 250, 500, 1_000, 2_500, 5_000, 10_000, 30_000, 60_000, 300_000,
+];
+
+/// Upper bounds, in bytes, of the buckets message bodies are counted in.
+pub const SIZE_BUCKETS_BYTES: &[u64] = &[
+    64, 256, 1_024, 4_096, 16_384, 65_536, 262_144, 1_048_576, 4_194_304, 16_777_216,
+];
+
+/// Observations counted into fixed buckets, in the shape of a Prometheus histogram: each
+/// bucket holds the values at or below its bound and above the previous one, plus one
+/// for everything past the last bound.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Histogram {
+    bounds: &'static [u64],
+    counts: Vec<u64>,
+    sum: u64,
+}
+
+impl Histogram {
+    pub fn new(bounds: &'static [u64]) -> Histogram {
+        Histogram {
+            bounds,
+            counts: vec![0; bounds.len() + 1],
+            sum: 0,
+        }
+    }
+
+    pub fn observe(&mut self, value: u64) {
+        let bucket = self.bounds.partition_point(|&bound| bound < value);
+        self.counts[bucket] += 1;
+        self.sum = self.sum.saturating_add(value);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    pub fn sum(&self) -> u64 {
+        self.sum
+    }
+
+    /// `(bound, observations at or below it)` for every bucket, the last one with no
+    /// bound. Counts are cumulative, as Prometheus' `le` buckets are.
+    pub fn cumulative(&self) -> Vec<(Option<u64>, u64)> {
+        let bounds = self.bounds.iter().copied().map(Some).chain([None]);
+        let mut total = 0;
+        bounds
+            .zip(&self.counts)
+            .map(|(bound, count)| {
+                total += count;
+                (bound, total)
+            })
+            .collect()
+    }
+
+    /// Adds `other`'s observations, which must have been counted with the same bounds.
+    pub fn merge(&mut self, other: &Histogram) {
+        debug_assert_eq!(self.bounds, other.bounds);
+        for (count, other) in self.counts.iter_mut().zip(&other.counts) {
+            *count += other;
+        }
+        self.sum = self.sum.saturating_add(other.sum);
+    }
+}
+
+/// Milliseconds from `from` to `to`, both in ms since the epoch. Clocks that went
+/// backwards count as 0.
+pub fn elapsed_ms(from: i64, to: i64) -> u64 {
+    to.saturating_sub(from).max(0) as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::histogram::*;
+
+    #[test]
+    fn test_observe() {
+        let mut histogram = Histogram::new(&[10, 100]);
+        for value in [0, 10, 11, 100, 5000] {
+            histogram.observe(value);
+        }
+        assert_eq!(histogram.count(), 5);
+        assert_eq!(histogram.sum(), 5121);
+        assert_eq!(
+            histogram.cumulative(),
+            [(Some(10), 2), (Some(100), 4), (None, 5)]
+        );
+
+        let mut merged = Histogram::new(&[10, 100]);
+        merged.observe(50);
+        merged.merge(&histogram);
+        assert_eq!(
+            merged.cumulative(),
+            [(Some(10), 2), (Some(100), 5), (None, 6)]
+        );
+        assert_eq!(merged.sum(), 5171);
+    }
+
+    #[test]
+    fn test_elapsed() {
+        assert_eq!(elapsed_ms(1_000, 1_250), 250);
+        assert_eq!(elapsed_ms(1_250, 1_000), 0);
+    }
+}
@@ -0,0 +1,269 @@
+use crate::queue::now_ms;
+use crate::resp_value::RespValue;
+use std::collections::BTreeMap;
+use std::io;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use tracing::{info, warn};
+
+/// Finished jobs remembered for `JOB STATUS`; older ones are forgotten as new ones start.
+pub const FINISHED_JOBS_KEPT: usize = 100;
+/// Messages a job handles each time it takes the queue lock, so connections get a turn
+/// between batches.
+pub const JOB_BATCH: usize = 1024;
+
+pub type JobId = u64;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum JobState {
+    Running,
+    Done,
+    Failed,
+    Cancelled,
+}
+
+impl JobState {
+    pub fn name(&self) -> &'static str {
+        match self {
+            JobState::Running => "running",
+            JobState::Done => "done",
+            JobState::Failed => "failed",
+            JobState::Cancelled => "cancelled",
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Outcome {
+    state: JobState,
+    error: Option<String>,
+    finished_at: i64,
+}
+
+/// A long admin operation such as `QUEUE PURGE`, run on a thread of its own. The work
+/// reports progress with `set_total`/`advance` and stops early once `cancelled`.
+#[derive(Debug)]
+pub struct Job {
+    id: JobId,
+    kind: &'static str,
+    target: String,
+    started_at: i64,
+    cancel: AtomicBool,
+    done: AtomicUsize,
+    total: AtomicUsize,
+    outcome: Mutex<Option<Outcome>>,
+}
+
+impl Job {
+    pub fn id(&self) -> JobId {
+        self.id
+    }
+
+    pub fn cancelled(&self) -> bool {
+        self.cancel.load(Ordering::Relaxed)
+    }
+
+    pub fn set_total(&self, total: usize) {
+        self.total.store(total, Ordering::Relaxed);
+    }
+
+    pub fn advance(&self, by: usize) {
+        self.done.fetch_add(by, Ordering::Relaxed);
+    }
+
+    pub fn done(&self) -> usize {
+        self.done.load(Ordering::Relaxed)
+    }
+
+    pub fn state(&self) -> JobState {
+        self.outcome
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map_or(JobState::Running, |outcome| outcome.state)
+    }
+
+    fn finish(&self, result: Result<(), String>) {
+        let (state, error) = match result {
+            Err(e) => (JobState::Failed, Some(e)),
+            Ok(()) if self.cancelled() => (JobState::Cancelled, None),
+            Ok(()) => (JobState::Done, None),
+        };
+        match &error {
+            Some(e) => {
+                warn!(job = self.id, kind = self.kind, target = %self.target, error = %e, "job failed")
+            }
+            None => {
+                info!(job = self.id, kind = self.kind, target = %self.target, done = self.done(), state = state.name(), "job finished")
+            }
+        }
+        *self.outcome.lock().unwrap() = Some(Outcome {
+            state,
+            error,
+            finished_at: now_ms(),
+        });
+    }
+
+    /// The `JOB STATUS` reply.
+    pub fn status(&self) -> RespValue {
+        let outcome = self.outcome.lock().unwrap();
+        let mut status = RespValue::map()
+            .field("id", self.id)
+            .field("kind", RespValue::bulk(self.kind))
+            .field("target", RespValue::bulk(&self.target))
+            .field(
+                "state",
+                outcome
+                    .as_ref()
+                    .map_or(JobState::Running, |outcome| outcome.state)
+                    .name(),
+            )
+            .field("done", self.done())
+            .field("total", self.total.load(Ordering::Relaxed))
+            .field("started-at", self.started_at);
+        if let Some(outcome) = outcome.as_ref() {
+            status = status.field("finished-at", outcome.finished_at);
+            if let Some(error) = &outcome.error {
+                status = status.field("error", RespValue::bulk(error));
+            }
+        }
+        status.build()
+    }
+}
+
+/// Every job started since the server came up, minus the oldest finished ones.
+#[derive(Debug, Default)]
+pub struct Jobs {
+    next_id: AtomicU64,
+    jobs: Mutex<BTreeMap<JobId, Arc<Job>>>,
+}
+
+impl Jobs {
+    /// Starts `work` on a new thread and returns its id right away.
+    pub fn spawn<F>(&self, kind: &'static str, target: &str, work: F) -> io::Result<JobId>
+    where
+        F: FnOnce(&Job) -> Result<(), String> + Send + 'static,
+    {
+        let job = Arc::new(Job {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed) + 1,
+            kind,
+            target: target.to_string(),
+            started_at: now_ms(),
+            cancel: AtomicBool::new(false),
+            done: AtomicUsize::new(0),
+            total: AtomicUsize::new(0),
+            outcome: Mutex::new(None),
+        });
+        let worker = job.clone();
+        std::thread::Builder::new()
+            .name(format!("job-{}", job.id))
+            .spawn(move || worker.finish(work(&worker)))?;
+        info!(job = job.id, kind, target, "job started");
+
+        let mut jobs = self.jobs.lock().unwrap();
+        jobs.insert(job.id, job.clone());
+        let finished: Vec<JobId> = jobs
+            .values()
+            .filter(|job| job.state() != JobState::Running)
+            .map(|job| job.id)
+            .collect();
+        for id in finished
+            .iter()
+            .take(finished.len().saturating_sub(FINISHED_JOBS_KEPT))
+        {
+            jobs.remove(id);
+        }
+        Ok(job.id)
+    }
+
+    pub fn get(&self, id: JobId) -> Option<Arc<Job>> {
+        self.jobs.lock().unwrap().get(&id).cloned()
+    }
+
+    /// Oldest first.
+    pub fn list(&self) -> Vec<Arc<Job>> {
+        self.jobs.lock().unwrap().values().cloned().collect()
+    }
+
+    /// Asks the job to stop after its current batch. `None` for an unknown id, otherwise
+    /// whether it was still running.
+    pub fn cancel(&self, id: JobId) -> Option<bool> {
+        let job = self.get(id)?;
+        let running = job.state() == JobState::Running;
+        if running {
+            job.cancel.store(true, Ordering::Relaxed);
+        }
+        Some(running)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::jobs::*;
+    use crate::test_utils::wait_for_job as wait;
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    #[test]
+    fn test_job_outcomes() {
+        let jobs = Jobs::default();
+        let done = jobs
+            .spawn("count", "jobs", |job| {
+                job.set_total(3);
+                job.advance(3);
+                Ok(())
+            })
+            .unwrap();
+        let failed = jobs
+            .spawn("fail", "jobs", |_| Err("disk full".to_string()))
+            .unwrap();
+        assert_eq!(wait(&jobs, done), JobState::Done);
+        assert_eq!(wait(&jobs, failed), JobState::Failed);
+        assert_eq!(jobs.get(done).unwrap().done(), 3);
+        assert!(jobs
+            .get(failed)
+            .unwrap()
+            .status()
+            .encode()
+            .ends_with(b"$9\r\ndisk full\r\n"));
+        assert_eq!(jobs.cancel(done), Some(false));
+        assert_eq!(jobs.cancel(99), None);
+        assert_eq!(jobs.list().len(), 2);
+    }
+
+    #[test]
+    fn test_cancel_job() {
+        let jobs = Jobs::default();
+        let (started, wait_started) = mpsc::channel();
+        let id = jobs
+            .spawn("spin", "jobs", move |job| {
+                started.send(()).unwrap();
+                while !job.cancelled() {
+                    job.advance(1);
+                    std::thread::sleep(Duration::from_millis(1));
+                }
+                Ok(())
+            })
+            .unwrap();
+        wait_started.recv().unwrap();
+        assert_eq!(jobs.get(id).unwrap().state(), JobState::Running);
+        assert_eq!(jobs.cancel(id), Some(true));
+        assert_eq!(wait(&jobs, id), JobState::Cancelled);
+    }
+
+    #[test]
+    fn test_finished_jobs_forgotten() {
+        let jobs = Jobs::default();
+        let first = jobs.spawn("noop", "jobs", |_| Ok(())).unwrap();
+        wait(&jobs, first);
+        for _ in 0..FINISHED_JOBS_KEPT {
+            let id = jobs.spawn("noop", "jobs", |_| Ok(())).unwrap();
+            wait(&jobs, id);
+        }
+        // The last one may still have been running when the list was trimmed.
+        let id = jobs.spawn("noop", "jobs", |_| Ok(())).unwrap();
+        wait(&jobs, id);
+        assert!(jobs.get(first).is_none());
+        assert!(jobs.list().len() <= FINISHED_JOBS_KEPT + 1);
+    }
+}
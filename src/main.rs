@@ -1,16 +1,209 @@
+#![allow(dead_code)]
+
+use crate::auth::{EnvAuth, StaticAuth};
+use crate::config::{BootstrapSource, ConfigError, NetworkBackend, ServerConfig};
 use crate::server::TcpServer;
+use crate::units::{ByteSize, HumanDuration};
+use std::fmt::Display;
+use std::str::FromStr;
+use std::sync::Arc;
 
+mod auth;
+mod bootstrap;
+mod client;
+mod command_stats;
+mod commands;
+mod compression;
+mod config;
 mod constants;
+mod deadline;
+mod dedup;
+mod deprecation;
+#[cfg(feature = "embedded")]
+mod embedded;
+mod error_code;
+mod events;
+mod histogram;
+mod jobs;
+mod metrics;
+mod overload;
+mod profiler;
+mod proxy_protocol;
 mod queue;
+mod rate;
+mod registry;
 mod resp;
 mod resp_buffered_reader;
 mod resp_reader;
+mod resp_value;
+mod routing;
+mod rules;
+mod schedule;
+mod self_test;
 mod server;
+mod session;
+mod snapshot;
+mod telemetry;
 mod test_utils;
+mod trace_sampling;
+mod units;
+#[cfg(feature = "io-uring")]
+mod uring;
 mod utils;
+mod wait_line;
+mod wire;
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    if args.iter().any(|arg| arg == "--wire-schema") {
+        println!("{}", wire::schema_json());
+        return;
+    }
+    if args.iter().any(|arg| arg == "--self-test") {
+        if let Err(e) = self_test::run() {
+            eprintln!("self-test failed: {}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+    let dev = args.iter().any(|arg| arg == "--dev");
+    let mut config = if dev {
+        ServerConfig::dev()
+    } else {
+        ServerConfig::default()
+    };
+    if args.iter().any(|arg| arg == "--io-uring") {
+        config.backend = NetworkBackend::IoUring;
+    }
+    if args.iter().any(|arg| arg == "--proxy-protocol") {
+        config.proxy_protocol = true;
+    }
+    if args.iter().any(|arg| arg == "--dead-letter-expired") {
+        config.dead_letter_expired = true;
+    }
+    let mut errors = Vec::new();
+    if let Some(level) = parsed_flag(&args, "--log-level", &mut errors) {
+        config.log_level = level;
+    }
+    if let Some(mode) = parsed_flag(&args, "--checksums", &mut errors) {
+        config.checksums = mode;
+    }
+    // Durations are in ms unless given with a unit, e.g. `--sweep-interval-ms 2s`.
+    if let Some(HumanDuration(expiration)) =
+        parsed_flag(&args, "--in-flight-expiration-ms", &mut errors)
+    {
+        config.in_flight_expiration_ms = expiration.as_millis() as i64;
+    }
+    if let Some(HumanDuration(interval)) = parsed_flag(&args, "--sweep-interval-ms", &mut errors) {
+        config.sweep_interval = interval;
+    }
+    if let Some(HumanDuration(window)) = parsed_flag(&args, "--dedup-window", &mut errors) {
+        config.dedup_window = window;
+    }
+    if let Some(mb) = parsed_flag::<usize>(&args, "--max-memory-mb", &mut errors) {
+        config.overload.max_memory_bytes = Some(mb * 1024 * 1024);
+    }
+    if let Some(ByteSize(bytes)) = parsed_flag(&args, "--max-message-size", &mut errors) {
+        config.frame_limits.max_bulk_len = bytes;
+    }
+    if let Some(ByteSize(bytes)) = parsed_flag(&args, "--compress-bodies-over", &mut errors) {
+        config.compress_bodies_over = Some(bytes);
+    }
+    if let Some(ByteSize(bytes)) = parsed_flag(&args, "--max-memory", &mut errors) {
+        config.overload.max_memory_bytes = Some(bytes);
+    }
+    if let Some(load) = parsed_flag(&args, "--max-cpu-load", &mut errors) {
+        config.overload.max_cpu_load = Some(load);
+    }
+    if let Some(HumanDuration(timeout)) = parsed_flag(&args, "--command-timeout-ms", &mut errors) {
+        // 0 lets commands run to completion.
+        config.command_timeout = (!timeout.is_zero()).then_some(timeout);
+    }
+    if let Some(path) = flag_value(&args, "--auth-file") {
+        match StaticAuth::from_file(std::path::Path::new(path)) {
+            Ok(auth) => config.auth = Arc::new(auth),
+            Err(e) => errors.push(ConfigError::new(
+                "--auth-file",
+                format!("couldn't load {}: {}", path, e),
+            )),
+        }
+    }
+    if let Some(address) = flag_value(&args, "--bootstrap-from") {
+        let mut source = BootstrapSource::new(address);
+        if let Some(user) = flag_value(&args, "--bootstrap-user") {
+            source.user = user.to_string();
+        }
+        if let Some(password) = flag_value(&args, "--bootstrap-password") {
+            source.password = password.to_string();
+        }
+        config.bootstrap_from = Some(source);
+    }
+    if args.iter().any(|arg| arg == "--auth-env") {
+        config.auth = Arc::new(EnvAuth::default());
+    }
+    if let Err(problems) = config.validate() {
+        errors.extend(problems);
+    }
+    if !errors.is_empty() {
+        eprintln!("invalid configuration:");
+        for error in &errors {
+            eprintln!("  {}", error);
+        }
+        std::process::exit(1);
+    }
+    tracing_subscriber::fmt()
+        .with_max_level(config.log_level)
+        .init();
+    if dev {
+        print_dev_banner(&config);
+    }
+    let server = TcpServer::new(config);
+    server.run().expect("TODO: panic message");
+}
+
+fn print_dev_banner(config: &ServerConfig) {
+    println!("infinity_q {} (dev mode)", env!("CARGO_PKG_VERSION"));
+    println!("  queues are created on first use, kept in memory only, no auth required");
+    for address in &config.bind_addresses {
+        println!("  listening on {}", address);
+    }
+    println!();
+    println!("try it with redis-cli:");
+    println!("  redis-cli PUSH jobs hello");
+    println!("  redis-cli POP jobs 10");
+    println!("  redis-cli ACK jobs <id>");
+    println!("  redis-cli SHUTDOWN");
+}
+
+/// The value of `flag` parsed as `T`. A value that doesn't parse, or a flag given
+/// without one, is noted in `errors` so every bad flag is reported together.
+fn parsed_flag<T: FromStr>(args: &[String], flag: &str, errors: &mut Vec<ConfigError>) -> Option<T>
+where
+    T::Err: Display,
+{
+    if !args.iter().any(|arg| arg == flag) {
+        return None;
+    }
+    let Some(value) = flag_value(args, flag) else {
+        errors.push(ConfigError::new(flag, "needs a value"));
+        return None;
+    };
+    match value.parse() {
+        Ok(value) => Some(value),
+        Err(e) => {
+            errors.push(ConfigError::new(
+                flag,
+                format!("invalid value '{}': {}", value, e),
+            ));
+            None
+        }
+    }
+}
 
-#[tokio::main]
-async fn main() {
-    let server = TcpServer::new();
-    server.start().await.expect("TODO: panic message");
+/// Value following `flag`, e.g. `--log-level debug`.
+fn flag_value<'a>(args: &'a [String], flag: &str) -> Option<&'a str> {
+    args.iter()
+        .position(|arg| arg == flag)
+        .and_then(|i| args.get(i + 1))
+        .map(String::as_str)
 }
@@ -0,0 +1,333 @@
+use crate::deadline::Deadline;
+use crate::histogram::Histogram;
+use crate::queue::Queue;
+use crate::resp::RespError;
+use std::collections::HashMap;
+use std::fmt::Write;
+
+/// Bucket that every queue past the label cap is summed into.
+pub const OTHER_QUEUES: &str = "other";
+
+struct Gauge {
+    name: &'static str,
+    help: &'static str,
+    value: fn(&Queue) -> usize,
+}
+
+const GAUGES: &[Gauge] = &[
+    Gauge {
+        name: "infinity_q_queue_depth",
+        help: "Messages waiting to be popped.",
+        value: Queue::depth,
+    },
+    Gauge {
+        name: "infinity_q_queue_in_flight",
+        help: "Messages leased out and not yet acked.",
+        value: Queue::in_flight_count,
+    },
+    Gauge {
+        name: "infinity_q_queue_in_flight_bytes",
+        help: "Body bytes leased out and not yet acked.",
+        value: Queue::in_flight_bytes,
+    },
+    Gauge {
+        name: "infinity_q_queue_max_in_flight_bytes",
+        help: "Most body bytes the queue leases out at once, 0 when it has no cap.",
+        value: |q| q.max_in_flight_bytes().unwrap_or(0),
+    },
+    Gauge {
+        name: "infinity_q_queue_dead_letters",
+        help: "Messages that ran out of delivery attempts.",
+        value: Queue::dead_letter_count,
+    },
+    Gauge {
+        name: "infinity_q_queue_overflow",
+        help: "Pushes held in the overflow buffer until the queue has room.",
+        value: Queue::overflow_depth,
+    },
+];
+
+struct Counter {
+    name: &'static str,
+    help: &'static str,
+    value: fn(&Queue) -> u64,
+}
+
+const COUNTERS: &[Counter] = &[
+    Counter {
+        name: "infinity_q_queue_expired_at_delivery_total",
+        help: "Messages not delivered because their TTL had run out when they came up.",
+        value: Queue::expired_at_delivery,
+    },
+    Counter {
+        name: "infinity_q_queue_expired_waiting_total",
+        help: "Messages taken out of line by the sweep because their TTL had run out.",
+        value: Queue::expired_waiting,
+    },
+    Counter {
+        name: "infinity_q_queue_overflowed_total",
+        help: "Pushes to a full queue absorbed by its overflow buffer.",
+        value: Queue::overflowed,
+    },
+    Counter {
+        name: "infinity_q_queue_dropped_total",
+        help: "Waiting messages dropped to make room in a full queue.",
+        value: Queue::dropped,
+    },
+    Counter {
+        name: "infinity_q_queue_deduplicated_total",
+        help: "Duplicate pushes acknowledged without being queued.",
+        value: Queue::deduplicated,
+    },
+    Counter {
+        name: "infinity_q_queue_ack_cache_hits_total",
+        help: "Repeated acks answered from the ack cache.",
+        value: Queue::ack_cache_hits,
+    },
+];
+
+struct HistogramMetric {
+    name: &'static str,
+    help: &'static str,
+    value: fn(&Queue) -> &Histogram,
+    /// What the recorded values are divided by to get the exported unit: latencies are
+    /// kept in milliseconds and exported in seconds, as Prometheus expects.
+    scale: f64,
+}
+
+const HISTOGRAMS: &[HistogramMetric] = &[
+    HistogramMetric {
+        name: "infinity_q_queue_time_in_queue_seconds",
+        help: "Time from push to first delivery.",
+        value: Queue::time_in_queue,
+        scale: 1000.0,
+    },
+    HistogramMetric {
+        name: "infinity_q_queue_processing_seconds",
+        help: "Time from delivery to ack.",
+        value: Queue::processing_time,
+        scale: 1000.0,
+    },
+    HistogramMetric {
+        name: "infinity_q_queue_body_size_bytes",
+        help: "Sizes of a sample of the bodies pushed.",
+        value: Queue::body_sizes,
+        scale: 1.0,
+    },
+];
+
+/// Per-queue gauges and histograms in the Prometheus text exposition format. Only the
+/// `max_queue_labels` deepest queues, ties broken by name, get a series of their own;
+/// the rest are added up under `queue="other"`, so a deployment with thousands of
+/// tenant queues still exports a bounded number of series. `deadline` is checked
+/// before each metric.
+pub fn render(
+    queues: &HashMap<String, Queue>,
+    max_queue_labels: usize,
+    deadline: &Deadline,
+) -> Result<String, RespError> {
+    let mut ranked: Vec<(&String, &Queue)> = queues.iter().collect();
+    ranked.sort_by(|(a_name, a), (b_name, b)| b.depth().cmp(&a.depth()).then(a_name.cmp(b_name)));
+    let (labelled, rest) = ranked.split_at(max_queue_labels.min(ranked.len()));
+
+    let mut out = String::new();
+    let _ = writeln!(out, "# HELP infinity_q_queues Queues on this broker.");
+    let _ = writeln!(out, "# TYPE infinity_q_queues gauge");
+    let _ = writeln!(out, "infinity_q_queues {}", queues.len());
+    for gauge in GAUGES {
+        deadline.check("SERVER METRICS")?;
+        write_series(
+            &mut out,
+            gauge.name,
+            gauge.help,
+            "gauge",
+            labelled,
+            rest,
+            |q| (gauge.value)(q) as u64,
+        );
+    }
+    for counter in COUNTERS {
+        deadline.check("SERVER METRICS")?;
+        let (name, help) = (counter.name, counter.help);
+        write_series(
+            &mut out,
+            name,
+            help,
+            "counter",
+            labelled,
+            rest,
+            counter.value,
+        );
+    }
+    for metric in HISTOGRAMS {
+        deadline.check("SERVER METRICS")?;
+        let _ = writeln!(out, "# HELP {} {}", metric.name, metric.help);
+        let _ = writeln!(out, "# TYPE {} histogram", metric.name);
+        for (name, q) in labelled {
+            write_histogram(&mut out, metric, &escape(name), (metric.value)(q));
+        }
+        if let Some(((_, first), others)) = rest.split_first() {
+            let mut total = (metric.value)(first).clone();
+            for (_, q) in others {
+                total.merge((metric.value)(q));
+            }
+            write_histogram(&mut out, metric, OTHER_QUEUES, &total);
+        }
+    }
+    Ok(out)
+}
+
+/// One value per labelled queue, plus the sum of `rest` under `queue="other"`.
+fn write_series(
+    out: &mut String,
+    name: &str,
+    help: &str,
+    kind: &str,
+    labelled: &[(&String, &Queue)],
+    rest: &[(&String, &Queue)],
+    value: impl Fn(&Queue) -> u64,
+) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+    for (queue, q) in labelled {
+        let _ = writeln!(out, "{}{{queue=\"{}\"}} {}", name, escape(queue), value(q));
+    }
+    if !rest.is_empty() {
+        let total: u64 = rest.iter().map(|(_, q)| value(q)).sum();
+        let _ = writeln!(out, "{}{{queue=\"{}\"}} {}", name, OTHER_QUEUES, total);
+    }
+}
+
+fn write_histogram(out: &mut String, metric: &HistogramMetric, queue: &str, histogram: &Histogram) {
+    let name = metric.name;
+    let scaled = |value: u64| value as f64 / metric.scale;
+    for (bound, count) in histogram.cumulative() {
+        let le = bound.map_or("+Inf".to_string(), |bound| scaled(bound).to_string());
+        let _ = writeln!(
+            out,
+            "{}_bucket{{queue=\"{}\",le=\"{}\"}} {}",
+            name, queue, le, count
+        );
+    }
+    let _ = writeln!(
+        out,
+        "{}_sum{{queue=\"{}\"}} {}",
+        name,
+        queue,
+        scaled(histogram.sum())
+    );
+    let _ = writeln!(
+        out,
+        "{}_count{{queue=\"{}\"}} {}",
+        name,
+        queue,
+        histogram.count()
+    );
+}
+
+/// Label values may hold anything but backslash, double quote and newline unescaped.
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error_code::ErrorCode;
+    use crate::metrics::*;
+    use crate::queue::Message;
+
+    fn queues(depths: &[(&str, usize)]) -> HashMap<String, Queue> {
+        depths
+            .iter()
+            .map(|&(name, depth)| {
+                let mut q = Queue::create(name.to_string());
+                for _ in 0..depth {
+                    q.add(Message::new(name.to_string(), "hello"));
+                }
+                (name.to_string(), q)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_render_every_queue() {
+        let mut queues = queues(&[("jobs", 2), ("mail", 1)]);
+        queues
+            .get_mut("jobs")
+            .unwrap()
+            .set_max_in_flight_bytes(Some(1024));
+        let out = render(&queues, 10, &Deadline::never()).unwrap();
+        assert!(out.contains("infinity_q_queues 2\n"));
+        assert!(out.contains("# TYPE infinity_q_queue_depth gauge\n"));
+        assert!(out.contains("infinity_q_queue_depth{queue=\"jobs\"} 2\n"));
+        assert!(out.contains("infinity_q_queue_depth{queue=\"mail\"} 1\n"));
+        assert!(out.contains("infinity_q_queue_max_in_flight_bytes{queue=\"jobs\"} 1024\n"));
+        assert!(out.contains("infinity_q_queue_max_in_flight_bytes{queue=\"mail\"} 0\n"));
+        assert!(out.contains("# TYPE infinity_q_queue_expired_at_delivery_total counter\n"));
+        assert!(out.contains("infinity_q_queue_expired_at_delivery_total{queue=\"jobs\"} 0\n"));
+        assert!(!out.contains(OTHER_QUEUES));
+    }
+
+    #[test]
+    fn test_render_ack_cache_hits() {
+        let mut queues = queues(&[("jobs", 1)]);
+        let q = queues.get_mut("jobs").unwrap();
+        let leased = q.pop_for(1, 1);
+        q.complete(leased[0].id());
+        q.complete(leased[0].id());
+        let out = render(&queues, 10, &Deadline::never()).unwrap();
+        assert!(out.contains("infinity_q_queue_ack_cache_hits_total{queue=\"jobs\"} 1\n"));
+    }
+
+    #[test]
+    fn test_render_caps_labels() {
+        let out = render(
+            &queues(&[("a", 1), ("b", 5), ("c", 2), ("d", 0)]),
+            2,
+            &Deadline::never(),
+        )
+        .unwrap();
+        assert!(out.contains("infinity_q_queue_depth{queue=\"b\"} 5\n"));
+        assert!(out.contains("infinity_q_queue_depth{queue=\"c\"} 2\n"));
+        assert!(out.contains("infinity_q_queue_depth{queue=\"other\"} 1\n"));
+        assert!(!out.contains("queue=\"a\""));
+        assert_eq!(out.matches("infinity_q_queue_in_flight{").count(), 3);
+    }
+
+    #[test]
+    fn test_render_cancelled() {
+        let spent = Deadline::after(Some(std::time::Duration::ZERO));
+        let err = render(&queues(&[("jobs", 2)]), 10, &spent).unwrap_err();
+        assert_eq!(err.code(), ErrorCode::TIMEOUT);
+    }
+
+    #[test]
+    fn test_render_histograms() {
+        let mut queues = queues(&[("jobs", 2), ("mail", 1), ("spam", 1)]);
+        for q in queues.values_mut() {
+            let leased = q.pop_for(1, 1);
+            q.complete(leased[0].id());
+        }
+        let out = render(&queues, 2, &Deadline::never()).unwrap();
+        let name = "infinity_q_queue_time_in_queue_seconds";
+        assert!(out.contains(&format!("# TYPE {} histogram\n", name)));
+        assert!(out.contains(&format!("{}_bucket{{queue=\"jobs\",le=\"0.001\"}}", name)));
+        assert!(out.contains(&format!(
+            "{}_bucket{{queue=\"jobs\",le=\"+Inf\"}} 1\n",
+            name
+        )));
+        assert!(out.contains(&format!("{}_count{{queue=\"other\"}} 1\n", name)));
+        assert!(out.contains("infinity_q_queue_processing_seconds_count{queue=\"mail\"} 1\n"));
+        let sizes = "infinity_q_queue_body_size_bytes";
+        assert!(out.contains(&format!("{}_bucket{{queue=\"jobs\",le=\"64\"}} 1\n", sizes)));
+        assert!(out.contains(&format!("{}_count{{queue=\"other\"}} 1\n", sizes)));
+    }
+
+    #[test]
+    fn test_escape_label() {
+        assert_eq!(escape("a\"b\\c\nd"), "a\\\"b\\\\c\\nd");
+    }
+}
@@ -0,0 +1,133 @@
+use crate::config::OverloadConfig;
+use std::sync::atomic::{AtomicBool, Ordering};
+use tracing::{info, warn};
+
+/// How much a command matters when the broker is short on resources. Only `Low` is
+/// shed; everything else is what producers and consumers need to make progress.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    /// Introspection such as stats, peeks and browses.
+    Low,
+    Normal,
+    /// Pushes, acks and connection/server control.
+    Critical,
+}
+
+/// One reading of the resources the thresholds are checked against. `None` where the
+/// platform doesn't expose the number.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Pressure {
+    /// Resident set size of the broker process.
+    pub memory_bytes: Option<usize>,
+    /// 1 minute load average divided by the number of CPUs.
+    pub cpu_load: Option<f64>,
+}
+
+impl Pressure {
+    /// Reads `/proc`; on other platforms nothing is known and nothing is ever shed.
+    pub fn sample() -> Pressure {
+        Pressure {
+            memory_bytes: read_rss(),
+            cpu_load: read_load(),
+        }
+    }
+}
+
+fn read_rss() -> Option<usize> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let kb = status
+        .lines()
+        .find_map(|line| line.strip_prefix("VmRSS:"))?
+        .trim()
+        .strip_suffix("kB")?
+        .trim()
+        .parse::<usize>()
+        .ok()?;
+    Some(kb * 1024)
+}
+
+fn read_load() -> Option<f64> {
+    let loadavg = std::fs::read_to_string("/proc/loadavg").ok()?;
+    let load = loadavg.split_whitespace().next()?.parse::<f64>().ok()?;
+    let cpus = std::thread::available_parallelism().ok()?.get();
+    Some(load / cpus as f64)
+}
+
+impl OverloadConfig {
+    pub fn exceeded_by(&self, pressure: &Pressure) -> bool {
+        let memory = matches!(
+            (self.max_memory_bytes, pressure.memory_bytes),
+            (Some(max), Some(used)) if used > max
+        );
+        let cpu = matches!(
+            (self.max_cpu_load, pressure.cpu_load),
+            (Some(max), Some(load)) if load > max
+        );
+        memory || cpu
+    }
+}
+
+/// Whether low priority commands are currently being turned away.
+#[derive(Debug, Default)]
+pub struct LoadShedder {
+    shedding: AtomicBool,
+}
+
+impl LoadShedder {
+    pub fn update(&self, config: &OverloadConfig, pressure: &Pressure) {
+        let overloaded = config.exceeded_by(pressure);
+        let was = self.shedding.swap(overloaded, Ordering::Relaxed);
+        if overloaded && !was {
+            warn!(?pressure, "overloaded, shedding low priority commands");
+        } else if was && !overloaded {
+            info!(?pressure, "load back to normal");
+        }
+    }
+
+    pub fn admits(&self, priority: Priority) -> bool {
+        priority > Priority::Low || !self.shedding.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::OverloadConfig;
+    use crate::overload::*;
+
+    #[test]
+    fn test_shedding() {
+        let config = OverloadConfig {
+            max_memory_bytes: Some(1000),
+            max_cpu_load: Some(0.9),
+            ..OverloadConfig::default()
+        };
+        let shedder = LoadShedder::default();
+        let pressure = |memory_bytes, cpu_load| Pressure {
+            memory_bytes,
+            cpu_load,
+        };
+
+        shedder.update(&config, &pressure(Some(900), Some(0.5)));
+        assert!(shedder.admits(Priority::Low));
+
+        shedder.update(&config, &pressure(Some(1001), None));
+        assert!(!shedder.admits(Priority::Low));
+        assert!(shedder.admits(Priority::Normal));
+        assert!(shedder.admits(Priority::Critical));
+
+        shedder.update(&config, &pressure(None, Some(1.5)));
+        assert!(!shedder.admits(Priority::Low));
+
+        shedder.update(&config, &pressure(None, None));
+        assert!(shedder.admits(Priority::Low));
+    }
+
+    #[test]
+    fn test_no_thresholds_never_shed() {
+        let config = OverloadConfig::default();
+        assert!(!config.exceeded_by(&Pressure {
+            memory_bytes: Some(usize::MAX),
+            cpu_load: Some(100.0),
+        }));
+    }
+}
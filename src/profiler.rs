@@ -0,0 +1,124 @@
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// Longest capture `DEBUG PROFILE` accepts.
+pub const MAX_PROFILE_SECONDS: u64 = 300;
+
+/// Where a profile goes when `DEBUG PROFILE` isn't given a path.
+pub fn default_path() -> PathBuf {
+    PathBuf::from(format!(
+        "infinity_q.{}.folded",
+        chrono::Utc::now().format("%Y%m%dT%H%M%S")
+    ))
+}
+
+/// Samples every thread for `duration` on a thread of its own and then writes the
+/// stacks to `path` in the folded format read by `flamegraph.pl`, inferno and
+/// speedscope. Only one capture runs at a time.
+#[cfg(feature = "profiling")]
+pub fn start(duration: Duration, path: PathBuf) -> Result<std::thread::JoinHandle<()>, String> {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use tracing::{info, warn};
+
+    /// Samples per second; off by one from 100 so sampling doesn't line up with timers.
+    const FREQUENCY: i32 = 99;
+    static RUNNING: AtomicBool = AtomicBool::new(false);
+
+    if RUNNING.swap(true, Ordering::AcqRel) {
+        return Err("a profile is already being captured".to_string());
+    }
+    let guard = match pprof::ProfilerGuardBuilder::default()
+        .frequency(FREQUENCY)
+        .blocklist(&["libc", "libgcc", "pthread", "vdso"])
+        .build()
+    {
+        Ok(guard) => guard,
+        Err(e) => {
+            RUNNING.store(false, Ordering::Release);
+            return Err(e.to_string());
+        }
+    };
+    info!(?duration, path = %path.display(), "profiling started");
+    std::thread::Builder::new()
+        .name("profiler".to_string())
+        .spawn(move || {
+            std::thread::sleep(duration);
+            let written = guard
+                .report()
+                .build()
+                .map_err(|e| e.to_string())
+                .and_then(|report| {
+                    std::fs::write(&path, folded(&report)).map_err(|e| e.to_string())
+                });
+            drop(guard);
+            RUNNING.store(false, Ordering::Release);
+            match written {
+                Ok(()) => info!(path = %path.display(), "profile written"),
+                Err(e) => warn!(error = %e, path = %path.display(), "couldn't write profile"),
+            }
+        })
+        .map_err(|e| e.to_string())
+}
+
+#[cfg(not(feature = "profiling"))]
+pub fn start(_: Duration, _: PathBuf) -> Result<std::thread::JoinHandle<()>, String> {
+    Err("built without the `profiling` feature".to_string())
+}
+
+/// One `thread;outermost;...;innermost count` line per distinct stack.
+#[cfg(feature = "profiling")]
+fn folded(report: &pprof::Report) -> String {
+    use std::fmt::Write;
+
+    let mut out = String::new();
+    for (stack, count) in &report.data {
+        out.push_str(&stack.thread_name_or_id());
+        for symbol in stack
+            .frames
+            .iter()
+            .rev()
+            .flat_map(|frame| frame.iter().rev())
+        {
+            let _ = write!(out, ";{}", symbol);
+        }
+        let _ = writeln!(out, " {}", count);
+    }
+    out
+}
+
+#[cfg(all(test, feature = "profiling"))]
+mod tests {
+    use crate::profiler::*;
+
+    #[test]
+    fn test_profile_written() {
+        let path = std::env::temp_dir().join(format!(
+            "infinity_q_profile_{}.folded",
+            uuid::Uuid::new_v4()
+        ));
+        let capture = start(Duration::from_millis(300), path.clone()).unwrap();
+        assert!(start(Duration::from_millis(10), default_path()).is_err());
+
+        let mut spin = 0u64;
+        let until = std::time::Instant::now() + Duration::from_millis(250);
+        while std::time::Instant::now() < until {
+            spin = std::hint::black_box(spin.wrapping_add(1));
+        }
+        capture.join().unwrap();
+
+        let profile = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(profile.lines().all(|line| line
+            .rsplit_once(' ')
+            .unwrap()
+            .1
+            .parse::<u64>()
+            .is_ok()));
+        // Free again once the capture is written.
+        start(Duration::from_millis(10), path.clone())
+            .unwrap()
+            .join()
+            .unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,205 @@
+use std::fmt;
+use std::fmt::Formatter;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+use tokio::io::AsyncReadExt;
+use tokio::net::TcpStream;
+
+const V1_PREFIX: &[u8] = b"PROXY ";
+/// Longest legal v1 header, including the trailing CRLF.
+const V1_MAX_LEN: usize = 107;
+const V2_SIGNATURE: &[u8] = b"\r\n\r\n\0\r\nQUIT\n";
+const V2_HEADER_LEN: usize = 16;
+const MAX_HEADER_LEN: usize = V2_HEADER_LEN + 216;
+const PARTIAL_HEADER_BACKOFF: Duration = Duration::from_millis(5);
+
+#[derive(Debug, PartialEq)]
+pub enum ProxyError {
+    MissingHeader,
+    MalformedHeader(String),
+    UnsupportedVersion(u8),
+}
+
+impl fmt::Display for ProxyError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            ProxyError::MissingHeader => write!(f, "connection did not start with a PROXY header"),
+            ProxyError::MalformedHeader(err) => write!(f, "malformed PROXY header: {}", err),
+            ProxyError::UnsupportedVersion(v) => write!(f, "unsupported PROXY version {}", v),
+        }
+    }
+}
+
+impl From<ProxyError> for io::Error {
+    fn from(err: ProxyError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, err.to_string())
+    }
+}
+
+/// A complete header: the original peer, if the proxy disclosed one, and the header length.
+#[derive(Debug, PartialEq)]
+pub struct ProxyHeader {
+    pub source: Option<SocketAddr>,
+    pub len: usize,
+}
+
+/// Parses a v1 or v2 header from the start of `buf`. `Ok(None)` means more bytes are needed.
+pub fn parse(buf: &[u8]) -> Result<Option<ProxyHeader>, ProxyError> {
+    if buf.starts_with(V2_SIGNATURE) || V2_SIGNATURE.starts_with(buf) {
+        return parse_v2(buf);
+    }
+    if buf.starts_with(V1_PREFIX) || V1_PREFIX.starts_with(buf) {
+        return parse_v1(buf);
+    }
+    Err(ProxyError::MissingHeader)
+}
+
+fn parse_v1(buf: &[u8]) -> Result<Option<ProxyHeader>, ProxyError> {
+    let Some(end) = buf.windows(2).position(|w| w == b"\r\n") else {
+        if buf.len() >= V1_MAX_LEN {
+            return Err(ProxyError::MalformedHeader(
+                "v1 header too long".to_string(),
+            ));
+        }
+        return Ok(None);
+    };
+    let line = std::str::from_utf8(&buf[..end])
+        .map_err(|_| ProxyError::MalformedHeader("v1 header is not ascii".to_string()))?;
+    let fields: Vec<&str> = line.split(' ').collect();
+    let source = match fields.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => None,
+        ["PROXY", "TCP4" | "TCP6", src, _dst, src_port, _dst_port] => {
+            let ip = src
+                .parse::<IpAddr>()
+                .map_err(|_| ProxyError::MalformedHeader(src.to_string()))?;
+            let port = src_port
+                .parse::<u16>()
+                .map_err(|_| ProxyError::MalformedHeader(src_port.to_string()))?;
+            Some(SocketAddr::new(ip, port))
+        }
+        _ => return Err(ProxyError::MalformedHeader(line.to_string())),
+    };
+    Ok(Some(ProxyHeader {
+        source,
+        len: end + 2,
+    }))
+}
+
+fn parse_v2(buf: &[u8]) -> Result<Option<ProxyHeader>, ProxyError> {
+    if buf.len() < V2_HEADER_LEN {
+        return Ok(None);
+    }
+    let version = buf[12] >> 4;
+    if version != 2 {
+        return Err(ProxyError::UnsupportedVersion(version));
+    }
+    let command = buf[12] & 0x0f;
+    let family = buf[13];
+    let len = V2_HEADER_LEN + u16::from_be_bytes([buf[14], buf[15]]) as usize;
+    if buf.len() < len {
+        return Ok(None);
+    }
+    let addrs = &buf[V2_HEADER_LEN..len];
+    // LOCAL connections (health checks from the proxy itself) carry no client address.
+    if command == 0 {
+        return Ok(Some(ProxyHeader { source: None, len }));
+    }
+    let source = match family {
+        0x11 if addrs.len() >= 12 => {
+            let ip = Ipv4Addr::new(addrs[0], addrs[1], addrs[2], addrs[3]);
+            Some(SocketAddr::new(
+                ip.into(),
+                u16::from_be_bytes([addrs[8], addrs[9]]),
+            ))
+        }
+        0x21 if addrs.len() >= 36 => {
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(&addrs[..16]);
+            let ip = Ipv6Addr::from(octets);
+            Some(SocketAddr::new(
+                ip.into(),
+                u16::from_be_bytes([addrs[32], addrs[33]]),
+            ))
+        }
+        0x11 | 0x21 => {
+            return Err(ProxyError::MalformedHeader(
+                "address block too short".to_string(),
+            ))
+        }
+        _ => None,
+    };
+    Ok(Some(ProxyHeader { source, len }))
+}
+
+/// Consumes the PROXY header from a freshly accepted stream, leaving the first RESP
+/// frame untouched, and returns the original peer if the proxy disclosed one.
+pub async fn read_header(stream: &mut TcpStream) -> Result<Option<SocketAddr>, io::Error> {
+    let mut buf = [0u8; MAX_HEADER_LEN];
+    loop {
+        let peeked = stream.peek(&mut buf).await?;
+        if peeked == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        if let Some(header) = parse(&buf[..peeked])? {
+            stream.read_exact(&mut buf[..header.len]).await?;
+            return Ok(header.source);
+        }
+        if peeked == buf.len() {
+            return Err(ProxyError::MalformedHeader("header too long".to_string()).into());
+        }
+        // Peeked bytes keep the socket readable, so wait a beat instead of spinning.
+        tokio::time::sleep(PARTIAL_HEADER_BACKOFF).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::proxy_protocol::*;
+
+    fn v2_tcp4() -> Vec<u8> {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend_from_slice(&[0x21, 0x11, 0, 12]);
+        header.extend_from_slice(&[192, 168, 1, 7, 10, 0, 0, 1]);
+        header.extend_from_slice(&50000u16.to_be_bytes());
+        header.extend_from_slice(&6379u16.to_be_bytes());
+        header
+    }
+
+    #[test]
+    fn test_parse_v1() {
+        let raw = b"PROXY TCP4 192.168.1.7 10.0.0.1 50000 6379\r\n*1\r\n";
+        let header = parse(raw).unwrap().unwrap();
+        assert_eq!(header.source, Some("192.168.1.7:50000".parse().unwrap()));
+        assert_eq!(&raw[header.len..], b"*1\r\n");
+
+        let header = parse(b"PROXY UNKNOWN\r\n").unwrap().unwrap();
+        assert_eq!(header.source, None);
+    }
+
+    #[test]
+    fn test_parse_v2() {
+        let mut raw = v2_tcp4();
+        let len = raw.len();
+        raw.extend_from_slice(b"*1\r\n");
+        let header = parse(&raw).unwrap().unwrap();
+        assert_eq!(header.source, Some("192.168.1.7:50000".parse().unwrap()));
+        assert_eq!(header.len, len);
+    }
+
+    #[test]
+    fn test_parse_partial() {
+        assert_eq!(parse(b"PROX"), Ok(None));
+        assert_eq!(parse(b"PROXY TCP4 192.168"), Ok(None));
+        assert_eq!(parse(&v2_tcp4()[..20]), Ok(None));
+    }
+
+    #[test]
+    fn test_parse_missing_header() {
+        assert_eq!(
+            parse(b"*1\r\n$4\r\nPING\r\n"),
+            Err(ProxyError::MissingHeader)
+        );
+        assert!(parse(b"PROXY TCP4 nope\r\n").is_err());
+    }
+}
@@ -1,119 +1,1378 @@
 use std::cmp::{min};
 use serde::{Deserialize, Serialize};
-use std::collections::{VecDeque};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
 use chrono::{DateTime, Duration, Utc};
 use uuid::{Uuid};
+use bytes::Bytes;
+use crate::histogram::{elapsed_ms, Histogram, LATENCY_BUCKETS_MS, SIZE_BUCKETS_BYTES};
+use crate::rate::{RateMeter, TokenBucket};
+use crate::dedup::{DedupKey, DedupWindow};
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Message {
-    #[serde(rename="messageBody")]
-    body: String,
+    /// May share its allocation with the frame the body was pushed in.
+    #[serde(rename="messageBody", with="body_format")]
+    body: Bytes,
     #[serde(rename="queueUrl")]
     queue_url: String,
     #[serde(default="default_message_id")]
     id: String,
     #[serde(default="default_attempt")]
-    attempt: u8
+    attempt: u8,
+    /// Times the message was leased out, which numbers the receipts of its deliveries.
+    /// Unlike `attempt` it never goes back down.
+    #[serde(default)]
+    leased: u32,
+    /// Milliseconds since the epoch at push. Snapshots written before it was recorded
+    /// load with the time they were loaded.
+    #[serde(rename="enqueuedAt", default="now_ms")]
+    enqueued_at: i64,
+    /// Milliseconds since the epoch of the first lease; redeliveries leave it alone.
+    #[serde(rename="firstDeliveredAt", default, skip_serializing_if="Option::is_none")]
+    first_delivered_at: Option<i64>,
+    /// Higher is delivered sooner by queues created with `QueueOrder::Priority`.
+    #[serde(default)]
+    priority: u8,
+    /// Milliseconds since the epoch after which the message is dropped instead of delivered.
+    #[serde(rename="expiresAt", default, skip_serializing_if="Option::is_none")]
+    expires_at: Option<i64>,
+    /// CRC32 of the body, taken at push when checksums are enabled.
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    checksum: Option<u32>,
+    /// Messages of one group are leased one at a time, in the order they were pushed.
+    #[serde(rename="groupId", default, skip_serializing_if="Option::is_none")]
+    group: Option<String>,
+    /// Producer set metadata such as a content type or trace id, delivered with the body.
+    #[serde(default, skip_serializing_if="BTreeMap::is_empty")]
+    attributes: BTreeMap<String, String>,
+    /// The body is kept LZ4 compressed, prefixed with its length, until it is delivered.
+    #[serde(default, skip_serializing_if="std::ops::Not::not")]
+    compressed: bool
+}
+
+impl Message {
+    pub fn id(&self) -> &String {
+        &self.id
+    }
+
+    pub fn body(&self) -> &[u8] {
+        &self.body
+    }
+
+    /// Length of the body as it was pushed, read from the size prefix of a compressed one.
+    pub fn body_len(&self) -> usize {
+        match self.body.first_chunk::<4>() {
+            Some(prefix) if self.compressed => u32::from_le_bytes(*prefix) as usize,
+            _ => self.body.len()
+        }
+    }
+
+    /// Settles the message's latest delivery with ACK, NACK or TOUCH: the id for the first
+    /// one, so clients tracking ids only keep working, and `<id>:<n>` for the nth.
+    pub fn receipt(&self) -> String {
+        if self.leased <= 1 {
+            self.id.clone()
+        } else {
+            format!("{}:{}", self.id, self.leased)
+        }
+    }
+
+    /// 1 for the first delivery, counting up with every lease that expired unacked.
+    pub fn attempt(&self) -> u8 {
+        self.attempt
+    }
+
+    pub fn enqueued_at(&self) -> i64 {
+        self.enqueued_at
+    }
+
+    pub fn first_delivered_at(&self) -> Option<i64> {
+        self.first_delivered_at
+    }
+
+    pub fn priority(&self) -> u8 {
+        self.priority
+    }
+
+    pub fn expires_at(&self) -> Option<i64> {
+        self.expires_at
+    }
+
+    /// Whether the message's TTL ran out by `now`, in ms since the epoch.
+    pub fn expired(&self, now: i64) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+
+    pub fn new(queue_url: String, body: impl Into<Bytes>) -> Message {
+        Message {
+            body: body.into(),
+            queue_url,
+            id: default_message_id(),
+            attempt: default_attempt(),
+            leased: 0,
+            enqueued_at: now_ms(),
+            first_delivered_at: None,
+            priority: 0,
+            expires_at: None,
+            checksum: None,
+            group: None,
+            attributes: BTreeMap::new(),
+            compressed: false
+        }
+    }
+
+    pub fn checksum(&self) -> Option<u32> {
+        self.checksum
+    }
+
+    pub fn group(&self) -> Option<&str> {
+        self.group.as_deref()
+    }
+
+    pub fn attributes(&self) -> &BTreeMap<String, String> {
+        &self.attributes
+    }
+
+    /// A fresh message for `queue_url` with the same body, priority, expiry, checksum,
+    /// group and attributes, compressed if this one is.
+    pub fn copy_to(&self, queue_url: String) -> Message {
+        Message {
+            priority: self.priority,
+            expires_at: self.expires_at,
+            checksum: self.checksum,
+            group: self.group.clone(),
+            attributes: self.attributes.clone(),
+            compressed: self.compressed,
+            ..Message::new(queue_url, self.body.clone())
+        }
+    }
+
+    /// The same message, id included, filed under `queue_url`.
+    pub fn move_to(mut self, queue_url: String) -> Message {
+        self.queue_url = queue_url;
+        self
+    }
+
+    pub fn with_priority(mut self, priority: u8) -> Message {
+        self.priority = priority;
+        self
+    }
+
+    /// Drops the message instead of delivering it once `ttl_ms` have passed since now.
+    pub fn with_ttl(mut self, ttl_ms: i64) -> Message {
+        self.expires_at = Some(now_ms().saturating_add(ttl_ms));
+        self
+    }
+
+    pub fn with_group(mut self, group: String) -> Message {
+        self.group = Some(group);
+        self
+    }
+
+    pub fn with_attributes(mut self, attributes: BTreeMap<String, String>) -> Message {
+        self.attributes = attributes;
+        self
+    }
+
+    /// Stores the body's checksum so it travels with the message from now on.
+    pub fn with_checksum(mut self) -> Message {
+        self.checksum = Some(body_checksum(&self.body));
+        self
+    }
+
+    /// Keeps the body compressed while the message waits, unless that wouldn't make it
+    /// any smaller.
+    pub fn with_compression(mut self) -> Message {
+        if self.compressed {
+            return self;
+        }
+        let compressed = lz4_flex::compress_prepend_size(&self.body);
+        if compressed.len() < self.body.len() {
+            self.body = Bytes::from(compressed);
+            self.compressed = true;
+        }
+        self
+    }
+
+    pub fn compressed(&self) -> bool {
+        self.compressed
+    }
+
+    /// A copy with the body as it was pushed, for handing to consumers.
+    pub fn inflated(&self) -> Message {
+        let mut msg = self.clone();
+        if msg.compressed {
+            let body = lz4_flex::decompress_size_prepended(&msg.body).expect("body compressed by with_compression");
+            msg.body = Bytes::from(body);
+            msg.compressed = false;
+        }
+        msg
+    }
+
+    /// False only when a checksum was stored and the body no longer matches it.
+    pub fn body_intact(&self) -> bool {
+        self.checksum.is_none_or(|checksum| checksum == body_checksum(&self.body))
+    }
+}
+
+pub fn body_checksum(body: &[u8]) -> u32 {
+    crc32fast::hash(body)
+}
+
+/// FNV-1a, which unlike std's hasher is the same on every build, so digests taken by
+/// different brokers can be compared.
+fn id_hash(id: &str) -> u64 {
+    id.bytes().fold(0xcbf29ce484222325, |hash, byte| (hash ^ byte as u64).wrapping_mul(0x100000001b3))
+}
+
+/// Bodies are written as a JSON string when they are valid UTF-8, which keeps snapshots
+/// readable and loadable by older versions, and as an array of bytes otherwise.
+mod body_format {
+    use bytes::Bytes;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Body {
+        Text(String),
+        Bytes(Vec<u8>)
+    }
+
+    pub fn serialize<S: Serializer>(body: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        match std::str::from_utf8(body) {
+            Ok(text) => serializer.serialize_str(text),
+            Err(_) => body.serialize(serializer)
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Bytes, D::Error> {
+        Ok(match Body::deserialize(deserializer)? {
+            Body::Text(text) => Bytes::from(text),
+            Body::Bytes(bytes) => Bytes::from(bytes)
+        })
+    }
 }
 
 pub fn default_attempt() -> u8 { 1 }
 
-pub fn default_message_id() -> String { Uuid::new_v4().to_string() }
+/// Id of the message a `Message::receipt` was issued for.
+pub fn receipt_id(receipt: &str) -> &str {
+    match receipt.rsplit_once(':') {
+        Some((id, n)) if n.parse::<u32>().is_ok() => id,
+        _ => receipt
+    }
+}
+
+pub fn default_message_id() -> String { Uuid::new_v4().to_string() }
+
+pub fn now_ms() -> i64 { Utc::now().timestamp_millis() }
+
+/// Identifies the connection a message was leased to.
+pub type ConsumerId = u64;
+
+/// Consumer supplied status for a message that is still being worked on.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum Progress {
+    Percent(u8),
+    Status(String)
+}
+
+#[derive(Clone, Debug)]
+pub struct InflightMessage {
+    msg: Message,
+    created_at: DateTime<Utc>,
+    /// When the lease runs out: `created_at` plus the visibility timeout it was taken with.
+    expires_at: DateTime<Utc>,
+    progress: Option<Progress>,
+    progress_updated_at: Option<DateTime<Utc>>,
+    consumer: Option<ConsumerId>,
+    /// Nacked with a delay: the lease only holds the message back until its retry.
+    delayed: bool,
+    cancelled: bool
+}
+
+impl InflightMessage {
+    pub fn message(&self) -> &Message {
+        &self.msg
+    }
+
+    pub fn consumer(&self) -> Option<ConsumerId> {
+        self.consumer
+    }
+
+    /// Milliseconds since the epoch when the message was leased out.
+    pub fn leased_at(&self) -> i64 {
+        self.created_at.timestamp_millis()
+    }
+
+    /// Milliseconds since the epoch when the lease runs out.
+    pub fn expires_at(&self) -> i64 {
+        self.expires_at.timestamp_millis()
+    }
+
+    pub fn progress(&self) -> Option<&Progress> {
+        self.progress.as_ref()
+    }
+
+    /// Milliseconds since the epoch of the latest `report_progress`.
+    pub fn progress_updated_at(&self) -> Option<i64> {
+        self.progress_updated_at.map(|at| at.timestamp_millis())
+    }
+}
+
+/// Where redriven dead letters are delivered relative to the live backlog.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RedrivePriority {
+    /// Delivered before anything already waiting.
+    Boost,
+    /// Queued behind the current backlog like a fresh push.
+    Backlog,
+    /// Delivered one for every `n` live messages so neither side starves.
+    Interleave(usize)
+}
+
+/// Which waiting message POP delivers next. Retries and boosted redrives go ahead of
+/// the backlog, or with `Priority` ahead of the messages sharing their priority.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum QueueOrder {
+    /// Oldest push first.
+    #[default]
+    Fifo,
+    /// Newest push first.
+    Lifo,
+    /// Highest `Message::priority` first, oldest first among equals.
+    Priority
+}
+
+/// What a push to a full queue does once its overflow buffer is full too.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum FullPolicy {
+    /// Fails with `QUEUEFULL`.
+    #[default]
+    Reject,
+    /// Waits until leases make room. FANOUT can't wait on several queues at once, so it
+    /// is rejected instead.
+    Block,
+    /// Makes room by dropping the longest waiting message.
+    DropOldest
+}
+
+/// One named queue: its waiting messages, kept in `QueueOrder`, the leases handed out
+/// from them, and its limits and counters.
+pub struct Queue {
+    name: String,
+    order: QueueOrder,
+    in_flight_expiration_ms: i64,
+    queue: VecDeque<Message>,
+    /// Leases not yet acked, by message id.
+    in_flight: HashMap<String, InflightMessage>,
+    /// When each lease runs out, soonest first. Acks and nacks leave their lease's entry
+    /// behind and extensions add another, so an entry only counts while the lease it
+    /// names is still in `in_flight` with the same expiry.
+    expiry: VecDeque<(DateTime<Utc>, String)>,
+    dead_letters: VecDeque<Message>,
+    /// Deliveries a message gets before it goes to the dead letters.
+    max_attempts: u8,
+    /// Queue `ServerState::sweep` moves the dead letters to, rather than leaving them
+    /// here for `QUEUE REDRIVE`.
+    dead_letter_queue: Option<String>,
+    redriven: VecDeque<Message>,
+    redrive_every: usize,
+    live_since_redrive: usize,
+    /// Recently acked ids, oldest first, so a retried ACK still succeeds.
+    acked: VecDeque<String>,
+    acked_index: HashSet<String>,
+    ack_cache_size: usize,
+    ack_cache_hits: u64,
+    /// Messages whose TTL had run out by the time they were next in line for delivery.
+    expired_at_delivery: u64,
+    /// Messages whose TTL ran out while they waited further back in line, found by
+    /// `expire`.
+    expired_waiting: u64,
+    /// Expired messages go to the dead letters instead of being dropped.
+    dead_letter_expired: bool,
+    /// Longest a message may wait from its push, whatever TTL it was pushed with.
+    retention_ms: Option<i64>,
+    /// No waiting message expires before this; `expire` skips the backlog until then.
+    next_expiry: Option<i64>,
+    /// Pushes that came in while the queue was full, waiting for room in the backlog.
+    overflow: VecDeque<Message>,
+    /// How many messages `overflow` may hold; 0 rejects pushes to a full queue.
+    overflow_limit: usize,
+    /// Pushes ever absorbed by `overflow`.
+    overflowed: u64,
+    /// Most messages the backlog may hold, overriding `ServerConfig::queue_capacity`.
+    max_depth: Option<usize>,
+    full_policy: FullPolicy,
+    /// Largest body, in bytes, a push may bring.
+    max_message_bytes: Option<usize>,
+    /// Waiting messages dropped to make room under `FullPolicy::DropOldest`.
+    dropped: u64,
+    /// Set with `QUEUE PAUSE`: pushes are still taken but nothing is leased out.
+    paused: bool,
+    /// Keys of the pushes made in the last `dedup_window_ms`.
+    dedup: DedupWindow,
+    dedup_window_ms: i64,
+    /// Pushes without a dedup key are deduplicated by their body.
+    dedup_by_content: bool,
+    /// Pushes acknowledged without being queued because they were duplicates.
+    deduplicated: u64,
+    /// Leasing stops once the unacked bodies add up to this many bytes.
+    max_in_flight_bytes: Option<usize>,
+    /// Leasing stops once this many messages are leased out and not yet acked, whichever
+    /// consumers hold them.
+    max_in_flight: Option<usize>,
+    /// Most deliveries a second, for consumers calling a service that can only take so
+    /// many requests.
+    rate_limit: Option<TokenBucket>,
+    /// The last lease left messages waiting because `rate_limit` had run out.
+    throttled: bool,
+    /// Connection of an ephemeral queue, which is deleted when it closes.
+    owner: Option<ConsumerId>,
+    /// Only `consumer` may lease, from its first POP until its connection closes.
+    exclusive: bool,
+    consumer: Option<ConsumerId>,
+    /// Push to first lease, in ms: how long messages wait on the broker.
+    time_in_queue: Histogram,
+    /// Lease to ack, in ms: how long consumers take with a message.
+    processing_time: Histogram,
+    /// Body sizes of every `SIZE_SAMPLE_EVERY`th message added.
+    body_sizes: Histogram,
+    /// Messages queued for delivery, pushed or copied in.
+    enqueued: RateMeter,
+    /// Messages leased out, redeliveries included.
+    dequeued: RateMeter,
+    /// Failed deliveries put back in line for another attempt.
+    redelivered: u64,
+    added: u64
+}
+
+impl Queue {
+    const MAX_ATTEMPT: u8 = 3;
+    const DEFAULT_ACK_CACHE_SIZE: usize = 1024;
+    const DEFAULT_DEDUP_WINDOW_MS: i64 = 5 * 60 * 1000;
+    /// One in this many added messages has its body size recorded, starting with the first.
+    pub const SIZE_SAMPLE_EVERY: u64 = 16;
+
+    pub fn create(name: String) -> Queue {
+        Self::create_with_expiration(name, 1000)
+    }
+
+    pub fn create_with_expiration(name: String, in_flight_expiration_ms: i64) -> Queue {
+        Queue {
+            name,
+            order: QueueOrder::default(),
+            in_flight_expiration_ms,
+            queue: VecDeque::new(),
+            in_flight: HashMap::new(),
+            expiry: VecDeque::new(),
+            dead_letters: VecDeque::new(),
+            max_attempts: Self::MAX_ATTEMPT,
+            dead_letter_queue: None,
+            redriven: VecDeque::new(),
+            redrive_every: 1,
+            live_since_redrive: 0,
+            acked: VecDeque::new(),
+            acked_index: HashSet::new(),
+            ack_cache_size: Self::DEFAULT_ACK_CACHE_SIZE,
+            ack_cache_hits: 0,
+            expired_at_delivery: 0,
+            expired_waiting: 0,
+            dead_letter_expired: false,
+            retention_ms: None,
+            next_expiry: None,
+            overflow: VecDeque::new(),
+            overflow_limit: 0,
+            overflowed: 0,
+            max_depth: None,
+            full_policy: FullPolicy::default(),
+            max_message_bytes: None,
+            dropped: 0,
+            paused: false,
+            dedup: DedupWindow::default(),
+            dedup_window_ms: Self::DEFAULT_DEDUP_WINDOW_MS,
+            dedup_by_content: false,
+            deduplicated: 0,
+            max_in_flight_bytes: None,
+            max_in_flight: None,
+            rate_limit: None,
+            throttled: false,
+            owner: None,
+            exclusive: false,
+            consumer: None,
+            time_in_queue: Histogram::new(LATENCY_BUCKETS_MS),
+            processing_time: Histogram::new(LATENCY_BUCKETS_MS),
+            body_sizes: Histogram::new(SIZE_BUCKETS_BYTES),
+            enqueued: RateMeter::default(),
+            dequeued: RateMeter::default(),
+            redelivered: 0,
+            added: 0
+        }
+    }
+
+    /// How long leases last when the POP doesn't say; leases already out keep theirs.
+    pub fn set_in_flight_expiration_ms(&mut self, expiration_ms: i64) {
+        self.in_flight_expiration_ms = expiration_ms;
+    }
+
+    pub fn in_flight_expiration_ms(&self) -> i64 {
+        self.in_flight_expiration_ms
+    }
+
+    pub fn set_max_attempts(&mut self, attempts: u8) {
+        self.max_attempts = attempts;
+    }
+
+    pub fn max_attempts(&self) -> u8 {
+        self.max_attempts
+    }
+
+    pub fn set_dead_letter_queue(&mut self, queue: Option<String>) {
+        self.dead_letter_queue = queue;
+    }
+
+    pub fn dead_letter_queue(&self) -> Option<&str> {
+        self.dead_letter_queue.as_deref()
+    }
+
+    pub fn set_order(&mut self, order: QueueOrder) {
+        self.order = order;
+    }
+
+    pub fn order(&self) -> QueueOrder {
+        self.order
+    }
+
+    pub fn set_max_in_flight_bytes(&mut self, max: Option<usize>) {
+        self.max_in_flight_bytes = max;
+    }
+
+    pub fn max_in_flight_bytes(&self) -> Option<usize> {
+        self.max_in_flight_bytes
+    }
+
+    pub fn set_max_in_flight(&mut self, max: Option<usize>) {
+        self.max_in_flight = max;
+    }
+
+    pub fn max_in_flight(&self) -> Option<usize> {
+        self.max_in_flight
+    }
+
+    /// Body bytes leased out and not yet acked, cancelled or expired.
+    pub fn in_flight_bytes(&self) -> usize {
+        self.in_flight.values()
+            .filter(|x| !x.cancelled)
+            .map(|x| x.msg.body_len())
+            .sum()
+    }
+
+    /// Leases handed out and not yet acked, cancelled or expired.
+    pub fn in_flight_count(&self) -> usize {
+        self.in_flight.values().filter(|x| !x.cancelled).count()
+    }
+
+    /// Leases `consumer` holds and has yet to ack or nack.
+    pub fn leased_by(&self, consumer: ConsumerId) -> usize {
+        self.in_flight.values().filter(|x| x.consumer == Some(consumer) && !x.delayed).count()
+    }
+
+    /// Leases held back by a delayed nack, counted in `in_flight_count` too.
+    pub fn delayed_count(&self) -> usize {
+        self.in_flight.values().filter(|x| x.delayed && !x.cancelled).count()
+    }
+
+    /// When the longest waiting message was pushed, in ms since the epoch.
+    pub fn oldest_enqueued_at(&self) -> Option<i64> {
+        self.queue.iter().chain(self.redriven.iter()).map(|msg| msg.enqueued_at).min()
+    }
+
+    /// Messages queued per second over the last minute.
+    pub fn enqueue_rate(&mut self) -> f64 {
+        self.enqueued.per_second(now_ms())
+    }
+
+    /// Messages leased out per second over the last minute.
+    pub fn dequeue_rate(&mut self) -> f64 {
+        self.dequeued.per_second(now_ms())
+    }
+
+    pub fn redelivered(&self) -> u64 {
+        self.redelivered
+    }
+
+    pub fn dead_letter_count(&self) -> usize {
+        self.dead_letters.len()
+    }
+
+    pub fn time_in_queue(&self) -> &Histogram {
+        &self.time_in_queue
+    }
+
+    pub fn processing_time(&self) -> &Histogram {
+        &self.processing_time
+    }
+
+    pub fn body_sizes(&self) -> &Histogram {
+        &self.body_sizes
+    }
+
+    /// Caps how many acked ids are remembered. 0 turns duplicate detection off.
+    pub fn set_ack_cache_size(&mut self, size: usize) {
+        self.ack_cache_size = size;
+        self.trim_ack_cache();
+    }
+
+    /// Duplicate ACKs answered from the cache.
+    pub fn ack_cache_hits(&self) -> u64 {
+        self.ack_cache_hits
+    }
+
+    pub fn expired_at_delivery(&self) -> u64 {
+        self.expired_at_delivery
+    }
+
+    pub fn expired_waiting(&self) -> u64 {
+        self.expired_waiting
+    }
+
+    pub fn set_dead_letter_expired(&mut self, dead_letter: bool) {
+        self.dead_letter_expired = dead_letter;
+    }
+
+    /// Applies to messages added from now on; those already waiting keep their expiry.
+    pub fn set_retention_ms(&mut self, retention_ms: Option<i64>) {
+        self.retention_ms = retention_ms;
+    }
+
+    pub fn retention_ms(&self) -> Option<i64> {
+        self.retention_ms
+    }
+
+    /// Brings the expiry of `msg` forward to the end of the retention period.
+    fn retain(&self, msg: &mut Message) {
+        if let Some(retention_ms) = self.retention_ms {
+            let retained_until = msg.enqueued_at + retention_ms;
+            msg.expires_at = Some(msg.expires_at.map_or(retained_until, |at| at.min(retained_until)));
+        }
+    }
+
+    /// Takes the messages whose TTL ran out out of the backlog, the redriven dead
+    /// letters and the overflow buffer, wherever they are in line, so they don't sit
+    /// there until they come up for delivery. Returns how many there were.
+    pub fn expire(&mut self, now: i64) -> usize {
+        if self.next_expiry.is_none_or(|next| next > now) {
+            return 0;
+        }
+        let mut expired = Vec::new();
+        for waiting in [&mut self.queue, &mut self.redriven, &mut self.overflow] {
+            let mut kept = VecDeque::with_capacity(waiting.len());
+            for msg in waiting.drain(..) {
+                if msg.expired(now) {
+                    expired.push(msg);
+                } else {
+                    kept.push_back(msg);
+                }
+            }
+            *waiting = kept;
+        }
+        self.next_expiry = self.queue.iter().chain(self.redriven.iter()).chain(self.overflow.iter())
+            .filter_map(|msg| msg.expires_at)
+            .min();
+        self.expired_waiting += expired.len() as u64;
+        let count = expired.len();
+        for msg in expired {
+            self.discard_expired(msg);
+        }
+        count
+    }
+
+    /// Drops a message whose TTL ran out, or dead letters it when the queue keeps them.
+    fn discard_expired(&mut self, msg: Message) {
+        if self.dead_letter_expired {
+            self.dead_letters.push_back(msg);
+        }
+    }
+
+    /// Lets `expire` know when `msg` runs out.
+    fn note_expiry(&mut self, msg: &Message) {
+        if let Some(expires_at) = msg.expires_at {
+            self.next_expiry = Some(self.next_expiry.map_or(expires_at, |next| next.min(expires_at)));
+        }
+    }
+
+    pub fn set_overflow_limit(&mut self, limit: usize) {
+        self.overflow_limit = limit;
+    }
+
+    pub fn overflow_limit(&self) -> usize {
+        self.overflow_limit
+    }
+
+    /// Messages absorbed by the overflow buffer and not yet moved into the backlog.
+    pub fn overflow_depth(&self) -> usize {
+        self.overflow.len()
+    }
+
+    pub fn overflowed(&self) -> u64 {
+        self.overflowed
+    }
+
+    pub fn set_rate_limit(&mut self, per_second: Option<usize>) {
+        self.rate_limit = per_second.map(|per_second| TokenBucket::new(per_second, now_ms()));
+    }
+
+    pub fn rate_limit(&self) -> Option<usize> {
+        self.rate_limit.as_ref().map(TokenBucket::per_second)
+    }
+
+    /// Messages are waiting that the rate limit held back, which may go out once it
+    /// has refilled.
+    pub fn throttled(&self) -> bool {
+        self.throttled && self.depth() > 0
+    }
+
+    pub fn set_owner(&mut self, owner: Option<ConsumerId>) {
+        self.owner = owner;
+    }
+
+    pub fn owner(&self) -> Option<ConsumerId> {
+        self.owner
+    }
+
+    pub fn set_exclusive(&mut self, exclusive: bool) {
+        self.exclusive = exclusive;
+    }
+
+    pub fn exclusive(&self) -> bool {
+        self.exclusive
+    }
+
+    /// Whether `consumer` may lease. The first consumer to ask for an exclusive queue
+    /// claims it, and keeps it until `release_consumer`.
+    pub fn claim(&mut self, consumer: ConsumerId) -> bool {
+        if !self.exclusive {
+            return true;
+        }
+        *self.consumer.get_or_insert(consumer) == consumer
+    }
+
+    pub fn set_max_depth(&mut self, max: Option<usize>) {
+        self.max_depth = max;
+    }
+
+    pub fn max_depth(&self) -> Option<usize> {
+        self.max_depth
+    }
+
+    pub fn set_max_message_bytes(&mut self, max: Option<usize>) {
+        self.max_message_bytes = max;
+    }
+
+    pub fn max_message_bytes(&self) -> Option<usize> {
+        self.max_message_bytes
+    }
+
+    pub fn set_full_policy(&mut self, policy: FullPolicy) {
+        self.full_policy = policy;
+    }
+
+    pub fn full_policy(&self) -> FullPolicy {
+        self.full_policy
+    }
+
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+
+    pub fn set_paused(&mut self, paused: bool) {
+        self.paused = paused;
+    }
+
+    pub fn paused(&self) -> bool {
+        self.paused
+    }
+
+    pub fn set_dedup_window(&mut self, window_ms: i64) {
+        self.dedup_window_ms = window_ms;
+    }
+
+    pub fn dedup_window_ms(&self) -> i64 {
+        self.dedup_window_ms
+    }
+
+    pub fn set_dedup_by_content(&mut self, by_content: bool) {
+        self.dedup_by_content = by_content;
+    }
+
+    pub fn dedups_by_content(&self) -> bool {
+        self.dedup_by_content
+    }
+
+    pub fn deduplicated(&self) -> u64 {
+        self.deduplicated
+    }
+
+    /// Id of the push made under `key` within the dedup window, which makes this one a
+    /// duplicate.
+    pub fn duplicate_of(&mut self, key: &DedupKey, now: i64) -> Option<String> {
+        let id = self.dedup.seen(key, now)?.to_string();
+        self.deduplicated += 1;
+        Some(id)
+    }
+
+    /// Remembers that `id` was pushed under `key` at `now`, for the dedup window.
+    pub fn remember_push(&mut self, key: DedupKey, id: String, now: i64) {
+        self.dedup.remember(key, id, now + self.dedup_window_ms);
+    }
+
+    /// Takes the longest waiting message, backlog and redriven dead letters alike, out of
+    /// line to make room for a push.
+    pub fn drop_oldest(&mut self) -> Option<Message> {
+        let oldest = |waiting: &VecDeque<Message>| {
+            waiting.iter().enumerate().min_by_key(|(_, msg)| msg.enqueued_at).map(|(i, msg)| (i, msg.enqueued_at))
+        };
+        let msg = match (oldest(&self.queue), oldest(&self.redriven)) {
+            (Some((_, queued)), Some((i, redriven))) if redriven < queued => self.redriven.remove(i),
+            (Some((i, _)), _) => self.queue.remove(i),
+            (None, Some((i, _))) => self.redriven.remove(i),
+            (None, None) => None
+        };
+        self.dropped += msg.is_some() as u64;
+        msg
+    }
+
+    /// Holds `msg` back until the backlog has room again, see `drain_overflow`. False,
+    /// with `msg` dropped, when the overflow buffer is full too.
+    pub fn absorb(&mut self, msg: Message) -> bool {
+        if self.overflow.len() >= self.overflow_limit {
+            return false;
+        }
+        self.note_expiry(&msg);
+        self.overflow.push_back(msg);
+        self.overflowed += 1;
+        true
+    }
+
+    /// Moves absorbed messages, oldest first, into the backlog until it holds `capacity`.
+    /// They are delivered in the queue's order from then on, so a burst may come out
+    /// behind pushes made after it. Returns how many were moved.
+    pub fn drain_overflow(&mut self, capacity: Option<usize>) -> usize {
+        let mut moved = 0;
+        while !self.overflow.is_empty() && capacity.is_none_or(|capacity| self.depth() < capacity) {
+            let msg = self.overflow.pop_front().unwrap();
+            self.add(msg);
+            moved += 1;
+        }
+        moved
+    }
+
+    fn remember_ack(&mut self, id: &str) {
+        self.cache_ack(id);
+        self.trim_ack_cache();
+    }
+
+    /// `remember_ack` without the trim, for batches that trim once at the end.
+    fn cache_ack(&mut self, id: &str) {
+        if self.ack_cache_size == 0 {
+            return;
+        }
+        if self.acked_index.insert(id.to_string()) {
+            self.acked.push_back(id.to_string());
+        }
+    }
+
+    fn trim_ack_cache(&mut self) {
+        while self.acked.len() > self.ack_cache_size {
+            if let Some(evicted) = self.acked.pop_front() {
+                self.acked_index.remove(&evicted);
+            }
+        }
+    }
+
+    fn message_expired(&self, msg: &InflightMessage) -> bool {
+        msg.expires_at < Utc::now()
+    }
+
+    /// Queues a pushed message where `order` delivers it. The backlog is always kept
+    /// in delivery order.
+    pub fn add(&mut self, mut msg: Message) {
+        self.retain(&mut msg);
+        self.sample_size(&msg);
+        self.enqueued.record(now_ms(), 1);
+        match self.order {
+            QueueOrder::Lifo => {
+                self.note_expiry(&msg);
+                self.queue.push_front(msg)
+            }
+            QueueOrder::Fifo | QueueOrder::Priority => self.queue_behind(msg)
+        }
+    }
+
+    /// `add` for every message, with room made in the backlog once.
+    pub fn add_batch(&mut self, msgs: Vec<Message>) {
+        self.queue.reserve(msgs.len());
+        for msg in msgs {
+            self.add(msg);
+        }
+    }
+
+    /// Queues `msg` as late as the order allows, so messages copied from another
+    /// queue's `waiting` keep their delivery order, LIFO queues included.
+    pub fn append(&mut self, mut msg: Message) {
+        self.retain(&mut msg);
+        self.sample_size(&msg);
+        self.enqueued.record(now_ms(), 1);
+        self.queue_behind(msg);
+    }
+
+    /// Behind everything waiting, or with `Priority` behind everything of the same or a
+    /// higher priority.
+    fn queue_behind(&mut self, msg: Message) {
+        self.note_expiry(&msg);
+        if self.order == QueueOrder::Priority {
+            let at = self.queue.partition_point(|waiting| waiting.priority >= msg.priority);
+            self.queue.insert(at, msg);
+        } else {
+            self.queue.push_back(msg);
+        }
+    }
+
+    /// Ahead of everything waiting, or with `Priority` ahead of everything of the same
+    /// or a lower priority.
+    fn queue_ahead(&mut self, msg: Message) {
+        self.note_expiry(&msg);
+        if self.order == QueueOrder::Priority {
+            let at = self.queue.partition_point(|waiting| waiting.priority > msg.priority);
+            self.queue.insert(at, msg);
+        } else {
+            self.queue.push_front(msg);
+        }
+    }
+
+    fn sample_size(&mut self, msg: &Message) {
+        if self.added.is_multiple_of(Self::SIZE_SAMPLE_EVERY) {
+            self.body_sizes.observe(msg.body_len() as u64);
+        }
+        self.added += 1;
+    }
+
+    /// Messages waiting to be popped, including redriven ones.
+    pub fn depth(&self) -> usize {
+        self.queue.len() + self.redriven.len()
+    }
+
+    /// Order independent digest of the ids in `snapshot`, with how many there are. Two
+    /// copies of a queue holding the same messages agree on it however they got there.
+    pub fn digest(&self) -> (usize, u64) {
+        let pending = self.snapshot();
+        let digest = pending.iter().fold(0u64, |digest, msg| digest.wrapping_add(id_hash(&msg.id)));
+        (pending.len(), digest)
+    }
+
+    /// Copies of the first `count` waiting messages, which are left where they are: the
+    /// backlog in delivery order, then the redriven dead letters.
+    pub fn peek(&self, count: usize) -> Vec<Message> {
+        self.queue.iter().chain(self.redriven.iter()).take(count).map(Message::inflated).collect()
+    }
+
+    /// Messages waiting to be popped: the backlog, then redriven dead letters.
+    pub fn waiting(&self) -> Vec<&Message> {
+        self.queue.iter().chain(self.redriven.iter()).collect()
+    }
+
+    /// Takes up to `cnt` waiting messages, next to be delivered first, with a fresh
+    /// attempt count, for moving them to another queue.
+    pub fn take_waiting(&mut self, cnt: usize) -> Vec<Message> {
+        let from_queue = min(cnt, self.queue.len());
+        let from_redriven = min(cnt - from_queue, self.redriven.len());
+        let mut msgs: Vec<Message> = self.queue.drain(..from_queue).chain(self.redriven.drain(..from_redriven)).collect();
+        for msg in msgs.iter_mut() {
+            msg.attempt = default_attempt();
+        }
+        msgs
+    }
+
+    /// Drops up to `cnt` waiting messages, next to be delivered first. Leases and dead letters are kept.
+    pub fn purge(&mut self, cnt: usize) -> usize {
+        let from_queue = min(cnt, self.queue.len());
+        self.queue.drain(..from_queue);
+        let from_redriven = min(cnt - from_queue, self.redriven.len());
+        self.redriven.drain(..from_redriven);
+        from_queue + from_redriven
+    }
 
-#[derive(Clone, Debug)]
-pub struct InflightMessage {
-    msg: Message,
-    complete: bool,
-    created_at: DateTime<Utc>
-}
+    /// Every message that would be lost on exit: waiting, redriven, overflowed and unacknowledged leases.
+    pub fn snapshot(&self) -> Vec<&Message> {
+        let in_flight = self.leases().filter(|x| !x.cancelled).map(|x| &x.msg);
+        self.queue.iter().chain(self.redriven.iter()).chain(self.overflow.iter()).chain(in_flight).collect()
+    }
 
-pub struct Lifo {
-    name: String,
-    in_flight_expiration_ms: i64,
-    queue: VecDeque<Message>,
-    in_flight: VecDeque<InflightMessage>
-}
+    /// Leases in the order they run out.
+    fn leases(&self) -> impl Iterator<Item = &InflightMessage> {
+        self.expiry.iter().filter_map(|(expires_at, id)| {
+            self.in_flight.get(id).filter(|x| x.expires_at == *expires_at)
+        })
+    }
 
-impl Lifo {
-    const MAX_ATTEMPT: u8 = 3;
+    /// The first `cnt` leases, soonest to run out first.
+    pub fn show_in_flight(&self, cnt: usize) -> Vec<&InflightMessage> {
+        self.leases().take(cnt).collect()
+    }
 
-    fn create(name: String) -> Lifo {
-        Lifo {
-            name,
-            in_flight_expiration_ms: 1000,
-            queue: VecDeque::new(),
-            in_flight: VecDeque::new()
+    pub fn complete(&mut self, id: &String) -> bool {
+        let Some(inflight_msg) = self.in_flight.remove(id) else {
+            if self.acked_index.contains(id) {
+                self.ack_cache_hits += 1;
+                return true;
+            }
+            return false;
+        };
+        let leased_at = inflight_msg.created_at.timestamp_millis();
+        self.processing_time.observe(elapsed_ms(leased_at, now_ms()));
+        self.remember_ack(id);
+        true
+    }
+
+    /// `complete` for every id, trimming the ack cache once at the end rather than once
+    /// per id. Says for each id whether it was acked.
+    pub fn complete_batch(&mut self, ids: &[String]) -> Vec<bool> {
+        let mut acked = vec![false; ids.len()];
+        let now = now_ms();
+        for (id, acked) in ids.iter().zip(acked.iter_mut()) {
+            if let Some(inflight_msg) = self.in_flight.remove(id) {
+                *acked = true;
+                let leased_at = inflight_msg.created_at.timestamp_millis();
+                self.processing_time.observe(elapsed_ms(leased_at, now));
+                self.cache_ack(id);
+            }
+        }
+        // repeats of an id, and ids acked before, are answered from the ack cache
+        for (id, acked) in ids.iter().zip(acked.iter_mut()) {
+            if !*acked && self.acked_index.contains(id) {
+                self.ack_cache_hits += 1;
+                *acked = true;
+            }
         }
+        self.trim_ack_cache();
+        acked
     }
 
-    fn create_with_expiration(name: String, in_flight_expiration_ms: i64) -> Lifo {
-        Lifo {
-            name,
-            in_flight_expiration_ms,
-            queue: VecDeque::new(),
-            in_flight: VecDeque::new()
+    /// Whether the message `receipt` was issued for is leased out and not yet acked.
+    pub fn is_leased(&self, receipt: &str) -> bool {
+        self.in_flight.contains_key(receipt_id(receipt))
+    }
+
+    /// Whether the message `receipt` was issued for has been leased out again since, so
+    /// the receipt no longer settles anything.
+    pub fn stale_receipt(&self, receipt: &str) -> bool {
+        self.in_flight.get(receipt_id(receipt)).is_some_and(|x| x.msg.receipt() != receipt)
+    }
+
+    /// Checksum of a message that is leased out and not yet acked.
+    pub fn in_flight_checksum(&self, id: &String) -> Option<u32> {
+        self.in_flight.get(id).and_then(|x| x.msg.checksum)
+    }
+
+    /// Records the latest progress of an in-flight message so operators can tell a slow
+    /// consumer from a stuck one. Returns false when the id is not in flight.
+    pub fn report_progress(&mut self, id: &String, progress: Progress) -> bool {
+        let Some(inflight_msg) = self.in_flight.get_mut(id) else {
+            return false;
+        };
+        let progress = match progress {
+            Progress::Percent(pct) => Progress::Percent(min(pct, 100)),
+            status => status
+        };
+        inflight_msg.progress = Some(progress);
+        inflight_msg.progress_updated_at = Some(Utc::now());
+        true
+    }
+
+    /// Flags an in-flight message as cancelled so it is never redelivered. Returns the
+    /// consumer holding the lease so it can be told to abandon the work, unless it was
+    /// cancelled already.
+    pub fn cancel(&mut self, id: &String) -> Option<ConsumerId> {
+        let inflight_msg = self.in_flight.get_mut(id).filter(|x| !x.cancelled)?;
+        inflight_msg.cancelled = true;
+        inflight_msg.consumer
+    }
+
+    /// Drops finished leases and puts expired ones back in the queue, or in the dead
+    /// letters once they are out of attempts. Returns true when something was requeued.
+    pub fn sweep_in_flight(&mut self) -> bool {
+        let mut requeued = false;
+        // the next lease to expire is always at the front
+        while let Some((expires_at, id)) = self.expiry.front() {
+            let Some(first_msg) = self.in_flight.get(id).filter(|x| x.expires_at == *expires_at) else {
+                self.expiry.pop_front();
+                continue;
+            };
+            if !first_msg.cancelled && !self.message_expired(first_msg) {
+                break;
+            }
+            let (_, id) = self.expiry.pop_front().unwrap();
+            let inflight_msg = self.in_flight.remove(&id).unwrap();
+            if !inflight_msg.cancelled {
+                requeued |= self.retry(inflight_msg.msg);
+            }
         }
+        requeued
     }
 
-    fn message_expired(&self, msg: &InflightMessage) -> bool {
-        msg.created_at + Duration::milliseconds(self.in_flight_expiration_ms) < Utc::now()
+    /// Gives up every lease `consumer` holds, as its connection has gone away, counting
+    /// each as a failed delivery, along with its claim on an exclusive queue. Leases held
+    /// back by a delayed nack are left to run out. Returns how many messages were requeued.
+    pub fn release_consumer(&mut self, consumer: ConsumerId) -> usize {
+        if self.consumer == Some(consumer) {
+            self.consumer = None;
+        }
+        let ids: Vec<String> = self.in_flight.values()
+            .filter(|x| x.consumer == Some(consumer) && !x.delayed)
+            .map(|x| x.msg.id.clone())
+            .collect();
+        let mut requeued = 0;
+        for id in ids {
+            let inflight_msg = self.in_flight.remove(&id).unwrap();
+            if !inflight_msg.cancelled && self.retry(inflight_msg.msg) {
+                requeued += 1;
+            }
+        }
+        requeued
     }
 
-    fn add(&mut self, msg: Message) {
-        self.queue.push_back(msg);
+    /// Puts a message whose delivery failed back in line, or in the dead letters once it
+    /// is out of attempts. Returns true when it was requeued.
+    fn retry(&mut self, mut msg: Message) -> bool {
+        if msg.attempt < self.max_attempts {
+            msg.attempt += 1;
+            self.redelivered += 1;
+            self.queue_ahead(msg);
+            true
+        } else {
+            self.dead_letters.push_back(msg);
+            false
+        }
     }
 
-    fn show_in_flight(&self, cnt: usize) -> Vec<&InflightMessage> {
-        let q_size = min(cnt, self.in_flight.len());
-        self.in_flight.range(..q_size).into_iter().collect::<Vec<&InflightMessage>>()
+    /// Gives up a lease before it runs out, counting it as a failed delivery. The message
+    /// is retried right away, or with a `delay_ms` once the lease, shortened to it, expires.
+    /// Returns false when `id` is not in flight.
+    pub fn nack(&mut self, id: &String, delay_ms: i64) -> bool {
+        let Some(mut inflight_msg) = self.take_lease(id) else {
+            return false;
+        };
+        if delay_ms > 0 {
+            inflight_msg.delayed = true;
+            self.relet(inflight_msg, delay_ms);
+        } else {
+            self.retry(inflight_msg.msg);
+        }
+        true
     }
 
-    fn complete(&mut self, id: &String) {
-        let idx = self.in_flight.iter().position(|x| &x.msg.id == id);
-        if idx.is_none() {
-            return;
+    /// Extends a lease the consumer is still working on so it runs out `visibility_ms`
+    /// from now, or the queue's expiration when not given. Returns false when `id` is
+    /// not in flight.
+    pub fn touch(&mut self, id: &String, visibility_ms: Option<i64>) -> bool {
+        let Some(inflight_msg) = self.take_lease(id) else {
+            return false;
+        };
+        self.relet(inflight_msg, visibility_ms.unwrap_or(self.in_flight_expiration_ms));
+        true
+    }
+
+    /// Removes the live lease on `id` from the leases.
+    fn take_lease(&mut self, id: &String) -> Option<InflightMessage> {
+        if self.in_flight.get(id)?.cancelled {
+            return None;
+        }
+        self.in_flight.remove(id)
+    }
+
+    /// Puts a lease taken with `take_lease` back, running out `visibility_ms` from now.
+    fn relet(&mut self, mut inflight_msg: InflightMessage, visibility_ms: i64) {
+        inflight_msg.expires_at = Utc::now() + Duration::milliseconds(visibility_ms);
+        self.expire_at(inflight_msg);
+    }
+
+    /// Adds a lease to `in_flight` and its expiry behind every lease running out no
+    /// later, which with one visibility timeout is the back.
+    fn expire_at(&mut self, inflight_msg: InflightMessage) {
+        let at = self.expiry.partition_point(|(expires_at, _)| *expires_at <= inflight_msg.expires_at);
+        self.expiry.insert(at, (inflight_msg.expires_at, inflight_msg.msg.id.clone()));
+        self.in_flight.insert(inflight_msg.msg.id.clone(), inflight_msg);
+    }
+
+    /// Takes up to `cnt` dead letters, oldest first, with a fresh attempt count.
+    pub fn take_dead_letters(&mut self, cnt: usize) -> Vec<Message> {
+        let taken = min(cnt, self.dead_letters.len());
+        let mut msgs: Vec<Message> = self.dead_letters.drain(..taken).collect();
+        for msg in msgs.iter_mut() {
+            msg.attempt = default_attempt();
+        }
+        msgs
+    }
+
+    /// Moves up to `cnt` dead letters back into delivery with a fresh attempt count.
+    pub fn redrive(&mut self, cnt: usize, priority: RedrivePriority) -> usize {
+        let msgs = self.take_dead_letters(cnt);
+        let moved = msgs.len();
+        for msg in &msgs {
+            self.note_expiry(msg);
         }
-        let i = idx.unwrap();
-        let inflight_msg = self.in_flight.get_mut(i).unwrap();
-        inflight_msg.complete = true;
-    }
-
-    fn sweep_in_flight(&mut self) {
-        while !self.in_flight.is_empty() {
-            let first_msg = self.in_flight.back().unwrap();
-            if first_msg.complete {
-                self.in_flight.pop_back();
-            } else if self.message_expired(first_msg) {
-                let mut inflight_msg = self.in_flight.pop_front().unwrap();
-                if inflight_msg.msg.attempt < Self::MAX_ATTEMPT {
-                    inflight_msg.msg.attempt += 1;
-                    self.queue.push_front(inflight_msg.msg);
+        match priority {
+            RedrivePriority::Boost => {
+                // pushed in reverse so the redriven messages keep their order
+                for msg in msgs.into_iter().rev() {
+                    self.queue_ahead(msg);
                 }
-            } else {
+            }
+            RedrivePriority::Backlog => msgs.into_iter().for_each(|msg| self.queue_behind(msg)),
+            RedrivePriority::Interleave(every) => {
+                self.redrive_every = every.max(1);
+                self.live_since_redrive = 0;
+                self.redriven.extend(msgs);
+            }
+        }
+        moved
+    }
+
+    fn redrive_due(&self) -> bool {
+        !self.redriven.is_empty()
+            && (self.live_since_redrive >= self.redrive_every || self.queue.is_empty())
+    }
+
+    /// Groups with a message leased out and not yet acked; their next message waits.
+    fn busy_groups(&self) -> HashSet<String> {
+        self.in_flight.values()
+            .filter(|x| !x.cancelled)
+            .filter_map(|x| x.msg.group.clone())
+            .collect()
+    }
+
+    /// Where the next message to lease waits, in `redriven` (true) or the backlog, and
+    /// at which position. Messages of the `busy` groups are passed over, so a group's
+    /// messages go out one at a time and in order while other groups carry on.
+    fn next_position(&self, busy: &HashSet<String>) -> Option<(bool, usize)> {
+        let deliverable = |waiting: &VecDeque<Message>| {
+            waiting.iter().position(|msg| msg.group.as_ref().is_none_or(|group| !busy.contains(group)))
+        };
+        if self.redrive_due() {
+            if let Some(i) = deliverable(&self.redriven) {
+                return Some((true, i));
+            }
+        }
+        deliverable(&self.queue).map(|i| (false, i))
+            .or_else(|| deliverable(&self.redriven).map(|i| (true, i)))
+    }
+
+    fn peek_next_message(&self, busy: &HashSet<String>) -> Option<&Message> {
+        match self.next_position(busy)? {
+            (true, i) => self.redriven.get(i),
+            (false, i) => self.queue.get(i)
+        }
+    }
+
+    /// Takes next messages whose TTL ran out out of line, so they are never leased.
+    fn drop_expired(&mut self, now: i64, busy: &HashSet<String>) {
+        while let Some((redriven, i)) = self.next_position(busy) {
+            let waiting = if redriven { &mut self.redriven } else { &mut self.queue };
+            if !waiting[i].expired(now) {
                 break;
             }
+            let msg = waiting.remove(i).unwrap();
+            self.discard_expired(msg);
+            self.expired_at_delivery += 1;
+        }
+    }
+
+    fn next_message(&mut self, busy: &HashSet<String>) -> Option<Message> {
+        match self.next_position(busy)? {
+            (true, i) => {
+                self.live_since_redrive = 0;
+                self.redriven.remove(i)
+            }
+            (false, i) => {
+                self.live_since_redrive += 1;
+                self.queue.remove(i)
+            }
         }
     }
 
     fn pop(&mut self, cnt: usize) -> Vec<Message> {
-        let mut deque_cnt = cnt.clone();
+        self.lease(None, cnt, None)
+    }
+
+    pub fn pop_for(&mut self, consumer: ConsumerId, cnt: usize) -> Vec<Message> {
+        self.lease(Some(consumer), cnt, None)
+    }
+
+    /// `pop_for` with leases that last `visibility_ms` instead of the queue's expiration.
+    pub fn pop_for_with_visibility(&mut self, consumer: ConsumerId, cnt: usize, visibility_ms: i64) -> Vec<Message> {
+        self.lease(Some(consumer), cnt, Some(visibility_ms))
+    }
+
+    fn lease(&mut self, consumer: Option<ConsumerId>, cnt: usize, visibility_ms: Option<i64>) -> Vec<Message> {
+        let visibility = Duration::milliseconds(visibility_ms.unwrap_or(self.in_flight_expiration_ms));
+        let mut deque_cnt = cnt;
+        if let Some(bucket) = &mut self.rate_limit {
+            let available = bucket.available(now_ms());
+            self.throttled = available < cnt;
+            deque_cnt = deque_cnt.min(available);
+        }
         self.sweep_in_flight();
+        let mut in_flight_bytes = self.in_flight_bytes();
+        let mut in_flight_count = self.in_flight_count();
+        let mut busy = self.busy_groups();
         let mut v = Vec::with_capacity(deque_cnt);
         while deque_cnt > 0 {
-            let wrapped_msg = self.queue.pop_front();
+            if self.max_in_flight.is_some_and(|max| in_flight_count >= max) {
+                break;
+            }
+            in_flight_count += 1;
+            self.drop_expired(now_ms(), &busy);
+            if let (Some(max), Some(next)) = (self.max_in_flight_bytes, self.peek_next_message(&busy)) {
+                // a message bigger than the cap still goes out once nothing else is in flight
+                if in_flight_bytes > 0 && in_flight_bytes + next.body_len() > max {
+                    break;
+                }
+                in_flight_bytes += next.body_len();
+            }
+            let wrapped_msg = self.next_message(&busy);
             if wrapped_msg.is_none() {
                 break;
             }
-            let msg = wrapped_msg.unwrap();
-            v.push(msg.clone());
+            let mut msg = wrapped_msg.unwrap();
+            if let Some(group) = &msg.group {
+                busy.insert(group.clone());
+            }
+            let now = Utc::now();
+            if msg.first_delivered_at.is_none() {
+                msg.first_delivered_at = Some(now.timestamp_millis());
+                self.time_in_queue.observe(elapsed_ms(msg.enqueued_at, now.timestamp_millis()));
+            }
+            msg.leased += 1;
+            v.push(msg.inflated());
             let new_msg = InflightMessage {
                 msg,
-                complete: false,
-                created_at: Utc::now()
+                created_at: now,
+                expires_at: now + visibility,
+                progress: None,
+                progress_updated_at: None,
+                consumer,
+                delayed: false,
+                cancelled: false
             };
-            self.in_flight.push_back(new_msg);
+            self.expire_at(new_msg);
             deque_cnt -= 1;
         }
+        if let Some(bucket) = &mut self.rate_limit {
+            bucket.spend(v.len());
+        }
+        if !v.is_empty() {
+            self.dequeued.record(now_ms(), v.len() as u64);
+        }
         v.shrink_to_fit();
         v
     }
@@ -129,26 +1388,44 @@ mod tests {
 
     fn create_msg() -> Message {
         Message {
-            body: MSG_BODY.to_string(),
+            body: Bytes::from_static(MSG_BODY.as_bytes()),
             queue_url: "123".to_string(),
             id: default_message_id(),
-            attempt: 1
+            attempt: 1,
+            leased: 0,
+            enqueued_at: now_ms(),
+            first_delivered_at: None,
+            priority: 0,
+            expires_at: None,
+            checksum: None,
+            group: None,
+            attributes: BTreeMap::new(),
+            compressed: false
         }
     }
 
-    fn setup() -> Lifo {
-        let mut q = Lifo::create(String::from(QUEUE_NAME));
+    fn setup() -> Queue {
+        let mut q = Queue::create(String::from(QUEUE_NAME));
         let msg = Message {
-            body: MSG_BODY.to_string(),
+            body: Bytes::from_static(MSG_BODY.as_bytes()),
             queue_url: "123".to_string(),
             id: default_message_id(),
-            attempt: 1
+            attempt: 1,
+            leased: 0,
+            enqueued_at: now_ms(),
+            first_delivered_at: None,
+            priority: 0,
+            expires_at: None,
+            checksum: None,
+            group: None,
+            attributes: BTreeMap::new(),
+            compressed: false
         };
         q.add(msg);
         q
     }
 
-    fn populate_wit_msgs(q: &mut Lifo) {
+    fn populate_wit_msgs(q: &mut Queue) {
         const MSG_CNT: usize = 1000;
         for _ in 0..MSG_CNT {
             let msg = create_msg();
@@ -166,7 +1443,7 @@ mod tests {
     fn test_add() {
         let q = setup();
         let loaded_msg = q.queue.back().unwrap();
-        assert_eq!(loaded_msg.body, MSG_BODY);
+        assert_eq!(loaded_msg.body, MSG_BODY.as_bytes());
     }
 
     #[test]
@@ -182,7 +1459,7 @@ mod tests {
     #[test]
     fn test_many_pop() {
         const MSG_CNT: usize = 1000;
-        let mut q = Lifo::create(String::from(QUEUE_NAME));
+        let mut q = Queue::create(String::from(QUEUE_NAME));
         let mut v = Vec::new();
         for _ in 0..MSG_CNT {
             let msg = create_msg();
@@ -205,10 +1482,10 @@ mod tests {
     #[test]
     fn test_sweep_in_flight() {
         const MSG_CNT: usize = 1000;
-        let mut q = Lifo::create_with_expiration(String::from(QUEUE_NAME), 0);
+        let mut q = Queue::create_with_expiration(String::from(QUEUE_NAME), 0);
         populate_wit_msgs(&mut q);
 
-        for _ in 1..Lifo::MAX_ATTEMPT {
+        for _ in 1..Queue::MAX_ATTEMPT {
             q.pop(MSG_CNT);
             q.sweep_in_flight();
             // should place all messages back in primary queue.
@@ -218,9 +1495,518 @@ mod tests {
         q.pop(MSG_CNT);
         q.sweep_in_flight();
 
-        // after the final attempt, the messages are dropped
+        // after the final attempt, the messages move to the dead letters
+        assert_eq!(q.queue.len(), 0);
+        assert_eq!(q.in_flight.len(), 0);
+        assert_eq!(q.dead_letter_count(), MSG_CNT);
+    }
+
+    #[test]
+    fn test_report_progress() {
+        let mut q = setup();
+        let msgs = q.pop(1);
+        let id = &msgs.first().unwrap().id;
+
+        assert!(q.report_progress(id, Progress::Percent(150)));
+        let in_flight_msg = q.show_in_flight(1)[0];
+        assert_eq!(in_flight_msg.progress, Some(Progress::Percent(100)));
+
+        assert!(q.report_progress(id, Progress::Status("resizing".to_string())));
+        let in_flight_msg = q.show_in_flight(1)[0];
+        assert_eq!(in_flight_msg.progress, Some(Progress::Status("resizing".to_string())));
+        assert!(in_flight_msg.progress_updated_at.is_some());
+
+        assert!(!q.report_progress(&default_message_id(), Progress::Percent(1)));
+    }
+
+    #[test]
+    fn test_release_consumer() {
+        const CONSUMER: ConsumerId = 7;
+        let mut q = Queue::create_with_expiration(String::from(QUEUE_NAME), 60_000);
+        for _ in 0..3 {
+            q.add(create_msg());
+        }
+        let mine = q.pop_for(CONSUMER, 2);
+        q.pop_for(CONSUMER + 1, 1);
+        assert!(q.nack(&mine[1].id, 60_000));
+
+        assert_eq!(q.release_consumer(CONSUMER), 1);
+        assert_eq!((q.depth(), q.in_flight_count()), (1, 2));
+        assert_eq!(q.pop(1)[0].attempt(), 2);
+        assert_eq!(q.release_consumer(CONSUMER), 0);
+    }
+
+    #[test]
+    fn test_cancel() {
+        const CONSUMER: ConsumerId = 7;
+        let mut q = Queue::create_with_expiration(String::from(QUEUE_NAME), 0);
+        q.add(create_msg());
+        let msgs = q.pop_for(CONSUMER, 1);
+        let id = &msgs.first().unwrap().id;
+
+        assert_eq!(q.cancel(id), Some(CONSUMER));
+        assert!(q.show_in_flight(1)[0].cancelled);
+
+        // a cancelled lease is dropped instead of being redelivered
+        q.sweep_in_flight();
         assert_eq!(q.queue.len(), 0);
         assert_eq!(q.in_flight.len(), 0);
+        assert_eq!(q.cancel(id), None);
+    }
+
+    fn dead_letter_all(q: &mut Queue, cnt: usize) {
+        for _ in 0..Queue::MAX_ATTEMPT {
+            q.pop(cnt);
+            q.sweep_in_flight();
+        }
+    }
+
+    #[test]
+    fn test_redrive_boost() {
+        let mut q = Queue::create_with_expiration(String::from(QUEUE_NAME), 0);
+        let dead = create_msg();
+        q.add(dead.clone());
+        dead_letter_all(&mut q, 1);
+        assert_eq!(q.dead_letters.len(), 1);
+
+        q.add(create_msg());
+        assert_eq!(q.redrive(10, RedrivePriority::Boost), 1);
+        let msgs = q.pop(2);
+        assert_eq!(msgs[0].id, dead.id);
+        assert_eq!(msgs[0].attempt, 1);
+        assert!(q.dead_letters.is_empty());
+    }
+
+    #[test]
+    fn test_redrive_backlog() {
+        let mut q = Queue::create_with_expiration(String::from(QUEUE_NAME), 0);
+        let dead = create_msg();
+        q.add(dead.clone());
+        dead_letter_all(&mut q, 1);
+
+        let live = create_msg();
+        q.add(live.clone());
+        q.redrive(1, RedrivePriority::Backlog);
+        let msgs = q.pop(2);
+        assert_eq!(msgs[0].id, live.id);
+        assert_eq!(msgs[1].id, dead.id);
+    }
+
+    #[test]
+    fn test_redrive_interleave() {
+        let mut q = Queue::create_with_expiration(String::from(QUEUE_NAME), 0);
+        for _ in 0..2 {
+            q.add(create_msg());
+        }
+        dead_letter_all(&mut q, 2);
+        let dead_ids: Vec<String> = q.dead_letters.iter().map(|m| m.id.clone()).collect();
+
+        populate_wit_msgs(&mut q);
+        q.redrive(2, RedrivePriority::Interleave(3));
+        let msgs = q.pop(8);
+        assert_eq!(msgs[3].id, dead_ids[0]);
+        assert_eq!(msgs[7].id, dead_ids[1]);
+    }
+
+    #[test]
+    fn test_purge() {
+        let mut q = Queue::create_with_expiration(String::from(QUEUE_NAME), 0);
+        q.add(create_msg());
+        dead_letter_all(&mut q, 1);
+        q.redrive(1, RedrivePriority::Interleave(5));
+        populate_wit_msgs(&mut q);
+        let leased = q.pop(1);
+        let waiting = q.depth();
+
+        assert_eq!(q.purge(2), 2);
+        assert_eq!(q.depth(), waiting - 2);
+        assert_eq!(q.purge(usize::MAX), waiting - 2);
+        assert_eq!(q.depth(), 0);
+        assert!(q.waiting().is_empty());
+        assert_eq!(q.snapshot()[0].id, leased[0].id);
+    }
+
+    #[test]
+    fn test_digest() {
+        let mut a = Queue::create(String::from(QUEUE_NAME));
+        let mut b = Queue::create(String::from(QUEUE_NAME));
+        let msgs: Vec<Message> = (0..3).map(|_| create_msg()).collect();
+        for msg in &msgs {
+            a.add(msg.clone());
+        }
+        for msg in msgs.iter().rev() {
+            b.add(msg.clone());
+        }
+        assert_eq!(a.digest(), b.digest());
+
+        // leased but unacked messages still count
+        a.pop(1);
+        assert_eq!(a.digest(), b.digest());
+        let popped = b.pop(1);
+        b.complete(&popped[0].id);
+        assert_eq!(b.digest().0, 2);
+        assert_ne!(a.digest(), b.digest());
+        assert_eq!(Queue::create(String::from(QUEUE_NAME)).digest(), (0, 0));
+    }
+
+    #[test]
+    fn test_latency_histograms() {
+        let mut q = Queue::create_with_expiration(String::from(QUEUE_NAME), 0);
+        let mut msg = create_msg();
+        msg.enqueued_at = now_ms() - 40;
+        q.add(msg);
+        let leased = q.pop(1);
+        assert!(leased[0].first_delivered_at.is_some());
+        assert_eq!(q.time_in_queue.count(), 1);
+        assert!(q.time_in_queue.sum() >= 40);
+
+        // a redelivery isn't another wait in the queue
+        q.sweep_in_flight();
+        let redelivered = q.pop(1);
+        assert_eq!(redelivered[0].attempt, 2);
+        assert_eq!(redelivered[0].first_delivered_at, leased[0].first_delivered_at);
+        assert_eq!(q.time_in_queue.count(), 1);
+
+        assert!(q.complete(&redelivered[0].id));
+        assert!(q.complete(&redelivered[0].id));
+        assert_eq!(q.processing_time.count(), 1);
+    }
+
+    #[test]
+    fn test_lifo_order() {
+        let mut q = Queue::create_with_expiration(String::from(QUEUE_NAME), 0);
+        q.set_order(QueueOrder::Lifo);
+        for body in ["1", "2", "3"] {
+            q.add(Message::new(QUEUE_NAME.to_string(), body));
+        }
+        let body = |msgs: Vec<Message>| msgs[0].body.clone();
+        assert_eq!(body(q.pop(1)), "3");
+        // an expired lease is retried ahead of the backlog
+        std::thread::sleep(std::time::Duration::from_millis(2));
+        q.sweep_in_flight();
+        assert_eq!(body(q.pop(1)), "3");
+        q.add(Message::new(QUEUE_NAME.to_string(), "4"));
+
+        let mut copy = Queue::create(String::from(QUEUE_NAME));
+        copy.set_order(QueueOrder::Lifo);
+        let waiting: Vec<Message> = q.waiting().into_iter().cloned().collect();
+        waiting.into_iter().for_each(|msg| copy.append(msg));
+        assert_eq!(body(copy.pop(1)), "4");
+        assert_eq!(body(copy.pop(1)), "2");
+    }
+
+    #[test]
+    fn test_priority_order() {
+        let mut q = Queue::create_with_expiration(String::from(QUEUE_NAME), 0);
+        q.set_order(QueueOrder::Priority);
+        for (body, priority) in [("low", 0), ("urgent", 9), ("normal", 5), ("urgent too", 9)] {
+            q.add(Message::new(QUEUE_NAME.to_string(), body).with_priority(priority));
+        }
+        let body = |msgs: Vec<Message>| msgs[0].body.clone();
+        assert_eq!(body(q.pop(1)), "urgent");
+        // a retry goes ahead of its own priority only
+        std::thread::sleep(std::time::Duration::from_millis(2));
+        q.sweep_in_flight();
+        let bodies: Vec<Bytes> = q.waiting().into_iter().map(|msg| msg.body.clone()).collect();
+        assert_eq!(bodies, ["urgent", "urgent too", "normal", "low"]);
+
+        let mut copy = Queue::create(String::from(QUEUE_NAME));
+        copy.set_order(QueueOrder::Priority);
+        for msg in q.waiting() {
+            copy.append(msg.copy_to(QUEUE_NAME.to_string()));
+        }
+        assert_eq!(body(copy.pop(1)), "urgent");
+        assert_eq!(copy.waiting()[2].priority(), 0);
+    }
+
+    #[test]
+    fn test_body_size_sampling() {
+        let mut q = Queue::create(String::from(QUEUE_NAME));
+        for _ in 0..Queue::SIZE_SAMPLE_EVERY + 1 {
+            q.add(Message::new(QUEUE_NAME.to_string(), vec![0u8; 100]));
+        }
+        assert_eq!(q.body_sizes().count(), 2);
+        assert_eq!(q.body_sizes().sum(), 200);
+    }
+
+    #[test]
+    fn test_visibility_per_pop() {
+        let mut q = Queue::create_with_expiration(String::from(QUEUE_NAME), 0);
+        q.add(create_msg());
+        q.add(create_msg());
+        let long = q.pop_for_with_visibility(1, 1, 60_000);
+        let short = q.pop_for(1, 1);
+        std::thread::sleep(std::time::Duration::from_millis(2));
+
+        // the short lease expired even though the long one is older
+        assert!(q.sweep_in_flight());
+        assert_eq!(q.in_flight_count(), 1);
+        assert_eq!(q.waiting()[0].id, short[0].id);
+        assert!(q.complete(&long[0].id));
+    }
+
+    #[test]
+    fn test_ttl_checked_at_delivery() {
+        let mut q = Queue::create(String::from(QUEUE_NAME));
+        q.add(create_msg().with_ttl(0));
+        q.add(create_msg().with_ttl(60_000));
+        q.add(create_msg().with_ttl(0));
+        q.add(create_msg());
+        assert_eq!(q.depth(), 4);
+
+        // expired messages are skipped over, wherever they are in line
+        let popped = q.pop(3);
+        assert_eq!(popped.len(), 2);
+        assert!(popped[0].expires_at().is_some());
+        assert_eq!(popped[1].expires_at(), None);
+        assert_eq!(q.expired_at_delivery(), 2);
+        assert_eq!(q.depth(), 0);
+    }
+
+    #[test]
+    fn test_ttl_checked_by_sweep() {
+        let mut q = Queue::create(String::from(QUEUE_NAME));
+        q.set_dead_letter_expired(true);
+        let now = now_ms();
+        assert_eq!(q.expire(now), 0);
+        q.add(create_msg().with_ttl(60_000));
+        q.add(create_msg().with_ttl(0));
+        q.add(create_msg());
+        assert_eq!(q.expire(now - 1_000), 0);
+
+        assert_eq!(q.expire(now + 1_000), 1);
+        assert_eq!((q.depth(), q.dead_letter_count(), q.expired_waiting()), (2, 1, 1));
+        // nothing else runs out for another minute
+        assert_eq!(q.expire(now + 2_000), 0);
+        assert_eq!(q.expire(now + 120_000), 1);
+        assert_eq!(q.depth(), 1);
+    }
+
+    #[test]
+    fn test_overflow() {
+        let mut q = Queue::create(String::from(QUEUE_NAME));
+        q.add(create_msg());
+        assert!(!q.absorb(create_msg()));
+        q.set_overflow_limit(2);
+        let first = create_msg();
+        let first_id = first.id.clone();
+        assert!(q.absorb(first));
+        assert!(q.absorb(create_msg()));
+        assert!(!q.absorb(create_msg()));
+        assert_eq!((q.depth(), q.overflow_depth(), q.overflowed()), (1, 2, 2));
+        assert_eq!(q.snapshot().len(), 3);
+
+        // still full
+        assert_eq!(q.drain_overflow(Some(1)), 0);
+        q.pop(1);
+        assert_eq!(q.drain_overflow(Some(1)), 1);
+        assert_eq!(q.waiting()[0].id, first_id);
+        assert_eq!(q.drain_overflow(None), 1);
+        assert_eq!((q.depth(), q.overflow_depth()), (2, 0));
+    }
+
+    #[test]
+    fn test_message_groups() {
+        let mut q = Queue::create_with_expiration(String::from(QUEUE_NAME), 60_000);
+        for (group, body) in [("a", "a1"), ("a", "a2"), ("b", "b1"), ("a", "a3")] {
+            q.add(Message::new(QUEUE_NAME.to_string(), body).with_group(group.to_string()));
+        }
+        q.add(create_msg());
+        let bodies = |msgs: &[Message]| -> Vec<Vec<u8>> { msgs.iter().map(|msg| msg.body().to_vec()).collect() };
+
+        // One of each group at a time, ungrouped messages as usual.
+        let first = q.pop_for(1, 10);
+        assert_eq!(bodies(&first), [b"a1".to_vec(), b"b1".to_vec(), MSG_BODY.as_bytes().to_vec()]);
+        assert!(q.pop_for(2, 10).is_empty());
+
+        // Until a1 is acked, a2 waits even though b1 was acked.
+        assert!(q.complete(first[1].id()));
+        assert!(q.pop_for(2, 10).is_empty());
+        assert!(q.complete(first[0].id()));
+        let next = q.pop_for(2, 10);
+        assert_eq!(bodies(&next), [b"a2".to_vec()]);
+        assert_eq!(next[0].group(), Some("a"));
+    }
+
+    #[test]
+    fn test_drop_oldest() {
+        let mut q = Queue::create(String::from(QUEUE_NAME));
+        q.set_order(QueueOrder::Lifo);
+        assert!(q.drop_oldest().is_none());
+        let mut ids = Vec::new();
+        for enqueued_at in [20, 10, 30] {
+            let mut msg = create_msg();
+            msg.enqueued_at = enqueued_at;
+            ids.push(msg.id.clone());
+            q.add(msg);
+        }
+        assert_eq!(q.drop_oldest().unwrap().id, ids[1]);
+        assert_eq!(q.drop_oldest().unwrap().id, ids[0]);
+        assert_eq!((q.depth(), q.dropped()), (1, 2));
+        assert_eq!(q.waiting()[0].id, ids[2]);
+    }
+
+    #[test]
+    fn test_nack() {
+        let mut q = Queue::create_with_expiration(String::from(QUEUE_NAME), 60_000);
+        q.add(create_msg());
+        let leased = q.pop(1);
+        assert!(q.nack(&leased[0].id, 0));
+        assert!(!q.nack(&leased[0].id, 0));
+        assert_eq!(q.in_flight_count(), 0);
+
+        // a delayed nack keeps the message out of reach until the delay is up
+        let leased = q.pop(1);
+        assert_eq!(leased[0].attempt(), 2);
+        assert!(q.nack(&leased[0].id, 1));
+        assert_eq!(q.delayed_count(), 1);
+        assert!(q.pop(1).is_empty());
+        std::thread::sleep(std::time::Duration::from_millis(3));
+        let leased = q.pop(1);
+        assert_eq!(leased[0].attempt(), 3);
+        assert_eq!((q.delayed_count(), q.redelivered()), (0, 2));
+
+        // out of attempts
+        assert!(q.nack(&leased[0].id, 0));
+        assert_eq!(q.dead_letter_count(), 1);
+        assert!(!q.nack(&"unknown".to_string(), 0));
+    }
+
+    #[test]
+    fn test_touch() {
+        let mut q = Queue::create_with_expiration(String::from(QUEUE_NAME), 0);
+        q.add(create_msg());
+        q.add(create_msg());
+        let leased = q.pop(2);
+        let (touched, untouched) = (&leased[0], &leased[1]);
+        assert!(q.touch(&touched.id, Some(60_000)));
+        std::thread::sleep(std::time::Duration::from_millis(2));
+
+        assert!(q.sweep_in_flight());
+        assert_eq!(q.in_flight_count(), 1);
+        // the expiry the touch replaced went with the sweep
+        assert_eq!(q.expiry.len(), 1);
+        assert_eq!(q.waiting()[0].id, untouched.id);
+        assert!(!q.touch(&untouched.id, None));
+        assert!(q.complete(&touched.id));
+        assert!(!q.touch(&touched.id, None));
+        assert!(!q.sweep_in_flight());
+        assert!(q.expiry.is_empty());
+    }
+
+    #[test]
+    fn test_batches() {
+        let mut q = Queue::create(String::from(QUEUE_NAME));
+        q.add_batch((0..4).map(|_| create_msg()).collect());
+        assert_eq!(q.depth(), 4);
+        let popped = q.pop(3);
+        q.complete(&popped[2].id);
+
+        let id = |i: usize| popped[i].id.clone();
+        let ids = vec![id(0), id(1), id(0), id(2), "unknown".to_string()];
+        assert_eq!(q.complete_batch(&ids), [true, true, true, true, false]);
+        assert_eq!(q.ack_cache_hits(), 2);
+        assert_eq!(q.in_flight_count(), 0);
+        assert_eq!(q.processing_time().count(), 3);
+    }
+
+    #[test]
+    fn test_duplicate_ack() {
+        let mut q = setup();
+        q.set_ack_cache_size(1);
+        q.add(create_msg());
+        let popped = q.pop(2);
+        assert!(q.complete(&popped[0].id));
+        assert!(q.complete(&popped[0].id));
+        assert_eq!(q.ack_cache_hits(), 1);
+
+        assert!(q.complete(&popped[1].id));
+        // Evicted to make room for the second id.
+        assert!(!q.complete(&popped[0].id));
+        assert!(!q.complete(&"unknown".to_string()));
+        assert_eq!(q.ack_cache_hits(), 1);
+    }
+
+    #[test]
+    fn test_receipts() {
+        let mut q = setup();
+        let first = q.pop(1).remove(0);
+        assert_eq!(first.receipt(), first.id);
+        assert!(!q.stale_receipt(&first.receipt()));
+        assert!(q.nack(&first.id, 0));
+
+        let second = q.pop(1).remove(0);
+        assert_eq!(second.receipt(), format!("{}:2", second.id));
+        assert_eq!(receipt_id(&second.receipt()), second.id);
+        assert!(q.stale_receipt(&first.receipt()));
+        assert!(!q.stale_receipt(&second.receipt()));
+    }
+
+    #[test]
+    fn test_compression() {
+        let msg = Message::new(QUEUE_NAME.to_string(), "x".repeat(1000)).with_checksum();
+        let compressed = msg.clone().with_compression();
+        assert!(compressed.compressed() && compressed.body.len() < 100);
+        let inflated = compressed.inflated();
+        assert_eq!(inflated.body(), msg.body());
+        assert!(!inflated.compressed() && inflated.body_intact());
+
+        // left alone when compressing wouldn't save anything
+        let tiny = Message::new(QUEUE_NAME.to_string(), "x").with_compression();
+        assert!(!tiny.compressed());
+    }
+
+    #[test]
+    fn test_max_in_flight_bytes() {
+        let mut q = Queue::create(String::from(QUEUE_NAME));
+        q.set_max_in_flight_bytes(Some(10));
+        for body in ["aaaa", "bbbb", "cccc", "dddddddddddddddd"] {
+            q.add(Message::new(QUEUE_NAME.to_string(), body.to_string()));
+        }
+        let popped = q.pop(10);
+        assert_eq!(popped.len(), 2);
+        assert_eq!(q.in_flight_bytes(), 8);
+        assert!(q.pop(10).is_empty());
+
+        q.complete(&popped[0].id);
+        q.complete(&popped[1].id);
+        assert_eq!(q.pop(10).len(), 1);
+        let id = q.show_in_flight(1)[0].msg.id.clone();
+        q.complete(&id);
+        // over the cap on its own, but nothing else is in flight
+        assert_eq!(q.pop(10).len(), 1);
+        assert_eq!(q.in_flight_bytes(), 16);
+    }
+
+    #[test]
+    fn test_max_in_flight_bytes_counts_compressed_bodies_in_full() {
+        let mut q = Queue::create(String::from(QUEUE_NAME));
+        q.set_max_in_flight_bytes(Some(150));
+        for _ in 0..3 {
+            let msg = Message::new(QUEUE_NAME.to_string(), "a".repeat(100)).with_compression();
+            assert!(msg.compressed() && msg.body.len() < 50);
+            assert_eq!(msg.body_len(), 100);
+            q.add(msg);
+        }
+        assert_eq!(q.pop(10).len(), 1);
+        assert_eq!(q.in_flight_bytes(), 100);
+        assert!(q.pop(10).is_empty());
+    }
+
+    #[test]
+    fn test_max_in_flight() {
+        let mut q = Queue::create(String::from(QUEUE_NAME));
+        q.set_max_in_flight(Some(2));
+        for _ in 0..4 {
+            q.add(create_msg());
+        }
+        let first = q.pop_for(1, 1);
+        assert_eq!(q.pop_for(2, 10).len(), 1);
+        assert!(q.pop_for(3, 10).is_empty());
+
+        q.complete(&first[0].id);
+        assert_eq!(q.pop_for(3, 10).len(), 1);
+        assert_eq!((q.in_flight_count(), q.depth()), (2, 1));
     }
 
     #[test]
@@ -232,4 +2018,35 @@ mod tests {
         let in_flight_msg = &v.first().unwrap().msg;
         assert_eq!(msg.id, in_flight_msg.id);
     }
+
+    #[test]
+    fn test_checksum() {
+        let mut q = setup();
+        let msg = Message::new(QUEUE_NAME.to_string(), "hello".to_string()).with_checksum();
+        assert_eq!(msg.checksum(), Some(0x3610a686));
+        assert!(msg.body_intact());
+        q.add(msg);
+
+        let popped = q.pop(5);
+        let id = popped.iter().find(|x| x.checksum.is_some()).unwrap().id.clone();
+        assert_eq!(q.in_flight_checksum(&id), Some(0x3610a686));
+        assert_eq!(q.in_flight_checksum(&popped[0].id), None);
+
+        let mut corrupted = popped.into_iter().find(|x| x.id == id).unwrap();
+        corrupted.body = Bytes::from([&corrupted.body[..], b"!"].concat());
+        assert!(!corrupted.body_intact());
+    }
+
+    #[test]
+    fn test_body_serialization() {
+        let text = Message::new(QUEUE_NAME.to_string(), "hello");
+        let json = serde_json::to_string(&text).unwrap();
+        assert!(json.contains(r#""messageBody":"hello""#));
+
+        let binary = Message::new(QUEUE_NAME.to_string(), vec![0xff, b'\r', b'\n']);
+        let json = serde_json::to_string(&binary).unwrap();
+        assert!(json.contains(r#""messageBody":[255,13,10]"#));
+        let loaded: Message = serde_json::from_str(&json).unwrap();
+        assert_eq!(loaded.body(), binary.body());
+    }
 }
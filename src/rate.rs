@@ -0,0 +1,120 @@
+/// Seconds of history a `RateMeter` averages over.
+pub const RATE_WINDOW_SECS: usize = 60;
+
+/// Events per second averaged over the last `RATE_WINDOW_SECS`, counted into one bucket
+/// per second so old events fall out of the window as time moves on.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RateMeter {
+    buckets: [u64; RATE_WINDOW_SECS],
+    /// Second since the epoch of the newest bucket.
+    second: i64,
+}
+
+impl Default for RateMeter {
+    fn default() -> RateMeter {
+        RateMeter {
+            buckets: [0; RATE_WINDOW_SECS],
+            second: 0,
+        }
+    }
+}
+
+impl RateMeter {
+    /// Counts `events` at `now_ms`, milliseconds since the epoch.
+    pub fn record(&mut self, now_ms: i64, events: u64) {
+        let second = self.advance(now_ms);
+        self.buckets[second] += events;
+    }
+
+    pub fn per_second(&mut self, now_ms: i64) -> f64 {
+        self.advance(now_ms);
+        self.buckets.iter().sum::<u64>() as f64 / RATE_WINDOW_SECS as f64
+    }
+
+    /// Clears the buckets of the seconds gone by since the last call and returns the
+    /// bucket of `now_ms`.
+    fn advance(&mut self, now_ms: i64) -> usize {
+        let second = now_ms.div_euclid(1000);
+        let gone = (second - self.second).clamp(0, RATE_WINDOW_SECS as i64);
+        for past in 1..=gone {
+            self.buckets[bucket_of(self.second + past)] = 0;
+        }
+        self.second = self.second.max(second);
+        bucket_of(second)
+    }
+}
+
+fn bucket_of(second: i64) -> usize {
+    second.rem_euclid(RATE_WINDOW_SECS as i64) as usize
+}
+
+/// Lets through `per_second` events a second on average, and bursts of up to a second's
+/// worth after a quiet spell.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TokenBucket {
+    per_second: usize,
+    tokens: f64,
+    /// When `tokens` was last topped up, in ms since the epoch.
+    refilled_at: i64,
+}
+
+impl TokenBucket {
+    /// A full bucket.
+    pub fn new(per_second: usize, now_ms: i64) -> TokenBucket {
+        TokenBucket {
+            per_second,
+            tokens: per_second as f64,
+            refilled_at: now_ms,
+        }
+    }
+
+    pub fn per_second(&self) -> usize {
+        self.per_second
+    }
+
+    /// Events that may happen at `now_ms` without going over the rate.
+    pub fn available(&mut self, now_ms: i64) -> usize {
+        let elapsed_secs = (now_ms - self.refilled_at).max(0) as f64 / 1000.0;
+        let capacity = self.per_second as f64;
+        self.tokens = (self.tokens + elapsed_secs * capacity).min(capacity);
+        self.refilled_at = self.refilled_at.max(now_ms);
+        self.tokens as usize
+    }
+
+    /// Counts `events` that `available` allowed.
+    pub fn spend(&mut self, events: usize) {
+        self.tokens = (self.tokens - events as f64).max(0.0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::rate::*;
+
+    #[test]
+    fn test_rate_meter() {
+        let mut rate = RateMeter::default();
+        let start = 1_700_000_000_000;
+        rate.record(start, 30);
+        rate.record(start + 500, 30);
+        rate.record(start + 10_000, 60);
+        assert_eq!(rate.per_second(start + 10_000), 2.0);
+
+        // the first second has left the window, the later one hasn't yet
+        assert_eq!(rate.per_second(start + 60_000), 1.0);
+        assert_eq!(rate.per_second(start + 600_000), 0.0);
+    }
+
+    #[test]
+    fn test_token_bucket() {
+        let start = 1_700_000_000_000;
+        let mut bucket = TokenBucket::new(10, start);
+        assert_eq!(bucket.available(start), 10);
+        bucket.spend(10);
+        assert_eq!(bucket.available(start + 50), 0);
+        assert_eq!(bucket.available(start + 250), 2);
+        bucket.spend(2);
+        // a long quiet spell refills no more than a second's worth
+        assert_eq!(bucket.available(start + 60_000), 10);
+    }
+}
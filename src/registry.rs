@@ -0,0 +1,245 @@
+use crate::queue::Queue;
+use crate::resp::Cmd;
+use crate::routing::{RouteKey, ShardRouter};
+use std::collections::HashMap;
+use std::sync::{Arc, LockResult, Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use uuid::Uuid;
+
+pub type Queues = HashMap<String, Queue>;
+
+/// A queue created with `QUEUE CREATE ... SHARDS`, kept as `count` ordinary queues named
+/// by `shard_name`.
+struct ShardSet {
+    count: usize,
+    /// Picks the shard of every push, as `ServerConfig::routing_for` the queue says.
+    router: Box<dyn ShardRouter>,
+    /// Shard the next POP looks at first, so consumers drain them in turn.
+    next_pop: usize,
+}
+
+impl ShardSet {
+    fn route_push(&mut self, group: Option<&str>) -> usize {
+        let message_id = Uuid::new_v4().to_string();
+        let key = RouteKey {
+            message_id: &message_id,
+            group_id: group,
+            partition: None,
+        };
+        self.router.route(&key, self.count)
+    }
+}
+
+/// The queue holding shard `index` of `queue`.
+pub fn shard_name(queue: &str, index: usize) -> String {
+    format!("{}:{}", queue, index)
+}
+
+/// Every queue by name, created on demand by `lookup_queue` or with `QUEUE CREATE`, and
+/// shared by all connections, the sweeper and background jobs.
+///
+/// Commands that only look at queues, such as `QUEUE LIST`, `PEEK`, `SERVER METRICS`
+/// and snapshots, share it through `read` and run side by side. Anything that changes a
+/// queue, POP included, takes it whole with `lock`. It stays one lock rather than one
+/// per queue because FANOUT and `QUEUE CLONE` act on several queues at once and must
+/// never be seen half done.
+///
+/// A sharded queue is several queues in the registry, one per shard, that clients still
+/// address by the one name; `route` picks the shard. Each shard has its own backlog,
+/// leases and wait line, but they share this lock with every other queue.
+#[derive(Clone, Default)]
+pub struct QueueRegistry {
+    queues: Arc<RwLock<Queues>>,
+    /// Taken after `queues` when both are held, never before; `route` lets go of it first.
+    shards: Arc<Mutex<HashMap<String, ShardSet>>>,
+}
+
+impl QueueRegistry {
+    /// Exclusive access, for commands that change queues or the set of them.
+    pub fn lock(&self) -> LockResult<RwLockWriteGuard<'_, Queues>> {
+        self.queues.write()
+    }
+
+    /// Shared access, for commands that only look.
+    pub fn read(&self) -> LockResult<RwLockReadGuard<'_, Queues>> {
+        self.queues.read()
+    }
+
+    /// Makes `queue` a name for its `count` shards, which must already be in the registry.
+    pub fn add_shards(&self, queue: &str, count: usize, router: Box<dyn ShardRouter>) {
+        let set = ShardSet {
+            count,
+            router,
+            next_pop: 0,
+        };
+        self.shards.lock().unwrap().insert(queue.to_string(), set);
+    }
+
+    /// Forgets that `queue` is sharded, returning how many shards it had.
+    pub fn remove_shards(&self, queue: &str) -> Option<usize> {
+        self.shards
+            .lock()
+            .unwrap()
+            .remove(queue)
+            .map(|set| set.count)
+    }
+
+    pub fn shard_count(&self, queue: &str) -> Option<usize> {
+        self.shards.lock().unwrap().get(queue).map(|set| set.count)
+    }
+
+    /// Points a queue command sent to a sharded queue at one of its shards: a push at
+    /// the shard its router picks, keeping a group on one shard, a POP or PEEK at the
+    /// first shard with messages waiting, taking turns, and ACK, NACK, TOUCH,
+    /// PROGRESS and CANCEL at the shard holding the lease. Other commands, and commands for unsharded queues, are
+    /// returned as they are.
+    ///
+    /// A POP finding every shard empty goes to the next shard in turn, so a blocked POP
+    /// waits on that shard alone.
+    pub fn route(&self, mut cmd: Cmd) -> Cmd {
+        let mut shards = self.shards.lock().unwrap();
+        if shards.is_empty() {
+            return cmd;
+        }
+        let advance = matches!(cmd, Cmd::POP { .. });
+        match &mut cmd {
+            Cmd::PUSH { queue, group, .. } => {
+                if let Some(set) = shards.get_mut(queue.as_str()) {
+                    let index = set.route_push(group.as_deref());
+                    *queue = shard_name(queue, index);
+                }
+            }
+            Cmd::MPUSH { queue, .. } => {
+                if let Some(set) = shards.get_mut(queue.as_str()) {
+                    let index = set.route_push(None);
+                    *queue = shard_name(queue, index);
+                }
+            }
+            Cmd::POP { queue, .. } | Cmd::PEEK { queue, .. } => {
+                let Some((count, start)) = shards
+                    .get(queue.as_str())
+                    .map(|set| (set.count, set.next_pop))
+                else {
+                    return cmd;
+                };
+                drop(shards);
+                let index = {
+                    let queues = self.queues.read().unwrap();
+                    (start..start + count)
+                        .map(|i| i % count)
+                        .find(|&i| {
+                            queues
+                                .get(&shard_name(queue, i))
+                                .is_some_and(|q| q.depth() > 0)
+                        })
+                        .unwrap_or(start % count)
+                };
+                if advance {
+                    if let Some(set) = self.shards.lock().unwrap().get_mut(queue.as_str()) {
+                        set.next_pop = (index + 1) % set.count;
+                    }
+                }
+                *queue = shard_name(queue, index);
+            }
+            Cmd::ACK { queue, ids, .. } => {
+                // A batch is settled on the shard holding its first receipt.
+                let receipt = ids.first().map_or("", String::as_str);
+                if let Some(index) = self.lease_shard(shards, queue, receipt) {
+                    *queue = shard_name(queue, index);
+                }
+            }
+            Cmd::NACK { queue, id, .. }
+            | Cmd::TOUCH { queue, id, .. }
+            | Cmd::PROGRESS { queue, id, .. }
+            | Cmd::CANCEL { queue, id } => {
+                if let Some(index) = self.lease_shard(shards, queue, id) {
+                    *queue = shard_name(queue, index);
+                }
+            }
+            Cmd::CHANNEL { cmd: inner, .. } => {
+                drop(shards);
+                let routed = self.route(std::mem::replace(inner.as_mut(), Cmd::Unknown));
+                **inner = routed;
+            }
+            _ => {}
+        }
+        cmd
+    }
+
+    /// The shard of `queue` holding the lease behind `receipt`, or shard 0 when none
+    /// does, so the error comes from there. `None` when `queue` is not sharded.
+    fn lease_shard(
+        &self,
+        shards: MutexGuard<'_, HashMap<String, ShardSet>>,
+        queue: &str,
+        receipt: &str,
+    ) -> Option<usize> {
+        let count = shards.get(queue)?.count;
+        drop(shards);
+        let queues = self.queues.read().unwrap();
+        let index = (0..count)
+            .find(|&i| {
+                queues
+                    .get(&shard_name(queue, i))
+                    .is_some_and(|q| q.is_leased(receipt))
+            })
+            .unwrap_or(0);
+        Some(index)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::queue::Queue;
+    use crate::registry::QueueRegistry;
+    use crate::resp::Cmd;
+    use crate::routing::RoutingStrategy;
+    use bytes::Bytes;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn test_readers_share_the_registry() {
+        let registry = QueueRegistry::default();
+        registry
+            .lock()
+            .unwrap()
+            .insert("jobs".to_string(), Queue::create("jobs".to_string()));
+
+        let first = registry.read().unwrap();
+        let second = registry.clone();
+        let second = second.read().unwrap();
+        assert!(first.contains_key("jobs") && second.contains_key("jobs"));
+        assert!(registry.queues.try_write().is_err());
+        drop((first, second));
+        assert!(registry.queues.try_write().is_ok());
+    }
+
+    #[test]
+    fn test_groups_stay_on_one_shard() {
+        let registry = QueueRegistry::default();
+        registry.add_shards("hot", 8, RoutingStrategy::HashGroup.router());
+        let push = |group: &str| Cmd::PUSH {
+            queue: "hot".to_string(),
+            body: Bytes::from_static(b"body"),
+            checksum: None,
+            priority: 0,
+            ttl: None,
+            dedup: None,
+            group: Some(group.to_string()),
+            attributes: BTreeMap::new(),
+        };
+        let shard = registry
+            .route(push("order-42"))
+            .queue()
+            .unwrap()
+            .to_string();
+        assert!(shard.starts_with("hot:"));
+        for _ in 0..10 {
+            assert_eq!(
+                registry.route(push("order-42")).queue(),
+                Some(shard.as_str())
+            );
+        }
+        assert_eq!(registry.remove_shards("hot"), Some(8));
+        assert_eq!(registry.route(push("order-42")).queue(), Some("hot"));
+    }
+}
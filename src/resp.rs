@@ -1,8 +1,22 @@
-use crate::resp_buffered_reader::RespBufferedReader;
+use crate::deprecation::{Deprecation, HELLO_PASSWORD};
+use crate::error_code::ErrorCode;
+use crate::jobs::JobId;
+use crate::overload::Priority;
+use crate::profiler::MAX_PROFILE_SECONDS;
+use crate::queue::{FullPolicy, Progress, QueueOrder, RedrivePriority};
+use crate::resp_value::RespValue;
+use crate::rules::{Rule, RuleError};
+use crate::schedule::CronExpr;
+use crate::trace_sampling::TraceScope;
+use crate::units::{ByteSize, HumanDuration};
+use crate::wire::{find_command, CommandSpec};
+use bytes::Bytes;
+use std::collections::BTreeMap;
 use std::fmt;
 use std::fmt::Formatter;
-use std::str::{FromStr, Split};
-use strum_macros::EnumString;
+use std::str::FromStr;
+use std::time::Duration;
+use strum_macros::{EnumIter, EnumString, IntoStaticStr};
 
 #[derive(Debug)]
 pub enum RespError {
@@ -13,6 +27,29 @@ pub enum RespError {
     InvalidArgument(String),
     ProtocolOutOfRange(String),
     CmdNotImplemented(String),
+    AuthRequired,
+    QueueFull(String),
+    /// A body bigger than the queue's `MAXSIZE`, which is given in bytes.
+    MessageTooLarge(String, usize),
+    /// A POP from a consumer already holding its `PREFETCH` limit of unacked messages.
+    PrefetchReached(usize),
+    /// A POP from an `EXCLUSIVE` queue that another consumer has claimed.
+    ExclusiveQueue(String),
+    ChecksumMismatch(String),
+    Busy,
+    NoPermission(String),
+    Timeout(String),
+    /// Too few or too many words for the command, named in lowercase as Redis does.
+    WrongArity(String),
+    UnknownQueue(String),
+    QueueExists(String),
+    UnknownJob(JobId),
+    /// `QUEUE DELETE` without `FORCE` on a queue with this many leases out.
+    QueueLeased(String, usize),
+    /// A queue command was sent without a queue before `USE` set a default.
+    NoDefaultQueue,
+    /// A rule that doesn't compile, or ran out of budget on the message at hand.
+    InvalidRule(RuleError),
 }
 
 impl fmt::Display for RespError {
@@ -25,30 +62,328 @@ impl fmt::Display for RespError {
             RespError::NoData => write!(f, "no data"),
             RespError::ProtocolOutOfRange(err) => write!(f, "{} protocol out of range", err),
             RespError::CmdNotImplemented(err) => write!(f, "{} not implemented", err),
-            _ => {
-                todo!()
+            RespError::AuthRequired => write!(f, "Authentication required."),
+            RespError::QueueFull(queue) => write!(f, "queue '{}' is at capacity", queue),
+            RespError::MessageTooLarge(queue, max) => {
+                write!(f, "queue '{}' takes bodies of at most {} bytes", queue, max)
             }
+            RespError::PrefetchReached(limit) => write!(
+                f,
+                "consumer holds {} unacked messages, its PREFETCH limit",
+                limit
+            ),
+            RespError::ExclusiveQueue(queue) => {
+                write!(f, "queue '{}' is exclusive to another consumer", queue)
+            }
+            RespError::ChecksumMismatch(what) => write!(f, "checksum mismatch for {}", what),
+            RespError::Busy => write!(f, "server is overloaded, try again later"),
+            RespError::NoPermission(cmd) => write!(f, "user may not run {}", cmd),
+            RespError::Timeout(cmd) => write!(f, "{} ran out of time and was cancelled", cmd),
+            RespError::WrongArity(cmd) => write!(f, "wrong number of arguments for '{}'", cmd),
+            RespError::UnknownQueue(queue) => write!(f, "unknown queue '{}'", queue),
+            RespError::QueueExists(queue) => write!(f, "queue '{}' already exists", queue),
+            RespError::UnknownJob(id) => write!(f, "unknown job {}", id),
+            RespError::QueueLeased(queue, leased) => write!(
+                f,
+                "queue '{}' has {} leased messages, delete with FORCE to drop them",
+                queue, leased
+            ),
+            RespError::NoDefaultQueue => write!(f, "no queue given and none set with USE"),
+            RespError::InvalidRule(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl RespError {
+    /// Machine readable prefix sent ahead of the message in an error reply.
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            RespError::InvalidPassword(_) => ErrorCode::WRONGPASS,
+            RespError::ProtocolOutOfRange(_) => ErrorCode::NOPROTO,
+            RespError::AuthRequired => ErrorCode::NOAUTH,
+            RespError::QueueFull(_) => ErrorCode::QUEUEFULL,
+            RespError::MessageTooLarge(..) => ErrorCode::TOOLARGE,
+            RespError::PrefetchReached(_) => ErrorCode::PREFETCH,
+            RespError::ExclusiveQueue(_) => ErrorCode::EXCLUSIVE,
+            RespError::ChecksumMismatch(_) => ErrorCode::BADCHECKSUM,
+            RespError::Busy => ErrorCode::BUSY,
+            RespError::NoPermission(_) => ErrorCode::NOPERM,
+            RespError::Timeout(_) => ErrorCode::TIMEOUT,
+            RespError::UnknownQueue(_) => ErrorCode::NOQUEUE,
+            RespError::QueueExists(_) | RespError::QueueLeased(..) => ErrorCode::BUSYQUEUE,
+            RespError::UnknownJob(_) => ErrorCode::NOJOB,
+            RespError::InvalidArgument(_)
+            | RespError::CommandNotFound(_)
+            | RespError::IncompleteCommand
+            | RespError::NoData
+            | RespError::CmdNotImplemented(_)
+            | RespError::WrongArity(_)
+            | RespError::NoDefaultQueue
+            | RespError::InvalidRule(_) => ErrorCode::ERR,
         }
     }
+
+    /// Encodes the error as a RESP simple error, e.g. `-NOAUTH Authentication required.`
+    pub fn to_reply(&self) -> Vec<u8> {
+        RespValue::from(self).encode()
+    }
+}
+
+impl From<&RespError> for RespValue {
+    fn from(err: &RespError) -> Self {
+        RespValue::error(err.code(), &err.to_string())
+    }
+}
+
+impl From<RespError> for RespValue {
+    fn from(err: RespError) -> Self {
+        RespValue::from(&err)
+    }
 }
 
 pub type Result<T> = std::result::Result<T, RespError>;
 
-#[derive(Debug, EnumString)]
-enum CommandSet {
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, EnumString, EnumIter, IntoStaticStr)]
+#[strum(ascii_case_insensitive)]
+pub(crate) enum CommandSet {
     HELLO,
     PUSH,
+    MPUSH,
+    FANOUT,
+    PEEK,
     ACK,
+    NACK,
+    TOUCH,
+    PROGRESS,
+    CANCEL,
+    USE,
+    PREFETCH,
     QUEUE,
+    SHUTDOWN,
+    POP,
+    CHANNEL,
+    SERVER,
+    DEBUG,
+    COMMAND,
+    JOB,
+    SCHEDULE,
+    INFO,
+}
+
+impl CommandSet {
+    /// Arity, flags and docs of the command, as `COMMAND` reports them.
+    pub fn spec(&self) -> &'static CommandSpec {
+        find_command(self.into()).expect("every command has a wire spec")
+    }
+}
+
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, EnumString)]
+#[strum(ascii_case_insensitive)]
+enum QueueSubcommand {
+    CREATE,
+    PURGE,
+    CLONE,
+    EXPORT,
+    REDRIVE,
+    DIGEST,
+    DELETE,
+    LIST,
+    STATS,
+    INFLIGHT,
+    PAUSE,
+    RESUME,
+    CONFIG,
+}
+
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, EnumString)]
+#[strum(ascii_case_insensitive)]
+enum ConfigKeys {
+    GET,
+    SET,
 }
 
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Debug, EnumString)]
+#[strum(ascii_case_insensitive)]
+enum ScheduleSubcommand {
+    ADD,
+    DEL,
+    LIST,
+}
+
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, EnumString)]
+#[strum(ascii_case_insensitive)]
+enum ServerSubcommand {
+    DRAIN,
+    RESUME,
+    TELEMETRY,
+    METRICS,
+    SNAPSHOT,
+    SNAPSHOTREAD,
+    RESETSTATS,
+}
+
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, EnumString)]
+#[strum(ascii_case_insensitive)]
+enum CommandSubcommand {
+    COUNT,
+    INFO,
+    DOCS,
+}
+
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, EnumString)]
+#[strum(ascii_case_insensitive)]
+enum JobSubcommand {
+    STATUS,
+    CANCEL,
+    LIST,
+}
+
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, EnumString)]
+#[strum(ascii_case_insensitive)]
+enum DebugSubcommand {
+    PROFILE,
+    TRACE,
+}
+
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, EnumString)]
+#[strum(ascii_case_insensitive)]
+enum TraceKeys {
+    COMMAND,
+    QUEUE,
+    LIST,
+}
+
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, EnumString)]
+#[strum(ascii_case_insensitive)]
+enum PopKeys {
+    ARRAY,
+    NULL,
+    BLOCK,
+    VISIBILITY,
+}
+
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, EnumString)]
+#[strum(ascii_case_insensitive)]
+enum CreateKeys {
+    FIFO,
+    LIFO,
+    PRIORITY,
+    OVERFLOW,
+    CONCURRENCY,
+    MAXDEPTH,
+    ONFULL,
+    DEDUP,
+    SHARDS,
+    MAXSIZE,
+    RATE,
+    EPHEMERAL,
+    EXCLUSIVE,
+}
+
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, EnumString)]
+#[strum(ascii_case_insensitive)]
+enum OnFullKeys {
+    REJECT,
+    BLOCK,
+    DROPOLDEST,
+}
+
+/// Settings of a live queue read and changed with `QUEUE CONFIG`.
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, Clone, Copy, PartialEq, EnumString)]
+#[strum(ascii_case_insensitive)]
+pub enum QueueParam {
+    VISIBILITY,
+    MAXATTEMPTS,
+    CONCURRENCY,
+    MAXDEPTH,
+    ONFULL,
+    MAXSIZE,
+    RATE,
+    DEADLETTER,
+    RETENTION,
+}
+
+/// New value for a `QueueParam`. The limits are lifted with `NONE`, which for
+/// `Concurrency` means the server's `max_in_flight` applies again, and `NONE` also
+/// keeps the dead letters in the queue and messages until their own TTL.
+#[derive(Debug, Clone, PartialEq)]
+pub enum QueueSetting {
+    Visibility(Duration),
+    MaxAttempts(u8),
+    Concurrency(Option<usize>),
+    MaxDepth(Option<usize>),
+    OnFull(FullPolicy),
+    MaxSize(Option<usize>),
+    Rate(Option<usize>),
+    DeadLetter(Option<String>),
+    Retention(Option<Duration>),
+}
+
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, EnumString)]
+#[strum(ascii_case_insensitive)]
+enum PushKeys {
+    CHECKSUM,
+    PRIORITY,
+    TTL,
+    DEDUP,
+    GROUP,
+    ATTR,
+}
+
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, EnumString)]
+#[strum(ascii_case_insensitive)]
+enum MpushKeys {
+    PRIORITY,
+    TTL,
+    GROUP,
+    ATTR,
+    BODIES,
+}
+
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, EnumString)]
+#[strum(ascii_case_insensitive)]
+enum RedriveKeys {
+    TO,
+    BOOST,
+    BACKLOG,
+    INTERLEAVE,
+}
+
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, EnumString)]
+#[strum(ascii_case_insensitive)]
+enum ShutdownKeys {
+    SAVE,
+    NOSAVE,
+}
+
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, EnumString)]
+#[strum(ascii_case_insensitive)]
 enum HelloKeys {
     SETNAME,
     AUTH,
     PASSWORD,
+    COMPRESS,
 }
 
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Debug)]
 pub enum Cmd {
     LPOP {
@@ -64,50 +399,1249 @@ pub enum Cmd {
         password: Option<String>,
         protocol_version: u8,
         setname: Option<String>,
+        compress: Option<String>,
     },
     SADD {
         key: String,
         member: Vec<String>,
     },
+    SHUTDOWN {
+        save: bool,
+    },
+    PUSH {
+        queue: String,
+        body: Bytes,
+        /// CRC32 the producer computed, verified when checksums are enabled.
+        checksum: Option<u32>,
+        /// Only orders delivery in queues created with `PRIORITY`.
+        priority: u8,
+        /// Dropped instead of delivered once this long has passed since the push.
+        ttl: Option<Duration>,
+        /// A later push with the same key within the queue's dedup window is
+        /// acknowledged with this push's id and not queued.
+        dedup: Option<String>,
+        /// Messages of one group are delivered in order, one at a time; FIFO queues only.
+        group: Option<String>,
+        /// Delivered with the message, untouched by the broker.
+        attributes: BTreeMap<String, String>,
+    },
+    /// Pushes every one of `bodies` to `queue` in order or, if it can't take them all,
+    /// none of them. The options are those of PUSH and apply to every message.
+    MPUSH {
+        queue: String,
+        bodies: Vec<Bytes>,
+        priority: u8,
+        ttl: Option<Duration>,
+        group: Option<String>,
+        attributes: BTreeMap<String, String>,
+    },
+    /// Pushes `body` to every one of `queues` or, if any can't take it, to none.
+    FANOUT {
+        body: Bytes,
+        queues: Vec<String>,
+        /// Queues named with `IF <rule>` only get the message when the rule matches it.
+        rules: BTreeMap<String, Rule>,
+        attributes: BTreeMap<String, String>,
+    },
+    /// The next `count` messages of `queue`, without leasing them.
+    PEEK {
+        queue: String,
+        count: usize,
+    },
+    POP {
+        queue: String,
+        count: usize,
+        on_empty: EmptyPop,
+        /// How long these leases last, when not the queue's `in_flight_expiration_ms`.
+        visibility: Option<Duration>,
+    },
+    ACK {
+        queue: String,
+        /// Receipts of the deliveries being settled, see `Message::receipt`.
+        ids: Vec<String>,
+        /// CRC32 of the body the consumer received, echoed back for verification.
+        checksum: Option<u32>,
+    },
+    /// Hands a leased message back for redelivery, after `delay` if it isn't zero.
+    NACK {
+        queue: String,
+        id: String,
+        delay: Duration,
+    },
+    /// Extends a lease still being worked on, to `visibility` from now or the queue's
+    /// `in_flight_expiration_ms`.
+    TOUCH {
+        queue: String,
+        id: String,
+        visibility: Option<Duration>,
+    },
+    /// Records how far the consumer has got with a leased message, for `QUEUE INFLIGHT`.
+    PROGRESS {
+        queue: String,
+        id: String,
+        progress: Progress,
+    },
+    /// Stops message `id` from being redelivered and tells the consumer holding its
+    /// lease to abandon it.
+    CANCEL {
+        queue: String,
+        id: String,
+    },
+    /// Sets the queue this connection's queue commands use when sent with an empty one.
+    USE {
+        queue: String,
+    },
+    /// Most unacked messages each consumer of this connection may hold at once; 0 lifts
+    /// the limit.
+    PREFETCH {
+        count: usize,
+    },
+    QUEUE(QueueCmd),
+    SERVER(ServerCmd),
+    DEBUG(DebugCmd),
+    COMMAND(CommandCmd),
+    JOB(JobCmd),
+    SCHEDULE(ScheduleCmd),
+    /// `INFO [section]`; the section name is lowercased.
+    INFO {
+        section: Option<String>,
+    },
+    /// Runs `cmd` on a virtual channel of the connection; see `Session::channel_consumer`.
+    CHANNEL {
+        channel: u32,
+        cmd: Box<Cmd>,
+    },
     Unknown,
 }
 
-const ADMIN: &str = "admin";
-const ADMIN_PW: &str = "password";
+impl Cmd {
+    /// Command name as clients send it, for telemetry and logs.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Cmd::LPOP { .. } => "LPOP",
+            Cmd::LPUSH { .. } => "LPUSH",
+            Cmd::HELLO { .. } => "HELLO",
+            Cmd::SADD { .. } => "SADD",
+            Cmd::SHUTDOWN { .. } => "SHUTDOWN",
+            Cmd::PUSH { .. } => "PUSH",
+            Cmd::MPUSH { .. } => "MPUSH",
+            Cmd::FANOUT { .. } => "FANOUT",
+            Cmd::PEEK { .. } => "PEEK",
+            Cmd::POP { .. } => "POP",
+            Cmd::ACK { .. } => "ACK",
+            Cmd::NACK { .. } => "NACK",
+            Cmd::TOUCH { .. } => "TOUCH",
+            Cmd::PROGRESS { .. } => "PROGRESS",
+            Cmd::CANCEL { .. } => "CANCEL",
+            Cmd::USE { .. } => "USE",
+            Cmd::PREFETCH { .. } => "PREFETCH",
+            Cmd::QUEUE(_) => "QUEUE",
+            Cmd::SERVER(_) => "SERVER",
+            Cmd::DEBUG(_) => "DEBUG",
+            Cmd::COMMAND(_) => "COMMAND",
+            Cmd::JOB(_) => "JOB",
+            Cmd::SCHEDULE(_) => "SCHEDULE",
+            Cmd::INFO { .. } => "INFO",
+            Cmd::CHANNEL { .. } => "CHANNEL",
+            Cmd::Unknown => "UNKNOWN",
+        }
+    }
 
-fn return_next<'a>(payload: &mut Split<'a, &str>) -> Result<&'a str> {
-    match payload.next() {
-        None => Err(RespError::NoData),
-        Some(v) => {
-            if v.starts_with("$") {
-                return return_next(payload);
+    /// Deprecated commands and options this use of the command relies on.
+    pub fn deprecations(&self) -> Vec<&'static Deprecation> {
+        match self {
+            Cmd::HELLO {
+                auth: None,
+                password: Some(_),
+                ..
+            } => vec![&HELLO_PASSWORD],
+            Cmd::CHANNEL { cmd, .. } => cmd.deprecations(),
+            _ => vec![],
+        }
+    }
+
+    /// The queue a delivery command works on.
+    pub fn queue(&self) -> Option<&str> {
+        match self {
+            Cmd::PUSH { queue, .. }
+            | Cmd::MPUSH { queue, .. }
+            | Cmd::PEEK { queue, .. }
+            | Cmd::POP { queue, .. }
+            | Cmd::ACK { queue, .. }
+            | Cmd::NACK { queue, .. }
+            | Cmd::TOUCH { queue, .. }
+            | Cmd::PROGRESS { queue, .. }
+            | Cmd::CANCEL { queue, .. } => Some(queue),
+            Cmd::LPUSH { key, .. } | Cmd::LPOP { key, .. } => Some(key),
+            Cmd::CHANNEL { cmd, .. } => cmd.queue(),
+            _ => None,
+        }
+    }
+
+    /// Fills in `default` as the queue of queue commands sent with an empty one, so
+    /// workers that `USE` a queue needn't repeat its name.
+    pub fn with_default_queue(mut self, default: Option<&str>) -> Result<Cmd> {
+        match &mut self {
+            Cmd::PUSH { queue, .. }
+            | Cmd::MPUSH { queue, .. }
+            | Cmd::PEEK { queue, .. }
+            | Cmd::POP { queue, .. }
+            | Cmd::ACK { queue, .. }
+            | Cmd::NACK { queue, .. }
+            | Cmd::TOUCH { queue, .. }
+            | Cmd::PROGRESS { queue, .. }
+            | Cmd::CANCEL { queue, .. }
+                if queue.is_empty() =>
+            {
+                *queue = default.ok_or(RespError::NoDefaultQueue)?.to_string();
+            }
+            Cmd::CHANNEL { cmd, .. } => {
+                let inner = std::mem::replace(cmd.as_mut(), Cmd::Unknown);
+                **cmd = inner.with_default_queue(default)?;
+            }
+            _ => {}
+        }
+        Ok(self)
+    }
+
+    /// What gets shed first under overload; see `LoadShedder`.
+    pub fn priority(&self) -> Priority {
+        match self {
+            Cmd::HELLO { .. }
+            | Cmd::USE { .. }
+            | Cmd::PREFETCH { .. }
+            | Cmd::SHUTDOWN { .. }
+            | Cmd::PUSH { .. }
+            | Cmd::MPUSH { .. }
+            | Cmd::FANOUT { .. }
+            | Cmd::ACK { .. }
+            | Cmd::NACK { .. }
+            | Cmd::TOUCH { .. }
+            | Cmd::PROGRESS { .. }
+            | Cmd::CANCEL { .. } => Priority::Critical,
+            Cmd::SERVER(
+                ServerCmd::TELEMETRY
+                | ServerCmd::METRICS
+                | ServerCmd::SNAPSHOT
+                | ServerCmd::SNAPSHOTREAD { .. },
+            )
+            | Cmd::INFO { .. }
+            | Cmd::QUEUE(QueueCmd::STATS { .. } | QueueCmd::INFLIGHT { .. })
+            | Cmd::PEEK { .. }
+            | Cmd::COMMAND(_)
+            | Cmd::Unknown => Priority::Low,
+            Cmd::SERVER(_) => Priority::Critical,
+            Cmd::CHANNEL { cmd, .. } => cmd.priority(),
+            Cmd::LPOP { .. } | Cmd::LPUSH { .. } | Cmd::SADD { .. } => Priority::Normal,
+            // Kept under overload, which is when a profile is most wanted.
+            Cmd::POP { .. } | Cmd::QUEUE(_) | Cmd::DEBUG(_) | Cmd::JOB(_) | Cmd::SCHEDULE(_) => {
+                Priority::Normal
+            }
+        }
+    }
+}
+
+/// What POP replies when the queue has nothing to hand out, picked per call with
+/// `POP <queue> [count] [ARRAY | NULL | BLOCK <ms>]`.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum EmptyPop {
+    /// An empty array, like an SQS receive that found nothing.
+    #[default]
+    Array,
+    /// Null, like Redis' LPOP on an empty list.
+    Null,
+    /// Waits up to the timeout for a push, then replies null like BLPOP. A zero timeout
+    /// waits forever. Blocking is done by the connection, see `Session::resume`.
+    Block(Duration),
+}
+
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug)]
+pub enum QueueCmd {
+    CREATE {
+        name: String,
+        order: QueueOrder,
+        /// Pushes past `queue_capacity` wait in an overflow buffer of up to this many
+        /// messages instead of being rejected; see `Queue::absorb`.
+        overflow: Option<usize>,
+        /// Most messages leased out at once, overriding `ServerConfig::max_in_flight`.
+        concurrency: Option<usize>,
+        /// Most messages waiting, overriding `ServerConfig::queue_capacity`.
+        max_depth: Option<usize>,
+        /// What a push does once the queue and its overflow buffer are full.
+        on_full: FullPolicy,
+        /// Dedup window, overriding `ServerConfig::dedup_window`, within which pushes
+        /// without a key are deduplicated by their body too.
+        dedup: Option<Duration>,
+        /// Spreads the queue over this many shards; see `QueueRegistry::route`.
+        shards: Option<usize>,
+        /// Largest body a push may bring, in bytes. Frames are still only bounded by
+        /// `FrameLimits::max_bulk_len` while they are read.
+        max_size: Option<usize>,
+        /// Most messages leased out a second, across every consumer.
+        rate: Option<usize>,
+        /// Deleted, with everything in it, once the connection that created it closes.
+        ephemeral: bool,
+        /// Only one consumer may POP at a time; see `Queue::claim`.
+        exclusive: bool,
+    },
+    /// Drops the messages waiting in `name`; leases and dead letters are kept.
+    PURGE {
+        name: String,
+    },
+    /// Creates `destination` holding a copy, under new ids, of what waits in `source`.
+    CLONE {
+        source: String,
+        destination: String,
+    },
+    /// Writes every message of `name`, leases included, to `path` as JSON lines.
+    EXPORT {
+        name: String,
+        path: String,
+    },
+    /// Pending message count and id digest, see `Queue::digest`, for comparing copies of
+    /// a queue kept on different brokers.
+    DIGEST {
+        name: String,
+    },
+    /// Puts up to `count` dead letters, or all of them, back into delivery.
+    REDRIVE {
+        name: String,
+        count: Option<usize>,
+        priority: RedrivePriority,
+    },
+    /// Moves up to `count` of the messages waiting in `name`, a dead letter queue, or
+    /// all of them, to the back of `target` with a fresh attempt count.
+    REDRIVETO {
+        name: String,
+        target: String,
+        count: Option<usize>,
+    },
+    /// Removes `name` and everything in it. Refused while messages are leased out
+    /// unless `force` is given, in which case their acks fail with `NOQUEUE`.
+    DELETE {
+        name: String,
+        force: bool,
+    },
+    /// Stops leasing out of `name` until `RESUME`; pushes are still accepted and
+    /// blocked POPs keep waiting.
+    PAUSE {
+        name: String,
+    },
+    RESUME {
+        name: String,
+    },
+    /// Current value of one of `name`'s settings.
+    GETCONFIG {
+        name: String,
+        param: QueueParam,
+    },
+    /// Changes one of `name`'s settings without recreating it. Messages already waiting
+    /// or leased stay where they are even if the new limits would not have let them in.
+    SETCONFIG {
+        name: String,
+        setting: QueueSetting,
+    },
+    /// Names of every queue.
+    LIST,
+    /// Depth, leases, rates and redeliveries of `name`, for watching its health.
+    STATS {
+        name: String,
+    },
+    /// The first `count` leases of `name`, soonest to run out first, with the progress
+    /// their consumers reported.
+    INFLIGHT {
+        name: String,
+        count: Option<usize>,
+    },
+}
+
+/// Recurring messages; see `schedule::Schedules`.
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug)]
+pub enum ScheduleCmd {
+    /// Pushes `body` to `queue` every time `cron` comes round, replacing any schedule
+    /// already called `name`.
+    ADD {
+        name: String,
+        queue: String,
+        cron: CronExpr,
+        body: Bytes,
+    },
+    DEL {
+        name: String,
+    },
+    LIST,
+}
+
+/// Background jobs started by the long `QUEUE` subcommands; see `jobs::Jobs`.
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug)]
+pub enum JobCmd {
+    STATUS(JobId),
+    /// Stops the job after the batch it is working on.
+    CANCEL(JobId),
+    LIST,
+}
+
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug)]
+pub enum ServerCmd {
+    /// Stop handing out messages on POP; pushes and acks keep working.
+    DRAIN,
+    RESUME,
+    /// Protocol versions, command usage and deprecated paths; see `Telemetry`.
+    TELEMETRY,
+    /// Per-queue gauges in the Prometheus text format; see `metrics::render`.
+    METRICS,
+    /// Captures every queue for a replica to fetch in chunks; replies with the transfer
+    /// id and its length in bytes. See `bootstrap`.
+    SNAPSHOT,
+    /// Up to `count` bytes of the transfer `id`, starting at `offset`.
+    SNAPSHOTREAD {
+        id: String,
+        offset: usize,
+        count: usize,
+    },
+    /// Starts `INFO commandstats` over from zero.
+    RESETSTATS,
+}
+
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug)]
+pub enum DebugCmd {
+    /// Samples the broker's stacks for `seconds` and writes them to `path`, or a
+    /// timestamped file in the working directory; see `profiler::start`.
+    PROFILE { seconds: u64, path: Option<String> },
+    /// Traces one in every `every` commands named `name`, or run on the queue named
+    /// `name`; 0 stops it. See `TraceSampling`.
+    TRACE {
+        scope: TraceScope,
+        name: String,
+        every: u64,
+    },
+    /// The trace rates set with `TRACE`.
+    TRACES,
+}
+
+/// Introspection of the commands in `CommandSet`. Names are matched case insensitively
+/// and unknown ones are skipped, as Redis does.
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug)]
+pub enum CommandCmd {
+    /// `COMMAND`: info for every command.
+    LIST,
+    COUNT,
+    INFO(Vec<String>),
+    /// Docs for the named commands, or all of them when none are named.
+    DOCS(Vec<String>),
+}
+
+/// RESP3 push frame telling the consumer holding message `id` to abandon it.
+pub fn cancel_push(queue: &str, id: &str) -> RespValue {
+    RespValue::push("cancel")
+        .item(RespValue::bulk(queue))
+        .item(RespValue::bulk(id))
+        .build()
+}
+
+/// RESP3 push frame sent ahead of a PUSH reply once the queue is past its soft limit.
+pub fn soft_limit_push(queue: &str, depth: usize, capacity: usize) -> RespValue {
+    RespValue::push("soft-limit")
+        .item(RespValue::bulk(queue))
+        .item(depth)
+        .item(capacity)
+        .build()
+}
+
+/// The arguments of one command frame, each cut out by its `$N` length so it may hold
+/// CRLF or any other bytes.
+pub struct Args<'a> {
+    frame: &'a Bytes,
+    pos: usize,
+    remaining: usize,
+}
+
+impl<'a> Args<'a> {
+    /// Reads the `*N` header of `frame`.
+    pub fn new(frame: &'a Bytes) -> Result<Args<'a>> {
+        let mut args = Args {
+            frame,
+            pos: 0,
+            remaining: 0,
+        };
+        let header = args.line()?;
+        args.remaining = match header {
+            // A null array holds no command, like an empty one.
+            b"*-1" => 0,
+            header => header
+                .strip_prefix(b"*")
+                .and_then(parse_len)
+                .ok_or(RespError::IncompleteCommand)?,
+        };
+        Ok(args)
+    }
+
+    fn line(&mut self) -> Result<&'a [u8]> {
+        let rest = &self.frame[self.pos..];
+        let end = rest
+            .windows(2)
+            .position(|w| w == b"\r\n")
+            .ok_or(RespError::IncompleteCommand)?;
+        self.pos += end + 2;
+        Ok(&rest[..end])
+    }
+
+    pub fn next_bytes(&mut self) -> Result<&'a [u8]> {
+        self.next_arg()?
+            .ok_or_else(|| RespError::InvalidArgument("null".to_string()))
+    }
+
+    /// The next argument, `None` if the client sent it as a null bulk string (`$-1`).
+    fn next_arg(&mut self) -> Result<Option<&'a [u8]>> {
+        if self.remaining == 0 {
+            return Err(RespError::NoData);
+        }
+        self.remaining -= 1;
+        let header = self.line()?;
+        if header == b"$-1" {
+            return Ok(None);
+        }
+        let len = header
+            .strip_prefix(b"$")
+            .and_then(parse_len)
+            .ok_or(RespError::IncompleteCommand)?;
+        self.take(len).map(Some)
+    }
+
+    /// Like `next_bytes`, as a view sharing the frame's buffer instead of a copy.
+    pub fn next_shared(&mut self) -> Result<Bytes> {
+        let arg = self.next_bytes()?;
+        Ok(self.frame.slice_ref(arg))
+    }
+
+    /// The next `len` bytes, which must be followed by CRLF.
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        let end = self.pos + len;
+        if self.frame.get(end..end + 2) != Some(&b"\r\n"[..]) {
+            return Err(RespError::IncompleteCommand);
+        }
+        let arg = &self.frame[self.pos..end];
+        self.pos = end + 2;
+        Ok(arg)
+    }
+
+    /// Joins the `;<len>` chunks of a `$?` string, up to the closing `;0`.
+    fn chunks(&mut self) -> Result<Vec<u8>> {
+        let mut joined = Vec::new();
+        loop {
+            let len = self
+                .line()?
+                .strip_prefix(b";")
+                .and_then(parse_len)
+                .ok_or(RespError::IncompleteCommand)?;
+            if len == 0 {
+                return Ok(joined);
             }
-            Ok(v)
+            joined.extend_from_slice(self.take(len)?);
+        }
+    }
+
+    /// Everything but message bodies has to be text.
+    pub fn next_str(&mut self) -> Result<&'a str> {
+        let arg = self.next_bytes()?;
+        std::str::from_utf8(arg)
+            .map_err(|_| RespError::InvalidArgument(String::from_utf8_lossy(arg).to_string()))
+    }
+
+    /// The next argument, or `None` once the frame has no more. An empty or null
+    /// argument counts as absent, as clients pad optional trailing arguments with them.
+    pub fn next_optional(&mut self) -> Result<Option<&'a str>> {
+        let arg = match self.next_arg() {
+            Err(RespError::NoData) | Ok(None) => return Ok(None),
+            Ok(Some(arg)) => arg,
+            Err(err) => return Err(err),
+        };
+        match std::str::from_utf8(arg) {
+            Ok("") => Ok(None),
+            Ok(arg) => Ok(Some(arg)),
+            Err(_) => Err(RespError::InvalidArgument(
+                String::from_utf8_lossy(arg).to_string(),
+            )),
         }
     }
+
+    /// The next argument as a number or any other `FromStr` type.
+    pub fn next_parsed<T: FromStr>(&mut self) -> Result<T> {
+        parse_arg(self.next_str()?)
+    }
+
+    /// Milliseconds, or a number with a unit such as `30s`; see `HumanDuration`.
+    pub fn next_duration(&mut self) -> Result<Duration> {
+        self.next_parsed::<HumanDuration>().map(Duration::from)
+    }
+
+    /// The subcommand following `command`, e.g. `DRAIN` in `SERVER DRAIN`.
+    fn next_subcommand<T: FromStr>(&mut self, command: &str) -> Result<T> {
+        let raw = self.next_str()?;
+        T::from_str(raw).map_err(|_| RespError::CommandNotFound(format!("{} {}", command, raw)))
+    }
+}
+
+fn parse_arg<T: FromStr>(arg: &str) -> Result<T> {
+    arg.parse()
+        .map_err(|_| RespError::InvalidArgument(arg.to_string()))
 }
 
-pub fn read_raw_cmd(raw_cmd: RespBufferedReader) -> Result<Cmd> {
-    let cmd_utf8 = raw_cmd.write_to_utf8().unwrap();
-    let mut it = cmd_utf8.split("\r\n");
-    map_command(&mut it)
+fn parse_len(raw: &[u8]) -> Option<usize> {
+    std::str::from_utf8(raw).ok()?.parse().ok()
 }
 
-pub fn map_command(payload: &mut Split<&str>) -> Result<Cmd> {
+/// Rewrites a command sent with RESP3 streaming, as a `*?` array closed by `.` or with
+/// `$?` arguments sent in chunks, into the plain `*N`/`$N` form. Other frames are
+/// returned as they are.
+fn unstream(frame: &Bytes) -> Result<Bytes> {
+    if !frame.starts_with(b"*?") && !frame.windows(4).any(|w| w == b"\r\n$?") {
+        return Ok(frame.clone());
+    }
+    let mut args = Args {
+        frame,
+        pos: 0,
+        remaining: 0,
+    };
+    let count = match args.line()? {
+        b"*?" => None,
+        header => Some(
+            header
+                .strip_prefix(b"*")
+                .and_then(parse_len)
+                .ok_or(RespError::IncompleteCommand)?,
+        ),
+    };
+    let mut items = Vec::new();
+    while count != Some(items.len()) {
+        let item = match args.line()? {
+            b"." if count.is_none() => break,
+            b"$?" => RespValue::BulkString(args.chunks()?),
+            b"$-1" => RespValue::NullBulk,
+            header => {
+                let len = header
+                    .strip_prefix(b"$")
+                    .and_then(parse_len)
+                    .ok_or(RespError::IncompleteCommand)?;
+                RespValue::bulk(args.take(len)?)
+            }
+        };
+        items.push(item);
+    }
+    Ok(Bytes::from(RespValue::Array(items).encode()))
+}
+
+fn return_next<'a>(payload: &mut Args<'a>) -> Result<&'a str> {
+    payload.next_str()
+}
+
+/// Maps a complete frame such as `*1\r\n$8\r\nSHUTDOWN\r\n` to a command.
+pub fn parse_cmd(raw_cmd: &[u8]) -> Result<Cmd> {
+    parse_frame(&Bytes::copy_from_slice(raw_cmd))
+}
+
+/// Like `parse_cmd`, without copying: PUSH bodies are views into `frame`.
+pub fn parse_frame(frame: &Bytes) -> Result<Cmd> {
+    map_command(&mut Args::new(&unstream(frame)?)?)
+}
+
+pub fn map_command(payload: &mut Args) -> Result<Cmd> {
     let first_word = return_next(payload)?;
-    let type_of_cmd_result = CommandSet::from_str(&first_word);
+    let type_of_cmd_result = CommandSet::from_str(first_word);
     let Ok(type_of_cmd) = type_of_cmd_result else {
         return Err(RespError::CommandNotFound(first_word.to_string()));
     };
+    check_arity(type_of_cmd.spec(), payload)?;
     match type_of_cmd {
         CommandSet::HELLO => deserialize_auth(payload),
-        CommandSet::QUEUE | CommandSet::ACK | CommandSet::PUSH => {
-            Err(RespError::CmdNotImplemented(first_word.to_string()))
+        CommandSet::SHUTDOWN => deserialize_shutdown(payload),
+        CommandSet::PUSH => deserialize_push(payload),
+        CommandSet::MPUSH => deserialize_mpush(payload),
+        CommandSet::FANOUT => deserialize_fanout(payload),
+        CommandSet::PEEK => deserialize_peek(payload),
+        CommandSet::POP => deserialize_pop(payload),
+        CommandSet::ACK => deserialize_ack(payload),
+        CommandSet::NACK => deserialize_nack(payload),
+        CommandSet::TOUCH => Ok(Cmd::TOUCH {
+            queue: return_next(payload)?.to_string(),
+            id: return_next(payload)?.to_string(),
+            visibility: match payload.next_optional()? {
+                Some(visibility) => Some(parse_arg::<HumanDuration>(visibility)?.into()),
+                None => None,
+            },
+        }),
+        CommandSet::PROGRESS => Ok(Cmd::PROGRESS {
+            queue: return_next(payload)?.to_string(),
+            id: return_next(payload)?.to_string(),
+            progress: next_progress(payload)?,
+        }),
+        CommandSet::CANCEL => Ok(Cmd::CANCEL {
+            queue: return_next(payload)?.to_string(),
+            id: return_next(payload)?.to_string(),
+        }),
+        CommandSet::USE => Ok(Cmd::USE {
+            queue: return_next(payload)?.to_string(),
+        }),
+        CommandSet::PREFETCH => Ok(Cmd::PREFETCH {
+            count: payload.next_parsed()?,
+        }),
+        CommandSet::QUEUE => deserialize_queue(payload),
+        CommandSet::CHANNEL => deserialize_channel(payload),
+        CommandSet::SERVER => deserialize_server(payload),
+        CommandSet::DEBUG => deserialize_debug(payload),
+        CommandSet::COMMAND => deserialize_command(payload),
+        CommandSet::JOB => deserialize_job(payload),
+        CommandSet::SCHEDULE => deserialize_schedule(payload),
+        CommandSet::INFO => Ok(Cmd::INFO {
+            section: payload.next_optional()?.map(str::to_lowercase),
+        }),
+    }
+}
+
+/// Rejects a frame with fewer or more words than the command's wire spec allows before
+/// its parser runs. Argument types are checked as the parser reads them.
+fn check_arity(spec: &CommandSpec, payload: &Args) -> Result<()> {
+    let words = payload.remaining + 1;
+    if words < spec.min_words() || spec.max_words().is_some_and(|max| words > max) {
+        return Err(RespError::WrongArity(spec.name.to_lowercase()));
+    }
+    Ok(())
+}
+
+/// `CHANNEL <channel> <command> [arg ...]`, where the command can't be another
+/// CHANNEL, HELLO or PREFETCH.
+fn deserialize_channel(payload: &mut Args) -> Result<Cmd> {
+    let channel = payload.next_parsed::<u32>()?;
+    match map_command(payload)? {
+        Cmd::CHANNEL { .. } | Cmd::HELLO { .. } | Cmd::PREFETCH { .. } => Err(
+            RespError::InvalidArgument(format!("CHANNEL {} only wraps queue commands", channel)),
+        ),
+        cmd => Ok(Cmd::CHANNEL {
+            channel,
+            cmd: Box::new(cmd),
+        }),
+    }
+}
+
+fn deserialize_server(payload: &mut Args) -> Result<Cmd> {
+    match payload.next_subcommand("SERVER")? {
+        ServerSubcommand::DRAIN => Ok(Cmd::SERVER(ServerCmd::DRAIN)),
+        ServerSubcommand::RESUME => Ok(Cmd::SERVER(ServerCmd::RESUME)),
+        ServerSubcommand::TELEMETRY => Ok(Cmd::SERVER(ServerCmd::TELEMETRY)),
+        ServerSubcommand::METRICS => Ok(Cmd::SERVER(ServerCmd::METRICS)),
+        ServerSubcommand::SNAPSHOT => Ok(Cmd::SERVER(ServerCmd::SNAPSHOT)),
+        ServerSubcommand::SNAPSHOTREAD => Ok(Cmd::SERVER(ServerCmd::SNAPSHOTREAD {
+            id: payload.next_str()?.to_string(),
+            offset: payload.next_parsed()?,
+            count: payload.next_parsed()?,
+        })),
+        ServerSubcommand::RESETSTATS => Ok(Cmd::SERVER(ServerCmd::RESETSTATS)),
+    }
+}
+
+fn deserialize_command(payload: &mut Args) -> Result<Cmd> {
+    if payload.remaining == 0 {
+        return Ok(Cmd::COMMAND(CommandCmd::LIST));
+    }
+    let subcommand = payload.next_subcommand("COMMAND")?;
+    let mut names = Vec::new();
+    while let Some(name) = payload.next_optional()? {
+        names.push(name.to_string());
+    }
+    Ok(Cmd::COMMAND(match subcommand {
+        CommandSubcommand::COUNT => CommandCmd::COUNT,
+        CommandSubcommand::INFO => CommandCmd::INFO(names),
+        CommandSubcommand::DOCS => CommandCmd::DOCS(names),
+    }))
+}
+
+fn deserialize_debug(payload: &mut Args) -> Result<Cmd> {
+    match payload.next_subcommand("DEBUG")? {
+        DebugSubcommand::PROFILE => {
+            let seconds = payload.next_parsed::<u64>()?;
+            if !(1..=MAX_PROFILE_SECONDS).contains(&seconds) {
+                return Err(RespError::InvalidArgument(seconds.to_string()));
+            }
+            let path = payload.next_optional()?.map(str::to_string);
+            Ok(Cmd::DEBUG(DebugCmd::PROFILE { seconds, path }))
+        }
+        DebugSubcommand::TRACE => {
+            let arg = return_next(payload)?;
+            let scope = match TraceKeys::from_str(arg) {
+                Ok(TraceKeys::LIST) => return Ok(Cmd::DEBUG(DebugCmd::TRACES)),
+                Ok(TraceKeys::COMMAND) => TraceScope::Command,
+                Ok(TraceKeys::QUEUE) => TraceScope::Queue,
+                Err(_) => return Err(RespError::InvalidArgument(arg.to_string())),
+            };
+            Ok(Cmd::DEBUG(DebugCmd::TRACE {
+                scope,
+                name: return_next(payload)?.to_string(),
+                every: payload.next_parsed()?,
+            }))
+        }
+    }
+}
+
+fn deserialize_queue(payload: &mut Args) -> Result<Cmd> {
+    let subcommand = payload.next_subcommand("QUEUE")?;
+    if let QueueSubcommand::LIST = subcommand {
+        return Ok(Cmd::QUEUE(QueueCmd::LIST));
+    }
+    // Optional in the spec only because LIST takes none.
+    let Some(name) = payload.next_optional()?.map(str::to_string) else {
+        return Err(RespError::WrongArity("queue".to_string()));
+    };
+    Ok(Cmd::QUEUE(match subcommand {
+        QueueSubcommand::CREATE => {
+            let mut order = QueueOrder::default();
+            let mut overflow = None;
+            let mut concurrency = None;
+            let mut max_depth = None;
+            let mut on_full = FullPolicy::default();
+            let mut dedup = None;
+            let mut shards = None;
+            let mut max_size = None;
+            let mut rate = None;
+            let mut ephemeral = false;
+            let mut exclusive = false;
+            while let Some(arg) = payload.next_optional()? {
+                match CreateKeys::from_str(arg) {
+                    Ok(CreateKeys::FIFO) => order = QueueOrder::Fifo,
+                    Ok(CreateKeys::LIFO) => order = QueueOrder::Lifo,
+                    Ok(CreateKeys::PRIORITY) => order = QueueOrder::Priority,
+                    Ok(CreateKeys::OVERFLOW) => overflow = Some(payload.next_parsed()?),
+                    Ok(CreateKeys::CONCURRENCY) => match payload.next_parsed()? {
+                        0 => return Err(RespError::InvalidArgument("0".to_string())),
+                        max => concurrency = Some(max),
+                    },
+                    Ok(CreateKeys::MAXDEPTH) => match payload.next_parsed()? {
+                        0 => return Err(RespError::InvalidArgument("0".to_string())),
+                        max => max_depth = Some(max),
+                    },
+                    Ok(CreateKeys::ONFULL) => on_full = next_full_policy(payload)?,
+                    Ok(CreateKeys::DEDUP) => dedup = Some(payload.next_duration()?),
+                    Ok(CreateKeys::SHARDS) => match payload.next_parsed()? {
+                        0 => return Err(RespError::InvalidArgument("0".to_string())),
+                        count => shards = Some(count),
+                    },
+                    Ok(CreateKeys::MAXSIZE) => match payload.next_parsed::<ByteSize>()? {
+                        ByteSize(0) => return Err(RespError::InvalidArgument("0".to_string())),
+                        ByteSize(max) => max_size = Some(max),
+                    },
+                    Ok(CreateKeys::RATE) => match payload.next_parsed()? {
+                        0 => return Err(RespError::InvalidArgument("0".to_string())),
+                        per_second => rate = Some(per_second),
+                    },
+                    Ok(CreateKeys::EPHEMERAL) => ephemeral = true,
+                    Ok(CreateKeys::EXCLUSIVE) => exclusive = true,
+                    Err(_) => return Err(RespError::InvalidArgument(arg.to_string())),
+                }
+            }
+            // Shards would outlive the connection one by one, leaving the name behind.
+            if ephemeral && shards.is_some() {
+                return Err(RespError::InvalidArgument("EPHEMERAL".to_string()));
+            }
+            // Each shard would take its own consumer.
+            if exclusive && shards.is_some() {
+                return Err(RespError::InvalidArgument("EXCLUSIVE".to_string()));
+            }
+            QueueCmd::CREATE {
+                name,
+                order,
+                overflow,
+                concurrency,
+                max_depth,
+                on_full,
+                dedup,
+                shards,
+                max_size,
+                rate,
+                ephemeral,
+                exclusive,
+            }
+        }
+        QueueSubcommand::PURGE => QueueCmd::PURGE { name },
+        QueueSubcommand::CLONE => QueueCmd::CLONE {
+            source: name,
+            destination: return_next(payload)?.to_string(),
+        },
+        QueueSubcommand::EXPORT => QueueCmd::EXPORT {
+            name,
+            path: return_next(payload)?.to_string(),
+        },
+        QueueSubcommand::REDRIVE => deserialize_redrive(name, payload)?,
+        QueueSubcommand::DIGEST => QueueCmd::DIGEST { name },
+        QueueSubcommand::DELETE => {
+            let force = match payload.next_optional()? {
+                None => false,
+                Some(arg) if arg.eq_ignore_ascii_case("FORCE") => true,
+                Some(arg) => return Err(RespError::InvalidArgument(arg.to_string())),
+            };
+            QueueCmd::DELETE { name, force }
+        }
+        QueueSubcommand::STATS => QueueCmd::STATS { name },
+        QueueSubcommand::INFLIGHT => QueueCmd::INFLIGHT {
+            name,
+            count: payload.next_optional()?.map(parse_arg).transpose()?,
+        },
+        QueueSubcommand::PAUSE => QueueCmd::PAUSE { name },
+        QueueSubcommand::RESUME => QueueCmd::RESUME { name },
+        QueueSubcommand::CONFIG => deserialize_queue_config(name, payload)?,
+        QueueSubcommand::LIST => unreachable!("handled above"),
+    }))
+}
+
+fn next_full_policy(payload: &mut Args) -> Result<FullPolicy> {
+    let policy = return_next(payload)?;
+    match OnFullKeys::from_str(policy) {
+        Ok(OnFullKeys::REJECT) => Ok(FullPolicy::Reject),
+        Ok(OnFullKeys::BLOCK) => Ok(FullPolicy::Block),
+        Ok(OnFullKeys::DROPOLDEST) => Ok(FullPolicy::DropOldest),
+        Err(_) => Err(RespError::InvalidArgument(policy.to_string())),
+    }
+}
+
+/// A percentage such as `40` or `40%`, capped at 100, or else a status such as
+/// `resizing`.
+fn next_progress(payload: &mut Args) -> Result<Progress> {
+    let value = return_next(payload)?;
+    Ok(
+        match value.strip_suffix('%').unwrap_or(value).parse::<u64>() {
+            Ok(percent) => Progress::Percent(percent.min(100) as u8),
+            Err(_) => Progress::Status(value.to_string()),
+        },
+    )
+}
+
+/// A positive limit, or `NONE` for no limit.
+fn next_limit<T: FromStr + Into<usize>>(payload: &mut Args) -> Result<Option<usize>> {
+    let arg = return_next(payload)?;
+    if arg.eq_ignore_ascii_case("NONE") {
+        return Ok(None);
+    }
+    match parse_arg::<T>(arg)?.into() {
+        0 => Err(RespError::InvalidArgument("0".to_string())),
+        limit => Ok(Some(limit)),
+    }
+}
+
+/// `QUEUE CONFIG <queue> GET <param>` or `QUEUE CONFIG <queue> SET <param> <value>`.
+fn deserialize_queue_config(name: String, payload: &mut Args) -> Result<QueueCmd> {
+    let action = payload.next_subcommand::<ConfigKeys>("QUEUE CONFIG")?;
+    let raw = return_next(payload)?;
+    let param =
+        QueueParam::from_str(raw).map_err(|_| RespError::InvalidArgument(raw.to_string()))?;
+    if let ConfigKeys::GET = action {
+        return Ok(QueueCmd::GETCONFIG { name, param });
+    }
+    let setting = match param {
+        QueueParam::VISIBILITY => match payload.next_duration()? {
+            Duration::ZERO => return Err(RespError::InvalidArgument("0".to_string())),
+            visibility => QueueSetting::Visibility(visibility),
+        },
+        QueueParam::MAXATTEMPTS => match payload.next_parsed()? {
+            0 => return Err(RespError::InvalidArgument("0".to_string())),
+            attempts => QueueSetting::MaxAttempts(attempts),
+        },
+        QueueParam::CONCURRENCY => QueueSetting::Concurrency(next_limit::<usize>(payload)?),
+        QueueParam::MAXDEPTH => QueueSetting::MaxDepth(next_limit::<usize>(payload)?),
+        QueueParam::ONFULL => QueueSetting::OnFull(next_full_policy(payload)?),
+        QueueParam::MAXSIZE => QueueSetting::MaxSize(next_limit::<ByteSize>(payload)?),
+        QueueParam::RATE => QueueSetting::Rate(next_limit::<usize>(payload)?),
+        QueueParam::DEADLETTER => match return_next(payload)? {
+            none if none.eq_ignore_ascii_case("NONE") => QueueSetting::DeadLetter(None),
+            queue if queue == name => return Err(RespError::InvalidArgument(queue.to_string())),
+            queue => QueueSetting::DeadLetter(Some(queue.to_string())),
+        },
+        QueueParam::RETENTION => match return_next(payload)? {
+            none if none.eq_ignore_ascii_case("NONE") => QueueSetting::Retention(None),
+            retention => match Duration::from(parse_arg::<HumanDuration>(retention)?) {
+                Duration::ZERO => return Err(RespError::InvalidArgument("0".to_string())),
+                retention => QueueSetting::Retention(Some(retention)),
+            },
+        },
+    };
+    Ok(QueueCmd::SETCONFIG { name, setting })
+}
+
+/// `QUEUE REDRIVE <queue> [count] [BOOST | BACKLOG | INTERLEAVE <n>]`, or
+/// `QUEUE REDRIVE <dlq> TO <target> [count]` to replay a dead letter queue.
+fn deserialize_redrive(name: String, payload: &mut Args) -> Result<QueueCmd> {
+    let mut count = None;
+    let mut priority = RedrivePriority::Backlog;
+    let mut first = true;
+    while let Some(arg) = payload.next_optional()? {
+        match RedriveKeys::from_str(arg) {
+            Ok(RedriveKeys::TO) if first => {
+                let target = return_next(payload)?.to_string();
+                if target == name {
+                    return Err(RespError::InvalidArgument(target));
+                }
+                return Ok(QueueCmd::REDRIVETO {
+                    name,
+                    target,
+                    count: payload.next_optional()?.map(parse_arg).transpose()?,
+                });
+            }
+            Ok(RedriveKeys::TO) => return Err(RespError::InvalidArgument(arg.to_string())),
+            Ok(RedriveKeys::BOOST) => priority = RedrivePriority::Boost,
+            Ok(RedriveKeys::BACKLOG) => priority = RedrivePriority::Backlog,
+            Ok(RedriveKeys::INTERLEAVE) => {
+                priority = RedrivePriority::Interleave(payload.next_parsed()?);
+            }
+            Err(_) => count = Some(parse_arg(arg)?),
+        }
+        first = false;
+    }
+    Ok(QueueCmd::REDRIVE {
+        name,
+        count,
+        priority,
+    })
+}
+
+fn deserialize_job(payload: &mut Args) -> Result<Cmd> {
+    Ok(Cmd::JOB(match payload.next_subcommand("JOB")? {
+        JobSubcommand::STATUS => JobCmd::STATUS(payload.next_parsed()?),
+        JobSubcommand::CANCEL => JobCmd::CANCEL(payload.next_parsed()?),
+        JobSubcommand::LIST => JobCmd::LIST,
+    }))
+}
+
+/// `SCHEDULE ADD <name> <queue> <cron> <body>`, `SCHEDULE DEL <name>` or `SCHEDULE LIST`;
+/// the cron expression is a single argument, e.g. `"*/5 * * * *"`.
+fn deserialize_schedule(payload: &mut Args) -> Result<Cmd> {
+    Ok(Cmd::SCHEDULE(
+        match payload.next_subcommand("SCHEDULE")? {
+            ScheduleSubcommand::ADD => ScheduleCmd::ADD {
+                name: return_next(payload)?.to_string(),
+                queue: return_next(payload)?.to_string(),
+                cron: payload.next_parsed()?,
+                body: payload.next_shared()?,
+            },
+            ScheduleSubcommand::DEL => ScheduleCmd::DEL {
+                name: return_next(payload)?.to_string(),
+            },
+            ScheduleSubcommand::LIST => ScheduleCmd::LIST,
+        },
+    ))
+}
+
+/// `PUSH <queue> <body> [CHECKSUM <crc32>] [PRIORITY <n>] [TTL <ttl>] [DEDUP <key>]
+/// [GROUP <group>] [ATTR <key> <value> ...]`; setting an attribute twice is an error.
+fn deserialize_push(payload: &mut Args) -> Result<Cmd> {
+    let queue = return_next(payload)?.to_string();
+    let body = payload.next_shared()?;
+    let mut checksum = None;
+    let mut priority = 0;
+    let mut ttl = None;
+    let mut dedup = None;
+    let mut group = None;
+    let mut attributes = BTreeMap::new();
+    while let Some(arg) = payload.next_optional()? {
+        match PushKeys::from_str(arg) {
+            Ok(PushKeys::CHECKSUM) => checksum = Some(payload.next_parsed()?),
+            Ok(PushKeys::PRIORITY) => priority = payload.next_parsed()?,
+            Ok(PushKeys::TTL) => ttl = Some(payload.next_duration()?),
+            Ok(PushKeys::DEDUP) => dedup = Some(return_next(payload)?.to_string()),
+            Ok(PushKeys::GROUP) => group = Some(return_next(payload)?.to_string()),
+            Ok(PushKeys::ATTR) => {
+                let key = return_next(payload)?.to_string();
+                let value = return_next(payload)?.to_string();
+                if attributes.contains_key(&key) {
+                    return Err(RespError::InvalidArgument(key));
+                }
+                attributes.insert(key, value);
+            }
+            Err(_) => return Err(RespError::InvalidArgument(arg.to_string())),
+        }
+    }
+    Ok(Cmd::PUSH {
+        queue,
+        body,
+        checksum,
+        priority,
+        ttl,
+        dedup,
+        group,
+        attributes,
+    })
+}
+
+/// `MPUSH <queue> [PRIORITY <n>] [TTL <ttl>] [GROUP <group>] [ATTR <key> <value>]...
+/// [BODIES] <body> [body ...]`. The options end at the first argument that isn't one, so
+/// a first body that reads as an option needs `BODIES` ahead of it.
+fn deserialize_mpush(payload: &mut Args) -> Result<Cmd> {
+    let queue = return_next(payload)?.to_string();
+    let mut priority = 0;
+    let mut ttl = None;
+    let mut group = None;
+    let mut attributes = BTreeMap::new();
+    let mut bodies = Vec::new();
+    while bodies.is_empty() {
+        let arg = payload.next_shared()?;
+        let Some(key) = std::str::from_utf8(&arg)
+            .ok()
+            .and_then(|arg| MpushKeys::from_str(arg).ok())
+        else {
+            bodies.push(arg);
+            break;
+        };
+        match key {
+            MpushKeys::PRIORITY => priority = payload.next_parsed()?,
+            MpushKeys::TTL => ttl = Some(payload.next_duration()?),
+            MpushKeys::GROUP => group = Some(return_next(payload)?.to_string()),
+            MpushKeys::ATTR => {
+                let key = return_next(payload)?.to_string();
+                let value = return_next(payload)?.to_string();
+                if attributes.contains_key(&key) {
+                    return Err(RespError::InvalidArgument(key));
+                }
+                attributes.insert(key, value);
+            }
+            MpushKeys::BODIES => bodies.push(payload.next_shared()?),
+        }
+    }
+    while payload.remaining > 0 {
+        bodies.push(payload.next_shared()?);
+    }
+    Ok(Cmd::MPUSH {
+        queue,
+        bodies,
+        priority,
+        ttl,
+        group,
+        attributes,
+    })
+}
+
+/// `PEEK <queue> [count]`.
+fn deserialize_peek(payload: &mut Args) -> Result<Cmd> {
+    let queue = return_next(payload)?.to_string();
+    let count = match payload.next_optional()? {
+        Some(count) => parse_arg(count)?,
+        None => 1,
+    };
+    Ok(Cmd::PEEK { queue, count })
+}
+
+/// `FANOUT <body> <queue> [IF <rule>] [<queue> [IF <rule>] ...] [ATTR <name> <value> ...]`;
+/// naming a queue twice is an error.
+fn deserialize_fanout(payload: &mut Args) -> Result<Cmd> {
+    let body = payload.next_shared()?;
+    let mut queues: Vec<String> = vec![return_next(payload)?.to_string()];
+    let mut rules = BTreeMap::new();
+    let mut attributes = BTreeMap::new();
+    while let Some(arg) = payload.next_optional()? {
+        if arg.eq_ignore_ascii_case("ATTR") {
+            let key = return_next(payload)?.to_string();
+            let value = return_next(payload)?.to_string();
+            if attributes.insert(key.clone(), value).is_some() {
+                return Err(RespError::InvalidArgument(key));
+            }
+        } else if !attributes.is_empty() || queues.iter().any(|named| named == arg) {
+            // the queues and their rules all come before the attributes
+            return Err(RespError::InvalidArgument(arg.to_string()));
+        } else if arg.eq_ignore_ascii_case("IF") {
+            let queue = queues[queues.len() - 1].clone();
+            let rule = Rule::compile(return_next(payload)?).map_err(RespError::InvalidRule)?;
+            if rules.insert(queue, rule).is_some() {
+                return Err(RespError::InvalidArgument(arg.to_string()));
+            }
+        } else {
+            queues.push(arg.to_string());
+        }
+    }
+    Ok(Cmd::FANOUT {
+        body,
+        queues,
+        rules,
+        attributes,
+    })
+}
+
+/// `ACK <queue> <receipt> [receipt ...] [CHECKSUM <crc32>]`. A checksum vouches for a
+/// single body, so it only goes with a single receipt.
+fn deserialize_ack(payload: &mut Args) -> Result<Cmd> {
+    let queue = return_next(payload)?.to_string();
+    let mut ids = vec![return_next(payload)?.to_string()];
+    let mut checksum = None;
+    while let Some(arg) = payload.next_optional()? {
+        if arg.eq_ignore_ascii_case("CHECKSUM") {
+            checksum = Some(payload.next_parsed()?);
+            break;
         }
+        ids.push(arg.to_string());
     }
+    if let Some(extra) = payload.next_optional()? {
+        return Err(RespError::InvalidArgument(extra.to_string()));
+    }
+    if checksum.is_some() && ids.len() > 1 {
+        return Err(RespError::InvalidArgument("CHECKSUM".to_string()));
+    }
+    Ok(Cmd::ACK {
+        queue,
+        ids,
+        checksum,
+    })
+}
+
+/// `NACK <queue> <id> [DELAY <ms>]`.
+fn deserialize_nack(payload: &mut Args) -> Result<Cmd> {
+    let queue = return_next(payload)?.to_string();
+    let id = return_next(payload)?.to_string();
+    let delay = match payload.next_optional()? {
+        None => Duration::ZERO,
+        Some(key) if key.eq_ignore_ascii_case("DELAY") => payload.next_duration()?,
+        Some(other) => return Err(RespError::InvalidArgument(other.to_string())),
+    };
+    Ok(Cmd::NACK { queue, id, delay })
 }
 
-fn get_protocol_version(payload: &mut Split<&str>) -> Result<u8> {
+fn deserialize_pop(payload: &mut Args) -> Result<Cmd> {
+    let queue = return_next(payload)?.to_string();
+    let mut count = 1;
+    let mut on_empty = EmptyPop::default();
+    let mut visibility = None;
+    while let Some(arg) = payload.next_optional()? {
+        match PopKeys::from_str(arg) {
+            Ok(PopKeys::ARRAY) => on_empty = EmptyPop::Array,
+            Ok(PopKeys::NULL) => on_empty = EmptyPop::Null,
+            Ok(PopKeys::BLOCK) => {
+                on_empty = EmptyPop::Block(payload.next_duration()?);
+            }
+            Ok(PopKeys::VISIBILITY) => {
+                visibility = Some(payload.next_duration()?);
+            }
+            Err(_) => count = parse_arg(arg)?,
+        }
+    }
+    Ok(Cmd::POP {
+        queue,
+        count,
+        on_empty,
+        visibility,
+    })
+}
+
+fn deserialize_shutdown(payload: &mut Args) -> Result<Cmd> {
+    let Some(arg) = payload.next_optional()? else {
+        return Ok(Cmd::SHUTDOWN { save: false });
+    };
+    match ShutdownKeys::from_str(arg) {
+        Ok(ShutdownKeys::NOSAVE) => Ok(Cmd::SHUTDOWN { save: false }),
+        Ok(ShutdownKeys::SAVE) => Ok(Cmd::SHUTDOWN { save: true }),
+        Err(_) => Err(RespError::InvalidArgument(arg.to_string())),
+    }
+}
+
+fn get_protocol_version(payload: &mut Args) -> Result<u8> {
     let raw_next = return_next(payload)?;
 
     let protocol_version_result = raw_next.parse::<u8>();
@@ -117,17 +1651,22 @@ fn get_protocol_version(payload: &mut Split<&str>) -> Result<u8> {
     }
 }
 
-fn deserialize_auth(payload: &mut Split<&str>) -> Result<Cmd> {
+fn deserialize_auth(payload: &mut Args) -> Result<Cmd> {
     let protocol_version = get_protocol_version(payload)?;
     let mut auth: Option<String> = None;
     let mut password: Option<String> = None;
     let mut setname: Option<String> = None;
-    while let (Some(key), Some(value)) = (payload.next(), payload.next()) {
+    let mut compress: Option<String> = None;
+    while let (Ok(key), Ok(value)) = (return_next(payload), return_next(payload)) {
         let valid_key = HelloKeys::from_str(key);
         match valid_key {
             Ok(hello_key) => match hello_key {
                 HelloKeys::AUTH => {
                     auth = Some(value.to_string());
+                    // AUTH <username> <password>, as sent by Redis clients
+                    if let Ok(pw) = return_next(payload) {
+                        password = Some(pw.to_string()).filter(|pw| !pw.is_empty());
+                    }
                 }
                 HelloKeys::SETNAME => {
                     setname = Some(value.to_string());
@@ -135,6 +1674,9 @@ fn deserialize_auth(payload: &mut Split<&str>) -> Result<Cmd> {
                 HelloKeys::PASSWORD => {
                     password = Some(value.to_string());
                 }
+                HelloKeys::COMPRESS => {
+                    compress = Some(value.to_string());
+                }
             },
             Err(_) => return Err(RespError::InvalidArgument(value.to_string())),
         }
@@ -145,5 +1687,741 @@ fn deserialize_auth(payload: &mut Split<&str>) -> Result<Cmd> {
         password,
         protocol_version,
         setname,
+        compress,
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::queue::{FullPolicy, Progress, QueueOrder, RedrivePriority};
+    use crate::resp::{
+        parse_cmd, parse_frame, Cmd, CommandCmd, DebugCmd, EmptyPop, JobCmd, QueueCmd,
+        QueueSetting, RespError, ScheduleCmd, ServerCmd,
+    };
+    use crate::test_utils::frame;
+    use crate::trace_sampling::TraceScope;
+    use bytes::Bytes;
+    use std::time::Duration;
+
+    #[test]
+    fn test_parse_shutdown() {
+        let cmd = parse_cmd(b"*1\r\n$8\r\nSHUTDOWN\r\n").unwrap();
+        assert!(matches!(cmd, Cmd::SHUTDOWN { save: false }));
+
+        let cmd = parse_cmd(b"*2\r\n$8\r\nSHUTDOWN\r\n$4\r\nSAVE\r\n").unwrap();
+        assert!(matches!(cmd, Cmd::SHUTDOWN { save: true }));
+
+        assert!(parse_cmd(b"*2\r\n$8\r\nSHUTDOWN\r\n$5\r\nLATER\r\n").is_err());
+    }
+
+    #[test]
+    fn test_parse_hello_auth() {
+        let cmd = parse_cmd(
+            b"*5\r\n$5\r\nHELLO\r\n$1\r\n3\r\n$4\r\nAUTH\r\n$5\r\nadmin\r\n$8\r\npassword\r\n",
+        )
+        .unwrap();
+        let Cmd::HELLO { auth, password, .. } = cmd else {
+            panic!("expected HELLO, got {:?}", cmd);
+        };
+        assert_eq!(auth.as_deref(), Some("admin"));
+        assert_eq!(password.as_deref(), Some("password"));
+    }
+
+    #[test]
+    fn test_parse_hello_compress() {
+        let cmd =
+            parse_cmd(b"*4\r\n$5\r\nHELLO\r\n$1\r\n3\r\n$8\r\nCOMPRESS\r\n$3\r\nlz4\r\n").unwrap();
+        assert!(matches!(cmd, Cmd::HELLO { compress: Some(c), .. } if c == "lz4"));
+    }
+
+    #[test]
+    fn test_parse_queue_create() {
+        let cmd = parse_cmd(b"*3\r\n$5\r\nQUEUE\r\n$6\r\nCREATE\r\n$4\r\njobs\r\n").unwrap();
+        assert!(matches!(
+            cmd,
+            Cmd::QUEUE(QueueCmd::CREATE { name, order: QueueOrder::Fifo, overflow: None, concurrency: None, max_depth: None, on_full: FullPolicy::Reject, dedup: None, shards: None, max_size: None, rate: None, ephemeral: false, exclusive: false }) if name == "jobs"
+        ));
+        let cmd = parse_cmd(b"*4\r\n$5\r\nQUEUE\r\n$6\r\nCREATE\r\n$4\r\njobs\r\n$4\r\nlifo\r\n");
+        assert!(matches!(
+            cmd.unwrap(),
+            Cmd::QUEUE(QueueCmd::CREATE {
+                order: QueueOrder::Lifo,
+                ..
+            })
+        ));
+        let cmd = parse_cmd(&frame(&[
+            "queue", "create", "jobs", "overflow", "100", "lifo",
+        ]));
+        assert!(matches!(
+            cmd.unwrap(),
+            Cmd::QUEUE(QueueCmd::CREATE {
+                order: QueueOrder::Lifo,
+                overflow: Some(100),
+                ..
+            })
+        ));
+        let cmd = parse_cmd(&frame(&[
+            "queue",
+            "create",
+            "jobs",
+            "fifo",
+            "overflow",
+            "100",
+            "concurrency",
+            "4",
+        ]));
+        assert!(matches!(
+            cmd.unwrap(),
+            Cmd::QUEUE(QueueCmd::CREATE {
+                overflow: Some(100),
+                concurrency: Some(4),
+                ..
+            })
+        ));
+        let cmd = parse_cmd(&frame(&["queue", "create", "jobs", "concurrency", "0"]));
+        assert!(cmd.is_err());
+        let cmd = parse_cmd(&frame(&["QUEUE", "CREATE", "jobs", "MAXSIZE", "1kb"]));
+        assert!(matches!(
+            cmd.unwrap(),
+            Cmd::QUEUE(QueueCmd::CREATE {
+                max_size: Some(1024),
+                ..
+            })
+        ));
+        assert!(parse_cmd(&frame(&["QUEUE", "CREATE", "jobs", "MAXSIZE", "0"])).is_err());
+        let cmd = parse_cmd(&frame(&[
+            "queue",
+            "create",
+            "jobs",
+            "maxdepth",
+            "10",
+            "onfull",
+            "dropOldest",
+        ]));
+        assert!(matches!(
+            cmd.unwrap(),
+            Cmd::QUEUE(QueueCmd::CREATE {
+                max_depth: Some(10),
+                on_full: FullPolicy::DropOldest,
+                ..
+            })
+        ));
+        let cmd = parse_cmd(&frame(&["queue", "create", "jobs", "dedup", "10m"]));
+        assert!(matches!(
+            cmd.unwrap(),
+            Cmd::QUEUE(QueueCmd::CREATE { dedup: Some(window), .. }) if window == Duration::from_secs(600)
+        ));
+        let cmd = parse_cmd(&frame(&["queue", "create", "jobs", "shards", "4"]));
+        assert!(matches!(
+            cmd.unwrap(),
+            Cmd::QUEUE(QueueCmd::CREATE {
+                shards: Some(4),
+                ..
+            })
+        ));
+        assert!(parse_cmd(&frame(&["queue", "create", "jobs", "shards", "0"])).is_err());
+        let cmd = parse_cmd(&frame(&[
+            "queue",
+            "create",
+            "jobs",
+            "shards",
+            "4",
+            "ephemeral",
+        ]));
+        assert!(cmd.is_err());
+        let cmd = parse_cmd(&frame(&[
+            "queue",
+            "create",
+            "jobs",
+            "shards",
+            "4",
+            "exclusive",
+        ]));
+        assert!(cmd.is_err());
+        let cmd = parse_cmd(&frame(&["queue", "create", "jobs", "onfull", "wait"]));
+        assert_eq!(
+            cmd.unwrap_err().to_reply(),
+            b"-ERR invalid arg for wait\r\n"
+        );
+        let cmd = parse_cmd(b"*4\r\n$5\r\nQUEUE\r\n$6\r\nCREATE\r\n$4\r\njobs\r\n$4\r\nLILO\r\n");
+        assert_eq!(
+            cmd.unwrap_err().to_reply(),
+            b"-ERR invalid arg for LILO\r\n"
+        );
+    }
+
+    #[test]
+    fn test_parse_debug_trace() {
+        let cmd = parse_cmd(&frame(&["debug", "trace", "queue", "jobs", "10"])).unwrap();
+        assert!(matches!(
+            cmd,
+            Cmd::DEBUG(DebugCmd::TRACE { scope: TraceScope::Queue, name, every: 10 }) if name == "jobs"
+        ));
+        let cmd = parse_cmd(&frame(&["debug", "trace", "list"])).unwrap();
+        assert!(matches!(cmd, Cmd::DEBUG(DebugCmd::TRACES)));
+        assert!(parse_cmd(&frame(&["debug", "trace", "consumer", "1", "1"])).is_err());
+    }
+
+    #[test]
+    fn test_parse_queue_delete_and_list() {
+        let cmd = parse_cmd(&frame(&["queue", "delete", "jobs", "force"])).unwrap();
+        assert!(matches!(
+            cmd,
+            Cmd::QUEUE(QueueCmd::DELETE { name, force: true }) if name == "jobs"
+        ));
+        let cmd = parse_cmd(&frame(&["queue", "list"])).unwrap();
+        assert!(matches!(cmd, Cmd::QUEUE(QueueCmd::LIST)));
+        let cmd = parse_cmd(&frame(&["queue", "pause", "jobs"])).unwrap();
+        assert!(matches!(cmd, Cmd::QUEUE(QueueCmd::PAUSE { name }) if name == "jobs"));
+        assert!(parse_cmd(&frame(&["QUEUE", "RESUME"])).is_err());
+        let cmd = parse_cmd(&frame(&[
+            "queue", "config", "jobs", "set", "onfull", "block",
+        ]))
+        .unwrap();
+        assert!(matches!(
+            cmd,
+            Cmd::QUEUE(QueueCmd::SETCONFIG {
+                setting: QueueSetting::OnFull(FullPolicy::Block),
+                ..
+            })
+        ));
+        assert!(parse_cmd(&frame(&["QUEUE", "CONFIG", "jobs", "SET", "MAXDEPTH", "0"])).is_err());
+        assert!(parse_cmd(&frame(&["QUEUE", "CONFIG", "jobs", "GET", "COLOR"])).is_err());
+        assert_eq!(
+            parse_cmd(&frame(&["queue", "delete"]))
+                .unwrap_err()
+                .to_reply(),
+            b"-ERR wrong number of arguments for 'queue'\r\n"
+        );
+    }
+
+    #[test]
+    fn test_parse_debug_profile() {
+        let cmd = parse_cmd(b"*3\r\n$5\r\nDEBUG\r\n$7\r\nPROFILE\r\n$2\r\n30\r\n").unwrap();
+        assert!(matches!(
+            cmd,
+            Cmd::DEBUG(DebugCmd::PROFILE {
+                seconds: 30,
+                path: None
+            })
+        ));
+
+        let cmd =
+            parse_cmd(b"*4\r\n$5\r\nDEBUG\r\n$7\r\nPROFILE\r\n$1\r\n5\r\n$5\r\np.txt\r\n").unwrap();
+        assert!(
+            matches!(cmd, Cmd::DEBUG(DebugCmd::PROFILE { seconds: 5, path: Some(p) }) if p == "p.txt")
+        );
+
+        assert!(parse_cmd(b"*3\r\n$5\r\nDEBUG\r\n$7\r\nPROFILE\r\n$1\r\n0\r\n").is_err());
+        assert!(parse_cmd(b"*3\r\n$5\r\nDEBUG\r\n$7\r\nPROFILE\r\n$4\r\n9999\r\n").is_err());
+    }
+
+    #[test]
+    fn test_parse_queue_jobs() {
+        let cmd = parse_cmd(&frame(&["QUEUE", "CLONE", "jobs", "jobs.copy"])).unwrap();
+        assert!(matches!(
+            cmd,
+            Cmd::QUEUE(QueueCmd::CLONE { source, destination })
+                if source == "jobs" && destination == "jobs.copy"
+        ));
+        let cmd = parse_cmd(&frame(&["QUEUE", "REDRIVE", "jobs", "interleave", "4"])).unwrap();
+        assert!(matches!(
+            cmd,
+            Cmd::QUEUE(QueueCmd::REDRIVE {
+                count: None,
+                priority: RedrivePriority::Interleave(4),
+                ..
+            })
+        ));
+        let cmd = parse_cmd(&frame(&["QUEUE", "REDRIVE", "jobs", "10", "BOOST"])).unwrap();
+        assert!(matches!(
+            cmd,
+            Cmd::QUEUE(QueueCmd::REDRIVE {
+                count: Some(10),
+                priority: RedrivePriority::Boost,
+                ..
+            })
+        ));
+        let cmd = parse_cmd(&frame(&[
+            "QUEUE",
+            "REDRIVE",
+            "jobs.failed",
+            "to",
+            "42",
+            "5",
+        ]))
+        .unwrap();
+        assert!(matches!(
+            cmd,
+            Cmd::QUEUE(QueueCmd::REDRIVETO { name, target, count: Some(5) })
+                if name == "jobs.failed" && target == "42"
+        ));
+        assert!(parse_cmd(&frame(&["QUEUE", "REDRIVE", "jobs", "TO", "jobs"])).is_err());
+        assert!(parse_cmd(&frame(&["QUEUE", "REDRIVE", "jobs", "10", "TO", "other"])).is_err());
+        assert!(matches!(
+            parse_cmd(&frame(&["QUEUE", "INFLIGHT", "jobs", "10"])).unwrap(),
+            Cmd::QUEUE(QueueCmd::INFLIGHT {
+                count: Some(10),
+                ..
+            })
+        ));
+        assert!(matches!(
+            parse_cmd(&frame(&["PROGRESS", "jobs", "id", "250%"])).unwrap(),
+            Cmd::PROGRESS {
+                progress: Progress::Percent(100),
+                ..
+            }
+        ));
+        assert!(matches!(
+            parse_cmd(&frame(&["PROGRESS", "jobs", "id", "stuck?"])).unwrap(),
+            Cmd::PROGRESS { progress: Progress::Status(status), .. } if status == "stuck?"
+        ));
+        assert!(parse_cmd(&frame(&["QUEUE", "EXPORT", "jobs"])).is_err());
+
+        let cmd = parse_cmd(&frame(&["JOB", "cancel", "7"])).unwrap();
+        assert!(matches!(cmd, Cmd::JOB(JobCmd::CANCEL(7))));
+        assert!(matches!(
+            parse_cmd(&frame(&["JOB", "LIST"])).unwrap(),
+            Cmd::JOB(JobCmd::LIST)
+        ));
+        assert!(parse_cmd(&frame(&["JOB", "STATUS", "x"])).is_err());
+    }
+
+    #[test]
+    fn test_parse_server_snapshot() {
+        assert!(matches!(
+            parse_cmd(&frame(&["SERVER", "snapshot"])).unwrap(),
+            Cmd::SERVER(ServerCmd::SNAPSHOT)
+        ));
+        let cmd = parse_cmd(&frame(&["SERVER", "SNAPSHOTREAD", "abc", "1024", "512"])).unwrap();
+        assert!(matches!(
+            cmd,
+            Cmd::SERVER(ServerCmd::SNAPSHOTREAD { id, offset: 1024, count: 512 }) if id == "abc"
+        ));
+        assert!(parse_cmd(&frame(&["SERVER", "SNAPSHOTREAD", "abc", "-1", "512"])).is_err());
+        assert!(parse_cmd(&frame(&["SERVER", "SNAPSHOTREAD", "abc"])).is_err());
+    }
+
+    #[test]
+    fn test_parse_schedule() {
+        let cmd = parse_cmd(&frame(&[
+            "SCHEDULE",
+            "add",
+            "nightly",
+            "reports",
+            "0 2 * * *",
+            "run",
+        ]))
+        .unwrap();
+        assert!(matches!(
+            cmd,
+            Cmd::SCHEDULE(ScheduleCmd::ADD { name, queue, cron, body })
+                if name == "nightly" && queue == "reports" && cron.to_string() == "0 2 * * *"
+                    && body == "run"
+        ));
+        assert!(parse_cmd(&frame(&[
+            "SCHEDULE", "ADD", "nightly", "reports", "0 2 *", "run"
+        ]))
+        .is_err());
+        assert!(matches!(
+            parse_cmd(&frame(&["SCHEDULE", "LIST"])).unwrap(),
+            Cmd::SCHEDULE(ScheduleCmd::LIST)
+        ));
+    }
+
+    #[test]
+    fn test_lowercase_commands_and_keywords() {
+        let cmd = parse_cmd(&frame(&[
+            "hello", "3", "auth", "admin", "pw", "setname", "app",
+        ]));
+        assert!(matches!(
+            cmd.unwrap(),
+            Cmd::HELLO { auth: Some(user), setname: Some(name), .. } if user == "admin" && name == "app"
+        ));
+        let cmd = parse_cmd(&frame(&["Pop", "jobs", "2", "block", "50"])).unwrap();
+        assert!(matches!(
+            cmd,
+            Cmd::POP {
+                count: 2,
+                on_empty: EmptyPop::Block(timeout),
+                ..
+            } if timeout == Duration::from_millis(50)
+        ));
+        assert!(matches!(
+            parse_cmd(&frame(&["pop", "jobs", "null"])).unwrap(),
+            Cmd::POP {
+                on_empty: EmptyPop::Null,
+                visibility: None,
+                ..
+            }
+        ));
+        let cmd = parse_cmd(&frame(&["pop", "jobs", "visibility", "500", "3"])).unwrap();
+        assert!(matches!(
+            cmd,
+            Cmd::POP {
+                count: 3,
+                visibility: Some(visibility),
+                ..
+            } if visibility == Duration::from_millis(500)
+        ));
+        assert!(matches!(
+            parse_cmd(&frame(&["shutdown", "save"])).unwrap(),
+            Cmd::SHUTDOWN { save: true }
+        ));
+        assert!(matches!(
+            parse_cmd(&frame(&["push", "jobs", "x", "checksum", "1"])).unwrap(),
+            Cmd::PUSH {
+                checksum: Some(1),
+                ..
+            }
+        ));
+        assert!(matches!(
+            parse_cmd(&frame(&[
+                "push", "jobs", "x", "priority", "7", "CHECKSUM", "1"
+            ]))
+            .unwrap(),
+            Cmd::PUSH {
+                checksum: Some(1),
+                priority: 7,
+                ..
+            }
+        ));
+        assert!(parse_cmd(&frame(&["PUSH", "jobs", "x", "PRIORITY", "256"])).is_err());
+        assert!(matches!(
+            parse_cmd(&frame(&["push", "jobs", "x", "ttl", "30000"])).unwrap(),
+            Cmd::PUSH {
+                ttl: Some(ttl),
+                ..
+            } if ttl == Duration::from_secs(30)
+        ));
+        assert!(matches!(
+            parse_cmd(&frame(&["PUSH", "jobs", "x", "DEDUP", "order-1"])).unwrap(),
+            Cmd::PUSH { dedup: Some(key), .. } if key == "order-1"
+        ));
+        assert!(parse_cmd(&frame(&["PUSH", "jobs", "x", "DEDUP"])).is_err());
+        assert!(matches!(
+            parse_cmd(&frame(&["PUSH", "jobs", "x", "group", "customer-1"])).unwrap(),
+            Cmd::PUSH { group: Some(group), .. } if group == "customer-1"
+        ));
+        let cmd = parse_cmd(&frame(&[
+            "PUSH", "jobs", "x", "ATTR", "trace-id", "abc", "attr", "type", "json",
+        ]))
+        .unwrap();
+        let Cmd::PUSH { attributes, .. } = cmd else {
+            panic!("expected PUSH, got {:?}", cmd);
+        };
+        assert_eq!(attributes.len(), 2);
+        assert_eq!(attributes["trace-id"], "abc");
+        assert!(parse_cmd(&frame(&[
+            "PUSH", "jobs", "x", "ATTR", "a", "1", "ATTR", "a", "2"
+        ]))
+        .is_err());
+        assert!(parse_cmd(&frame(&["PUSH", "jobs", "x", "ATTR", "a"])).is_err());
+        assert!(matches!(
+            parse_cmd(&frame(&["queue", "redrive", "jobs", "Boost"])).unwrap(),
+            Cmd::QUEUE(QueueCmd::REDRIVE {
+                priority: RedrivePriority::Boost,
+                ..
+            })
+        ));
+        assert!(matches!(
+            parse_cmd(&frame(&["channel", "1", "ack", "jobs", "id"])).unwrap(),
+            Cmd::CHANNEL { .. }
+        ));
+        // Queue names and other values keep their case.
+        assert!(matches!(
+            parse_cmd(&frame(&["push", "Jobs", "Body"])).unwrap(),
+            Cmd::PUSH { queue, body, .. } if queue == "Jobs" && body == "Body"
+        ));
+    }
+
+    #[test]
+    fn test_null_arguments() {
+        // A null where an optional argument goes counts as leaving it out.
+        let cmd = parse_cmd(b"*3\r\n$3\r\nPOP\r\n$4\r\njobs\r\n$-1\r\n").unwrap();
+        assert!(matches!(cmd, Cmd::POP { count: 1, .. }));
+        assert!(matches!(
+            parse_cmd(&frame(&["nack", "jobs", "id"])).unwrap(),
+            Cmd::NACK {
+                delay: Duration::ZERO,
+                ..
+            }
+        ));
+        let cmd = parse_cmd(&frame(&["NACK", "jobs", "id", "delay", "1500"])).unwrap();
+        assert!(matches!(
+            cmd,
+            Cmd::NACK { delay, .. } if delay == Duration::from_millis(1500)
+        ));
+        assert!(parse_cmd(&frame(&["NACK", "jobs", "id", "later", "1"])).is_err());
+        let cmd = parse_cmd(&frame(&["FANOUT", "hi", "jobs", "audit"])).unwrap();
+        assert!(matches!(cmd, Cmd::FANOUT { queues, .. } if queues == ["jobs", "audit"]));
+        assert!(parse_cmd(&frame(&["FANOUT", "hi", "jobs", "jobs"])).is_err());
+        assert!(parse_cmd(&frame(&["FANOUT", "hi"])).is_err());
+        let cmd = parse_cmd(&frame(&[
+            "FANOUT",
+            "hi",
+            "eu-jobs",
+            "IF",
+            r#"attributes.region == "eu""#,
+            "audit",
+            "ATTR",
+            "region",
+            "us",
+        ]))
+        .unwrap();
+        assert!(matches!(
+            cmd,
+            Cmd::FANOUT { queues, rules, attributes, .. }
+                if queues == ["eu-jobs", "audit"]
+                    && rules.keys().eq(["eu-jobs"])
+                    && attributes["region"] == "us"
+        ));
+        assert!(matches!(
+            parse_cmd(&frame(&[
+                "FANOUT",
+                "hi",
+                "jobs",
+                "IF",
+                "attributes.region =="
+            ])),
+            Err(RespError::InvalidRule(_))
+        ));
+        assert!(parse_cmd(&frame(&["FANOUT", "hi", "jobs", "ATTR", "a", "b", "audit"])).is_err());
+        let cmd = parse_cmd(&frame(&["MPUSH", "jobs", "a", "", "c"])).unwrap();
+        assert!(matches!(cmd, Cmd::MPUSH { bodies, .. } if bodies == ["a", "", "c"]));
+        assert!(parse_cmd(&frame(&["MPUSH", "jobs"])).is_err());
+        let cmd = parse_cmd(&frame(&["PEEK", "jobs", "10"])).unwrap();
+        assert!(matches!(cmd, Cmd::PEEK { count: 10, .. }));
+        let cmd = parse_cmd(&frame(&["peek", "jobs"])).unwrap();
+        assert!(matches!(cmd, Cmd::PEEK { count: 1, .. }));
+        assert!(parse_cmd(&frame(&["PEEK", "jobs", "many"])).is_err());
+        let cmd = parse_cmd(&frame(&["CHANNEL", "1", "ACK", "", "id"])).unwrap();
+        assert!(matches!(
+            cmd.with_default_queue(Some("jobs")).unwrap(),
+            Cmd::CHANNEL { cmd, .. } if matches!(&*cmd, Cmd::ACK { queue, .. } if queue == "jobs")
+        ));
+        let cmd = parse_cmd(&frame(&["ACK", "", "id"])).unwrap();
+        assert_eq!(
+            cmd.with_default_queue(None).unwrap_err().to_reply(),
+            b"-ERR no queue given and none set with USE\r\n"
+        );
+        assert!(matches!(
+            parse_cmd(&frame(&["touch", "jobs", "id"])).unwrap(),
+            Cmd::TOUCH {
+                visibility: None,
+                ..
+            }
+        ));
+        assert!(matches!(
+            parse_cmd(&frame(&["TOUCH", "jobs", "id", "10m"])).unwrap(),
+            Cmd::TOUCH { visibility: Some(visibility), .. } if visibility == Duration::from_secs(600)
+        ));
+        let cmd = parse_cmd(b"*4\r\n$3\r\nACK\r\n$4\r\njobs\r\n$2\r\nid\r\n$-1\r\n").unwrap();
+        assert!(matches!(cmd, Cmd::ACK { checksum: None, .. }));
+        let cmd = parse_cmd(b"*?\r\n$8\r\nSHUTDOWN\r\n$-1\r\n.\r\n").unwrap();
+        assert!(matches!(cmd, Cmd::SHUTDOWN { save: false }));
+
+        let err = parse_cmd(b"*3\r\n$4\r\nPUSH\r\n$4\r\njobs\r\n$-1\r\n").unwrap_err();
+        assert_eq!(err.to_reply(), b"-ERR invalid arg for null\r\n");
+        let err = parse_cmd(b"*-1\r\n").unwrap_err();
+        assert_eq!(err.to_reply(), b"-ERR no data\r\n");
+    }
+
+    #[test]
+    fn test_wrong_arity() {
+        let err = |args: &[&str]| parse_cmd(&frame(args)).unwrap_err().to_reply();
+        assert_eq!(
+            err(&["PUSH", "jobs"]),
+            b"-ERR wrong number of arguments for 'push'\r\n"
+        );
+        assert_eq!(
+            err(&["server", "SNAPSHOTREAD", "abc", "0", "10", "now"]),
+            b"-ERR wrong number of arguments for 'server'\r\n"
+        );
+        assert_eq!(
+            err(&["HELLO"]),
+            b"-ERR wrong number of arguments for 'hello'\r\n"
+        );
+        assert_eq!(
+            err(&["POP", "jobs", "1", "BLOCK", "10", "VISIBILITY", "10", "x"]),
+            b"-ERR wrong number of arguments for 'pop'\r\n"
+        );
+        // Checked for the wrapped command too.
+        assert_eq!(
+            err(&["CHANNEL", "1", "ACK", "jobs"]),
+            b"-ERR wrong number of arguments for 'ack'\r\n"
+        );
+        assert!(parse_cmd(&frame(&["COMMAND", "INFO", "push", "pop", "ack", "queue"])).is_ok());
+    }
+
+    #[test]
+    fn test_parse_command() {
+        let cmd = parse_cmd(&frame(&["COMMAND"])).unwrap();
+        assert!(matches!(cmd, Cmd::COMMAND(CommandCmd::LIST)));
+        let cmd = parse_cmd(&frame(&["command", "docs", "push", "pop"])).unwrap();
+        assert!(matches!(cmd, Cmd::COMMAND(CommandCmd::DOCS(names)) if names == ["push", "pop"]));
+        assert!(parse_cmd(&frame(&["COMMAND", "BOGUS"])).is_err());
+    }
+
+    #[test]
+    fn test_parse_channel() {
+        let cmd =
+            parse_cmd(b"*4\r\n$7\r\nCHANNEL\r\n$1\r\n7\r\n$3\r\nPOP\r\n$4\r\njobs\r\n").unwrap();
+        let Cmd::CHANNEL { channel, cmd } = cmd else {
+            panic!("expected CHANNEL, got {:?}", cmd);
+        };
+        assert_eq!(channel, 7);
+        assert!(matches!(*cmd, Cmd::POP { count: 1, .. }));
+
+        assert!(
+            parse_cmd(b"*3\r\n$7\r\nCHANNEL\r\n$1\r\n7\r\n$5\r\nHELLO\r\n$1\r\n3\r\n").is_err()
+        );
+        assert!(parse_cmd(b"*3\r\n$7\r\nCHANNEL\r\n$1\r\nx\r\n$3\r\nPOP\r\n").is_err());
+    }
+
+    #[test]
+    fn test_parse_pop_count() {
+        let cmd = parse_cmd(b"*2\r\n$3\r\nPOP\r\n$4\r\njobs\r\n").unwrap();
+        assert!(matches!(cmd, Cmd::POP { count: 1, .. }));
+
+        let cmd = parse_cmd(b"*3\r\n$3\r\nPOP\r\n$4\r\njobs\r\n$2\r\n10\r\n").unwrap();
+        assert!(matches!(cmd, Cmd::POP { count: 10, .. }));
+
+        assert!(parse_cmd(b"*3\r\n$3\r\nPOP\r\n$4\r\njobs\r\n$1\r\nx\r\n").is_err());
+    }
+
+    #[test]
+    fn test_parse_pop_on_empty() {
+        let cmd = parse_cmd(b"*2\r\n$3\r\nPOP\r\n$4\r\njobs\r\n").unwrap();
+        assert!(matches!(
+            cmd,
+            Cmd::POP {
+                on_empty: EmptyPop::Array,
+                ..
+            }
+        ));
+
+        let cmd = parse_cmd(b"*4\r\n$3\r\nPOP\r\n$4\r\njobs\r\n$1\r\n5\r\n$4\r\nNULL\r\n").unwrap();
+        assert!(matches!(
+            cmd,
+            Cmd::POP {
+                count: 5,
+                on_empty: EmptyPop::Null,
+                ..
+            }
+        ));
+
+        let cmd =
+            parse_cmd(b"*4\r\n$3\r\nPOP\r\n$4\r\njobs\r\n$5\r\nBLOCK\r\n$3\r\n250\r\n").unwrap();
+        let Cmd::POP { on_empty, .. } = cmd else {
+            panic!("expected POP, got {:?}", cmd);
+        };
+        assert_eq!(on_empty, EmptyPop::Block(Duration::from_millis(250)));
+
+        assert!(parse_cmd(b"*3\r\n$3\r\nPOP\r\n$4\r\njobs\r\n$5\r\nBLOCK\r\n").is_err());
+
+        let cmd = parse_cmd(&frame(&["POP", "jobs", "BLOCK", "2s", "VISIBILITY", "5m"])).unwrap();
+        let Cmd::POP {
+            on_empty,
+            visibility,
+            ..
+        } = cmd
+        else {
+            panic!("expected POP, got {:?}", cmd);
+        };
+        assert_eq!(on_empty, EmptyPop::Block(Duration::from_secs(2)));
+        assert_eq!(visibility, Some(Duration::from_secs(300)));
+        assert_eq!(
+            parse_cmd(&frame(&["POP", "jobs", "BLOCK", "2sec"]))
+                .unwrap_err()
+                .to_reply(),
+            b"-ERR invalid arg for 2sec\r\n"
+        );
+    }
+
+    #[test]
+    fn test_parse_checksum() {
+        let cmd = parse_cmd(b"*3\r\n$4\r\nPUSH\r\n$4\r\njobs\r\n$5\r\nhello\r\n").unwrap();
+        assert!(matches!(cmd, Cmd::PUSH { checksum: None, .. }));
+
+        let cmd = parse_cmd(
+            b"*5\r\n$3\r\nACK\r\n$4\r\njobs\r\n$2\r\nid\r\n$8\r\nCHECKSUM\r\n$9\r\n907060870\r\n",
+        )
+        .unwrap();
+        assert!(matches!(
+            cmd,
+            Cmd::ACK {
+                checksum: Some(907060870),
+                ..
+            }
+        ));
+
+        assert!(
+            parse_cmd(b"*4\r\n$3\r\nACK\r\n$4\r\njobs\r\n$2\r\nid\r\n$8\r\nCHECKSUM\r\n").is_err()
+        );
+        let several = frame(&["ACK", "jobs", "a", "b", "CHECKSUM", "907060870"]);
+        assert!(parse_cmd(&several).is_err());
+        assert!(parse_cmd(&frame(&["ACK", "jobs", "id", "CHECKSUM", "1", "foo"])).is_err());
+        let cmd = parse_cmd(&frame(&["ACK", "jobs", "a", "b"])).unwrap();
+        assert!(matches!(cmd, Cmd::ACK { ids, checksum: None, .. } if ids == ["a", "b"]));
+    }
+
+    #[test]
+    fn test_parse_arguments_that_look_like_headers() {
+        let cmd = parse_cmd(&frame(&["PUSH", "$jobs", "$5\r\n*2\r\n"])).unwrap();
+        let Cmd::PUSH { queue, body, .. } = cmd else {
+            panic!("expected PUSH, got {:?}", cmd);
+        };
+        assert_eq!(queue, "$jobs");
+        assert_eq!(&body[..], b"$5\r\n*2\r\n");
+
+        let cmd = parse_cmd(&frame(&["ACK", "*1", "$3", "CHECKSUM", "7"])).unwrap();
+        assert!(matches!(
+            cmd,
+            Cmd::ACK { queue, ids, checksum: Some(7) } if queue == "*1" && ids == ["$3"]
+        ));
+    }
+
+    #[test]
+    fn test_parse_binary_body() {
+        let cmd = parse_cmd(b"*3\r\n$4\r\nPUSH\r\n$4\r\njobs\r\n$6\r\na\r\n\xff\x00b\r\n").unwrap();
+        let Cmd::PUSH { queue, body, .. } = cmd else {
+            panic!("expected PUSH, got {:?}", cmd);
+        };
+        assert_eq!(queue, "jobs");
+        assert_eq!(&body[..], b"a\r\n\xff\x00b");
+
+        // Lengths are honoured, so a short payload can't swallow the next argument.
+        assert!(parse_cmd(b"*3\r\n$4\r\nPUSH\r\n$4\r\njobs\r\n$9\r\nhello\r\n").is_err());
+        assert!(parse_cmd(b"*2\r\n$3\r\nPOP\r\n$4\r\n\xff\xfe\xfd\xfc\r\n").is_err());
+    }
+
+    #[test]
+    fn test_parse_frame_shares_body() {
+        let frame = Bytes::from_static(b"*3\r\n$4\r\nPUSH\r\n$4\r\njobs\r\n$5\r\nhello\r\n");
+        let Ok(Cmd::PUSH { body, .. }) = parse_frame(&frame) else {
+            panic!("expected PUSH");
+        };
+        assert_eq!(body.as_ptr(), frame[frame.len() - 7..].as_ptr());
+    }
+
+    #[test]
+    fn test_parse_streamed() {
+        let cmd = parse_cmd(
+            b"*?\r\n$4\r\nPUSH\r\n$4\r\njobs\r\n$?\r\n;3\r\nhel\r\n;4\r\nlo\r\n\r\n;0\r\n.\r\n",
+        )
+        .unwrap();
+        let Cmd::PUSH { queue, body, .. } = cmd else {
+            panic!("expected PUSH, got {:?}", cmd);
+        };
+        assert_eq!(queue, "jobs");
+        assert_eq!(&body[..], b"hello\r\n");
+
+        assert!(matches!(
+            parse_cmd(b"*2\r\n$?\r\n;2\r\nPO\r\n;1\r\nP\r\n;0\r\n$1\r\nq\r\n"),
+            Ok(Cmd::POP { .. })
+        ));
+        assert!(parse_cmd(b"*?\r\n$3\r\nPOP\r\n$1\r\nq\r\n").is_err());
+        assert!(parse_cmd(b"*1\r\n$?\r\n;9\r\nPOP\r\n;0\r\n").is_err());
+    }
+}
@@ -67,16 +67,12 @@ impl RespBufferedReader {
                 let first_line_result = from_utf8_without_delimiter(&self.data[1..=eol]);
                 match first_line_result {
                     Ok(first_line) => {
-                        let command_size_result = first_line.parse::<usize>();
-                        if command_size_result.is_err() {
-                            Err(SerializeError::UnreadableCommandSize)
-                        } else {
-                            let cmd_size =
-                                command_size_result.expect("Unable to parse command size");
-                            let cmd_size_with_attr_lengths = cmd_size * 2 + 1;
-                            self.size = Some(cmd_size_with_attr_lengths);
-                            Ok(cmd_size_with_attr_lengths)
-                        }
+                        let Ok(cmd_size) = first_line.parse::<usize>() else {
+                            return Err(SerializeError::UnreadableCommandSize);
+                        };
+                        let cmd_size_with_attr_lengths = cmd_size * 2 + 1;
+                        self.size = Some(cmd_size_with_attr_lengths);
+                        Ok(cmd_size_with_attr_lengths)
                     }
                     Err(_) => Err(SerializeError::UnsupportedTextEncoding),
                 }
@@ -89,7 +85,7 @@ impl RespBufferedReader {
         if self.data.len() < 4 {
             return false;
         }
-        let mut n = self.data.len() - 1;
+        let n = self.data.len() - 1;
         self.data[n - 1] == ASCII_CARRIAGE_RETURN && self.data[n] == ASCII_LINE_FEED
     }
 
@@ -116,7 +112,7 @@ impl RespBufferedReader {
 
     pub fn extend(&mut self, buff: &[u8]) -> Result<bool> {
         self.data.extend(buff);
-        Ok(self.all_lines_received()?)
+        self.all_lines_received()
     }
 
     pub fn read(&mut self, buff: &[u8]) -> Result<usize> {
@@ -124,15 +120,17 @@ impl RespBufferedReader {
         while read_cursor < buff.len() {
             let line = read_line(read_cursor, buff);
             read_cursor += line.len();
-            let msg_processing_result = self.extend(&line);
+            let msg_processing_result = self.extend(line);
             match msg_processing_result {
                 Err(err) => match &err {
                     SerializeError::IncompleteLine
                     | SerializeError::MissingContentSize
                     | SerializeError::IncompleteCommand
                     | SerializeError::UnreadableCommandSize => continue,
-                    SerializeError::UnsupportedTextEncoding => {
-                        return Err(SerializeError::UnsupportedTextEncoding);
+                    SerializeError::UnsupportedTextEncoding
+                    | SerializeError::UnsupportedType
+                    | SerializeError::LimitExceeded { .. } => {
+                        return Err(err);
                     }
                 },
                 Ok(command_transmission_complete) => {
@@ -181,13 +179,13 @@ mod tests {
     #[test]
     fn test_is_last_line_complete_returns_false_without_data() {
         let cmd = RespBufferedReader::new();
-        assert_eq!(false, cmd.is_last_line_complete());
+        assert!(!cmd.is_last_line_complete());
     }
 
     #[test]
     fn test_is_last_line_complete_returns_true_with_data() {
         let cmd = create_hello_cmd();
-        assert_eq!(true, cmd.is_last_line_complete());
+        assert!(cmd.is_last_line_complete());
     }
 
     #[test]
@@ -206,13 +204,13 @@ mod tests {
     #[test]
     fn test_message_ready_returns_true() {
         let mut cmd = create_hello_cmd();
-        assert_eq!(true, cmd.all_lines_received().unwrap());
+        assert!(cmd.all_lines_received().unwrap());
     }
 
     #[test]
     fn test_message_ready_returns_false() {
         let mut cmd = create_partial_hello_cmd();
-        assert_eq!(false, cmd.all_lines_received().unwrap());
+        assert!(!cmd.all_lines_received().unwrap());
         assert_eq!(5, cmd.last_read_idx);
         assert_eq!(1, cmd.delimiter_cnt);
     }
@@ -1,118 +1,351 @@
-use crate::constants::{ASCII_ASTERISK, RESP_BUFFER_SIZE};
+use crate::config::FrameLimits;
 use crate::server::SerializeError;
-use crate::utils::{from_utf8_without_delimiter, index_is_at_delimiter};
+use bytes::{Bytes, BytesMut};
 
 pub type Result<T> = std::result::Result<T, SerializeError>;
 
+/// Aggregate whose elements are still being read.
 #[derive(Debug, Clone)]
-pub struct RespBuffer {
-    data: [u8; RESP_BUFFER_SIZE],
-    bytes_read: usize,
+struct Pending {
+    remaining: u64,
+    /// Attributes annotate the next value instead of being one, so finishing
+    /// an attribute does not count towards its parent.
+    attribute: bool,
+    /// Sent with `?` for its length; only a `.` line closes it.
+    streamed: bool,
 }
 
-#[derive(Debug, Clone)]
+/// Incremental decoder for RESP2/RESP3 frames of any type. Bytes are appended to one
+/// buffer as they arrive and every complete frame is split off its front without being
+/// copied. Aggregates are tracked with a stack of pending element counts and blob
+/// payloads are skipped by their declared length, so a payload may itself contain CRLF.
+/// RESP3 streamed strings and aggregates, whose length is not known upfront, are
+/// followed to their end marker.
+#[derive(Debug, Clone, Default)]
 pub struct RespReader {
-    data: Vec<RespBuffer>,
-    delimiters_read: u32,
-    read_buffer_size: usize,
-    pub expected_delimiter_cnt: u32,
-    pub reached_end_of_msg: bool,
+    buf: BytesMut,
+    /// Bytes at the front of `buf` already decoded as part of the current frame.
+    scanned: usize,
+    stack: Vec<Pending>,
+    /// Payload bytes, plus the trailing CRLF, left in the current blob.
+    blob_remaining: usize,
+    /// Inside a `$?` string, reading `;<len>` chunks until `;0`.
+    chunked: bool,
+    reached_end_of_msg: bool,
 }
 
 impl RespReader {
     pub fn new() -> Self {
-        RespReader {
-            data: Vec::new(),
-            delimiters_read: 0,
-            expected_delimiter_cnt: 0,
-            read_buffer_size: RESP_BUFFER_SIZE,
-            reached_end_of_msg: false,
-        }
+        RespReader::default()
+    }
+
+    /// Where reads land; frames are cut from it by `next_frame`.
+    pub fn buffer(&mut self) -> &mut BytesMut {
+        &mut self.buf
     }
 
+    /// Bytes read and not yet cut into frames.
+    pub fn buffered(&self) -> usize {
+        self.buf.len()
+    }
+
+    /// Forgets the frame being decoded and everything buffered after it.
     pub fn reset(&mut self) {
-        self.data.clear();
-        self.delimiters_read = 0;
-        self.read_buffer_size = 0;
-        self.expected_delimiter_cnt = 0;
+        self.buf.clear();
+        self.restart();
+    }
+
+    fn restart(&mut self) {
+        self.scanned = 0;
+        self.stack.clear();
+        self.blob_remaining = 0;
+        self.chunked = false;
         self.reached_end_of_msg = false;
     }
 
-    pub fn try_read_size(&self, buff: &[u8]) -> Result<u32> {
-        if buff.len() < 4 || buff[0] != ASCII_ASTERISK {
-            return Err(SerializeError::IncompleteCommand);
+    fn parse_len(line: &[u8]) -> Result<i64> {
+        std::str::from_utf8(line)
+            .map_err(|_| SerializeError::UnsupportedTextEncoding)?
+            .parse::<i64>()
+            .map_err(|_| SerializeError::UnreadableCommandSize)
+    }
+
+    /// Handles a complete header line, CRLF stripped.
+    fn read_header(&mut self, line: &[u8], limits: &FrameLimits) -> Result<()> {
+        let Some((&prefix, rest)) = line.split_first() else {
+            return Err(SerializeError::MissingContentSize);
+        };
+        if self.chunked {
+            return self.read_chunk_header(prefix, rest, limits);
         }
-        let size_utf8 = from_utf8_without_delimiter(&buff[1..])?;
-        let size = size_utf8
-            .parse::<u32>()
-            .map_err(|_| SerializeError::UnsupportedTextEncoding)?;
-        // The expected command size for the array incoming is multiplied by two
-        // Each array element will contain the size and then element.
-        // One is added in because the first element in the array is array size.
-        Ok(size * 2 + 1)
-    }
-
-    fn read_byte(&mut self, i: usize, buff: &[u8]) -> Result<bool> {
-        if index_is_at_delimiter(i, buff) {
-            if self.expected_delimiter_cnt == 0 {
-                self.expected_delimiter_cnt = self.try_read_size(&buff[..=i])?;
+        match prefix {
+            b'*' | b'~' | b'>' | b'%' if rest == b"?" => self.stack.push(Pending {
+                remaining: 0,
+                attribute: false,
+                streamed: true,
+            }),
+            b'.' if rest.is_empty() => match self.stack.pop() {
+                Some(Pending { streamed: true, .. }) => self.complete_value(false),
+                _ => return Err(SerializeError::UnsupportedType),
+            },
+            b'*' | b'~' | b'>' | b'%' | b'|' => {
+                let len = Self::parse_len(rest)?;
+                if len > limits.max_elements as i64 {
+                    return Err(SerializeError::LimitExceeded {
+                        limit: "aggregate length",
+                        max: limits.max_elements,
+                    });
+                }
+                let per_entry = if matches!(prefix, b'%' | b'|') { 2 } else { 1 };
+                if len <= 0 {
+                    // Empty and null aggregates are complete values on their own.
+                    self.complete_value(prefix == b'|');
+                } else {
+                    self.stack.push(Pending {
+                        remaining: len as u64 * per_entry,
+                        attribute: prefix == b'|',
+                        streamed: false,
+                    });
+                }
             }
-            self.delimiters_read += 1;
+            b'$' if rest == b"?" => self.chunked = true,
+            b'$' | b'=' | b'!' => match Self::parse_len(rest)? {
+                -1 => self.complete_value(false),
+                len => self.start_blob(len, limits)?,
+            },
+            b'+' | b'-' | b':' | b',' | b'#' | b'_' | b'(' => self.complete_value(false),
+            _ => return Err(SerializeError::UnsupportedType),
         }
-        self.reached_end_of_msg =
-            self.expected_delimiter_cnt != 0 && self.delimiters_read == self.expected_delimiter_cnt;
-        let continue_reading = !self.reached_end_of_msg && i < buff.len();
-        Ok(continue_reading)
-    }
-
-    pub fn read(
-        &mut self,
-        read_start: usize,
-        read_end: usize,
-        buff: [u8; RESP_BUFFER_SIZE],
-    ) -> Result<usize> {
-        let mut i = read_start;
-        while self.read_byte(i, &buff[..read_end])? {
-            i += 1
+        Ok(())
+    }
+
+    /// `;<len>` opens the next chunk of a streamed string and `;0` ends the string.
+    fn read_chunk_header(&mut self, prefix: u8, rest: &[u8], limits: &FrameLimits) -> Result<()> {
+        if prefix != b';' {
+            return Err(SerializeError::UnsupportedType);
         }
-        self.data.push(RespBuffer {
-            data: buff,
-            bytes_read: i,
-        });
-        Ok(i)
-    }
-
-    pub fn write_to_utf8(&self) -> Result<String> {
-        let mut utf_data = Vec::with_capacity(self.data.len());
-        for i in 0..self.data.len() {
-            let resp_buffer = &self.data[i];
-            utf_data[i] = String::from_utf8_lossy(&resp_buffer.data[..=resp_buffer.bytes_read]);
+        match Self::parse_len(rest)? {
+            0 => {
+                self.chunked = false;
+                self.complete_value(false);
+            }
+            len => self.start_blob(len, limits)?,
         }
-        Ok(utf_data.join(""))
+        Ok(())
+    }
+
+    fn start_blob(&mut self, len: i64, limits: &FrameLimits) -> Result<()> {
+        if len < 0 {
+            return Err(SerializeError::UnreadableCommandSize);
+        }
+        if len > limits.max_bulk_len as i64 {
+            return Err(SerializeError::LimitExceeded {
+                limit: "bulk string length",
+                max: limits.max_bulk_len,
+            });
+        }
+        self.blob_remaining = len as usize + 2;
+        Ok(())
+    }
+
+    fn complete_value(&mut self, attribute: bool) {
+        if attribute {
+            return;
+        }
+        while let Some(top) = self.stack.last_mut() {
+            if top.streamed {
+                return;
+            }
+            top.remaining -= 1;
+            if top.remaining > 0 {
+                return;
+            }
+            let finished = self.stack.pop().unwrap();
+            if finished.attribute {
+                return;
+            }
+        }
+        self.reached_end_of_msg = true;
+    }
+
+    /// Splits the next complete frame off the buffer, exactly as the client sent it.
+    /// `None` until all of it has arrived; what was decoded so far is kept. Frames that
+    /// break `limits` are rejected as soon as their headers give them away.
+    pub fn next_frame(&mut self, limits: &FrameLimits) -> Result<Option<Bytes>> {
+        while !self.reached_end_of_msg {
+            if self.scanned + self.blob_remaining > limits.max_frame_bytes {
+                return Err(SerializeError::LimitExceeded {
+                    limit: "frame size",
+                    max: limits.max_frame_bytes,
+                });
+            }
+            let unread = self.buf.len() - self.scanned;
+            if self.blob_remaining > 0 {
+                if unread == 0 {
+                    return Ok(None);
+                }
+                let skipped = unread.min(self.blob_remaining);
+                self.scanned += skipped;
+                self.blob_remaining -= skipped;
+                // A chunk ends only itself; the string goes on until `;0`.
+                if self.blob_remaining == 0 && !self.chunked {
+                    self.complete_value(false);
+                }
+                continue;
+            }
+            let Some(eol) = self.buf[self.scanned..]
+                .windows(2)
+                .position(|w| w == b"\r\n")
+            else {
+                // Everything buffered belongs to this frame, which has to end eventually.
+                if self.buf.len() > limits.max_frame_bytes {
+                    return Err(SerializeError::LimitExceeded {
+                        limit: "frame size",
+                        max: limits.max_frame_bytes,
+                    });
+                }
+                return Ok(None);
+            };
+            let line = self.buf[self.scanned..self.scanned + eol].to_vec();
+            self.scanned += eol + 2;
+            self.read_header(&line, limits)?;
+        }
+        let frame = self.buf.split_to(self.scanned).freeze();
+        self.restart();
+        Ok(Some(frame))
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use crate::config::FrameLimits;
     use crate::resp_reader::RespReader;
     use crate::test_utils::*;
 
+    fn read_all(frame: &[u8]) -> (RespReader, Option<Vec<u8>>) {
+        let mut reader = RespReader::new();
+        reader.buffer().extend_from_slice(frame);
+        let decoded = reader
+            .next_frame(&FrameLimits::default())
+            .unwrap()
+            .map(|frame| frame.to_vec());
+        (reader, decoded)
+    }
+
     #[test]
     fn test_read() {
         let hello = create_hello();
-        let buff = convert_to_arr(&hello);
-        let mut r = RespReader::new();
-        let bytes_read = r.read(0, hello.len(), buff).unwrap();
-        assert_eq!(bytes_read, hello.len() - 1);
+        let (reader, frame) = read_all(&hello);
+        assert_eq!(frame.unwrap(), hello);
+        assert!(reader.buf.is_empty());
     }
 
     #[test]
     fn test_read_chunked_transmission() {
-        let mut reader = RespReader::new();
         let cmds = create_lpush_and_sadd_cmds();
-        let mut buffer = convert_to_arr(&cmds);
-        let bytes_read = reader.read(0, buffer.len(), buffer).unwrap();
-        assert_eq!(reader.reached_end_of_msg, true);
-        assert_eq!(bytes_read, 49);
+        let (mut reader, frame) = read_all(&cmds);
+        assert_eq!(frame.unwrap().len(), 50);
+        assert_eq!(
+            reader.next_frame(&FrameLimits::default()).unwrap().unwrap(),
+            &cmds[50..86]
+        );
+        assert_eq!(
+            reader.next_frame(&FrameLimits::default()).unwrap().unwrap(),
+            &cmds[86..]
+        );
+        assert!(reader
+            .next_frame(&FrameLimits::default())
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn test_read_any_type() {
+        let frames: [&[u8]; 5] = [
+            b"*3\r\n$4\r\nPUSH\r\n:42\r\n$-1\r\n",
+            b"*2\r\n*2\r\n+a\r\n,1.5\r\n%1\r\n+k\r\n#t\r\n",
+            b"*0\r\n",
+            b"|1\r\n+ttl\r\n:3\r\n*1\r\n_\r\n",
+            b"*1\r\n$4\r\na\r\nb\r\n",
+        ];
+        for frame in frames {
+            let (_, decoded) = read_all(frame);
+            assert_eq!(decoded.as_deref(), Some(frame));
+        }
+    }
+
+    #[test]
+    fn test_read_stops_at_frame_end() {
+        let (reader, frame) = read_all(b"*1\r\n:1\r\n*1\r\n:2\r\n");
+        assert_eq!(frame.unwrap(), b"*1\r\n:1\r\n");
+        assert_eq!(&reader.buf[..], b"*1\r\n:2\r\n");
+    }
+
+    #[test]
+    fn test_read_split_blob() {
+        let mut reader = RespReader::new();
+        reader.buffer().extend_from_slice(b"*1\r\n$5\r\nhe");
+        assert!(reader
+            .next_frame(&FrameLimits::default())
+            .unwrap()
+            .is_none());
+        reader.buffer().extend_from_slice(b"llo\r\n");
+        let frame = reader.next_frame(&FrameLimits::default()).unwrap().unwrap();
+        assert_eq!(frame, &b"*1\r\n$5\r\nhello\r\n"[..]);
+    }
+
+    #[test]
+    fn test_read_streamed() {
+        let frames: [&[u8]; 3] = [
+            b"*2\r\n$4\r\nPUSH\r\n$?\r\n;4\r\nhell\r\n;3\r\no\r\n\r\n;0\r\n",
+            b"*?\r\n$4\r\nPUSH\r\n*?\r\n:1\r\n.\r\n%?\r\n+k\r\n$?\r\n;1\r\nv\r\n;0\r\n.\r\n.\r\n",
+            b"$?\r\n;0\r\n",
+        ];
+        for frame in frames {
+            let (_, decoded) = read_all(frame);
+            assert_eq!(decoded.as_deref(), Some(frame));
+        }
+
+        let (_, frame) = read_all(b"*?\r\n$4\r\nPUSH\r\n");
+        assert!(frame.is_none());
+        let mut reader = RespReader::new();
+        reader.buffer().extend_from_slice(b"*1\r\n.\r\n");
+        assert!(reader.next_frame(&FrameLimits::default()).is_err());
+    }
+
+    #[test]
+    fn test_read_limits() {
+        let limits = FrameLimits {
+            max_elements: 2,
+            max_bulk_len: 4,
+            max_frame_bytes: 32,
+        };
+        let within = b"*2\r\n$4\r\nPUSH\r\n$4\r\njobs\r\n";
+        let rejected: [&[u8]; 4] = [
+            b"*99999999\r\n",
+            b"*1\r\n$5\r\n",
+            b"*2\r\n$?\r\n;9\r\n",
+            b"*2\r\n*2\r\n$4\r\nPUSH\r\n$4\r\njobs\r\n*2\r\n$4\r\n",
+        ];
+        let mut reader = RespReader::new();
+        reader.buffer().extend_from_slice(within);
+        assert!(reader.next_frame(&limits).unwrap().is_some());
+        for frame in rejected {
+            let mut reader = RespReader::new();
+            reader.buffer().extend_from_slice(frame);
+            assert!(reader.next_frame(&limits).is_err(), "{:?}", frame);
+        }
+
+        // A header that never ends is cut off too.
+        let mut reader = RespReader::new();
+        reader.buffer().extend_from_slice(&[b'*'; 40]);
+        assert!(reader.next_frame(&limits).is_err());
+    }
+
+    #[test]
+    fn test_read_rejects_unknown_type() {
+        let mut reader = RespReader::new();
+        reader.buffer().extend_from_slice(b"*1\r\n?x\r\n");
+        assert!(reader.next_frame(&FrameLimits::default()).is_err());
     }
 }
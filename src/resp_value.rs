@@ -0,0 +1,461 @@
+use crate::error_code::ErrorCode;
+use std::io::Write;
+
+/// Every RESP3 type a reply can be built from.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RespValue {
+    SimpleString(String),
+    /// The message must already start with its code, e.g. `NOQUEUE unknown queue`;
+    /// build it with `RespValue::error`.
+    Error(String),
+    Integer(i64),
+    /// Binary safe; bodies are whatever bytes the producer pushed.
+    BulkString(Vec<u8>),
+    Array(Vec<RespValue>),
+    Null,
+    /// RESP2 null bulk string (`$-1`), what `Null` becomes for RESP2 clients.
+    NullBulk,
+    /// RESP2 null array (`*-1`), for RESP2 clients in place of an array; see `null_array`.
+    NullArray,
+    Double(f64),
+    Boolean(bool),
+    Map(Vec<(RespValue, RespValue)>),
+    Set(Vec<RespValue>),
+    BigNumber(String),
+    Push(Vec<RespValue>),
+    /// RESP3 streamed string: `$?` followed by `;<len>` chunks and a closing `;0`.
+    ChunkedString(Vec<Vec<u8>>),
+    /// An aggregate sent with `?` in place of its length and closed by `.`, so it can
+    /// be written before the number of elements is known.
+    Streamed(Box<RespValue>),
+    /// `value` preceded by a RESP3 attribute map describing it.
+    Annotated {
+        attributes: Vec<(RespValue, RespValue)>,
+        value: Box<RespValue>,
+    },
+}
+
+impl RespValue {
+    /// Starts a map reply, e.g. `RespValue::map().field("server", "infinity_q").build()`.
+    pub fn map() -> MapBuilder {
+        MapBuilder::default()
+    }
+
+    pub fn array() -> ArrayBuilder {
+        ArrayBuilder::new(RespValue::Array)
+    }
+
+    /// Starts an out-of-band push frame whose first element names its `kind`.
+    pub fn push(kind: &str) -> ArrayBuilder {
+        ArrayBuilder::new(RespValue::Push).item(kind)
+    }
+
+    pub fn ok() -> RespValue {
+        RespValue::SimpleString("OK".to_string())
+    }
+
+    pub fn simple(value: &str) -> RespValue {
+        RespValue::SimpleString(value.to_string())
+    }
+
+    pub fn bulk(value: impl AsRef<[u8]>) -> RespValue {
+        RespValue::BulkString(value.as_ref().to_vec())
+    }
+
+    /// `value` as a streamed string of `chunk_size` byte chunks.
+    pub fn chunked(value: &[u8], chunk_size: usize) -> RespValue {
+        RespValue::ChunkedString(
+            value
+                .chunks(chunk_size.max(1))
+                .map(<[u8]>::to_vec)
+                .collect(),
+        )
+    }
+
+    /// Null standing in for an array reply: `_` for RESP3 clients and `*-1` for RESP2
+    /// ones, where plain `Null` would be sent as a null bulk string.
+    pub fn null_array(protocol: u8) -> RespValue {
+        if protocol >= 3 {
+            RespValue::Null
+        } else {
+            RespValue::NullArray
+        }
+    }
+
+    pub fn error(code: ErrorCode, message: &str) -> RespValue {
+        RespValue::Error(format!("{} {}", code, message))
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        self.write_to(&mut out);
+        out
+    }
+
+    /// Encodes for a client that negotiated `protocol` with HELLO.
+    pub fn encode_for(&self, protocol: u8) -> Vec<u8> {
+        if protocol >= 3 {
+            self.encode()
+        } else {
+            self.to_resp2().encode()
+        }
+    }
+
+    /// Rewrites RESP3-only types the way Redis does for RESP2 clients: maps and sets
+    /// become flat arrays, doubles and big numbers become bulk strings and booleans
+    /// become 1/0. RESP2 has no streaming, so streamed values are sent whole.
+    pub fn to_resp2(&self) -> RespValue {
+        let all = |items: &[RespValue]| items.iter().map(RespValue::to_resp2).collect();
+        match self {
+            RespValue::Array(items) | RespValue::Set(items) | RespValue::Push(items) => {
+                RespValue::Array(all(items))
+            }
+            RespValue::Map(entries) => RespValue::Array(
+                entries
+                    .iter()
+                    .flat_map(|(key, value)| [key.to_resp2(), value.to_resp2()])
+                    .collect(),
+            ),
+            RespValue::Double(value) => RespValue::bulk(format_double(*value)),
+            RespValue::Boolean(value) => RespValue::Integer(*value as i64),
+            RespValue::BigNumber(value) => RespValue::bulk(value),
+            RespValue::Null => RespValue::NullBulk,
+            RespValue::ChunkedString(chunks) => RespValue::BulkString(chunks.concat()),
+            RespValue::Streamed(value) => value.to_resp2(),
+            // RESP2 has no attributes; the value is all its clients can read.
+            RespValue::Annotated { value, .. } => value.to_resp2(),
+            other => other.clone(),
+        }
+    }
+
+    fn write_to(&self, out: &mut Vec<u8>) {
+        // Writing to a Vec never fails.
+        match self {
+            RespValue::SimpleString(value) => {
+                let _ = write!(out, "+{}\r\n", value);
+            }
+            RespValue::Error(message) => {
+                let _ = write!(out, "-{}\r\n", message);
+            }
+            RespValue::Integer(value) => {
+                let _ = write!(out, ":{}\r\n", value);
+            }
+            RespValue::BulkString(value) => {
+                let _ = write!(out, "${}\r\n", value.len());
+                out.extend_from_slice(value);
+                out.extend_from_slice(b"\r\n");
+            }
+            RespValue::Array(items) => Self::write_aggregate(out, '*', items),
+            RespValue::Null => out.extend_from_slice(b"_\r\n"),
+            RespValue::NullBulk => out.extend_from_slice(b"$-1\r\n"),
+            RespValue::NullArray => out.extend_from_slice(b"*-1\r\n"),
+            RespValue::Double(value) => {
+                let _ = write!(out, ",{}\r\n", format_double(*value));
+            }
+            RespValue::Boolean(value) => {
+                let _ = write!(out, "#{}\r\n", if *value { 't' } else { 'f' });
+            }
+            RespValue::Map(entries) => {
+                let _ = write!(out, "%{}\r\n", entries.len());
+                for (key, value) in entries {
+                    key.write_to(out);
+                    value.write_to(out);
+                }
+            }
+            RespValue::Set(items) => Self::write_aggregate(out, '~', items),
+            RespValue::BigNumber(value) => {
+                let _ = write!(out, "({}\r\n", value);
+            }
+            RespValue::Push(items) => Self::write_aggregate(out, '>', items),
+            RespValue::ChunkedString(chunks) => {
+                out.extend_from_slice(b"$?\r\n");
+                for chunk in chunks.iter().filter(|chunk| !chunk.is_empty()) {
+                    let _ = write!(out, ";{}\r\n", chunk.len());
+                    out.extend_from_slice(chunk);
+                    out.extend_from_slice(b"\r\n");
+                }
+                out.extend_from_slice(b";0\r\n");
+            }
+            RespValue::Streamed(value) => value.write_streamed(out),
+            RespValue::Annotated { attributes, value } => {
+                let _ = write!(out, "|{}\r\n", attributes.len());
+                for (key, attribute) in attributes {
+                    key.write_to(out);
+                    attribute.write_to(out);
+                }
+                value.write_to(out);
+            }
+        }
+    }
+
+    fn write_streamed(&self, out: &mut Vec<u8>) {
+        let (prefix, items): (char, Vec<&RespValue>) = match self {
+            RespValue::Array(items) => ('*', items.iter().collect()),
+            RespValue::Set(items) => ('~', items.iter().collect()),
+            RespValue::Push(items) => ('>', items.iter().collect()),
+            RespValue::Map(entries) => (
+                '%',
+                entries
+                    .iter()
+                    .flat_map(|(key, value)| [key, value])
+                    .collect(),
+            ),
+            // Only aggregates have a streamed form.
+            other => return other.write_to(out),
+        };
+        let _ = write!(out, "{}?\r\n", prefix);
+        for item in items {
+            item.write_to(out);
+        }
+        out.extend_from_slice(b".\r\n");
+    }
+
+    fn write_aggregate(out: &mut Vec<u8>, prefix: char, items: &[RespValue]) {
+        let _ = write!(out, "{}{}\r\n", prefix, items.len());
+        for item in items {
+            item.write_to(out);
+        }
+    }
+}
+
+impl From<&str> for RespValue {
+    fn from(value: &str) -> Self {
+        RespValue::simple(value)
+    }
+}
+
+impl From<i64> for RespValue {
+    fn from(value: i64) -> Self {
+        RespValue::Integer(value)
+    }
+}
+
+impl From<usize> for RespValue {
+    fn from(value: usize) -> Self {
+        RespValue::from(value as u64)
+    }
+}
+
+/// Counts too big for a RESP integer are sent as big numbers rather than wrapping.
+impl From<u64> for RespValue {
+    fn from(value: u64) -> Self {
+        i64::try_from(value).map_or_else(
+            |_| RespValue::BigNumber(value.to_string()),
+            RespValue::Integer,
+        )
+    }
+}
+
+impl From<u32> for RespValue {
+    fn from(value: u32) -> Self {
+        RespValue::Integer(value as i64)
+    }
+}
+
+impl From<bool> for RespValue {
+    fn from(value: bool) -> Self {
+        RespValue::Boolean(value)
+    }
+}
+
+/// Builds a map, or the attributes of an annotated value, one field at a time. Keys
+/// are simple strings; `&str` values are too, integers become integers and anything
+/// else is given as a `RespValue`.
+#[derive(Debug, Default)]
+pub struct MapBuilder {
+    entries: Vec<(RespValue, RespValue)>,
+}
+
+impl MapBuilder {
+    pub fn field(mut self, key: &str, value: impl Into<RespValue>) -> Self {
+        self.entries.push((RespValue::simple(key), value.into()));
+        self
+    }
+
+    pub fn fields<K: AsRef<str>, V: Into<RespValue>>(
+        self,
+        fields: impl IntoIterator<Item = (K, V)>,
+    ) -> Self {
+        fields
+            .into_iter()
+            .fold(self, |map, (key, value)| map.field(key.as_ref(), value))
+    }
+
+    pub fn build(self) -> RespValue {
+        RespValue::Map(self.entries)
+    }
+
+    /// Sends the fields as an attribute ahead of `value` instead of as a map.
+    pub fn annotate(self, value: RespValue) -> RespValue {
+        RespValue::Annotated {
+            attributes: self.entries,
+            value: Box::new(value),
+        }
+    }
+}
+
+impl From<MapBuilder> for RespValue {
+    fn from(map: MapBuilder) -> Self {
+        map.build()
+    }
+}
+
+/// Builds an array or push frame one element at a time.
+#[derive(Debug)]
+pub struct ArrayBuilder {
+    kind: fn(Vec<RespValue>) -> RespValue,
+    items: Vec<RespValue>,
+}
+
+impl ArrayBuilder {
+    fn new(kind: fn(Vec<RespValue>) -> RespValue) -> Self {
+        ArrayBuilder {
+            kind,
+            items: Vec::new(),
+        }
+    }
+
+    pub fn item(mut self, value: impl Into<RespValue>) -> Self {
+        self.items.push(value.into());
+        self
+    }
+
+    pub fn items<V: Into<RespValue>>(mut self, values: impl IntoIterator<Item = V>) -> Self {
+        self.items.extend(values.into_iter().map(Into::into));
+        self
+    }
+
+    pub fn build(self) -> RespValue {
+        (self.kind)(self.items)
+    }
+}
+
+impl From<ArrayBuilder> for RespValue {
+    fn from(array: ArrayBuilder) -> Self {
+        array.build()
+    }
+}
+
+fn format_double(value: f64) -> String {
+    match value {
+        v if v.is_nan() => "nan".to_string(),
+        v if v.is_infinite() && v.is_sign_positive() => "inf".to_string(),
+        v if v.is_infinite() => "-inf".to_string(),
+        v => v.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error_code::ErrorCode;
+    use crate::resp_value::RespValue;
+
+    #[test]
+    fn test_encode_scalars() {
+        assert_eq!(RespValue::ok().encode(), b"+OK\r\n");
+        assert_eq!(
+            RespValue::error(ErrorCode::ERR, "no data").encode(),
+            b"-ERR no data\r\n"
+        );
+        assert_eq!(RespValue::Integer(-3).encode(), b":-3\r\n");
+        assert_eq!(RespValue::bulk("hello").encode(), b"$5\r\nhello\r\n");
+        assert_eq!(RespValue::Null.encode(), b"_\r\n");
+        assert_eq!(RespValue::NullBulk.encode(), b"$-1\r\n");
+        assert_eq!(RespValue::null_array(3).encode(), b"_\r\n");
+        assert_eq!(RespValue::null_array(2).encode_for(2), b"*-1\r\n");
+        assert_eq!(RespValue::Double(1.5).encode(), b",1.5\r\n");
+        assert_eq!(RespValue::Double(f64::NEG_INFINITY).encode(), b",-inf\r\n");
+        assert_eq!(RespValue::Boolean(true).encode(), b"#t\r\n");
+        assert_eq!(
+            RespValue::BigNumber("3492890328409238509324850943850943825024385".to_string())
+                .encode(),
+            b"(3492890328409238509324850943850943825024385\r\n"
+        );
+        assert_eq!(
+            RespValue::from(u64::MAX).encode(),
+            b"(18446744073709551615\r\n"
+        );
+        assert_eq!(RespValue::from(7u64).encode(), b":7\r\n");
+    }
+
+    #[test]
+    fn test_encode_resp2() {
+        let value = RespValue::Map(vec![
+            (RespValue::simple("proto"), RespValue::Integer(2)),
+            (RespValue::simple("ok"), RespValue::Boolean(true)),
+            (RespValue::simple("ratio"), RespValue::Double(0.5)),
+            (RespValue::simple("none"), RespValue::Null),
+        ]);
+        assert_eq!(
+            value.encode_for(2),
+            b"*8\r\n+proto\r\n:2\r\n+ok\r\n:1\r\n+ratio\r\n$3\r\n0.5\r\n+none\r\n$-1\r\n"
+        );
+        assert_eq!(value.encode_for(3), value.encode());
+    }
+
+    #[test]
+    fn test_encode_aggregates() {
+        let value = RespValue::Map(vec![(
+            RespValue::simple("queues"),
+            RespValue::Set(vec![RespValue::bulk("a")]),
+        )]);
+        assert_eq!(value.encode(), b"%1\r\n+queues\r\n~1\r\n$1\r\na\r\n");
+
+        let value = RespValue::Push(vec![RespValue::simple("cancel"), RespValue::Array(vec![])]);
+        assert_eq!(value.encode(), b">2\r\n+cancel\r\n*0\r\n");
+    }
+
+    #[test]
+    fn test_encode_binary_bulk() {
+        let value = RespValue::bulk([0xff, b'\r', b'\n', 0x00]);
+        assert_eq!(value.encode(), b"$4\r\n\xff\r\n\x00\r\n");
+    }
+
+    #[test]
+    fn test_encode_streamed() {
+        let value = RespValue::chunked(b"hello world", 5);
+        assert_eq!(
+            value.encode(),
+            b"$?\r\n;5\r\nhello\r\n;5\r\n worl\r\n;1\r\nd\r\n;0\r\n"
+        );
+        assert_eq!(value.encode_for(2), b"$11\r\nhello world\r\n");
+
+        let value = RespValue::Streamed(Box::new(RespValue::Map(vec![(
+            RespValue::simple("a"),
+            RespValue::Integer(1),
+        )])));
+        assert_eq!(value.encode(), b"%?\r\n+a\r\n:1\r\n.\r\n");
+        assert_eq!(value.encode_for(2), b"*2\r\n+a\r\n:1\r\n");
+    }
+
+    #[test]
+    fn test_builders() {
+        let value = RespValue::map()
+            .field("server", "infinity_q")
+            .field("proto", 3_i64)
+            .field("id", RespValue::bulk("a"))
+            .field("modules", RespValue::array())
+            .build();
+        assert_eq!(
+            value.encode(),
+            b"%4\r\n+server\r\n+infinity_q\r\n+proto\r\n:3\r\n+id\r\n$1\r\na\r\n+modules\r\n*0\r\n"
+        );
+
+        let value = RespValue::push("cancel")
+            .item(RespValue::bulk("jobs"))
+            .build();
+        assert_eq!(value.encode(), b">2\r\n+cancel\r\n$4\r\njobs\r\n");
+
+        let value = RespValue::array().items([1_i64, 2]).build();
+        assert_eq!(value.encode(), b"*2\r\n:1\r\n:2\r\n");
+    }
+
+    #[test]
+    fn test_encode_annotated() {
+        let value = RespValue::map()
+            .field("ttl", 3_i64)
+            .annotate(RespValue::bulk("v"));
+        assert_eq!(value.encode(), b"|1\r\n+ttl\r\n:3\r\n$1\r\nv\r\n");
+        assert_eq!(value.encode_for(2), b"$1\r\nv\r\n");
+    }
+}
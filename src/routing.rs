@@ -0,0 +1,126 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use strum_macros::EnumString;
+
+/// What a router gets to look at when placing a message.
+#[derive(Debug, Clone, Copy)]
+pub struct RouteKey<'a> {
+    pub message_id: &'a str,
+    /// Messages sharing a group id should land on the same shard to keep their order.
+    pub group_id: Option<&'a str>,
+    /// Shard picked by the producer, if any.
+    pub partition: Option<usize>,
+}
+
+/// Decides which of a queue's shards a message is stored on. Implement this to control
+/// locality without touching the queue code; `shards` is always at least 1.
+pub trait ShardRouter: Send {
+    fn route(&mut self, key: &RouteKey, shards: usize) -> usize;
+}
+
+/// Hashes the group id, falling back to the message id for ungrouped messages.
+#[derive(Debug, Default)]
+pub struct HashGroupRouter;
+
+impl ShardRouter for HashGroupRouter {
+    fn route(&mut self, key: &RouteKey, shards: usize) -> usize {
+        let mut hasher = DefaultHasher::new();
+        key.group_id.unwrap_or(key.message_id).hash(&mut hasher);
+        (hasher.finish() % shards as u64) as usize
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct RoundRobinRouter {
+    next: usize,
+}
+
+impl ShardRouter for RoundRobinRouter {
+    fn route(&mut self, _key: &RouteKey, shards: usize) -> usize {
+        let shard = self.next % shards;
+        self.next = shard + 1;
+        shard
+    }
+}
+
+/// Uses the producer's partition, wrapping it into range. Messages without one go to shard 0.
+#[derive(Debug, Default)]
+pub struct ExplicitRouter;
+
+impl ShardRouter for ExplicitRouter {
+    fn route(&mut self, key: &RouteKey, shards: usize) -> usize {
+        key.partition.unwrap_or(0) % shards
+    }
+}
+
+/// Built-in routers, selectable per queue by name.
+#[derive(Debug, Clone, Copy, PartialEq, Default, EnumString)]
+#[strum(serialize_all = "kebab-case", ascii_case_insensitive)]
+pub enum RoutingStrategy {
+    #[default]
+    HashGroup,
+    RoundRobin,
+    Explicit,
+}
+
+impl RoutingStrategy {
+    pub fn router(self) -> Box<dyn ShardRouter> {
+        match self {
+            RoutingStrategy::HashGroup => Box::new(HashGroupRouter),
+            RoutingStrategy::RoundRobin => Box::<RoundRobinRouter>::default(),
+            RoutingStrategy::Explicit => Box::new(ExplicitRouter),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::routing::*;
+    use std::str::FromStr;
+
+    fn key<'a>(group_id: Option<&'a str>, partition: Option<usize>) -> RouteKey<'a> {
+        RouteKey {
+            message_id: "id",
+            group_id,
+            partition,
+        }
+    }
+
+    #[test]
+    fn test_hash_group_is_stable() {
+        let mut router = RoutingStrategy::HashGroup.router();
+        let shard = router.route(&key(Some("order-42"), None), 8);
+        for _ in 0..10 {
+            assert_eq!(router.route(&key(Some("order-42"), None), 8), shard);
+        }
+        assert!(shard < 8);
+    }
+
+    #[test]
+    fn test_round_robin() {
+        let mut router = RoutingStrategy::RoundRobin.router();
+        let shards: Vec<usize> = (0..5).map(|_| router.route(&key(None, None), 3)).collect();
+        assert_eq!(shards, vec![0, 1, 2, 0, 1]);
+    }
+
+    #[test]
+    fn test_explicit() {
+        let mut router = RoutingStrategy::Explicit.router();
+        assert_eq!(router.route(&key(None, Some(2)), 4), 2);
+        assert_eq!(router.route(&key(None, Some(6)), 4), 2);
+        assert_eq!(router.route(&key(None, None), 4), 0);
+    }
+
+    #[test]
+    fn test_strategy_from_name() {
+        assert_eq!(
+            RoutingStrategy::from_str("round-robin").unwrap(),
+            RoutingStrategy::RoundRobin
+        );
+        assert_eq!(
+            RoutingStrategy::from_str("hash-group").unwrap(),
+            RoutingStrategy::HashGroup
+        );
+        assert!(RoutingStrategy::from_str("random").is_err());
+    }
+}
@@ -0,0 +1,432 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fmt::Formatter;
+
+/// Nodes a single evaluation may visit before it is cut off.
+pub const DEFAULT_BUDGET: usize = 256;
+/// Deepest nesting the parser accepts, so hostile rules can't blow the stack.
+const MAX_DEPTH: usize = 32;
+
+#[derive(Debug, PartialEq)]
+pub enum RuleError {
+    Syntax(String),
+    TooDeep,
+    BudgetExceeded,
+}
+
+impl fmt::Display for RuleError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            RuleError::Syntax(err) => write!(f, "rule syntax error: {}", err),
+            RuleError::TooDeep => write!(f, "rule nests deeper than {}", MAX_DEPTH),
+            RuleError::BudgetExceeded => write!(f, "rule exceeded its execution budget"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Str(String),
+    Num(f64),
+    Bool(bool),
+    /// An attribute the message doesn't have.
+    Missing,
+}
+
+impl Value {
+    fn truthy(&self) -> bool {
+        match self {
+            Value::Bool(b) => *b,
+            Value::Str(s) => !s.is_empty(),
+            Value::Num(n) => *n != 0.0,
+            Value::Missing => false,
+        }
+    }
+}
+
+/// What a rule can see of the message being routed.
+pub struct RuleContext<'a> {
+    pub attributes: &'a BTreeMap<String, String>,
+    pub body: &'a str,
+}
+
+impl RuleContext<'_> {
+    fn lookup(&self, path: &[String]) -> Value {
+        match path {
+            [root] if root == "body" => Value::Str(self.body.to_string()),
+            [root, key] if root == "attributes" => self
+                .attributes
+                .get(key)
+                .map(|v| Value::Str(v.clone()))
+                .unwrap_or(Value::Missing),
+            _ => Value::Missing,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum CmpOp {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Expr {
+    Literal(Value),
+    Path(Vec<String>),
+    Not(Box<Expr>),
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Cmp(CmpOp, Box<Expr>, Box<Expr>),
+}
+
+/// A compiled routing/filter rule such as `attributes.region == "eu" && !(attributes.tier == "free")`.
+#[derive(Debug, Clone)]
+pub struct Rule {
+    source: String,
+    expr: Expr,
+}
+
+impl Rule {
+    pub fn compile(source: &str) -> Result<Rule, RuleError> {
+        let tokens = tokenize(source)?;
+        let mut parser = Parser {
+            tokens,
+            pos: 0,
+            depth: 0,
+        };
+        let expr = parser.or()?;
+        if parser.pos != parser.tokens.len() {
+            return Err(RuleError::Syntax(format!(
+                "unexpected {:?}",
+                parser.tokens[parser.pos]
+            )));
+        }
+        Ok(Rule {
+            source: source.to_string(),
+            expr,
+        })
+    }
+
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    pub fn matches(&self, ctx: &RuleContext) -> Result<bool, RuleError> {
+        self.matches_with_budget(ctx, DEFAULT_BUDGET)
+    }
+
+    pub fn matches_with_budget(&self, ctx: &RuleContext, budget: usize) -> Result<bool, RuleError> {
+        let mut remaining = budget;
+        Ok(eval(&self.expr, ctx, &mut remaining)?.truthy())
+    }
+}
+
+fn eval(expr: &Expr, ctx: &RuleContext, budget: &mut usize) -> Result<Value, RuleError> {
+    if *budget == 0 {
+        return Err(RuleError::BudgetExceeded);
+    }
+    *budget -= 1;
+    Ok(match expr {
+        Expr::Literal(value) => value.clone(),
+        Expr::Path(path) => ctx.lookup(path),
+        Expr::Not(inner) => Value::Bool(!eval(inner, ctx, budget)?.truthy()),
+        Expr::And(lhs, rhs) => {
+            Value::Bool(eval(lhs, ctx, budget)?.truthy() && eval(rhs, ctx, budget)?.truthy())
+        }
+        Expr::Or(lhs, rhs) => {
+            Value::Bool(eval(lhs, ctx, budget)?.truthy() || eval(rhs, ctx, budget)?.truthy())
+        }
+        Expr::Cmp(op, lhs, rhs) => {
+            let lhs = eval(lhs, ctx, budget)?;
+            let rhs = eval(rhs, ctx, budget)?;
+            Value::Bool(compare(*op, &lhs, &rhs))
+        }
+    })
+}
+
+/// Attributes are strings on the wire, so a string compared to a number is parsed first.
+fn compare(op: CmpOp, lhs: &Value, rhs: &Value) -> bool {
+    let as_num = |v: &Value| match v {
+        Value::Num(n) => Some(*n),
+        Value::Str(s) => s.parse::<f64>().ok(),
+        _ => None,
+    };
+    let ordering = match (lhs, rhs) {
+        (Value::Num(_), _) | (_, Value::Num(_)) => match (as_num(lhs), as_num(rhs)) {
+            (Some(a), Some(b)) => a.partial_cmp(&b),
+            _ => None,
+        },
+        (Value::Str(a), Value::Str(b)) => Some(a.cmp(b)),
+        (Value::Bool(a), Value::Bool(b)) => Some(a.cmp(b)),
+        (Value::Missing, Value::Missing) => Some(std::cmp::Ordering::Equal),
+        _ => None,
+    };
+    match (op, ordering) {
+        (CmpOp::Ne, None) => true,
+        (_, None) => false,
+        (CmpOp::Eq, Some(o)) => o.is_eq(),
+        (CmpOp::Ne, Some(o)) => o.is_ne(),
+        (CmpOp::Lt, Some(o)) => o.is_lt(),
+        (CmpOp::Le, Some(o)) => o.is_le(),
+        (CmpOp::Gt, Some(o)) => o.is_gt(),
+        (CmpOp::Ge, Some(o)) => o.is_ge(),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Ident(String),
+    Str(String),
+    Num(f64),
+    Op(&'static str),
+    Dot,
+    LParen,
+    RParen,
+}
+
+const OPERATORS: [&str; 9] = ["==", "!=", "<=", ">=", "&&", "||", "<", ">", "!"];
+
+fn tokenize(source: &str) -> Result<Vec<Token>, RuleError> {
+    let chars: Vec<char> = source.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        if c.is_whitespace() {
+            i += 1;
+            continue;
+        }
+        match c {
+            '(' => tokens.push(Token::LParen),
+            ')' => tokens.push(Token::RParen),
+            '.' => tokens.push(Token::Dot),
+            '"' => {
+                let end = chars[i + 1..]
+                    .iter()
+                    .position(|&c| c == '"')
+                    .ok_or_else(|| RuleError::Syntax("unterminated string".to_string()))?;
+                tokens.push(Token::Str(chars[i + 1..i + 1 + end].iter().collect()));
+                i += end + 2;
+                continue;
+            }
+            c if c.is_ascii_digit() => {
+                let len = chars[i..]
+                    .iter()
+                    .take_while(|c| c.is_ascii_digit() || **c == '.')
+                    .count();
+                let raw: String = chars[i..i + len].iter().collect();
+                let num = raw
+                    .parse::<f64>()
+                    .map_err(|_| RuleError::Syntax(format!("bad number {}", raw)))?;
+                tokens.push(Token::Num(num));
+                i += len;
+                continue;
+            }
+            c if c.is_alphabetic() || c == '_' => {
+                let len = chars[i..]
+                    .iter()
+                    .take_while(|c| c.is_alphanumeric() || **c == '_' || **c == '-')
+                    .count();
+                tokens.push(Token::Ident(chars[i..i + len].iter().collect()));
+                i += len;
+                continue;
+            }
+            _ => {
+                let rest: String = chars[i..chars.len().min(i + 2)].iter().collect();
+                let Some(op) = OPERATORS.iter().find(|op| rest.starts_with(**op)) else {
+                    return Err(RuleError::Syntax(format!("unexpected '{}'", c)));
+                };
+                tokens.push(Token::Op(op));
+                i += op.len();
+                continue;
+            }
+        }
+        i += 1;
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+    depth: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn eat_op(&mut self, op: &str) -> bool {
+        if matches!(self.peek(), Some(Token::Op(o)) if *o == op) {
+            self.pos += 1;
+            return true;
+        }
+        false
+    }
+
+    fn descend(&mut self) -> Result<(), RuleError> {
+        self.depth += 1;
+        if self.depth > MAX_DEPTH {
+            return Err(RuleError::TooDeep);
+        }
+        Ok(())
+    }
+
+    fn or(&mut self) -> Result<Expr, RuleError> {
+        let mut lhs = self.and()?;
+        while self.eat_op("||") {
+            lhs = Expr::Or(Box::new(lhs), Box::new(self.and()?));
+        }
+        Ok(lhs)
+    }
+
+    fn and(&mut self) -> Result<Expr, RuleError> {
+        let mut lhs = self.unary()?;
+        while self.eat_op("&&") {
+            lhs = Expr::And(Box::new(lhs), Box::new(self.unary()?));
+        }
+        Ok(lhs)
+    }
+
+    fn unary(&mut self) -> Result<Expr, RuleError> {
+        if self.eat_op("!") {
+            self.descend()?;
+            let inner = self.unary()?;
+            self.depth -= 1;
+            return Ok(Expr::Not(Box::new(inner)));
+        }
+        self.comparison()
+    }
+
+    fn comparison(&mut self) -> Result<Expr, RuleError> {
+        let lhs = self.primary()?;
+        let ops = [
+            ("==", CmpOp::Eq),
+            ("!=", CmpOp::Ne),
+            ("<=", CmpOp::Le),
+            (">=", CmpOp::Ge),
+            ("<", CmpOp::Lt),
+            (">", CmpOp::Gt),
+        ];
+        for (token, op) in ops {
+            if self.eat_op(token) {
+                let rhs = self.primary()?;
+                return Ok(Expr::Cmp(op, Box::new(lhs), Box::new(rhs)));
+            }
+        }
+        Ok(lhs)
+    }
+
+    fn primary(&mut self) -> Result<Expr, RuleError> {
+        let token = self
+            .peek()
+            .cloned()
+            .ok_or_else(|| RuleError::Syntax("unexpected end of rule".to_string()))?;
+        self.pos += 1;
+        match token {
+            Token::LParen => {
+                self.descend()?;
+                let inner = self.or()?;
+                self.depth -= 1;
+                if self.peek() != Some(&Token::RParen) {
+                    return Err(RuleError::Syntax("missing ')'".to_string()));
+                }
+                self.pos += 1;
+                Ok(inner)
+            }
+            Token::Str(s) => Ok(Expr::Literal(Value::Str(s))),
+            Token::Num(n) => Ok(Expr::Literal(Value::Num(n))),
+            Token::Ident(ident) if ident == "true" => Ok(Expr::Literal(Value::Bool(true))),
+            Token::Ident(ident) if ident == "false" => Ok(Expr::Literal(Value::Bool(false))),
+            Token::Ident(ident) => {
+                let mut path = vec![ident];
+                while self.peek() == Some(&Token::Dot) {
+                    self.pos += 1;
+                    match self.peek().cloned() {
+                        Some(Token::Ident(part)) => {
+                            self.pos += 1;
+                            path.push(part);
+                        }
+                        other => {
+                            return Err(RuleError::Syntax(format!(
+                                "expected a name after '.', got {:?}",
+                                other
+                            )))
+                        }
+                    }
+                }
+                Ok(Expr::Path(path))
+            }
+            other => Err(RuleError::Syntax(format!("unexpected {:?}", other))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::rules::*;
+
+    fn ctx(attributes: &BTreeMap<String, String>) -> RuleContext<'_> {
+        RuleContext {
+            attributes,
+            body: "hello",
+        }
+    }
+
+    fn attrs(pairs: &[(&str, &str)]) -> BTreeMap<String, String> {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_matches() {
+        let eu = attrs(&[("region", "eu"), ("size", "12")]);
+        let us = attrs(&[("region", "us")]);
+        let rule = Rule::compile(r#"attributes.region == "eu""#).unwrap();
+        assert!(rule.matches(&ctx(&eu)).unwrap());
+        assert!(!rule.matches(&ctx(&us)).unwrap());
+
+        let rule =
+            Rule::compile(r#"attributes.size >= 10 && !(attributes.region == "us")"#).unwrap();
+        assert!(rule.matches(&ctx(&eu)).unwrap());
+        assert!(!rule.matches(&ctx(&us)).unwrap());
+
+        let rule = Rule::compile(r#"attributes.missing || body == "hello""#).unwrap();
+        assert!(rule.matches(&ctx(&us)).unwrap());
+    }
+
+    #[test]
+    fn test_syntax_errors() {
+        assert!(matches!(
+            Rule::compile(r#"attributes.region == "eu"#),
+            Err(RuleError::Syntax(_))
+        ));
+        assert!(matches!(
+            Rule::compile("attributes.region =="),
+            Err(RuleError::Syntax(_))
+        ));
+        assert!(matches!(Rule::compile("(true"), Err(RuleError::Syntax(_))));
+        assert!(matches!(Rule::compile("a $ b"), Err(RuleError::Syntax(_))));
+        let nested = format!("{}true{}", "(".repeat(64), ")".repeat(64));
+        assert_eq!(Rule::compile(&nested).unwrap_err(), RuleError::TooDeep);
+    }
+
+    #[test]
+    fn test_budget() {
+        let rule = Rule::compile("true && true && true && true").unwrap();
+        let empty = BTreeMap::new();
+        assert!(rule.matches_with_budget(&ctx(&empty), 7).unwrap());
+        assert_eq!(
+            rule.matches_with_budget(&ctx(&empty), 3),
+            Err(RuleError::BudgetExceeded)
+        );
+    }
+}
@@ -0,0 +1,293 @@
+use bytes::Bytes;
+use chrono::{DateTime, Datelike, Duration, TimeZone, Timelike, Utc};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Mutex;
+
+/// How far ahead `CronExpr::next_after` looks before deciding an expression such as
+/// `0 0 30 2 *` never matches.
+const SEARCH_YEARS: i64 = 5;
+
+/// A five field cron expression, `minute hour day-of-month month day-of-week`, read in
+/// UTC. Fields take `*`, values, ranges such as `1-5`, steps such as `*/15` or `8-18/2`,
+/// and comma separated lists of those. Sunday is 0 or 7.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CronExpr {
+    source: String,
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    /// Day of month and day of week were both restricted, in which case a day matching
+    /// either of them runs, as in cron.
+    either_day: bool,
+}
+
+impl FromStr for CronExpr {
+    type Err = String;
+
+    fn from_str(source: &str) -> Result<Self, Self::Err> {
+        let fields: Vec<&str> = source.split_whitespace().collect();
+        let [minutes, hours, days, months, weekdays] = fields[..] else {
+            return Err(format!("expected 5 fields, got {}", fields.len()));
+        };
+        let mut weekday_bits = parse_field(weekdays, 0, 7)?;
+        // 7 is another name for Sunday.
+        if weekday_bits & 1 << 7 != 0 {
+            weekday_bits = (weekday_bits | 1) & !(1 << 7);
+        }
+        Ok(CronExpr {
+            source: fields.join(" "),
+            minutes: parse_field(minutes, 0, 59)?,
+            hours: parse_field(hours, 0, 23)?,
+            days: parse_field(days, 1, 31)?,
+            months: parse_field(months, 1, 12)?,
+            weekdays: weekday_bits,
+            either_day: days != "*" && weekdays != "*",
+        })
+    }
+}
+
+impl fmt::Display for CronExpr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+/// Bit set of the values `field` allows out of `min..=max`.
+fn parse_field(field: &str, min: u32, max: u32) -> Result<u64, String> {
+    let mut bits = 0;
+    for part in field.split(',') {
+        let invalid = || format!("'{}' is not a cron field of {}-{}", part, min, max);
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, step.parse().map_err(|_| invalid())?),
+            None => (part, 1),
+        };
+        let (low, high) = match range.split_once('-') {
+            _ if range == "*" => (min, max),
+            Some((low, high)) => (
+                low.parse().map_err(|_| invalid())?,
+                high.parse().map_err(|_| invalid())?,
+            ),
+            // `5/15` runs from 5 to the end of the field.
+            None if step > 1 => (range.parse().map_err(|_| invalid())?, max),
+            None => {
+                let value = range.parse().map_err(|_| invalid())?;
+                (value, value)
+            }
+        };
+        if step == 0 || low < min || high > max || low > high {
+            return Err(invalid());
+        }
+        for value in (low..=high).step_by(step) {
+            bits |= 1 << value;
+        }
+    }
+    Ok(bits)
+}
+
+fn allows(bits: u64, value: u32) -> bool {
+    bits & 1 << value != 0
+}
+
+impl CronExpr {
+    /// First minute after `after` the expression matches, or `None` if it never does.
+    pub fn next_after(&self, after: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let mut at = after.with_second(0)?.with_nanosecond(0)? + Duration::minutes(1);
+        let give_up = after + Duration::days(366 * SEARCH_YEARS);
+        while at <= give_up {
+            if !allows(self.months, at.month()) {
+                let (year, month) = match at.month() {
+                    12 => (at.year() + 1, 1),
+                    month => (at.year(), month + 1),
+                };
+                at = Utc.with_ymd_and_hms(year, month, 1, 0, 0, 0).single()?;
+            } else if !self.allows_day(at) {
+                at = at.date_naive().and_hms_opt(0, 0, 0)?.and_utc() + Duration::days(1);
+            } else if !allows(self.hours, at.hour()) {
+                at = at.with_minute(0)? + Duration::hours(1);
+            } else if !allows(self.minutes, at.minute()) {
+                at += Duration::minutes(1);
+            } else {
+                return Some(at);
+            }
+        }
+        None
+    }
+
+    fn allows_day(&self, at: DateTime<Utc>) -> bool {
+        let day = allows(self.days, at.day());
+        let weekday = allows(self.weekdays, at.weekday().num_days_from_sunday());
+        if self.either_day {
+            day || weekday
+        } else {
+            day && weekday
+        }
+    }
+}
+
+/// A message pushed to `queue` every time `cron` comes round.
+#[derive(Debug, Clone)]
+pub struct Schedule {
+    pub queue: String,
+    pub cron: CronExpr,
+    pub body: Bytes,
+    pub next_run: DateTime<Utc>,
+    /// Messages pushed so far.
+    pub runs: u64,
+}
+
+/// Recurring messages registered with `SCHEDULE ADD`, by name. They live as long as the
+/// server does and are pushed by `ServerState::schedule_forever`.
+#[derive(Debug, Default)]
+pub struct Schedules {
+    schedules: Mutex<BTreeMap<String, Schedule>>,
+}
+
+impl Schedules {
+    /// Registers `name`, replacing any schedule already under it, and returns when it
+    /// first runs. Returns `None`, registering nothing, when `cron` never matches.
+    pub fn add(
+        &self,
+        name: String,
+        queue: String,
+        cron: CronExpr,
+        body: Bytes,
+        now: DateTime<Utc>,
+    ) -> Option<DateTime<Utc>> {
+        let next_run = cron.next_after(now)?;
+        let schedule = Schedule {
+            queue,
+            cron,
+            body,
+            next_run,
+            runs: 0,
+        };
+        self.schedules.lock().unwrap().insert(name, schedule);
+        Some(next_run)
+    }
+
+    pub fn remove(&self, name: &str) -> bool {
+        self.schedules.lock().unwrap().remove(name).is_some()
+    }
+
+    /// Every schedule by name.
+    pub fn list(&self) -> Vec<(String, Schedule)> {
+        let schedules = self.schedules.lock().unwrap();
+        schedules
+            .iter()
+            .map(|(name, schedule)| (name.clone(), schedule.clone()))
+            .collect()
+    }
+
+    /// Queue and body of every schedule due at `now`, each moved on to its next run.
+    /// A schedule pushes once however many of its runs were missed, and is dropped once
+    /// its expression stops matching.
+    pub fn due(&self, now: DateTime<Utc>) -> Vec<(String, Bytes)> {
+        let mut schedules = self.schedules.lock().unwrap();
+        let mut due = Vec::new();
+        schedules.retain(|_, schedule| {
+            if schedule.next_run > now {
+                return true;
+            }
+            due.push((schedule.queue.clone(), schedule.body.clone()));
+            schedule.runs += 1;
+            match schedule.cron.next_after(now) {
+                Some(next_run) => {
+                    schedule.next_run = next_run;
+                    true
+                }
+                None => false,
+            }
+        });
+        due
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::schedule::*;
+
+    fn at(text: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(text).unwrap().to_utc()
+    }
+
+    #[test]
+    fn test_cron_next_after() {
+        let next = |expr: &str, after: &str| {
+            let cron: CronExpr = expr.parse().unwrap();
+            cron.next_after(at(after)).map(|next| next.to_rfc3339())
+        };
+        let start = "2026-10-16T10:07:30Z";
+        assert_eq!(
+            next("* * * * *", start).unwrap(),
+            "2026-10-16T10:08:00+00:00"
+        );
+        assert_eq!(
+            next("*/15 * * * *", start).unwrap(),
+            "2026-10-16T10:15:00+00:00"
+        );
+        assert_eq!(
+            next("0 9 * * *", start).unwrap(),
+            "2026-10-17T09:00:00+00:00"
+        );
+        // 2026-10-16 is a Friday, so the next weekday morning is Monday's
+        assert_eq!(
+            next("30 8 * * 1-5", "2026-10-16T09:00:00Z").unwrap(),
+            "2026-10-19T08:30:00+00:00"
+        );
+        assert_eq!(
+            next("0 0 1 1 *", start).unwrap(),
+            "2027-01-01T00:00:00+00:00"
+        );
+        // either day field may match once both are restricted
+        assert_eq!(
+            next("0 0 20 * 7", start).unwrap(),
+            "2026-10-18T00:00:00+00:00"
+        );
+        assert_eq!(next("0 0 30 2 *", start), None);
+    }
+
+    #[test]
+    fn test_cron_parse_errors() {
+        for expr in [
+            "* * * *",
+            "60 * * * *",
+            "*/0 * * * *",
+            "5-1 * * * *",
+            "a * * * *",
+        ] {
+            assert!(expr.parse::<CronExpr>().is_err(), "{}", expr);
+        }
+        assert_eq!(
+            "*/5  *  * * *".parse::<CronExpr>().unwrap().to_string(),
+            "*/5 * * * *"
+        );
+    }
+
+    #[test]
+    fn test_schedules_due() {
+        let schedules = Schedules::default();
+        let now = at("2026-10-16T10:07:30Z");
+        let body = Bytes::from_static(b"report");
+        let cron: CronExpr = "*/5 * * * *".parse().unwrap();
+        let first = schedules.add("reports".to_string(), "jobs".to_string(), cron, body, now);
+        assert_eq!(first, Some(at("2026-10-16T10:10:00Z")));
+        assert!(schedules.due(now).is_empty());
+
+        // runs missed while nobody looked push a single message
+        let late = at("2026-10-16T10:21:00Z");
+        assert_eq!(
+            schedules.due(late),
+            [("jobs".to_string(), Bytes::from_static(b"report"))]
+        );
+        let (_, schedule) = &schedules.list()[0];
+        assert_eq!(schedule.runs, 1);
+        assert_eq!(schedule.next_run, at("2026-10-16T10:25:00Z"));
+
+        assert!(schedules.remove("reports"));
+        assert!(!schedules.remove("reports"));
+    }
+}
@@ -0,0 +1,262 @@
+use crate::auth::{ADMIN, ADMIN_PW};
+use crate::client::{Client, Reply};
+use crate::config::ServerConfig;
+use crate::server::TcpServer;
+use std::net::SocketAddr;
+use std::thread;
+use std::time::Duration;
+use tokio::net::TcpListener;
+
+const QUEUE: &str = "self-test";
+const VISIBILITY_TIMEOUT_MS: i64 = 100;
+
+fn expect(condition: bool, what: &str, reply: &Reply) -> Result<(), String> {
+    if condition {
+        Ok(())
+    } else {
+        Err(format!("expected {}, got {:?}", what, reply))
+    }
+}
+
+fn push(client: &mut Client, body: &[u8]) -> Result<String, String> {
+    match client.call(&[b"PUSH".as_slice(), QUEUE.as_bytes(), body])? {
+        Reply::Bulk(id) => Ok(String::from_utf8_lossy(&id).into_owned()),
+        other => Err(format!("expected a message id, got {:?}", other)),
+    }
+}
+
+/// Pops and returns the `(id, body)` pairs.
+fn pop(client: &mut Client, count: usize) -> Result<Vec<(String, Vec<u8>)>, String> {
+    let reply = client.call(&["POP", QUEUE, &count.to_string()])?;
+    let Reply::Array(msgs) = reply else {
+        return Err(format!("expected an array of messages, got {:?}", reply));
+    };
+    let mut popped = Vec::with_capacity(msgs.len());
+    for msg in msgs {
+        match msg {
+            Reply::Array(fields) => match fields.as_slice() {
+                [Reply::Bulk(id), Reply::Bulk(body)] => {
+                    popped.push((String::from_utf8_lossy(id).into_owned(), body.clone()))
+                }
+                _ => return Err(format!("malformed message {:?}", fields)),
+            },
+            other => return Err(format!("malformed message {:?}", other)),
+        }
+    }
+    Ok(popped)
+}
+
+fn ack(client: &mut Client, id: &str) -> Result<Reply, String> {
+    client.call(&["ACK", QUEUE, id])
+}
+
+fn hello(client: &mut Client) -> Result<(), String> {
+    let reply = client.call(&["HELLO", "3", "AUTH", ADMIN, ADMIN_PW])?;
+    expect(matches!(reply, Reply::Map(_)), "a handshake map", &reply)
+}
+
+fn create_queue(client: &mut Client) -> Result<(), String> {
+    let reply = client.call(&["QUEUE", "CREATE", QUEUE])?;
+    expect(reply == Reply::Simple("OK".to_string()), "OK", &reply)
+}
+
+fn push_pop_ack(client: &mut Client) -> Result<(), String> {
+    let id = push(client, b"push-pop-ack")?;
+    let popped = pop(client, 1)?;
+    if popped != vec![(id.clone(), b"push-pop-ack".to_vec())] {
+        return Err(format!("expected message {}, got {:?}", id, popped));
+    }
+    let reply = ack(client, &id)?;
+    expect(reply == Reply::Integer(1), "the ack to succeed", &reply)?;
+    let reply = ack(client, &id)?;
+    expect(
+        reply == Reply::Integer(1),
+        "a retried ack to succeed",
+        &reply,
+    )?;
+    let reply = ack(client, "unknown")?;
+    expect(
+        reply == Reply::Integer(0),
+        "an unknown id to be rejected",
+        &reply,
+    )
+}
+
+fn empty_pop(client: &mut Client) -> Result<(), String> {
+    let popped = pop(client, 10)?;
+    if popped.is_empty() {
+        Ok(())
+    } else {
+        Err(format!("expected an empty queue, got {:?}", popped))
+    }
+}
+
+fn expiry_redelivers(client: &mut Client) -> Result<(), String> {
+    let acked = push(client, b"acked")?;
+    let expiring = push(client, b"expiring")?;
+    pop(client, 2)?;
+    ack(client, &acked)?;
+
+    thread::sleep(Duration::from_millis(VISIBILITY_TIMEOUT_MS as u64 * 3));
+    let popped = pop(client, 10)?;
+    if popped != vec![(expiring.clone(), b"expiring".to_vec())] {
+        return Err(format!(
+            "expected only {} to be redelivered, got {:?}",
+            expiring, popped
+        ));
+    }
+    ack(client, &expiring)?;
+    Ok(())
+}
+
+/// A body that isn't UTF-8 and holds CRLF comes back byte for byte.
+fn binary_body(client: &mut Client) -> Result<(), String> {
+    let body: Vec<u8> = (0..=255u8).rev().collect();
+    let id = push(client, &body)?;
+    let popped = pop(client, 1)?;
+    if popped != vec![(id.clone(), body)] {
+        return Err(format!(
+            "expected message {} unchanged, got {:?}",
+            id, popped
+        ));
+    }
+    let reply = ack(client, &id)?;
+    expect(reply == Reply::Integer(1), "the ack to succeed", &reply)
+}
+
+fn unknown_queue(client: &mut Client) -> Result<(), String> {
+    let reply = client.call(&["POP", "self-test-missing"])?;
+    expect(
+        matches!(&reply, Reply::Error(e) if e.starts_with("NOQUEUE ")),
+        "a NOQUEUE error",
+        &reply,
+    )
+}
+
+type Scenario = (&'static str, fn(&mut Client) -> Result<(), String>);
+
+const SCENARIOS: &[Scenario] = &[
+    ("hello", hello),
+    ("create queue", create_queue),
+    ("push/pop/ack", push_pop_ack),
+    ("empty pop", empty_pop),
+    ("expiry redelivers", expiry_redelivers),
+    ("binary body", binary_body),
+    ("unknown queue", unknown_queue),
+];
+
+fn run_scenarios(addr: SocketAddr) -> Result<(), String> {
+    let mut client = Client::connect(addr.to_string())?;
+    let mut failures = 0;
+    for (name, scenario) in SCENARIOS {
+        match scenario(&mut client) {
+            Ok(()) => println!("self-test {} ... ok", name),
+            Err(e) => {
+                println!("self-test {} ... FAILED: {}", name, e);
+                failures += 1;
+            }
+        }
+    }
+    client.call(&["SHUTDOWN", "NOSAVE"])?;
+    if failures > 0 {
+        return Err(format!(
+            "{} of {} scenarios failed",
+            failures,
+            SCENARIOS.len()
+        ));
+    }
+    Ok(())
+}
+
+/// Boots a server on an ephemeral port and runs every scenario against it.
+pub async fn run_suite() -> Result<(), String> {
+    let listener = TcpListener::bind("127.0.0.1:0")
+        .await
+        .map_err(|e| e.to_string())?;
+    let addr = listener.local_addr().map_err(|e| e.to_string())?;
+    let config = ServerConfig {
+        bind_addresses: vec![addr.to_string()],
+        in_flight_expiration_ms: VISIBILITY_TIMEOUT_MS,
+        ..ServerConfig::default()
+    };
+    let server = TcpServer::new(config);
+    let server_task = tokio::spawn(async move { server.serve(vec![listener]).await });
+
+    let result = tokio::task::spawn_blocking(move || run_scenarios(addr))
+        .await
+        .map_err(|e| e.to_string())?;
+    if result.is_ok() {
+        server_task
+            .await
+            .map_err(|e| e.to_string())?
+            .map_err(|e| e.to_string())?;
+    }
+    result
+}
+
+/// Pushes, pops and acks through the embedded engine, and waits for an unacked lease
+/// to be redelivered, as the server scenarios do over the wire.
+#[cfg(feature = "embedded")]
+fn run_embedded() -> Result<(), String> {
+    use crate::embedded::Embedded;
+    use crate::queue::QueueOrder;
+
+    let engine = Embedded::start(VISIBILITY_TIMEOUT_MS).map_err(|e| e.to_string())?;
+    engine
+        .create(QUEUE, QueueOrder::Fifo)
+        .map_err(|e| e.to_string())?;
+    let acked = engine.push(QUEUE, "acked").map_err(|e| e.to_string())?;
+    let expiring = engine.push(QUEUE, "expiring").map_err(|e| e.to_string())?;
+    let popped = engine
+        .pop(QUEUE, 2, Duration::ZERO)
+        .map_err(|e| e.to_string())?;
+    if popped.len() != 2 {
+        return Err(format!("expected 2 messages, got {}", popped.len()));
+    }
+    if !engine.ack(QUEUE, &acked).map_err(|e| e.to_string())? {
+        return Err(format!("expected {} to be acked", acked));
+    }
+
+    let wait = Duration::from_millis(VISIBILITY_TIMEOUT_MS as u64 * 10);
+    let redelivered = engine.pop(QUEUE, 10, wait).map_err(|e| e.to_string())?;
+    let ids: Vec<&String> = redelivered.iter().map(|msg| msg.id()).collect();
+    if ids != [&expiring] {
+        return Err(format!(
+            "expected only {} to be redelivered, got {:?}",
+            expiring, ids
+        ));
+    }
+    Ok(())
+}
+
+/// Entry point for `--self-test`. With the `embedded` feature the embedded engine is
+/// run through its paces too.
+pub fn run() -> Result<(), String> {
+    let runtime = tokio::runtime::Runtime::new().map_err(|e| e.to_string())?;
+    runtime.block_on(run_suite())?;
+    #[cfg(feature = "embedded")]
+    match run_embedded() {
+        Ok(()) => println!("self-test embedded ... ok"),
+        Err(e) => {
+            println!("self-test embedded ... FAILED: {}", e);
+            return Err(e);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::self_test::run_suite;
+
+    #[tokio::test]
+    async fn test_self_test_passes() {
+        run_suite().await.unwrap();
+    }
+
+    #[cfg(feature = "embedded")]
+    #[test]
+    fn test_embedded_self_test_passes() {
+        crate::self_test::run_embedded().unwrap();
+    }
+}
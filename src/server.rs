@@ -1,19 +1,63 @@
-use crate::constants::{DEFAULT_CLIENT_SIZE, OKAY_RESPONSE, RESP_BUFFER_SIZE};
-use crate::resp_reader::RespReader;
-use std::collections::VecDeque;
+use crate::bootstrap::fetch_snapshot;
+use crate::command_stats::CommandStats;
+use crate::commands::{execute, new_queue, room_below};
+use crate::config::{NetworkBackend, ServerConfig, SocketConfig};
+use crate::constants::DEFAULT_CLIENT_SIZE;
+use crate::error_code::ErrorCode;
+use crate::events::{ServerEvent, EVENT_BACKLOG};
+use crate::jobs::Jobs;
+use crate::overload::{LoadShedder, Pressure};
+use crate::proxy_protocol;
+use crate::queue::{now_ms, ConsumerId, Queue};
+use crate::registry::{QueueRegistry, Queues};
+use crate::resp::Cmd;
+use crate::resp_value::RespValue;
+use crate::schedule::Schedules;
+use crate::session::Session;
+use crate::snapshot::{
+    decode_snapshot, encode_snapshot, write_snapshot, SNAPSHOT_CHUNK_BYTES, SNAPSHOT_TRANSFERS,
+};
+use crate::telemetry::Telemetry;
+use crate::trace_sampling::TraceSampling;
+use crate::wait_line::WaitLines;
+use bytes::{BufMut, Bytes};
+use chrono::{DateTime, Utc};
+use socket2::{SockRef, TcpKeepalive};
+use std::collections::{BTreeMap, VecDeque};
 use std::fmt::Formatter;
+use std::os::fd::AsFd;
 use std::string::FromUtf8Error;
-use std::{fmt, io};
+use std::sync::atomic::AtomicBool;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use std::{fmt, fs, io};
 use tokio::io::{AsyncWriteExt, Error, Interest};
 use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::{broadcast, watch, Notify};
+use tokio::task::JoinSet;
+use tokio::time::{Instant, MissedTickBehavior};
+use tracing::{debug, info, warn, Instrument};
+use uuid::Uuid;
 
-#[derive(Debug)]
+/// How often `ServerState::schedule_forever` looks for scheduled messages that are due.
+const SCHEDULE_INTERVAL: Duration = Duration::from_secs(1);
+/// Client scheduled pushes run as; connections are numbered from 1.
+const SCHEDULER_CONSUMER: ConsumerId = 0;
+
+#[derive(Clone, Debug)]
 pub enum SerializeError {
     IncompleteLine,
     MissingContentSize,
     IncompleteCommand,
     UnsupportedTextEncoding,
     UnreadableCommandSize,
+    UnsupportedType,
+    /// The frame broke one of the configured `FrameLimits`.
+    LimitExceeded {
+        limit: &'static str,
+        max: usize,
+    },
 }
 
 impl fmt::Display for SerializeError {
@@ -25,13 +69,42 @@ impl fmt::Display for SerializeError {
             SerializeError::MissingContentSize => write!(f, "Message does not contain size"),
             SerializeError::IncompleteCommand => write!(f, "Partial read occurred, "),
             SerializeError::UnsupportedTextEncoding => write!(f, "Could not serialize to utf8"),
-            SerializeError::UnreadableCommandSize => write!(f, "{}", "Unreadable command size"),
+            SerializeError::UnreadableCommandSize => write!(f, "Unreadable command size"),
+            SerializeError::UnsupportedType => write!(f, "Unsupported RESP type"),
+            SerializeError::LimitExceeded { limit, max } => {
+                write!(f, "{} exceeds the limit of {}", limit, max)
+            }
         }
     }
 }
 
+impl SerializeError {
+    /// Errors after which the rest of the stream can't be trusted, so the connection
+    /// is closed once the error is sent.
+    pub fn is_fatal(&self) -> bool {
+        matches!(self, SerializeError::LimitExceeded { .. })
+    }
+
+    /// Frames that couldn't be read are plain `ERR`s, as Redis replies to them.
+    pub fn code(&self) -> ErrorCode {
+        ErrorCode::ERR
+    }
+
+    /// Encodes the error as `-ERR Protocol error: ...`, the reply for a frame that
+    /// couldn't be read.
+    pub fn to_reply(&self) -> Vec<u8> {
+        RespValue::from(self).encode()
+    }
+}
+
+impl From<&SerializeError> for RespValue {
+    fn from(err: &SerializeError) -> Self {
+        RespValue::error(err.code(), &format!("Protocol error: {}", err))
+    }
+}
+
 impl From<FromUtf8Error> for SerializeError {
-    fn from(error: FromUtf8Error) -> Self {
+    fn from(_: FromUtf8Error) -> Self {
         SerializeError::UnsupportedTextEncoding
     }
 }
@@ -44,138 +117,902 @@ impl fmt::Display for TransmissionMissingArraySize {
     }
 }
 
-#[derive(Clone, Debug)]
-struct TcpClient {
-    name: String,
-    address: String,
-    version: String,
-    authenticated: bool,
-    msg_from_client: u32,
-    msg_cnt_to_client: u32,
-    resp_buff_reader: RespReader,
-    raw_msg_queue: VecDeque<String>,
-}
-
 #[derive(Debug)]
 struct BufferReadResult {
     read_to_end_of_message: bool,
     bytes_read: usize,
 }
 
-impl TcpClient {
-    pub fn new(address: String) -> TcpClient {
-        TcpClient {
-            name: "unknown".to_string(),
-            version: "unknown".to_string(),
-            address,
-            authenticated: false,
-            msg_from_client: 0,
-            msg_cnt_to_client: 0,
-            resp_buff_reader: RespReader::new(),
-            raw_msg_queue: VecDeque::new(),
-        }
-    }
-
-    pub fn read_buff(
-        &mut self,
-        buff: [u8; RESP_BUFFER_SIZE],
-        read_end: usize,
-    ) -> Result<(), SerializeError> {
-        let mut bytes_read = 0;
-        while bytes_read < read_end {
-            bytes_read += self.resp_buff_reader.read(bytes_read, read_end, buff)?;
-            if self.resp_buff_reader.reached_end_of_msg {
-                let msg_utf8: String = self.resp_buff_reader.write_to_utf8()?;
-                self.msg_from_client += 1;
-                self.raw_msg_queue.push_back(msg_utf8);
-                self.resp_buff_reader.reset();
+/// Waits out the POP `session` is blocked on, feeding it every wakeup until it gets
+/// messages or times out, and returns every reply produced along the way.
+pub(crate) async fn wait_unblocked(session: &mut Session, state: &ServerState) -> Vec<u8> {
+    let mut replies = Vec::new();
+    while session.is_blocked() {
+        let pushed = state.pushed.notified();
+        tokio::pin!(pushed);
+        // Register before looking, so a push landing in between still wakes us.
+        pushed.as_mut().enable();
+        replies.extend(session.resume(state, std::time::Instant::now()));
+        if !session.is_blocked() {
+            break;
+        }
+        match session.block_deadline() {
+            Some(deadline) => tokio::select! {
+                _ = pushed => {}
+                _ = tokio::time::sleep_until(Instant::from_std(deadline)) => {}
+            },
+            None => pushed.await,
+        }
+    }
+    replies
+}
+
+pub(crate) fn apply_socket_config<S: AsFd>(stream: &S, config: &SocketConfig) -> Result<(), Error> {
+    let socket = SockRef::from(stream);
+    socket.set_nodelay(config.nodelay)?;
+    if let Some(idle) = config.keepalive {
+        let mut keepalive = TcpKeepalive::new().with_time(idle);
+        if let Some(interval) = config.keepalive_interval {
+            keepalive = keepalive.with_interval(interval);
+        }
+        socket.set_tcp_keepalive(&keepalive)?;
+    }
+    if let Some(size) = config.recv_buffer_size {
+        socket.set_recv_buffer_size(size)?;
+    }
+    if let Some(size) = config.send_buffer_size {
+        socket.set_send_buffer_size(size)?;
+    }
+    Ok(())
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Shutdown {
+    /// Write a snapshot of every queue before exiting.
+    Save,
+    NoSave,
+}
+
+/// State shared by every connection regardless of which listener accepted it.
+pub struct ServerState {
+    pub config: ServerConfig,
+    /// Shared with the background jobs working through a queue.
+    pub queues: QueueRegistry,
+    /// Set by `SERVER DRAIN`: POP returns nothing so consumers run dry before an upgrade.
+    pub draining: AtomicBool,
+    /// Woken whenever messages may have become available, for blocked POPs, or room
+    /// in a full queue, for PUSHes blocked by `FullPolicy::Block`.
+    pub pushed: Arc<Notify>,
+    pub telemetry: Telemetry,
+    pub shedder: LoadShedder,
+    pub jobs: Jobs,
+    pub command_stats: CommandStats,
+    pub trace_sampling: TraceSampling,
+    /// Blocked POPs per queue, longest waiting first.
+    pub wait_lines: WaitLines,
+    pub schedules: Schedules,
+    /// Random for every start, so clients can tell a restarted server from the one they
+    /// were talking to.
+    pub run_id: String,
+    /// Out-of-band notifications; every connection subscribes and writes the ones it
+    /// wants between replies.
+    pub events: broadcast::Sender<ServerEvent>,
+    /// Snapshots taken by `SERVER SNAPSHOT` for replicas to fetch, oldest first, by
+    /// transfer id.
+    snapshots: Mutex<VecDeque<(String, Bytes)>>,
+    shutdown: watch::Sender<Option<Shutdown>>,
+}
+
+impl ServerState {
+    pub fn new(config: ServerConfig) -> ServerState {
+        ServerState {
+            config,
+            queues: QueueRegistry::default(),
+            draining: AtomicBool::new(false),
+            pushed: Arc::new(Notify::new()),
+            telemetry: Telemetry::default(),
+            shedder: LoadShedder::default(),
+            jobs: Jobs::default(),
+            command_stats: CommandStats::default(),
+            trace_sampling: TraceSampling::default(),
+            wait_lines: WaitLines::default(),
+            schedules: Schedules::default(),
+            run_id: Uuid::new_v4().simple().to_string(),
+            events: broadcast::Sender::new(EVENT_BACKLOG),
+            snapshots: Mutex::new(VecDeque::new()),
+            shutdown: watch::Sender::new(None),
+        }
+    }
+
+    /// Most messages `q` may hold: its own `QUEUE CREATE ... MAXDEPTH`, or else the
+    /// server's `queue_capacity`.
+    pub fn capacity(&self, q: &Queue) -> Option<usize> {
+        q.max_depth().or(self.config.queue_capacity)
+    }
+
+    pub fn announce(&self, event: ServerEvent) {
+        // Fails only when no client is connected to hear it.
+        let _ = self.events.send(event);
+    }
+
+    pub fn request_shutdown(&self, mode: Shutdown) {
+        self.shutdown.send_replace(Some(mode));
+    }
+
+    pub async fn wait_for_shutdown(&self) -> Shutdown {
+        let mut rx = self.shutdown.subscribe();
+        loop {
+            if let Some(mode) = *rx.borrow_and_update() {
+                return mode;
+            }
+            if rx.changed().await.is_err() {
+                return Shutdown::NoSave;
+            }
+        }
+    }
+
+    /// Everything the server does on a timer rather than in response to a client.
+    pub async fn run_background(self: Arc<Self>) {
+        tokio::join!(
+            self.clone().sweep_forever(),
+            self.clone().schedule_forever(),
+            self.monitor_load_forever()
+        );
+    }
+
+    /// Pushes the messages of `schedules` as they come due, checking once a second.
+    pub async fn schedule_forever(self: Arc<Self>) {
+        let mut ticks = tokio::time::interval(SCHEDULE_INTERVAL);
+        ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            ticks.tick().await;
+            self.run_schedules(Utc::now());
+        }
+    }
+
+    /// Pushes every scheduled message due at `now` as a PUSH from the server itself
+    /// would be, so the queue's limits and policies apply to it.
+    pub fn run_schedules(&self, now: DateTime<Utc>) {
+        for (queue, body) in self.schedules.due(now) {
+            let push = Cmd::PUSH {
+                queue: queue.clone(),
+                body,
+                checksum: None,
+                priority: 0,
+                ttl: None,
+                dedup: None,
+                group: None,
+                attributes: BTreeMap::new(),
+            };
+            let reply = execute(push, SCHEDULER_CONSUMER, self);
+            if reply.starts_with(b"-") {
+                let reply = String::from_utf8_lossy(&reply);
+                warn!(queue = %queue, reply = %reply.trim_end(), "scheduled push failed");
+            }
+        }
+    }
+
+    /// Samples memory and load so `shedder` knows when to turn low priority commands
+    /// away. Returns straight away when no threshold is configured.
+    pub async fn monitor_load_forever(self: Arc<Self>) {
+        let config = &self.config.overload;
+        if !config.enabled() {
+            return;
+        }
+        let mut ticks = tokio::time::interval(config.sample_interval);
+        ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            ticks.tick().await;
+            self.shedder.update(config, &Pressure::sample());
+        }
+    }
+
+    /// Returns expired leases to their queues every `sweep_interval`, so they are
+    /// redelivered on time even when nobody POPs the queue, moves overflowed pushes into
+    /// backlogs with room and dead letters to their dead letter queues, and wakes blocked
+    /// POPs. Queues that had run dry and got messages back this way are announced, so
+    /// idle consumers POP them again.
+    pub async fn sweep_forever(self: Arc<Self>) {
+        let mut ticks = tokio::time::interval(self.config.sweep_interval);
+        ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            ticks.tick().await;
+            self.sweep();
+        }
+    }
+
+    pub fn sweep(&self) {
+        let mut requeued = false;
+        let mut available = Vec::new();
+        let now = now_ms();
+        let mut queues = self.queues.lock().unwrap();
+        for (name, q) in queues.iter_mut() {
+            let was_empty = q.depth() == 0;
+            q.expire(now);
+            requeued |= q.sweep_in_flight();
+            let capacity = self.capacity(q);
+            requeued |= q.drain_overflow(capacity) > 0;
+            // Blocked POPs get another go once the rate limit has refilled.
+            requeued |= q.throttled();
+            if was_empty && q.depth() > 0 {
+                available.push(name.clone());
+            }
+        }
+        requeued |= self.forward_dead_letters(&mut queues) > 0;
+        drop(queues);
+        if requeued {
+            self.pushed.notify_waiters();
+        }
+        for queue in available {
+            self.announce(ServerEvent::MessagesAvailable(queue));
+        }
+    }
+
+    /// Takes a snapshot of every queue for a replica to fetch with `snapshot_chunk`, and
+    /// returns its transfer id and length. A few transfers are kept, so a replica that
+    /// lost its connection can pick up where it stopped.
+    pub fn begin_snapshot(&self) -> (String, usize) {
+        let data = encode_snapshot(&self.queues.read().unwrap())
+            .expect("messages keyed by queue name always serialise");
+        let id = Uuid::new_v4().simple().to_string();
+        let len = data.len();
+        let mut snapshots = self.snapshots.lock().unwrap();
+        if snapshots.len() == SNAPSHOT_TRANSFERS {
+            snapshots.pop_front();
+        }
+        snapshots.push_back((id.clone(), Bytes::from(data)));
+        info!(transfer = %id, bytes = len, "snapshot taken for a replica");
+        (id, len)
+    }
+
+    /// Up to `count` bytes of the transfer `id` from `offset`, capped at
+    /// `SNAPSHOT_CHUNK_BYTES`; `None` once newer transfers have pushed it out.
+    pub fn snapshot_chunk(&self, id: &str, offset: usize, count: usize) -> Option<Bytes> {
+        let snapshots = self.snapshots.lock().unwrap();
+        let (_, data) = snapshots.iter().find(|(transfer, _)| transfer == id)?;
+        let start = offset.min(data.len());
+        let end = start + count.min(SNAPSHOT_CHUNK_BYTES).min(data.len() - start);
+        Some(data.slice(start..end))
+    }
+
+    /// Creates the queues of a snapshot, configured as they were saved and with their
+    /// messages waiting to be popped again, and returns how many messages there were.
+    pub fn load_snapshot(&self, data: &[u8]) -> serde_json::Result<usize> {
+        let snapshot = decode_snapshot(data)?;
+        let mut queues = self.queues.lock().unwrap();
+        let mut loaded = 0;
+        for (name, saved) in snapshot {
+            let mut q = new_queue(&name, self);
+            saved.config.apply_to(&mut q);
+            let pending = saved.messages.len();
+            for msg in saved.messages {
+                q.append(msg);
+            }
+            info!(queue = %name, pending, "queue restored");
+            loaded += pending;
+            queues.insert(name, q);
+        }
+        Ok(loaded)
+    }
+
+    /// Moves the dead letters of every queue with a dead letter queue to it, with a
+    /// fresh attempt count, as far as its capacity and overflow buffer allow. The rest
+    /// stay put for a later sweep, as do all of them while that queue doesn't exist.
+    fn forward_dead_letters(&self, queues: &mut Queues) -> usize {
+        let routes: Vec<(String, String)> = queues
+            .iter()
+            .filter(|(_, q)| q.dead_letter_count() > 0)
+            .filter_map(|(name, q)| Some((name.clone(), q.dead_letter_queue()?.to_string())))
+            .filter(|(name, target)| name != target && queues.contains_key(target))
+            .collect();
+        let mut moved = 0;
+        for (name, target) in routes {
+            let Some(target_q) = queues.get(&target) else {
+                continue;
+            };
+            let capacity = self.capacity(target_q);
+            let room = room_below(target_q, capacity);
+            let Some(q) = queues.get_mut(&name) else {
+                continue;
+            };
+            let msgs = q.take_dead_letters(room);
+            let left = q.dead_letter_count();
+            if left > 0 {
+                debug!(queue = %name, %target, left, "dead letter queue full, dead letters kept");
+            }
+            let Some(target_q) = queues.get_mut(&target) else {
+                continue;
+            };
+            moved += msgs.len();
+            for msg in msgs {
+                let msg = msg.move_to(target.clone());
+                // Past the backlog they fit the overflow buffer, which `room_below` counted.
+                if capacity.is_some_and(|capacity| target_q.depth() >= capacity) {
+                    target_q.absorb(msg);
+                } else {
+                    target_q.append(msg);
+                }
+            }
+        }
+        moved
+    }
+
+    /// Leases `consumer` holds across every queue.
+    pub fn leased_by(&self, consumer: ConsumerId) -> usize {
+        let queues = self.queues.lock().unwrap();
+        queues.values().map(|q| q.leased_by(consumer)).sum()
+    }
+
+    /// Cleans up after a connection that has closed; `consumers` are its own id and
+    /// those of its channels.
+    pub fn connection_closed(&self, consumers: &[ConsumerId]) {
+        self.release_leases(consumers);
+        self.drop_ephemeral_queues(consumers);
+    }
+
+    /// Returns the leases of a connection that has closed to their queues straight away,
+    /// rather than leaving them to expire, so a crashed consumer's work is redelivered
+    /// without waiting out the visibility timeout.
+    fn release_leases(&self, consumers: &[ConsumerId]) {
+        let mut requeued = 0;
+        for q in self.queues.lock().unwrap().values_mut() {
+            for consumer in consumers {
+                requeued += q.release_consumer(*consumer);
             }
         }
+        if requeued > 0 {
+            info!(requeued, "leases of closed connection requeued");
+            self.pushed.notify_waiters();
+        }
+    }
+
+    /// Deletes the queues created with `QUEUE CREATE ... EPHEMERAL` by a connection that
+    /// has closed, along with everything in them.
+    fn drop_ephemeral_queues(&self, consumers: &[ConsumerId]) {
+        let mut queues = self.queues.lock().unwrap();
+        let before = queues.len();
+        queues.retain(|name, q| {
+            let owned = q.owner().is_some_and(|owner| consumers.contains(&owner));
+            if owned {
+                info!(queue = %name, pending = q.depth(), "ephemeral queue deleted");
+            }
+            !owned
+        });
+        if queues.len() < before {
+            // Blocked POPs on them wake up to `NOQUEUE`.
+            self.pushed.notify_waiters();
+        }
+    }
+
+    /// Loads the queues saved by `SHUTDOWN SAVE`, settings and messages, if there is a
+    /// snapshot to load.
+    pub fn restore_snapshot(&self) -> Result<(), Error> {
+        let path = &self.config.snapshot_path;
+        if self.config.in_memory || !path.exists() {
+            return Ok(());
+        }
+        self.load_snapshot(&fs::read(path)?)?;
+        Ok(())
+    }
+
+    /// Last step of the graceful shutdown path, run once the listeners have stopped.
+    pub fn finish_shutdown(&self, mode: Shutdown) -> Result<(), Error> {
+        if mode == Shutdown::Save && !self.config.in_memory {
+            let queues = self.queues.read().unwrap();
+            write_snapshot(&self.config.snapshot_path, &queues)?;
+        }
+        info!(?mode, "shutting down");
         Ok(())
     }
 }
 
 pub struct TcpServer {
-    redis_clients: Vec<TcpClient>,
+    state: Arc<ServerState>,
+    redis_clients: Vec<Session>,
 }
 
 impl TcpServer {
-    pub fn new() -> TcpServer {
+    pub fn new(config: ServerConfig) -> TcpServer {
         TcpServer {
+            state: Arc::new(ServerState::new(config)),
             redis_clients: Vec::with_capacity(DEFAULT_CLIENT_SIZE),
         }
     }
 
+    /// Runs the server to completion on the configured network backend.
+    pub fn run(&self) -> Result<(), Error> {
+        self.state.restore_snapshot()?;
+        if let Some(source) = &self.state.config.bootstrap_from {
+            let data = fetch_snapshot(source).map_err(Error::other)?;
+            let messages = self.state.load_snapshot(&data)?;
+            info!(primary = %source.address, messages, "bootstrapped from primary");
+        }
+        match self.state.config.backend {
+            NetworkBackend::Tokio => tokio::runtime::Runtime::new()?.block_on(self.start()),
+            #[cfg(feature = "io-uring")]
+            NetworkBackend::IoUring => crate::uring::start(self.state.clone()),
+            #[cfg(not(feature = "io-uring"))]
+            NetworkBackend::IoUring => Err(Error::new(
+                io::ErrorKind::Unsupported,
+                "built without the io-uring feature",
+            )),
+        }
+    }
+
     pub async fn start(&self) -> Result<(), Error> {
-        let listener = TcpListener::bind("127.0.0.1:6379").await?;
+        // Bind everything up front so a bad address fails startup instead of a single loop.
+        let listeners = Self::bind_listeners(&self.state.config.bind_addresses).await?;
+        self.serve(listeners).await
+    }
 
-        match listener.accept().await {
-            Ok((stream, _)) => {
-                self.handle_stream(stream).await?;
+    /// Serves already bound listeners until shutdown is requested.
+    pub async fn serve(&self, listeners: Vec<TcpListener>) -> Result<(), Error> {
+        let mut accept_loops = JoinSet::new();
+        for listener in listeners {
+            accept_loops.spawn(Self::accept_loop(listener, self.state.clone()));
+        }
+        let background = tokio::spawn(self.state.clone().run_background());
+
+        let mode = tokio::select! {
+            mode = self.state.wait_for_shutdown() => mode,
+            _ = tokio::signal::ctrl_c() => Shutdown::NoSave,
+            Some(result) = accept_loops.join_next() => {
+                result??;
+                Shutdown::NoSave
             }
-            Err(e) => println!("couldn't get client {:?}", e),
+        };
+        self.state.announce(ServerEvent::ShuttingDown);
+        accept_loops.abort_all();
+        background.abort();
+        self.state.finish_shutdown(mode)
+    }
+
+    async fn bind_listeners(addresses: &[String]) -> Result<Vec<TcpListener>, Error> {
+        let mut listeners = Vec::with_capacity(addresses.len());
+        for address in addresses {
+            listeners.push(TcpListener::bind(address).await?);
         }
+        Ok(listeners)
+    }
 
-        Ok(())
+    async fn accept_loop(listener: TcpListener, state: Arc<ServerState>) -> Result<(), Error> {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => {
+                    if let Err(e) = apply_socket_config(&stream, &state.config.socket) {
+                        warn!(error = %e, "couldn't configure client socket");
+                        continue;
+                    }
+                    let state = state.clone();
+                    tokio::spawn(async move {
+                        if let Err(e) = Self::handle_stream(stream, state).await {
+                            warn!(error = %e, "stream failed");
+                        }
+                    });
+                }
+                Err(e) => warn!(error = %e, "couldn't get client"),
+            }
+        }
+    }
+
+    async fn handle_stream(mut stream: TcpStream, state: Arc<ServerState>) -> Result<(), Error> {
+        let mut address = stream.peer_addr()?;
+        if state.config.proxy_protocol {
+            if let Some(source) = proxy_protocol::read_header(&mut stream).await? {
+                address = source;
+            }
+        }
+        let client = Session::new(address.to_string());
+        let span = client.span();
+        Self::serve_client(stream, client, state)
+            .instrument(span)
+            .await
     }
 
-    async fn handle_stream(&self, mut stream: TcpStream) -> Result<(), Error> {
-        let mut okay_sent = false;
-        let mut commands_to_process: VecDeque<Vec<Vec<u8>>> = VecDeque::new();
-        let mut prev_eol_found = false;
+    async fn serve_client(
+        mut stream: TcpStream,
+        mut client: Session,
+        state: Arc<ServerState>,
+    ) -> Result<(), Error> {
+        info!("client connected");
+        state.telemetry.connected(client.protocol());
+        let result = Self::read_loop(&mut stream, &mut client, &state).await;
+        state.connection_closed(&client.consumers());
+        state.telemetry.disconnected(client.protocol());
+        info!("stream ended");
+        result
+    }
+
+    async fn read_loop(
+        stream: &mut TcpStream,
+        client: &mut Session,
+        state: &ServerState,
+    ) -> Result<(), Error> {
+        let mut events = state.events.subscribe();
         loop {
-            let ready = stream.ready(Interest::READABLE).await?;
+            // Replies and pushes are written from this one task, so a push always lands
+            // between two whole replies.
+            let ready = tokio::select! {
+                ready = stream.ready(Interest::READABLE) => ready?,
+                event = events.recv() => {
+                    match event {
+                        Ok(event) => {
+                            if let Some(push) = client.event_push(&event) {
+                                stream.write_all(&push).await?;
+                            }
+                        }
+                        Err(RecvError::Lagged(missed)) => debug!(missed, "dropped server events"),
+                        Err(RecvError::Closed) => break,
+                    }
+                    continue;
+                }
+            };
             stream.writable().await?;
 
             if ready.is_readable() {
-                let mut data = [0; 4000];
-                match stream.try_read(&mut data) {
+                // Never zero here: anything queued has run unless a POP blocked, and a
+                // blocked POP is waited out below before reading again.
+                let budget = client.read_budget(&state.config.connection_limits);
+                match stream.try_read_buf(&mut client.read_buffer().limit(budget)) {
                     Ok(0) => break,
                     Ok(_) => {
-                        if !okay_sent {
-                            stream.write_all(OKAY_RESPONSE.as_bytes()).await?;
-                            okay_sent = true
-                        } else {
-                            stream.write_all("+OK\r\n".as_bytes()).await?;
+                        let replies = client.process_buffered(state);
+                        stream.write_all(&replies).await?;
+                        if client.is_blocked() {
+                            let replies = wait_unblocked(client, state).await;
+                            stream.write_all(&replies).await?;
+                        }
+                        if client.is_closing() {
+                            break;
                         }
                     }
                     Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
                         continue;
                     }
                     Err(e) => {
-                        return Err(e.into());
+                        return Err(e);
                     }
                 }
             }
         }
-        println!("stream ended");
         Ok(())
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::server::TcpClient;
+    use crate::bootstrap::fetch_snapshot;
+    use crate::commands::hello_reply;
+    use crate::config::{BootstrapSource, ServerConfig, SocketConfig};
+    use crate::events::ServerEvent;
+    use crate::proxy_protocol;
+    use crate::server::{apply_socket_config, wait_unblocked, ServerState, TcpServer};
+    use crate::session::Session;
+    use crate::snapshot::{decode_snapshot, SNAPSHOT_TRANSFERS};
     use crate::test_utils::*;
     use crate::utils::get_eol_index;
+    use chrono::Utc;
+    use socket2::SockRef;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
 
     #[test]
     fn test_find_next_cr() {
         let buff = &create_buffer();
-        let result = get_eol_index(1, &buff).unwrap();
+        let result = get_eol_index(1, buff).unwrap();
         let expected = 3;
         assert_eq!(result, expected);
     }
 
+    #[tokio::test]
+    async fn test_pushes_between_replies() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let state = Arc::new(ServerState::new(ServerConfig::dev()));
+        tokio::spawn(TcpServer::accept_loop(listener, state.clone()));
+        let mut consumer = TcpStream::connect(addr).await.unwrap();
+        let mut producer = TcpStream::connect(addr).await.unwrap();
+
+        consumer
+            .write_all(&frame(&["POP", "jobs", "NULL"]))
+            .await
+            .unwrap();
+        let mut reply = [0u8; 3];
+        consumer.read_exact(&mut reply).await.unwrap();
+        assert_eq!(&reply, b"_\r\n");
+
+        producer
+            .write_all(&frame(&["PUSH", "jobs", "hello"]))
+            .await
+            .unwrap();
+        let expected = ServerEvent::MessagesAvailable("jobs".to_string())
+            .push()
+            .encode();
+        let mut push = vec![0u8; expected.len()];
+        consumer.read_exact(&mut push).await.unwrap();
+        assert_eq!(push, expected);
+    }
+
+    #[tokio::test]
+    async fn test_blocking_pop() {
+        let state = ServerState::new(ServerConfig::dev());
+        let mut consumer = Session::new("0.0.0.0".to_string());
+        let mut producer = Session::new("0.0.0.0".to_string());
+
+        assert_eq!(
+            send(&mut consumer, &state, &["POP", "jobs", "BLOCK", "50"]),
+            ""
+        );
+        assert!(consumer.is_blocked());
+        // Queued behind the blocked POP, so it's answered after it.
+        assert_eq!(send(&mut consumer, &state, &["PUSH", "jobs", "late"]), "");
+        let replies = String::from_utf8(wait_unblocked(&mut consumer, &state).await).unwrap();
+        assert!(replies.starts_with("_\r\n$"));
+        assert!(!consumer.is_blocked());
+
+        let consumer_id = consumer.id;
+        assert_eq!(
+            send(&mut consumer, &state, &["POP", "other", "BLOCK", "0"]),
+            ""
+        );
+        let (replies, _) = tokio::join!(wait_unblocked(&mut consumer, &state), async {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            send(&mut producer, &state, &["PUSH", "other", "hello"])
+        });
+        let replies = String::from_utf8(replies).unwrap();
+        assert!(replies.starts_with("*1\r\n"));
+        let mut queues = state.queues.lock().unwrap();
+        assert_eq!(
+            queues
+                .get_mut("other")
+                .unwrap()
+                .pop_for(consumer_id, 10)
+                .len(),
+            0
+        );
+    }
+
+    #[tokio::test]
+    async fn test_sweep_redelivers_to_blocked_pop() {
+        let config = ServerConfig {
+            in_flight_expiration_ms: 30,
+            sweep_interval: Duration::from_millis(10),
+            ..ServerConfig::dev()
+        };
+        let state = Arc::new(ServerState::new(config));
+        let sweeper = tokio::spawn(state.clone().sweep_forever());
+        let mut slow = Session::new("0.0.0.0".to_string());
+        let mut waiting = Session::new("0.0.0.0".to_string());
+
+        send(&mut slow, &state, &["PUSH", "jobs", "hello"]);
+        assert!(send(&mut slow, &state, &["POP", "jobs"]).ends_with("$5\r\nhello\r\n"));
+        assert_eq!(
+            send(&mut waiting, &state, &["POP", "jobs", "BLOCK", "0"]),
+            ""
+        );
+        let replies = String::from_utf8(wait_unblocked(&mut waiting, &state).await).unwrap();
+        assert!(replies.ends_with("$5\r\nhello\r\n"));
+        sweeper.abort();
+    }
+
+    #[test]
+    fn test_sweep_announces_redeliveries() {
+        let config = ServerConfig {
+            in_flight_expiration_ms: 10,
+            ..ServerConfig::dev()
+        };
+        let state = ServerState::new(config);
+        let mut session = Session::new("0.0.0.0".to_string());
+        send(&mut session, &state, &["PUSH", "jobs", "hello"]);
+        send(&mut session, &state, &["PUSH", "idle", "hello"]);
+        send(&mut session, &state, &["POP", "jobs"]);
+        let mut events = state.events.subscribe();
+
+        state.sweep();
+        assert!(events.try_recv().is_err());
+        std::thread::sleep(Duration::from_millis(20));
+        state.sweep();
+        assert_eq!(
+            events.try_recv().unwrap(),
+            ServerEvent::MessagesAvailable("jobs".to_string())
+        );
+        assert!(events.try_recv().is_err());
+    }
+
     #[test]
-    fn test_client_buffer_process() {
-        let mut client = TcpClient::new("0.0.0.0".to_string());
-        let chunked_buffers = create_chunked_transmission();
-        for chunk in chunked_buffers.into_iter() {
-            let buff = convert_to_arr(&chunk);
-            client.read_buff(buff, chunk.len() - 1).unwrap();
-        }
-        let expected: u32 = 3;
-        assert_eq!(client.msg_from_client, expected);
+    fn test_run_schedules() {
+        let state = ServerState::new(ServerConfig::dev());
+        let mut session = Session::new("0.0.0.0".to_string());
+        let reply = send(
+            &mut session,
+            &state,
+            &["SCHEDULE", "ADD", "tick", "jobs", "* * * * *", "tick"],
+        );
+        assert!(reply.starts_with(':'));
+        assert!(send(&mut session, &state, &["SCHEDULE", "LIST"]).contains("+runs\r\n:0\r\n"));
+
+        state.run_schedules(Utc::now() + chrono::Duration::minutes(2));
+        assert!(send(&mut session, &state, &["POP", "jobs"]).ends_with("$4\r\ntick\r\n"));
+        assert!(send(&mut session, &state, &["SCHEDULE", "LIST"]).contains("+runs\r\n:1\r\n"));
+        assert_eq!(
+            send(&mut session, &state, &["SCHEDULE", "DEL", "tick"]),
+            ":1\r\n"
+        );
+        assert_eq!(
+            send(
+                &mut session,
+                &state,
+                &["SCHEDULE", "ADD", "never", "jobs", "0 0 30 2 *", "x"]
+            ),
+            "-ERR invalid arg for '0 0 30 2 *', which never runs\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_proxy_protocol_address() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        let (mut stream, _) = listener.accept().await.unwrap();
+
+        client
+            .write_all(b"PROXY TCP4 192.168.1.7 10.0.0.1 50000 6379\r\n*1\r\n")
+            .await
+            .unwrap();
+        let source = proxy_protocol::read_header(&mut stream).await.unwrap();
+        assert_eq!(source, Some("192.168.1.7:50000".parse().unwrap()));
+
+        let mut rest = [0u8; 4];
+        stream.read_exact(&mut rest).await.unwrap();
+        assert_eq!(&rest, b"*1\r\n");
+    }
+
+    #[tokio::test]
+    async fn test_apply_socket_config() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let _client = TcpStream::connect(addr).await.unwrap();
+        let (stream, _) = listener.accept().await.unwrap();
+
+        let config = SocketConfig {
+            nodelay: true,
+            keepalive: Some(Duration::from_secs(30)),
+            keepalive_interval: Some(Duration::from_secs(5)),
+            recv_buffer_size: Some(64 * 1024),
+            send_buffer_size: Some(64 * 1024),
+        };
+        apply_socket_config(&stream, &config).unwrap();
+
+        let socket = SockRef::from(&stream);
+        assert!(stream.nodelay().unwrap());
+        assert!(socket.keepalive().unwrap());
+        assert!(socket.recv_buffer_size().unwrap() >= 64 * 1024);
+    }
+
+    #[tokio::test]
+    async fn test_accept_on_every_listener() {
+        let addresses = vec!["127.0.0.1:0".to_string(), "127.0.0.1:0".to_string()];
+        let listeners = TcpServer::bind_listeners(&addresses).await.unwrap();
+        let state = Arc::new(ServerState::new(ServerConfig::default()));
+
+        let mut local_addrs = Vec::new();
+        for listener in listeners {
+            local_addrs.push(listener.local_addr().unwrap());
+            tokio::spawn(TcpServer::accept_loop(listener, state.clone()));
+        }
+
+        for addr in local_addrs {
+            let mut client = TcpStream::connect(addr).await.unwrap();
+            client.write_all(&frame(&["HELLO", "3"])).await.unwrap();
+            let mut reply = vec![0u8; hello_reply(&state, 3).encode().len()];
+            client.read_exact(&mut reply).await.unwrap();
+            assert_eq!(reply, hello_reply(&state, 3).encode());
+        }
+    }
+
+    #[test]
+    fn test_snapshot_chunks() {
+        let state = ServerState::new(ServerConfig::dev());
+        let mut client = Session::new("0.0.0.0".to_string());
+        send(&mut client, &state, &["PUSH", "jobs", "hello"]);
+
+        let (id, len) = state.begin_snapshot();
+        let mut data = Vec::new();
+        while data.len() < len {
+            data.extend_from_slice(&state.snapshot_chunk(&id, data.len(), 7).unwrap());
+        }
+        assert_eq!(decode_snapshot(&data).unwrap()["jobs"].messages.len(), 1);
+        assert!(state.snapshot_chunk(&id, len + 10, 7).unwrap().is_empty());
+
+        for _ in 0..SNAPSHOT_TRANSFERS {
+            state.begin_snapshot();
+        }
+        assert!(state.snapshot_chunk(&id, 0, 7).is_none());
+        let reply = send(
+            &mut client,
+            &state,
+            &["SERVER", "SNAPSHOTREAD", &id, "0", "7"],
+        );
+        assert!(reply.starts_with("-ERR "));
+    }
+
+    #[tokio::test]
+    async fn test_bootstrap_from_primary() {
+        let primary = Arc::new(ServerState::new(ServerConfig::dev()));
+        let mut client = Session::new("0.0.0.0".to_string());
+        send(&mut client, &primary, &["PUSH", "jobs", "h\u{e9}llo"]);
+        send(&mut client, &primary, &["PUSH", "mail", "hi"]);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let source = BootstrapSource::new(listener.local_addr().unwrap().to_string());
+        tokio::spawn(TcpServer::accept_loop(listener, primary));
+
+        let data = tokio::task::spawn_blocking(move || fetch_snapshot(&source))
+            .await
+            .unwrap()
+            .unwrap();
+        let replica = ServerState::new(ServerConfig::dev());
+        assert_eq!(replica.load_snapshot(&data).unwrap(), 2);
+        let reply = send(&mut client, &replica, &["POP", "jobs"]);
+        assert!(reply.ends_with("$6\r\nh\u{e9}llo\r\n"));
+    }
+
+    #[tokio::test]
+    async fn test_leases_requeued_on_disconnect() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let state = Arc::new(ServerState::new(ServerConfig::dev()));
+        tokio::spawn(TcpServer::accept_loop(listener, state.clone()));
+        send(
+            &mut Session::new("0.0.0.0".to_string()),
+            &state,
+            &["PUSH", "jobs", "hello"],
+        );
+
+        let mut consumer = TcpStream::connect(addr).await.unwrap();
+        consumer.write_all(&frame(&["POP", "jobs"])).await.unwrap();
+        let mut reply = Vec::new();
+        while !reply.ends_with(b"hello\r\n") {
+            let mut buf = [0u8; 512];
+            let n = consumer.read(&mut buf).await.unwrap();
+            reply.extend_from_slice(&buf[..n]);
+        }
+        assert_eq!(state.queues.lock().unwrap()["jobs"].in_flight_count(), 1);
+
+        drop(consumer);
+        for _ in 0..100 {
+            if state.queues.lock().unwrap()["jobs"].depth() == 1 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let queues = state.queues.lock().unwrap();
+        assert_eq!(
+            (queues["jobs"].depth(), queues["jobs"].in_flight_count()),
+            (1, 0)
+        );
+    }
+
+    #[test]
+    fn test_ephemeral_queues() {
+        let state = ServerState::new(ServerConfig::dev());
+        let mut owner = Session::new("0.0.0.0".to_string());
+        let mut other = Session::new("0.0.0.0".to_string());
+        let create = ["QUEUE", "CREATE", "replies", "EPHEMERAL"];
+        assert_eq!(send(&mut owner, &state, &create), "+OK\r\n");
+        send(
+            &mut owner,
+            &state,
+            &["CHANNEL", "1", "QUEUE", "CREATE", "rpc", "EPHEMERAL"],
+        );
+        send(&mut other, &state, &["PUSH", "replies", "answer"]);
+        send(&mut other, &state, &["QUEUE", "CREATE", "jobs"]);
+
+        state.connection_closed(&other.consumers());
+        assert_eq!(state.queues.lock().unwrap().len(), 3);
+        state.connection_closed(&owner.consumers());
+        let queues = state.queues.lock().unwrap();
+        assert_eq!(queues.keys().collect::<Vec<_>>(), ["jobs"]);
     }
 }
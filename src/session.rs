@@ -0,0 +1,1040 @@
+use crate::auth::Acl;
+use crate::commands::{execute_for, hello_reply, null_pop, push_blocks, try_pop};
+use crate::compression::{compress_bulk_strings, Compression};
+use crate::config::{ConnectionLimits, FrameLimits};
+use crate::constants::{DEFAULT_PROTOCOL, RESP_BUFFER_SIZE, SUPPORTED_PROTOCOLS};
+use crate::deprecation::Deprecation;
+use crate::events::ServerEvent;
+use crate::queue::{ConsumerId, Queue};
+use crate::resp::{parse_frame, Cmd, EmptyPop, RespError};
+use crate::resp_reader::RespReader;
+use crate::resp_value::RespValue;
+use crate::server::{SerializeError, ServerState};
+use crate::wait_line::Place;
+use bytes::{Bytes, BytesMut};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use tracing::{debug, info_span, warn, Span};
+
+static NEXT_CLIENT_ID: AtomicU64 = AtomicU64::new(1);
+
+/// One client's side of the protocol, without any I/O: bytes read from the client go in
+/// through `process` and the bytes to write back come out, with the replies in order.
+/// A POP or PUSH that blocks is left for the transport to wait out, see `resume`, so the same
+/// session runs over the tokio and io_uring listeners and anything else that can carry
+/// bytes, and can be driven a byte at a time in tests.
+#[derive(Debug)]
+pub(crate) struct Session {
+    pub(crate) id: ConsumerId,
+    name: String,
+    address: String,
+    version: String,
+    /// Permissions of the user that authenticated with HELLO, `None` until one has.
+    acl: Option<Acl>,
+    /// RESP version picked with HELLO; replies are encoded for it.
+    protocol: u8,
+    compression: Option<Compression>,
+    /// Consumer ids of the virtual channels opened with `CHANNEL <id> ...`.
+    channels: HashMap<u32, ConsumerId>,
+    /// Queues this connection has popped from, whose `MessagesAvailable` it is sent.
+    watched: HashSet<String>,
+    /// Set with `USE`, for queue commands sent with an empty queue.
+    default_queue: Option<String>,
+    /// Set with `PREFETCH`: most unacked messages each of the connection's consumers
+    /// may hold.
+    prefetch: Option<usize>,
+    /// Deprecations this connection has already been warned about.
+    warned: HashSet<&'static str>,
+    msg_from_client: u32,
+    msg_cnt_to_client: u32,
+    resp_buff_reader: RespReader,
+    /// Complete frames waiting to run, or the error that cut one short. Each is a view
+    /// into the buffer it was read into, so PUSH bodies are stored without a copy.
+    raw_msg_queue: VecDeque<Result<Bytes, SerializeError>>,
+    /// Bytes of the frames in `raw_msg_queue`.
+    queued_bytes: usize,
+    blocked: Option<Blocked>,
+    /// Set once a fatal protocol error has been answered; nothing else is read.
+    closing: bool,
+}
+
+/// A command still waiting to run. Commands sent after it stay queued until it is
+/// answered, so replies keep their order.
+#[derive(Debug)]
+enum Blocked {
+    Pop(BlockedPop),
+    /// A PUSH or MPUSH to a full queue with `FullPolicy::Block`, waiting for room.
+    Push {
+        cmd: Cmd,
+        consumer: ConsumerId,
+    },
+}
+
+/// A `POP ... BLOCK` still waiting for a push.
+#[derive(Debug)]
+struct BlockedPop {
+    queue: String,
+    count: usize,
+    consumer: ConsumerId,
+    visibility: Option<Duration>,
+    /// `None` waits forever.
+    deadline: Option<Instant>,
+    /// Only the longest waiting POP on a queue leases, see `WaitLines`.
+    place: Place,
+}
+
+impl Session {
+    pub fn new(address: String) -> Session {
+        Session {
+            id: NEXT_CLIENT_ID.fetch_add(1, Ordering::Relaxed),
+            name: "unknown".to_string(),
+            version: "unknown".to_string(),
+            address,
+            acl: None,
+            protocol: DEFAULT_PROTOCOL,
+            compression: None,
+            channels: HashMap::new(),
+            watched: HashSet::new(),
+            default_queue: None,
+            prefetch: None,
+            warned: HashSet::new(),
+            msg_from_client: 0,
+            msg_cnt_to_client: 0,
+            resp_buff_reader: RespReader::new(),
+            raw_msg_queue: VecDeque::new(),
+            queued_bytes: 0,
+            blocked: None,
+            closing: false,
+        }
+    }
+
+    /// Buffer the next read should go into, with room for at least `RESP_BUFFER_SIZE`
+    /// more bytes.
+    pub fn read_buffer(&mut self) -> &mut BytesMut {
+        let buffer = self.resp_buff_reader.buffer();
+        buffer.reserve(RESP_BUFFER_SIZE);
+        buffer
+    }
+
+    /// Queues every complete frame sitting in the read buffer.
+    pub fn read_frames(&mut self, limits: &FrameLimits) -> Result<(), SerializeError> {
+        self.read_frames_up_to(limits, usize::MAX)
+    }
+
+    /// Like `read_frames`, leaving frames in the buffer once `max_queued` are waiting.
+    fn read_frames_up_to(
+        &mut self,
+        limits: &FrameLimits,
+        max_queued: usize,
+    ) -> Result<(), SerializeError> {
+        while self.raw_msg_queue.len() < max_queued {
+            let Some(frame) = self.resp_buff_reader.next_frame(limits)? else {
+                break;
+            };
+            self.msg_from_client += 1;
+            self.queued_bytes += frame.len();
+            self.raw_msg_queue.push_back(Ok(frame));
+        }
+        Ok(())
+    }
+
+    /// How many more bytes may be read from the socket before the connection is over
+    /// `ConnectionLimits::max_buffered_bytes`. Zero means stop reading for now.
+    pub fn read_budget(&self, limits: &ConnectionLimits) -> usize {
+        let buffered = self.queued_bytes + self.resp_buff_reader.buffered();
+        limits.max_buffered_bytes.saturating_sub(buffered)
+    }
+
+    /// Span that every event for this connection is recorded under. `client` is filled
+    /// in once the client names itself with HELLO SETNAME.
+    pub fn span(&self) -> Span {
+        info_span!("conn", id = self.id, peer = %self.address, client = tracing::field::Empty)
+    }
+
+    /// Takes `data` read from the socket and returns the replies for every command it
+    /// completed, up to the first POP that blocks.
+    pub fn process(&mut self, state: &ServerState, data: &[u8]) -> Vec<u8> {
+        self.read_buffer().extend_from_slice(data);
+        self.process_buffered(state)
+    }
+
+    /// Like `process`, for data read straight into `read_buffer`. At most
+    /// `max_queued_commands` frames are split off at a time; the rest wait in the
+    /// buffer until those have run.
+    pub fn process_buffered(&mut self, state: &ServerState) -> Vec<u8> {
+        let max_queued = state.config.connection_limits.max_queued_commands;
+        let mut replies = Vec::new();
+        loop {
+            let queued = self.raw_msg_queue.len();
+            if let Err(e) = self.read_frames_up_to(&state.config.frame_limits, max_queued) {
+                warn!(error = %e, "couldn't read command");
+                self.resp_buff_reader.reset();
+                // Answered in turn, after the commands read before it.
+                self.raw_msg_queue.push_back(Err(e));
+            }
+            let read_more = self.raw_msg_queue.len() > queued;
+            replies.extend(self.run_queued(state));
+            if !read_more || self.blocked.is_some() || self.closing {
+                return replies;
+            }
+        }
+    }
+
+    pub fn protocol(&self) -> u8 {
+        self.protocol
+    }
+
+    /// The connection's own consumer id and those of its virtual channels.
+    pub fn consumers(&self) -> Vec<ConsumerId> {
+        let mut consumers = vec![self.id];
+        consumers.extend(self.channels.values());
+        consumers
+    }
+
+    /// The connection should be closed once the pending replies are written.
+    pub fn is_closing(&self) -> bool {
+        self.closing
+    }
+
+    /// The push frame to write for `event`, if this connection should hear about it.
+    pub fn event_push(&self, event: &ServerEvent) -> Option<Vec<u8>> {
+        if self.protocol < 3 {
+            return None;
+        }
+        match event {
+            ServerEvent::MessagesAvailable(queue) if !self.watched.contains(queue) => None,
+            ServerEvent::Cancelled { consumer, .. } if !self.consumers().contains(consumer) => None,
+            _ => Some(event.push().encode()),
+        }
+    }
+
+    pub fn is_blocked(&self) -> bool {
+        self.blocked.is_some()
+    }
+
+    /// When the blocked POP gives up, or `None` if it waits forever or nothing is
+    /// blocked. The transport calls `resume` by then at the latest.
+    pub fn block_deadline(&self) -> Option<Instant> {
+        match &self.blocked {
+            Some(Blocked::Pop(blocked)) => blocked.deadline,
+            _ => None,
+        }
+    }
+
+    /// Tries the blocked POP or PUSH again as of `now`. Once it gets through or times
+    /// out, its reply is followed by those of the commands queued behind it, which may
+    /// block again. Returns nothing while it stays blocked; the transport calls again
+    /// when the queue may have changed, see `ServerState::pushed`, or at `block_deadline`.
+    pub fn resume(&mut self, state: &ServerState, now: Instant) -> Vec<u8> {
+        let mut replies = Vec::new();
+        let blocked = match self.blocked.take() {
+            None => return replies,
+            Some(Blocked::Push { cmd, consumer }) => {
+                if push_blocks(&cmd, state) {
+                    self.blocked = Some(Blocked::Push { cmd, consumer });
+                    return replies;
+                }
+                let reply = execute_for(cmd, consumer, state, self.protocol);
+                self.write_reply(state, &reply, &mut replies);
+                replies.extend(self.process_buffered(state));
+                return replies;
+            }
+            Some(Blocked::Pop(blocked)) => blocked,
+        };
+        let timed_out = blocked.deadline.is_some_and(|deadline| now >= deadline);
+        let first = state.wait_lines.is_first(&blocked.queue, &blocked.place);
+        let leased = first
+            .then(|| {
+                try_pop(
+                    &blocked.queue,
+                    fair_share(&blocked.queue, blocked.count, state),
+                    blocked.consumer,
+                    blocked.visibility,
+                    state,
+                    self.protocol,
+                )
+            })
+            .flatten();
+        let reply = match leased {
+            Some(reply) => reply,
+            None if timed_out => null_pop(blocked.count, self.protocol).encode_for(self.protocol),
+            None => {
+                self.blocked = Some(Blocked::Pop(blocked));
+                return replies;
+            }
+        };
+        if first {
+            // Out of line now; whoever is next may find messages this one left.
+            drop(blocked);
+            state.pushed.notify_waiters();
+        }
+        self.write_reply(state, &reply, &mut replies);
+        replies.extend(self.process_buffered(state));
+        replies
+    }
+
+    /// Runs queued commands in order, stopping early if one of them blocks.
+    fn run_queued(&mut self, state: &ServerState) -> Vec<u8> {
+        let mut replies = Vec::new();
+        while self.blocked.is_none() {
+            let raw_cmd = match self.raw_msg_queue.pop_front() {
+                Some(Ok(raw_cmd)) => {
+                    self.queued_bytes -= raw_cmd.len();
+                    raw_cmd
+                }
+                Some(Err(e)) => {
+                    self.write_reply(state, &e.to_reply(), &mut replies);
+                    if e.is_fatal() {
+                        self.closing = true;
+                        self.raw_msg_queue.clear();
+                        self.queued_bytes = 0;
+                    }
+                    continue;
+                }
+                None => break,
+            };
+            let parsed = parse_frame(&raw_cmd)
+                .and_then(|cmd| cmd.with_default_queue(self.default_queue.as_deref()));
+            match &parsed {
+                Ok(cmd) => {
+                    debug!(?cmd, "executing command");
+                    state.telemetry.command(self.protocol, cmd.name());
+                    for deprecation in cmd.deprecations() {
+                        self.warn_deprecated(state, deprecation, &mut replies);
+                    }
+                }
+                Err(e) => debug!(error = %e, "couldn't parse command"),
+            }
+            let reply = match parsed {
+                Ok(Cmd::HELLO {
+                    auth,
+                    password,
+                    protocol_version,
+                    setname,
+                    compress,
+                }) => {
+                    let protocol = self.protocol;
+                    let reply =
+                        self.hello(state, protocol_version, auth, password, setname, compress);
+                    state.telemetry.switched(protocol, self.protocol);
+                    reply
+                }
+                Ok(_) if state.config.auth_required && self.acl.is_none() => {
+                    RespError::AuthRequired.to_reply()
+                }
+                Ok(cmd) if self.acl.as_ref().is_some_and(|acl| !acl.allows(&cmd)) => {
+                    RespError::NoPermission(cmd.name().to_string()).to_reply()
+                }
+                Ok(cmd) if !state.shedder.admits(cmd.priority()) => RespError::Busy.to_reply(),
+                Ok(Cmd::USE { queue }) => {
+                    debug!(queue = %queue, "default queue set");
+                    self.default_queue = Some(queue);
+                    RespValue::ok().encode_for(self.protocol)
+                }
+                Ok(Cmd::PREFETCH { count }) => {
+                    debug!(count, "prefetch set");
+                    self.prefetch = Some(count).filter(|count| *count > 0);
+                    RespValue::ok().encode_for(self.protocol)
+                }
+                Ok(Cmd::CHANNEL { channel, cmd }) => {
+                    let consumer = self.channel_consumer(channel);
+                    let Some(reply) = self.execute(*cmd, consumer, state) else {
+                        continue;
+                    };
+                    reply
+                }
+                Ok(cmd) => {
+                    let Some(reply) = self.execute(cmd, self.id, state) else {
+                        continue;
+                    };
+                    reply
+                }
+                Err(e) => e.to_reply(),
+            };
+            self.write_reply(state, &reply, &mut replies);
+        }
+        replies
+    }
+
+    /// Shrinks a POP to what `consumer` may still lease under `PREFETCH`, and fails it
+    /// once the consumer holds all it may. Failing rather than blocking means a consumer
+    /// can't wait on a POP that only its own acks, queued behind it, would let through.
+    fn within_prefetch(
+        &self,
+        mut cmd: Cmd,
+        consumer: ConsumerId,
+        state: &ServerState,
+    ) -> Result<Cmd, RespError> {
+        if let (Some(limit), Cmd::POP { count, .. }) = (self.prefetch, &mut cmd) {
+            let held = state.leased_by(consumer);
+            if held >= limit {
+                return Err(RespError::PrefetchReached(limit));
+            }
+            *count = (*count).min(limit - held);
+        }
+        Ok(cmd)
+    }
+
+    /// Runs `cmd` for `consumer`. `None` means it was a `POP ... BLOCK` that found the
+    /// queue empty, or a PUSH to a queue too full to take it, and now waits for `resume`.
+    fn execute(&mut self, cmd: Cmd, consumer: ConsumerId, state: &ServerState) -> Option<Vec<u8>> {
+        // Routed before deciding whether to block, so a blocked POP waits on one shard;
+        // `execute_for` leaves shard names as they are.
+        let cmd = state.queues.route(cmd);
+        let cmd = match self.within_prefetch(cmd, consumer, state) {
+            Ok(cmd) => cmd,
+            Err(e) => return Some(e.to_reply()),
+        };
+        match &cmd {
+            Cmd::POP { queue, .. } if !self.watched.contains(queue) => {
+                self.watched.insert(queue.clone());
+            }
+            Cmd::PUSH { queue, .. } | Cmd::MPUSH { queue, .. } if push_blocks(&cmd, state) => {
+                debug!(queue = %queue, "push blocked");
+                self.blocked = Some(Blocked::Push { cmd, consumer });
+                return None;
+            }
+            _ => {}
+        }
+        let Cmd::POP {
+            queue,
+            count,
+            on_empty: EmptyPop::Block(timeout),
+            visibility,
+        } = cmd
+        else {
+            return Some(execute_for(cmd, consumer, state, self.protocol));
+        };
+        // Consumers already waiting get the queue's messages first.
+        if !state.wait_lines.has_waiters(&queue) {
+            if let Some(reply) = try_pop(&queue, count, consumer, visibility, state, self.protocol)
+            {
+                return Some(reply);
+            }
+        }
+        debug!(queue = %queue, ?timeout, "pop blocked");
+        self.blocked = Some(Blocked::Pop(BlockedPop {
+            place: state.wait_lines.join(&queue),
+            queue,
+            count,
+            consumer,
+            visibility,
+            deadline: (!timeout.is_zero()).then(|| Instant::now() + timeout),
+        }));
+        None
+    }
+
+    /// Counts a use of `deprecation` and, the first time this connection makes one,
+    /// tells the client: a push frame ahead of the reply on RESP3, a log line on RESP2.
+    fn warn_deprecated(
+        &mut self,
+        state: &ServerState,
+        deprecation: &'static Deprecation,
+        replies: &mut Vec<u8>,
+    ) {
+        state.telemetry.deprecated(deprecation.name);
+        if !self.warned.insert(deprecation.name) {
+            return;
+        }
+        if self.protocol >= 3 {
+            replies.extend(deprecation.push().encode());
+        } else {
+            warn!(client = %self.name, address = %self.address, deprecated = deprecation.name, replacement = deprecation.replacement, "client used a deprecated command");
+        }
+    }
+
+    fn write_reply(&mut self, state: &ServerState, reply: &[u8], replies: &mut Vec<u8>) {
+        self.msg_cnt_to_client += 1;
+        match self.compression {
+            Some(compression) if reply.len() >= state.config.compression_threshold => {
+                let compressed =
+                    compress_bulk_strings(reply, state.config.compression_threshold, compression);
+                replies.extend_from_slice(&compressed);
+            }
+            _ => replies.extend_from_slice(reply),
+        }
+    }
+
+    /// Each channel leases messages under its own consumer id, so independent sessions
+    /// multiplexed over one connection never see each other's in-flight messages.
+    fn channel_consumer(&mut self, channel: u32) -> ConsumerId {
+        *self
+            .channels
+            .entry(channel)
+            .or_insert_with(|| NEXT_CLIENT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+
+    /// HELLO without credentials only completes the handshake; the connection stays
+    /// unauthenticated until a later HELLO carries valid ones. Nothing changes unless
+    /// the whole HELLO is accepted.
+    fn hello(
+        &mut self,
+        state: &ServerState,
+        protocol: u8,
+        auth: Option<String>,
+        password: Option<String>,
+        setname: Option<String>,
+        compress: Option<String>,
+    ) -> Vec<u8> {
+        if !SUPPORTED_PROTOCOLS.contains(&protocol) {
+            return RespError::ProtocolOutOfRange(protocol.to_string()).to_reply();
+        }
+        let compression = match compress {
+            // Compressed payloads are tagged with a RESP3 attribute.
+            Some(_) if protocol < 3 => {
+                return RespError::InvalidArgument("COMPRESS requires RESP3".to_string()).to_reply()
+            }
+            Some(algorithm) => match Compression::parse(&algorithm) {
+                Some(compression) => Some(compression),
+                None => return RespError::InvalidArgument(algorithm).to_reply(),
+            },
+            None => None,
+        };
+        if let Some(user) = auth {
+            let provider = state.config.auth.as_ref();
+            match password.and_then(|password| provider.verify(&user, &password)) {
+                Some(acl) => self.acl = Some(acl),
+                None => return RespError::InvalidPassword(user).to_reply(),
+            }
+        }
+        self.protocol = protocol;
+        self.compression = compression;
+        if let Some(name) = setname {
+            Span::current().record("client", name.as_str());
+            self.name = name;
+        }
+        hello_reply(state, protocol).encode_for(protocol)
+    }
+}
+
+/// How many of the `count` messages it asked for the POP at the front of `queue`'s line
+/// may lease: all of them when no one waits behind it, and otherwise an even share of
+/// what is waiting, so a consumer asking for many doesn't take a burst from the rest.
+fn fair_share(queue: &str, count: usize, state: &ServerState) -> usize {
+    let waiters = state.wait_lines.waiters(queue);
+    if waiters <= 1 {
+        return count;
+    }
+    let depth = state
+        .queues
+        .read()
+        .unwrap()
+        .get(queue)
+        .map_or(0, Queue::depth);
+    count.min(depth.div_ceil(waiters).max(1))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::auth::{Acl, AuthProvider};
+    use crate::commands::hello_reply;
+    use crate::config::{ConnectionLimits, FrameLimits, OverloadConfig, ServerConfig};
+    use crate::events::ServerEvent;
+    use crate::overload::Pressure;
+    use crate::resp::cancel_push;
+    use crate::server::ServerState;
+    use crate::session::Session;
+    use crate::test_utils::*;
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_client_buffer_process() {
+        let mut client = Session::new("0.0.0.0".to_string());
+        let chunked_buffers = create_chunked_transmission();
+        for chunk in chunked_buffers.into_iter() {
+            client.read_buffer().extend_from_slice(&chunk);
+            client.read_frames(&FrameLimits::default()).unwrap();
+        }
+        let expected: u32 = 3;
+        assert_eq!(client.msg_from_client, expected);
+    }
+
+    #[test]
+    fn test_auth_required() {
+        let state = ServerState::new(ServerConfig::default());
+        let mut client = Session::new("0.0.0.0".to_string());
+        let create = ["QUEUE", "CREATE", "jobs"];
+
+        assert_eq!(
+            send(&mut client, &state, &["HELLO", "3"]).as_bytes(),
+            hello_reply(&state, 3).encode()
+        );
+        assert!(send(&mut client, &state, &create).starts_with("-NOAUTH"));
+
+        let reply = send(
+            &mut client,
+            &state,
+            &["HELLO", "3", "AUTH", "admin", "nope"],
+        );
+        assert!(reply.starts_with("-WRONGPASS"));
+        assert!(send(&mut client, &state, &create).starts_with("-NOAUTH"));
+
+        let reply = send(
+            &mut client,
+            &state,
+            &["HELLO", "3", "AUTH", "admin", "password"],
+        );
+        assert_eq!(reply.as_bytes(), hello_reply(&state, 3).encode());
+        assert_eq!(send(&mut client, &state, &create), "+OK\r\n");
+    }
+
+    #[test]
+    fn test_auth_provider_acl() {
+        #[derive(Debug)]
+        struct OneQueue;
+        impl AuthProvider for OneQueue {
+            fn verify(&self, user: &str, password: &str) -> Option<Acl> {
+                (user == "etl" && password == "token").then(|| Acl {
+                    admin: false,
+                    queues: vec!["jobs".to_string()],
+                })
+            }
+        }
+        let state = ServerState::new(ServerConfig {
+            auth: Arc::new(OneQueue),
+            ..ServerConfig::dev()
+        });
+        let mut client = Session::new("0.0.0.0".to_string());
+
+        let reply = send(
+            &mut client,
+            &state,
+            &["HELLO", "3", "AUTH", "admin", "password"],
+        );
+        assert!(reply.starts_with("-WRONGPASS"));
+        let reply = send(&mut client, &state, &["HELLO", "3", "AUTH", "etl", "token"]);
+        assert_eq!(reply.as_bytes(), hello_reply(&state, 3).encode());
+
+        assert!(send(&mut client, &state, &["PUSH", "jobs", "x"]).starts_with("$"));
+        assert!(send(&mut client, &state, &["PUSH", "billing", "x"]).starts_with("-NOPERM"));
+        assert!(send(&mut client, &state, &["SERVER", "DRAIN"]).starts_with("-NOPERM"));
+        assert!(send(&mut client, &state, &["SHUTDOWN"]).starts_with("-NOPERM"));
+    }
+
+    #[test]
+    fn test_negotiated_compression() {
+        let state = ServerState::new(ServerConfig {
+            compression_threshold: 64,
+            ..ServerConfig::dev()
+        });
+        let mut client = Session::new("0.0.0.0".to_string());
+        let body = "x".repeat(512);
+        send(&mut client, &state, &["PUSH", "jobs", &body]);
+        send(&mut client, &state, &["PUSH", "jobs", &body]);
+
+        let reply = send(&mut client, &state, &["POP", "jobs"]);
+        assert!(reply.contains(&body));
+
+        let reply = send(&mut client, &state, &["HELLO", "3", "COMPRESS", "lz4"]);
+        assert_eq!(reply.as_bytes(), hello_reply(&state, 3).encode());
+        let bytes = frame(&["POP", "jobs"]);
+        let reply = client.process(&state, &bytes);
+        let marker = b"|1\r\n+compression\r\n+lz4\r\n";
+        assert!(reply.windows(marker.len()).any(|w| w == marker));
+        assert!(reply.len() < body.len());
+
+        let reply = send(&mut client, &state, &["HELLO", "3", "COMPRESS", "zip"]);
+        assert!(reply.starts_with("-ERR"));
+    }
+
+    #[test]
+    fn test_protocol_negotiation() {
+        let state = ServerState::new(ServerConfig::dev());
+        let mut client = Session::new("0.0.0.0".to_string());
+
+        let reply = send(&mut client, &state, &["HELLO", "2"]);
+        assert!(reply.starts_with("*14\r\n+server\r\n"));
+        assert!(reply.contains("+proto\r\n:2\r\n"));
+        assert!(send(&mut client, &state, &["HELLO", "2", "COMPRESS", "lz4"]).starts_with("-ERR"));
+
+        assert!(send(&mut client, &state, &["HELLO", "4"]).starts_with("-NOPROTO"));
+        assert!(send(&mut client, &state, &["HELLO", "x"]).starts_with("-NOPROTO"));
+        // A rejected HELLO leaves the connection on RESP2.
+        assert_eq!(client.protocol, 2);
+
+        let reply = send(&mut client, &state, &["HELLO", "3"]);
+        assert_eq!(reply.as_bytes(), hello_reply(&state, 3).encode());
+    }
+
+    #[test]
+    fn test_protocol_telemetry() {
+        let state = ServerState::new(ServerConfig::dev());
+        let mut legacy = Session::new("0.0.0.0".to_string());
+        state.telemetry.connected(legacy.protocol());
+        send(&mut legacy, &state, &["HELLO", "2", "PASSWORD", "password"]);
+        send(&mut legacy, &state, &["PUSH", "jobs", "a"]);
+
+        let reply = send(&mut legacy, &state, &["SERVER", "TELEMETRY"]);
+        assert!(reply.contains("+clients\r\n*4\r\n+resp2\r\n:1\r\n+resp3\r\n:0\r\n"));
+        // HELLO is counted under the protocol the connection was on when it arrived.
+        assert!(reply.contains("+resp2\r\n*4\r\n+PUSH\r\n:1\r\n+SERVER\r\n:1\r\n"));
+        assert!(reply.contains("+resp3\r\n*2\r\n+HELLO\r\n:1\r\n"));
+        assert!(reply.contains("+hello-password\r\n:1\r\n"));
+    }
+
+    #[test]
+    fn test_prefetch() {
+        let state = ServerState::new(ServerConfig::dev());
+        let mut client = Session::new("0.0.0.0".to_string());
+        for body in ["a", "b", "c", "d"] {
+            send(&mut client, &state, &["PUSH", "jobs", body]);
+        }
+        assert_eq!(send(&mut client, &state, &["PREFETCH", "2"]), "+OK\r\n");
+        assert!(send(&mut client, &state, &["POP", "jobs", "10"]).starts_with("*2\r\n"));
+        assert_eq!(
+            send(&mut client, &state, &["POP", "jobs"]),
+            "-PREFETCH consumer holds 2 unacked messages, its PREFETCH limit\r\n"
+        );
+        // channels are consumers of their own, each with the same limit
+        assert!(
+            send(&mut client, &state, &["CHANNEL", "1", "POP", "jobs", "10"]).starts_with("*2\r\n")
+        );
+
+        assert_eq!(send(&mut client, &state, &["PREFETCH", "0"]), "+OK\r\n");
+        send(&mut client, &state, &["PUSH", "jobs", "e"]);
+        assert!(send(&mut client, &state, &["POP", "jobs", "10"]).starts_with("*1\r\n"));
+    }
+
+    #[test]
+    fn test_default_queue() {
+        let state = ServerState::new(ServerConfig::dev());
+        let mut client = Session::new("0.0.0.0".to_string());
+        assert_eq!(
+            send(&mut client, &state, &["PUSH", "", "a"]),
+            "-ERR no queue given and none set with USE\r\n"
+        );
+        assert_eq!(send(&mut client, &state, &["USE", "jobs"]), "+OK\r\n");
+        send(&mut client, &state, &["PUSH", "", "a"]);
+        send(&mut client, &state, &["PUSH", "mail", "b"]);
+
+        let reply = send(&mut client, &state, &["POP", ""]);
+        assert!(reply.ends_with("$1\r\na\r\n"));
+        let id = reply.split("\r\n").nth(8).unwrap();
+        assert_eq!(send(&mut client, &state, &["ACK", "", id]), ":1\r\n");
+        assert!(send(&mut client, &state, &["CHANNEL", "1", "POP", "", "NULL"]).starts_with("_"));
+        assert!(send(&mut client, &state, &["POP", "mail"]).ends_with("$1\r\nb\r\n"));
+    }
+
+    #[test]
+    fn test_deprecation_warned_once() {
+        let state = ServerState::new(ServerConfig::dev());
+        let mut client = Session::new("0.0.0.0".to_string());
+        send(&mut client, &state, &["HELLO", "3"]);
+        let hello = ["HELLO", "3", "PASSWORD", "password"];
+        let reply = send(&mut client, &state, &hello);
+        assert!(reply.starts_with(">3\r\n+deprecated\r\n$14\r\nhello-password\r\n"));
+        assert!(reply.ends_with(std::str::from_utf8(&hello_reply(&state, 3).encode()).unwrap()));
+        assert!(!send(&mut client, &state, &hello).starts_with(">"));
+
+        let reply = send(&mut client, &state, &["SERVER", "TELEMETRY"]);
+        assert!(reply.contains("+hello-password\r\n:2\r\n"));
+    }
+
+    #[test]
+    fn test_channels_get_their_own_consumer() {
+        let state = ServerState::new(ServerConfig::dev());
+        let mut client = Session::new("0.0.0.0".to_string());
+        send(&mut client, &state, &["PUSH", "jobs", "a"]);
+        send(&mut client, &state, &["PUSH", "jobs", "b"]);
+
+        let reply = send(&mut client, &state, &["CHANNEL", "1", "POP", "jobs"]);
+        assert!(reply.ends_with("$1\r\na\r\n"));
+        let reply = send(&mut client, &state, &["CHANNEL", "2", "POP", "jobs"]);
+        assert!(reply.ends_with("$1\r\nb\r\n"));
+
+        let first = client.channel_consumer(1);
+        assert_eq!(client.channel_consumer(1), first);
+        assert_ne!(client.channel_consumer(2), first);
+        assert_ne!(first, client.id);
+    }
+
+    #[test]
+    fn test_event_push_filtering() {
+        let state = ServerState::new(ServerConfig::dev());
+        let available = |queue: &str| ServerEvent::MessagesAvailable(queue.to_string());
+        let mut legacy = Session::new("0.0.0.0".to_string());
+        send(&mut legacy, &state, &["HELLO", "2"]);
+        assert_eq!(legacy.event_push(&ServerEvent::ShuttingDown), None);
+
+        let mut client = Session::new("0.0.0.0".to_string());
+        assert_eq!(
+            client.event_push(&ServerEvent::Paused),
+            Some(ServerEvent::Paused.push().encode())
+        );
+        assert_eq!(client.event_push(&available("jobs")), None);
+        send(&mut client, &state, &["CHANNEL", "1", "POP", "jobs"]);
+        assert!(client.event_push(&available("jobs")).is_some());
+        assert_eq!(client.event_push(&available("other")), None);
+    }
+
+    #[test]
+    fn test_cancel_reaches_lease_holder() {
+        let state = ServerState::new(ServerConfig::dev());
+        let mut producer = Session::new("0.0.0.0".to_string());
+        let mut worker = Session::new("0.0.0.0".to_string());
+        let pushed = send(&mut producer, &state, &["PUSH", "jobs", "render"]);
+        let id = pushed.lines().nth(1).unwrap();
+        send(&mut worker, &state, &["CHANNEL", "1", "POP", "jobs"]);
+        let mut events = state.events.subscribe();
+
+        assert_eq!(
+            send(&mut producer, &state, &["CANCEL", "jobs", id]),
+            ":1\r\n"
+        );
+        let cancelled = events.try_recv().unwrap();
+        assert_eq!(
+            worker.event_push(&cancelled),
+            Some(cancel_push("jobs", id).encode())
+        );
+        assert_eq!(producer.event_push(&cancelled), None);
+        // the worker was told once already
+        assert_eq!(
+            send(&mut producer, &state, &["CANCEL", "jobs", id]),
+            ":0\r\n"
+        );
+        assert!(events.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_overload_sheds_low_priority() {
+        let config = ServerConfig {
+            overload: OverloadConfig {
+                max_memory_bytes: Some(1),
+                ..OverloadConfig::default()
+            },
+            ..ServerConfig::dev()
+        };
+        let state = ServerState::new(config);
+        let mut client = Session::new("0.0.0.0".to_string());
+        let pressure = Pressure {
+            memory_bytes: Some(2),
+            cpu_load: None,
+        };
+        state.shedder.update(&state.config.overload, &pressure);
+
+        let reply = send(&mut client, &state, &["SERVER", "TELEMETRY"]);
+        assert!(reply.starts_with("-BUSY"));
+        let reply = send(
+            &mut client,
+            &state,
+            &["CHANNEL", "1", "SERVER", "TELEMETRY"],
+        );
+        assert!(reply.starts_with("-BUSY"));
+        assert!(send(&mut client, &state, &["PUSH", "jobs", "hello"]).starts_with("$"));
+        assert!(send(&mut client, &state, &["POP", "jobs"]).starts_with("*1"));
+    }
+
+    #[test]
+    fn test_frame_limits_close_connection() {
+        let state = ServerState::new(ServerConfig::dev());
+        let mut client = Session::new("0.0.0.0".to_string());
+        let mut bytes = frame(&["PUSH", "jobs", "hello"]);
+        bytes.extend_from_slice(b"*99999999\r\n");
+        let replies = String::from_utf8(client.process(&state, &bytes)).unwrap();
+        assert!(replies.starts_with("$"));
+        assert!(replies
+            .ends_with("-ERR Protocol error: aggregate length exceeds the limit of 1048576\r\n"));
+        assert!(client.is_closing());
+    }
+
+    #[test]
+    fn test_connection_limits() {
+        let state = ServerState::new(ServerConfig {
+            connection_limits: ConnectionLimits {
+                max_queued_commands: 2,
+                ..ConnectionLimits::default()
+            },
+            ..ServerConfig::dev()
+        });
+        let mut consumer = Session::new("0.0.0.0".to_string());
+        let mut bytes = frame(&["POP", "jobs", "BLOCK", "0"]);
+        for _ in 0..5 {
+            bytes.extend(frame(&["PUSH", "other", "x"]));
+        }
+        assert_eq!(consumer.process(&state, &bytes), b"");
+        assert!(consumer.is_blocked());
+        // POP and one PUSH were split off and the POP ran; the rest wait in the buffer.
+        assert_eq!(consumer.raw_msg_queue.len(), 1);
+        let limits = state.config.connection_limits;
+        assert_eq!(
+            consumer.read_budget(&limits),
+            limits.max_buffered_bytes - 5 * frame(&["PUSH", "other", "x"]).len()
+        );
+
+        let mut producer = Session::new("0.0.0.0".to_string());
+        send(&mut producer, &state, &["PUSH", "jobs", "hello"]);
+        let replies = String::from_utf8(consumer.resume(&state, Instant::now())).unwrap();
+        assert_eq!(replies.matches("+receipt").count(), 1);
+        assert_eq!(replies.matches("\r\n$36\r\n").count(), 2 + 5);
+        assert!(consumer.raw_msg_queue.is_empty());
+        assert_eq!(consumer.read_budget(&limits), limits.max_buffered_bytes);
+    }
+
+    #[test]
+    fn test_block_times_out_without_io() {
+        let state = ServerState::new(ServerConfig::dev());
+        let mut consumer = Session::new("0.0.0.0".to_string());
+        assert_eq!(
+            send(&mut consumer, &state, &["POP", "jobs", "BLOCK", "50"]),
+            ""
+        );
+        let deadline = consumer.block_deadline().unwrap();
+
+        // Before the deadline nothing happens; after it the POP gives up.
+        assert_eq!(consumer.resume(&state, Instant::now()), b"");
+        assert!(consumer.is_blocked());
+        let later = deadline + Duration::from_millis(10);
+        assert_eq!(consumer.resume(&state, later), b"_\r\n");
+        assert!(!consumer.is_blocked());
+        assert_eq!(consumer.block_deadline(), None);
+    }
+
+    #[test]
+    fn test_blocked_pops_served_in_order() {
+        let state = ServerState::new(ServerConfig::dev());
+        let mut first = Session::new("0.0.0.0".to_string());
+        let mut second = Session::new("0.0.0.0".to_string());
+        assert_eq!(send(&mut first, &state, &["POP", "jobs", "BLOCK", "0"]), "");
+        assert_eq!(
+            send(&mut second, &state, &["POP", "jobs", "BLOCK", "0"]),
+            ""
+        );
+
+        // The second is woken first but has to let the first go ahead.
+        let mut producer = Session::new("0.0.0.0".to_string());
+        send(&mut producer, &state, &["PUSH", "jobs", "one"]);
+        assert_eq!(second.resume(&state, Instant::now()), b"");
+        let replies = String::from_utf8(first.resume(&state, Instant::now())).unwrap();
+        assert!(replies.ends_with("$3\r\none\r\n"));
+
+        // A newcomer queues behind the one still waiting.
+        let mut third = Session::new("0.0.0.0".to_string());
+        assert_eq!(send(&mut third, &state, &["POP", "jobs", "BLOCK", "0"]), "");
+        send(&mut producer, &state, &["PUSH", "jobs", "two"]);
+        assert_eq!(third.resume(&state, Instant::now()), b"");
+        let replies = String::from_utf8(second.resume(&state, Instant::now())).unwrap();
+        assert!(replies.ends_with("$3\r\ntwo\r\n"));
+        assert!(third.is_blocked());
+    }
+
+    #[test]
+    fn test_blocked_pops_share_a_burst() {
+        let state = ServerState::new(ServerConfig::dev());
+        let mut consumers: Vec<Session> = (0..3)
+            .map(|_| Session::new("0.0.0.0".to_string()))
+            .collect();
+        for consumer in consumers.iter_mut() {
+            let pop = ["POP", "jobs", "10", "BLOCK", "0"];
+            assert_eq!(send(consumer, &state, &pop), "");
+        }
+
+        let mut producer = Session::new("0.0.0.0".to_string());
+        send(
+            &mut producer,
+            &state,
+            &["MPUSH", "jobs", "1", "2", "3", "4", "5", "6"],
+        );
+        for consumer in consumers.iter_mut() {
+            let replies = String::from_utf8(consumer.resume(&state, Instant::now())).unwrap();
+            assert!(replies.starts_with("*2\r\n"), "{:?}", replies);
+        }
+    }
+
+    #[test]
+    fn test_push_blocks_until_room() {
+        let state = ServerState::new(ServerConfig::dev());
+        let mut producer = Session::new("0.0.0.0".to_string());
+        let create = [
+            "QUEUE", "CREATE", "jobs", "MAXDEPTH", "1", "ONFULL", "BLOCK",
+        ];
+        assert_eq!(send(&mut producer, &state, &create), "+OK\r\n");
+        send(&mut producer, &state, &["PUSH", "jobs", "first"]);
+        assert_eq!(send(&mut producer, &state, &["PUSH", "jobs", "second"]), "");
+        assert!(producer.is_blocked());
+        assert_eq!(producer.block_deadline(), None);
+        // Queued behind the blocked PUSH.
+        assert_eq!(send(&mut producer, &state, &["QUEUE", "LIST"]), "");
+        assert_eq!(producer.resume(&state, Instant::now()), b"");
+
+        let mut consumer = Session::new("0.0.0.0".to_string());
+        assert!(send(&mut consumer, &state, &["POP", "jobs"]).ends_with("$5\r\nfirst\r\n"));
+        let replies = String::from_utf8(producer.resume(&state, Instant::now())).unwrap();
+        // Past the soft limit, so the id comes after a warning.
+        assert!(replies.contains("\r\n$36\r\n"));
+        assert!(replies.ends_with("*1\r\n$4\r\njobs\r\n"));
+        assert!(!producer.is_blocked());
+
+        // A batch waits until there is room for all of it, unless it never could fit.
+        let batch = ["MPUSH", "jobs", "PRIORITY", "3", "BODIES", "a", "b"];
+        assert!(send(&mut producer, &state, &batch).starts_with("-QUEUEFULL"));
+        let create = [
+            "QUEUE", "CREATE", "batches", "MAXDEPTH", "2", "ONFULL", "BLOCK",
+        ];
+        send(&mut producer, &state, &create);
+        send(&mut producer, &state, &["PUSH", "batches", "first"]);
+        let batch = ["MPUSH", "batches", "a", "b"];
+        assert_eq!(send(&mut producer, &state, &batch), "");
+        assert!(producer.is_blocked());
+        send(&mut consumer, &state, &["POP", "batches"]);
+        let replies = String::from_utf8(producer.resume(&state, Instant::now())).unwrap();
+        assert!(replies.contains("*2\r\n$36\r\n"));
+        assert_eq!(state.queues.lock().unwrap()["batches"].depth(), 2);
+    }
+
+    #[test]
+    fn test_byte_at_a_time() {
+        let mut pipeline = frame(&["USE", "jobs"]);
+        pipeline.extend(frame(&["BOGUS"]));
+        pipeline.extend(frame(&["QUEUE", "LIST"]));
+        let state = ServerState::new(ServerConfig::dev());
+        let expected = Session::new("0.0.0.0".to_string()).process(&state, &pipeline);
+        assert!(expected.ends_with(b"*0\r\n"));
+
+        let mut client = Session::new("0.0.0.0".to_string());
+        let mut replies = Vec::new();
+        for byte in &pipeline {
+            replies.extend(client.process(&state, std::slice::from_ref(byte)));
+        }
+        assert_eq!(replies, expected);
+    }
+
+    #[test]
+    fn test_error_replies() {
+        let state = ServerState::new(ServerConfig::dev());
+        let mut client = Session::new("0.0.0.0".to_string());
+        let reply = send(&mut client, &state, &["BOGUS"]);
+        assert_eq!(reply, "-ERR invalid cmd for BOGUS\r\n");
+        let reply = send(&mut client, &state, &["POP", "jobs", "x"]);
+        assert!(reply.starts_with("-ERR"));
+
+        let bytes = b"*1\r\n$4\r\nPUSH\r\n?oops\r\n";
+        let replies = client.process(&state, bytes);
+        let replies = String::from_utf8(replies).unwrap();
+        assert!(replies.starts_with("-ERR"));
+        assert!(replies.ends_with("-ERR Protocol error: Unsupported RESP type\r\n"));
+
+        // The connection is still usable afterwards, and command names are case insensitive.
+        assert!(send(&mut client, &state, &["push", "jobs", "hello"]).starts_with('$'));
+    }
+
+    #[test]
+    fn test_binary_body() {
+        let state = ServerState::new(ServerConfig::dev());
+        let mut client = Session::new("0.0.0.0".to_string());
+        let push = b"*3\r\n$4\r\nPUSH\r\n$4\r\njobs\r\n$5\r\n\r\n\xff\r\n\r\n";
+        client.process(&state, push);
+        let pop = frame(&["POP", "jobs"]);
+        let reply = client.process(&state, &pop);
+        assert!(reply.ends_with(b"$5\r\n\r\n\xff\r\n\r\n"));
+    }
+
+    #[test]
+    fn test_dev_config_skips_auth() {
+        let state = ServerState::new(ServerConfig::dev());
+        let mut client = Session::new("0.0.0.0".to_string());
+        let reply = send(&mut client, &state, &["PUSH", "jobs", "hello"]);
+        assert!(reply.starts_with('$'));
+    }
+}
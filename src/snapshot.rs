@@ -0,0 +1,158 @@
+use crate::queue::{FullPolicy, Message, Queue, QueueOrder};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// Most bytes of a snapshot handed out by one `SERVER SNAPSHOTREAD`.
+pub const SNAPSHOT_CHUNK_BYTES: usize = 1024 * 1024;
+/// Snapshots kept for replicas to fetch; starting another transfer drops the oldest.
+pub const SNAPSHOT_TRANSFERS: usize = 4;
+
+/// The settings of a queue kept in a snapshot, so it is loaded configured the way it
+/// was saved, whether by `QUEUE CREATE` or by `QUEUE CONFIG SET` since.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct QueueConfig {
+    order: QueueOrder,
+    visibility_ms: i64,
+    max_attempts: u8,
+    concurrency: Option<usize>,
+    max_depth: Option<usize>,
+    on_full: FullPolicy,
+    overflow: usize,
+    max_size: Option<usize>,
+    rate: Option<usize>,
+    dead_letter_queue: Option<String>,
+    retention_ms: Option<i64>,
+    dedup_window_ms: i64,
+    dedup_by_content: bool,
+    exclusive: bool,
+}
+
+impl QueueConfig {
+    pub fn of(q: &Queue) -> QueueConfig {
+        QueueConfig {
+            order: q.order(),
+            visibility_ms: q.in_flight_expiration_ms(),
+            max_attempts: q.max_attempts(),
+            concurrency: q.max_in_flight(),
+            max_depth: q.max_depth(),
+            on_full: q.full_policy(),
+            overflow: q.overflow_limit(),
+            max_size: q.max_message_bytes(),
+            rate: q.rate_limit(),
+            dead_letter_queue: q.dead_letter_queue().map(str::to_string),
+            retention_ms: q.retention_ms(),
+            dedup_window_ms: q.dedup_window_ms(),
+            dedup_by_content: q.dedups_by_content(),
+            exclusive: q.exclusive(),
+        }
+    }
+
+    pub fn apply_to(self, q: &mut Queue) {
+        q.set_order(self.order);
+        q.set_in_flight_expiration_ms(self.visibility_ms);
+        q.set_max_attempts(self.max_attempts);
+        q.set_max_in_flight(self.concurrency);
+        q.set_max_depth(self.max_depth);
+        q.set_full_policy(self.on_full);
+        q.set_overflow_limit(self.overflow);
+        q.set_max_message_bytes(self.max_size);
+        q.set_rate_limit(self.rate);
+        q.set_dead_letter_queue(self.dead_letter_queue);
+        q.set_retention_ms(self.retention_ms);
+        q.set_dedup_window(self.dedup_window_ms);
+        q.set_dedup_by_content(self.dedup_by_content);
+        q.set_exclusive(self.exclusive);
+    }
+}
+
+/// One queue of a snapshot: its settings and every message that would otherwise be
+/// lost, in the order `Queue::snapshot` lists them.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SavedQueue<M> {
+    pub config: QueueConfig,
+    pub messages: Vec<M>,
+}
+
+/// The settings and contents of every queue as JSON, keyed by queue name. Ephemeral
+/// queues are left out, as their connection is gone by the time the snapshot is loaded.
+pub fn encode_snapshot(queues: &HashMap<String, Queue>) -> serde_json::Result<Vec<u8>> {
+    let snapshot: HashMap<&String, SavedQueue<&Message>> = queues
+        .iter()
+        .filter(|(_, queue)| queue.owner().is_none())
+        .map(|(name, queue)| {
+            let saved = SavedQueue {
+                config: QueueConfig::of(queue),
+                messages: queue.snapshot(),
+            };
+            (name, saved)
+        })
+        .collect();
+    serde_json::to_vec(&snapshot)
+}
+
+/// Reads back what `encode_snapshot` wrote.
+pub fn decode_snapshot(data: &[u8]) -> serde_json::Result<HashMap<String, SavedQueue<Message>>> {
+    serde_json::from_slice(data)
+}
+
+/// Writes the settings and contents of every queue, as `encode_snapshot` lays them out.
+///
+/// The snapshot is written next to `path` first and renamed into place so a crash
+/// mid-write never leaves a truncated file behind.
+pub fn write_snapshot(path: &Path, queues: &HashMap<String, Queue>) -> io::Result<()> {
+    let tmp_path = path.with_extension("tmp");
+    fs::write(&tmp_path, encode_snapshot(queues)?)?;
+    fs::rename(tmp_path, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::queue::{FullPolicy, Message, Queue, QueueOrder};
+    use crate::snapshot::{decode_snapshot, encode_snapshot, write_snapshot, QueueConfig};
+    use std::collections::HashMap;
+    use std::fs;
+
+    #[test]
+    fn test_write_snapshot() {
+        let mut q = Queue::create("jobs".to_string());
+        q.add(Message::new("jobs".to_string(), "hello".to_string()));
+        let queues = HashMap::from([("jobs".to_string(), q)]);
+
+        let path = std::env::temp_dir().join("infinity_q_test_write_snapshot.json");
+        write_snapshot(&path, &queues).unwrap();
+
+        let json: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(json["jobs"]["messages"][0]["messageBody"], "hello");
+        assert_eq!(json["jobs"]["config"]["maxAttempts"], 3);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_decode_snapshot() {
+        let mut q = Queue::create("jobs".to_string());
+        q.set_order(QueueOrder::Priority);
+        q.set_full_policy(FullPolicy::DropOldest);
+        q.set_dead_letter_queue(Some("failed".to_string()));
+        q.set_retention_ms(Some(60_000));
+        q.add(Message::new("jobs".to_string(), "low".to_string()));
+        q.add(Message::new("jobs".to_string(), "high".to_string()).with_priority(5));
+        let mut ephemeral = Queue::create("replies".to_string());
+        ephemeral.set_owner(Some(1));
+        let queues = HashMap::from([("jobs".to_string(), q), ("replies".to_string(), ephemeral)]);
+
+        let mut loaded = decode_snapshot(&encode_snapshot(&queues).unwrap()).unwrap();
+
+        assert!(!loaded.contains_key("replies"));
+        let saved = loaded.remove("jobs").unwrap();
+        assert_eq!(saved.config, QueueConfig::of(&queues["jobs"]));
+        let bodies: Vec<&[u8]> = saved.messages.iter().map(Message::body).collect();
+        assert_eq!(bodies, [&b"high"[..], b"low"]);
+        assert!(saved.messages[0].expires_at().is_some());
+        assert!(decode_snapshot(b"{\"jobs\": [").is_err());
+    }
+}
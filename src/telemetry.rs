@@ -0,0 +1,122 @@
+use crate::resp_value::RespValue;
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+/// Who still speaks RESP2, what they run and which deprecated paths they hit, so an
+/// operator can tell when legacy support is safe to turn off.
+#[derive(Debug, Default)]
+pub struct Telemetry {
+    counts: Mutex<Counts>,
+}
+
+#[derive(Debug, Default)]
+struct Counts {
+    /// Open connections by negotiated protocol.
+    clients: BTreeMap<u8, u64>,
+    commands: BTreeMap<u8, BTreeMap<&'static str, u64>>,
+    deprecated: BTreeMap<&'static str, u64>,
+}
+
+impl Telemetry {
+    pub fn connected(&self, protocol: u8) {
+        *self
+            .counts
+            .lock()
+            .unwrap()
+            .clients
+            .entry(protocol)
+            .or_default() += 1;
+    }
+
+    pub fn disconnected(&self, protocol: u8) {
+        if let Some(count) = self.counts.lock().unwrap().clients.get_mut(&protocol) {
+            *count = count.saturating_sub(1);
+        }
+    }
+
+    /// A connection renegotiated its protocol with HELLO.
+    pub fn switched(&self, from: u8, to: u8) {
+        if from != to {
+            self.disconnected(from);
+            self.connected(to);
+        }
+    }
+
+    pub fn command(&self, protocol: u8, name: &'static str) {
+        let mut counts = self.counts.lock().unwrap();
+        *counts
+            .commands
+            .entry(protocol)
+            .or_default()
+            .entry(name)
+            .or_default() += 1;
+    }
+
+    pub fn deprecated(&self, path: &'static str) {
+        *self
+            .counts
+            .lock()
+            .unwrap()
+            .deprecated
+            .entry(path)
+            .or_default() += 1;
+    }
+
+    /// `{clients: {resp2, resp3}, commands: {resp2: {...}, resp3: {...}}, deprecated: {...}}`
+    pub fn reply(&self) -> RespValue {
+        let counts = self.counts.lock().unwrap();
+        let protocol = |version: &u8| format!("resp{}", version);
+        let named = |entries: &BTreeMap<&'static str, u64>| {
+            RespValue::map()
+                .fields(entries.iter().map(|(name, count)| (name, *count)))
+                .build()
+        };
+        RespValue::map()
+            .field(
+                "clients",
+                RespValue::map().fields(
+                    counts
+                        .clients
+                        .iter()
+                        .map(|(version, count)| (protocol(version), *count)),
+                ),
+            )
+            .field(
+                "commands",
+                RespValue::map().fields(
+                    counts
+                        .commands
+                        .iter()
+                        .map(|(version, commands)| (protocol(version), named(commands))),
+                ),
+            )
+            .field("deprecated", named(&counts.deprecated))
+            .build()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::deprecation::HELLO_PASSWORD;
+    use crate::telemetry::*;
+
+    #[test]
+    fn test_protocol_counts() {
+        let telemetry = Telemetry::default();
+        telemetry.connected(3);
+        telemetry.connected(3);
+        telemetry.switched(3, 2);
+        telemetry.command(2, "PUSH");
+        telemetry.command(3, "POP");
+        telemetry.deprecated(HELLO_PASSWORD.name);
+        telemetry.disconnected(3);
+
+        assert_eq!(
+            telemetry.reply().encode(),
+            b"%3\r\n\
+              +clients\r\n%2\r\n+resp2\r\n:1\r\n+resp3\r\n:0\r\n\
+              +commands\r\n%2\r\n+resp2\r\n%1\r\n+PUSH\r\n:1\r\n+resp3\r\n%1\r\n+POP\r\n:1\r\n\
+              +deprecated\r\n%1\r\n+hello-password\r\n:1\r\n"
+        );
+    }
+}
@@ -1,6 +1,9 @@
 use crate::constants::RESP_BUFFER_SIZE;
+use crate::jobs::{JobId, JobState, Jobs};
+use crate::server::ServerState;
+use crate::session::Session;
 
-pub fn convert_to_arr(v: &Vec<u8>) -> [u8; RESP_BUFFER_SIZE] {
+pub fn convert_to_arr(v: &[u8]) -> [u8; RESP_BUFFER_SIZE] {
     let mut arr = [0u8; RESP_BUFFER_SIZE];
     for (i, &item) in v.iter().enumerate().take(RESP_BUFFER_SIZE) {
         arr[i] = item;
@@ -8,6 +11,31 @@ pub fn convert_to_arr(v: &Vec<u8>) -> [u8; RESP_BUFFER_SIZE] {
     arr
 }
 
+/// Encodes `args` as a RESP array of bulk strings, the way clients send commands.
+pub fn frame(args: &[&str]) -> Vec<u8> {
+    let mut frame = format!("*{}\r\n", args.len());
+    for arg in args {
+        frame.push_str(&format!("${}\r\n{}\r\n", arg.len(), arg));
+    }
+    frame.into_bytes()
+}
+
+/// Sends `args` as one command and returns the replies it produced.
+pub fn send(session: &mut Session, state: &ServerState, args: &[&str]) -> String {
+    String::from_utf8(session.process(state, &frame(args))).unwrap()
+}
+
+/// Polls until the job is no longer running.
+pub fn wait_for_job(jobs: &Jobs, id: JobId) -> JobState {
+    loop {
+        let state = jobs.get(id).unwrap().state();
+        if state != JobState::Running {
+            return state;
+        }
+        std::thread::sleep(std::time::Duration::from_millis(5));
+    }
+}
+
 pub fn create_buffer() -> Vec<u8> {
     //    Corresponds to ASCII code
     //   *   5   \r  \n  $   5   \r  \n   h    e    l    l    o  \r  \n
@@ -0,0 +1,115 @@
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+/// Traces one in every `every` of the commands it is set for.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Sampler {
+    every: u64,
+    seen: u64,
+}
+
+impl Sampler {
+    fn sample(&mut self) -> bool {
+        self.seen += 1;
+        self.seen.is_multiple_of(self.every)
+    }
+}
+
+#[derive(Debug, Default)]
+struct Samplers {
+    commands: BTreeMap<String, Sampler>,
+    queues: BTreeMap<String, Sampler>,
+}
+
+/// Which commands get an `info` level trace of what they did, set at run time with
+/// `DEBUG TRACE` so delivery on one troublesome queue can be followed in production
+/// while everything else stays at the configured log level.
+#[derive(Debug, Default)]
+pub struct TraceSampling {
+    samplers: Mutex<Samplers>,
+}
+
+/// What a `DEBUG TRACE` rate applies to.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TraceScope {
+    /// Command names, matched case insensitively.
+    Command,
+    Queue,
+}
+
+impl TraceSampling {
+    /// Traces one in every `every` of the commands or commands on queues named `name`;
+    /// 0 stops tracing them.
+    pub fn set(&self, scope: TraceScope, name: &str, every: u64) {
+        let mut samplers = self.samplers.lock().unwrap();
+        let (map, key) = match scope {
+            TraceScope::Command => (&mut samplers.commands, name.to_uppercase()),
+            TraceScope::Queue => (&mut samplers.queues, name.to_string()),
+        };
+        if every == 0 {
+            map.remove(&key);
+        } else {
+            map.insert(key, Sampler { every, seen: 0 });
+        }
+    }
+
+    /// Whether to trace this run of `command`. A command on a queue counts towards both
+    /// its command's rate and its queue's, and is traced if either picks it.
+    pub fn sample(&self, command: &str, queue: Option<&str>) -> bool {
+        let mut samplers = self.samplers.lock().unwrap();
+        if samplers.commands.is_empty() && samplers.queues.is_empty() {
+            return false;
+        }
+        let by_command = samplers
+            .commands
+            .get_mut(command)
+            .is_some_and(Sampler::sample);
+        let by_queue = queue
+            .and_then(|queue| samplers.queues.get_mut(queue))
+            .is_some_and(Sampler::sample);
+        by_command || by_queue
+    }
+
+    /// Rates in effect as `(scope, name, every)`, commands first.
+    pub fn rates(&self) -> Vec<(TraceScope, String, u64)> {
+        let samplers = self.samplers.lock().unwrap();
+        let commands = samplers
+            .commands
+            .iter()
+            .map(|(name, sampler)| (TraceScope::Command, name.clone(), sampler.every));
+        let queues = samplers
+            .queues
+            .iter()
+            .map(|(name, sampler)| (TraceScope::Queue, name.clone(), sampler.every));
+        commands.chain(queues).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::trace_sampling::*;
+
+    #[test]
+    fn test_trace_sampling() {
+        let tracing = TraceSampling::default();
+        assert!(!tracing.sample("PUSH", Some("jobs")));
+
+        tracing.set(TraceScope::Queue, "jobs", 1);
+        tracing.set(TraceScope::Command, "ack", 3);
+        assert!(tracing.sample("PUSH", Some("jobs")));
+        assert!(!tracing.sample("PUSH", Some("emails")));
+        let acks: Vec<bool> = (0..6).map(|_| tracing.sample("ACK", None)).collect();
+        assert_eq!(acks, [false, false, true, false, false, true]);
+        assert_eq!(
+            tracing.rates(),
+            [
+                (TraceScope::Command, "ACK".to_string(), 3),
+                (TraceScope::Queue, "jobs".to_string(), 1)
+            ]
+        );
+
+        tracing.set(TraceScope::Queue, "jobs", 0);
+        assert!(!tracing.sample("PUSH", Some("jobs")));
+        assert_eq!(tracing.rates().len(), 1);
+    }
+}
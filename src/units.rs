@@ -0,0 +1,137 @@
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// A duration as given in a flag or command argument: milliseconds, or a whole number
+/// with one of the units `ms`, `s`, `m`, `h` or `d`, e.g. `500ms`, `30s` or `2m`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HumanDuration(pub Duration);
+
+/// A size as given in a flag: bytes, or a whole number with one of the units `b`, `kb`,
+/// `mb` or `gb`, each 1024 times the one before, e.g. `64kb`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ByteSize(pub usize);
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct UnitError {
+    value: String,
+    expected: &'static str,
+}
+
+impl fmt::Display for UnitError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "'{}' is not {}", self.value, self.expected)
+    }
+}
+
+const DURATION_UNITS: &[(&str, u64)] = &[
+    ("", 1),
+    ("ms", 1),
+    ("s", 1_000),
+    ("m", 60_000),
+    ("h", 3_600_000),
+    ("d", 86_400_000),
+];
+
+const SIZE_UNITS: &[(&str, u64)] = &[
+    ("", 1),
+    ("b", 1),
+    ("kb", 1 << 10),
+    ("mb", 1 << 20),
+    ("gb", 1 << 30),
+];
+
+/// `value` split into its number and unit, scaled by the unit's factor. Units are case
+/// insensitive; anything else, a missing number and overflow are errors.
+fn parse_scaled(
+    value: &str,
+    units: &[(&str, u64)],
+    expected: &'static str,
+) -> Result<u64, UnitError> {
+    let error = || UnitError {
+        value: value.to_string(),
+        expected,
+    };
+    let split = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+    let number: u64 = number.parse().map_err(|_| error())?;
+    let factor = units
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case(unit))
+        .map(|(_, factor)| *factor)
+        .ok_or_else(error)?;
+    number.checked_mul(factor).ok_or_else(error)
+}
+
+impl FromStr for HumanDuration {
+    type Err = UnitError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let expected = "a duration such as 500ms, 30s, 2m, 1h or 1d";
+        parse_scaled(value, DURATION_UNITS, expected)
+            .map(|ms| HumanDuration(Duration::from_millis(ms)))
+    }
+}
+
+impl From<HumanDuration> for Duration {
+    fn from(duration: HumanDuration) -> Duration {
+        duration.0
+    }
+}
+
+impl From<ByteSize> for usize {
+    fn from(size: ByteSize) -> usize {
+        size.0
+    }
+}
+
+impl FromStr for ByteSize {
+    type Err = UnitError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let expected = "a size such as 512b, 64kb, 16mb or 1gb";
+        let bytes = parse_scaled(value, SIZE_UNITS, expected)?;
+        usize::try_from(bytes).map(ByteSize).map_err(|_| UnitError {
+            value: value.to_string(),
+            expected,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::units::*;
+
+    #[test]
+    fn test_parse_duration() {
+        let ms = |value: &str| value.parse::<HumanDuration>().map(|d| d.0.as_millis());
+        assert_eq!(ms("250"), Ok(250));
+        assert_eq!(ms("500ms"), Ok(500));
+        assert_eq!(ms("30s"), Ok(30_000));
+        assert_eq!(ms("2M"), Ok(120_000));
+        assert_eq!(ms("1h"), Ok(3_600_000));
+        assert_eq!(ms("1d"), Ok(86_400_000));
+        for bad in ["", "ms", "1.5s", "-1s", "5x", "1 s", "99999999999999999999"] {
+            assert!(ms(bad).is_err(), "{} parsed", bad);
+        }
+        assert_eq!(
+            "5x".parse::<HumanDuration>().unwrap_err().to_string(),
+            "'5x' is not a duration such as 500ms, 30s, 2m, 1h or 1d"
+        );
+    }
+
+    #[test]
+    fn test_parse_size() {
+        let bytes = |value: &str| value.parse::<ByteSize>().map(|size| size.0);
+        assert_eq!(bytes("100"), Ok(100));
+        assert_eq!(bytes("512b"), Ok(512));
+        assert_eq!(bytes("64kb"), Ok(65_536));
+        assert_eq!(bytes("16MB"), Ok(16 << 20));
+        assert_eq!(bytes("1gb"), Ok(1 << 30));
+        for bad in ["kb", "1k", "1tb", "1.5mb"] {
+            assert!(bytes(bad).is_err(), "{} parsed", bad);
+        }
+    }
+}
@@ -0,0 +1,159 @@
+use crate::constants::RESP_BUFFER_SIZE;
+use crate::events::ServerEvent;
+use crate::server::{apply_socket_config, wait_unblocked, ServerState, Shutdown};
+use crate::session::Session;
+use std::io::{Error, ErrorKind};
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::os::fd::{AsRawFd, BorrowedFd};
+use std::sync::Arc;
+use tokio::sync::broadcast::error::TryRecvError;
+use tokio_uring::buf::IoBuf;
+use tokio_uring::net::{TcpListener, TcpStream};
+use tracing::{debug, info, warn, Instrument};
+
+/// Serves every configured address from a single io_uring driven thread.
+pub fn start(state: Arc<ServerState>) -> Result<(), Error> {
+    if state.config.proxy_protocol {
+        return Err(Error::new(
+            ErrorKind::Unsupported,
+            "the PROXY protocol is only supported on the tokio backend",
+        ));
+    }
+    tokio_uring::start(async move {
+        let mut listeners = Vec::with_capacity(state.config.bind_addresses.len());
+        for address in state.config.bind_addresses.iter() {
+            listeners.push(TcpListener::bind(resolve(address)?)?);
+        }
+
+        let mut accept_loops = Vec::with_capacity(listeners.len());
+        for listener in listeners {
+            accept_loops.push(tokio_uring::spawn(accept_loop(listener, state.clone())));
+        }
+        let background = tokio_uring::spawn(state.clone().run_background());
+
+        let mode = tokio::select! {
+            mode = state.wait_for_shutdown() => mode,
+            _ = tokio::signal::ctrl_c() => Shutdown::NoSave,
+        };
+        state.announce(ServerEvent::ShuttingDown);
+        for accept_loop in accept_loops {
+            accept_loop.abort();
+        }
+        background.abort();
+        state.finish_shutdown(mode)
+    })
+}
+
+fn resolve(address: &str) -> Result<SocketAddr, Error> {
+    address
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| Error::new(ErrorKind::InvalidInput, address.to_string()))
+}
+
+async fn accept_loop(listener: TcpListener, state: Arc<ServerState>) -> Result<(), Error> {
+    loop {
+        match listener.accept().await {
+            Ok((stream, address)) => {
+                // The stream owns the fd for as long as the borrow is used.
+                let fd = unsafe { BorrowedFd::borrow_raw(stream.as_raw_fd()) };
+                if let Err(e) = apply_socket_config(&fd, &state.config.socket) {
+                    warn!(error = %e, "couldn't configure client socket");
+                    continue;
+                }
+                let client = Session::new(address.to_string());
+                let span = client.span();
+                tokio_uring::spawn(handle_stream(stream, client, state.clone()).instrument(span));
+            }
+            Err(e) => warn!(error = %e, "couldn't get client"),
+        }
+    }
+}
+
+async fn handle_stream(stream: TcpStream, mut client: Session, state: Arc<ServerState>) {
+    info!("client connected");
+    state.telemetry.connected(client.protocol());
+    let mut buff = vec![0u8; RESP_BUFFER_SIZE];
+    // A read can't be given up without losing its buffer, so pushes aren't written the
+    // moment they happen: whatever arrived since the last read goes out ahead of the
+    // next replies.
+    let mut events = state.events.subscribe();
+    loop {
+        // Not zero for the same reasons as in `TcpServer::read_loop`.
+        let budget = client
+            .read_budget(&state.config.connection_limits)
+            .min(RESP_BUFFER_SIZE);
+        let (result, returned_buff) = stream.read(buff.slice(..budget)).await;
+        buff = returned_buff.into_inner();
+        match result {
+            Ok(0) => break,
+            Ok(bytes_read) => {
+                let mut replies = Vec::new();
+                loop {
+                    match events.try_recv() {
+                        Ok(event) => replies.extend(client.event_push(&event).unwrap_or_default()),
+                        Err(TryRecvError::Lagged(missed)) => {
+                            debug!(missed, "dropped server events")
+                        }
+                        Err(_) => break,
+                    }
+                }
+                replies.extend(client.process(&state, &buff[..bytes_read]));
+                let (mut result, _) = stream.write_all(replies).await;
+                if result.is_ok() && client.is_blocked() {
+                    let replies = wait_unblocked(&mut client, &state).await;
+                    (result, _) = stream.write_all(replies).await;
+                }
+                if let Err(e) = result {
+                    warn!(error = %e, "stream failed");
+                    break;
+                }
+                if client.is_closing() {
+                    break;
+                }
+            }
+            Err(e) => {
+                warn!(error = %e, "stream failed");
+                break;
+            }
+        }
+    }
+    state.connection_closed(&client.consumers());
+    state.telemetry.disconnected(client.protocol());
+    info!("stream ended");
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::commands::hello_reply;
+    use crate::config::ServerConfig;
+    use crate::server::ServerState;
+    use crate::test_utils::frame;
+    use crate::uring::accept_loop;
+    use std::io::{Read, Write};
+    use std::sync::Arc;
+    use tokio_uring::net::TcpListener;
+
+    #[test]
+    fn test_uring_replies_to_hello() {
+        tokio_uring::start(async {
+            let listener = TcpListener::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+            let addr = listener.local_addr().unwrap();
+            let state = Arc::new(ServerState::new(ServerConfig::default()));
+            tokio_uring::spawn(accept_loop(listener, state.clone()));
+
+            let expected = hello_reply(&state, 3).encode();
+            let len = expected.len();
+            let reply = tokio::task::spawn_blocking(move || {
+                let mut client = std::net::TcpStream::connect(addr).unwrap();
+                client.write_all(&frame(&["HELLO", "3"])).unwrap();
+                let mut reply = vec![0u8; len];
+                client.read_exact(&mut reply).unwrap();
+                reply
+            })
+            .await
+            .unwrap();
+            assert_eq!(reply, expected);
+        });
+    }
+}
@@ -39,22 +39,18 @@ pub fn remove_empty_data(buff: &[u8]) -> &[u8] {
 }
 
 pub fn read_line(start: usize, buff: &[u8]) -> &[u8] {
-    let eol_idx_result = get_eol_index(start, &buff);
-    let mut read_idx_end: usize =
-        eol_idx_result.unwrap_or_else(|_| get_zero_byte_index(start, &buff));
+    let eol_idx_result = get_eol_index(start, buff);
+    let read_idx_end: usize = eol_idx_result.unwrap_or_else(|_| get_zero_byte_index(start, buff));
     &buff[start..=read_idx_end]
 }
 
 pub fn from_utf8_without_delimiter(buff: &[u8]) -> Result<&str, SerializeError> {
     let buff_end = buff.len() - 1;
-    let buff_read_to: usize;
 
-    buff_read_to = if index_is_at_delimiter(buff_end, buff) {
+    let buff_read_to = if index_is_at_delimiter(buff_end, buff) {
         buff_end - 2
     } else {
         buff_end
     };
-    let res =
-        str::from_utf8(&buff[..=buff_read_to]).map_err(|_| SerializeError::UnsupportedTextEncoding);
-    Ok(res?)
+    str::from_utf8(&buff[..=buff_read_to]).map_err(|_| SerializeError::UnsupportedTextEncoding)
 }
@@ -0,0 +1,89 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex, Weak};
+
+/// A blocked POP's place in its queue's line, given up when dropped, so a connection
+/// that goes away never holds up the consumers behind it.
+#[derive(Debug)]
+pub struct Place(Arc<()>);
+
+/// Blocked POPs per queue in the order they started waiting. Every push wakes all of
+/// them, and only the one that has waited longest may lease, so messages go round the
+/// waiting consumers fairly instead of to whichever is scheduled first. While others
+/// wait behind it, it takes no more than its share; see `Session::resume`.
+#[derive(Debug, Default)]
+pub struct WaitLines {
+    lines: Mutex<HashMap<String, VecDeque<Weak<()>>>>,
+}
+
+impl WaitLines {
+    /// Queues up behind the POPs already waiting on `queue`.
+    pub fn join(&self, queue: &str) -> Place {
+        let place = Arc::new(());
+        let mut lines = self.lines.lock().unwrap();
+        let line = lines.entry(queue.to_string()).or_default();
+        line.retain(|waiting| waiting.strong_count() > 0);
+        line.push_back(Arc::downgrade(&place));
+        Place(place)
+    }
+
+    /// Whether `place` is at the front of `queue`'s line.
+    pub fn is_first(&self, queue: &str, place: &Place) -> bool {
+        let mut lines = self.lines.lock().unwrap();
+        let Some(line) = lines.get_mut(queue) else {
+            return false;
+        };
+        while line.front().is_some_and(|first| first.strong_count() == 0) {
+            line.pop_front();
+        }
+        let first = line
+            .front()
+            .is_some_and(|first| Weak::ptr_eq(first, &Arc::downgrade(&place.0)));
+        if line.is_empty() {
+            lines.remove(queue);
+        }
+        first
+    }
+
+    /// How many POPs are waiting on `queue`.
+    pub fn waiters(&self, queue: &str) -> usize {
+        let lines = self.lines.lock().unwrap();
+        lines.get(queue).map_or(0, |line| {
+            line.iter()
+                .filter(|waiting| waiting.strong_count() > 0)
+                .count()
+        })
+    }
+
+    /// Whether any POP is still waiting on `queue`, which a new one has to queue behind.
+    pub fn has_waiters(&self, queue: &str) -> bool {
+        let lines = self.lines.lock().unwrap();
+        lines
+            .get(queue)
+            .is_some_and(|line| line.iter().any(|waiting| waiting.strong_count() > 0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::wait_line::*;
+
+    #[test]
+    fn test_wait_lines() {
+        let lines = WaitLines::default();
+        assert!(!lines.has_waiters("jobs"));
+        let first = lines.join("jobs");
+        let second = lines.join("jobs");
+        let other = lines.join("emails");
+        assert!(lines.is_first("jobs", &first));
+        assert!(!lines.is_first("jobs", &second));
+        assert!(lines.is_first("emails", &other));
+        assert_eq!(lines.waiters("jobs"), 2);
+
+        drop(first);
+        assert_eq!(lines.waiters("jobs"), 1);
+        assert!(lines.is_first("jobs", &second));
+        drop(second);
+        assert!(!lines.has_waiters("jobs"));
+        assert!(lines.has_waiters("emails"));
+    }
+}
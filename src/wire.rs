@@ -0,0 +1,624 @@
+use crate::resp_value::RespValue;
+use serde::Serialize;
+
+/// Type of a single command argument as it appears on the wire.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ArgKind {
+    String,
+    Integer,
+    Queue,
+    MessageId,
+    Keyword,
+    /// Milliseconds, or a whole number with a unit: `500ms`, `30s`, `2m`, `1h`, `1d`.
+    Duration,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ReplyKind {
+    SimpleString,
+    BulkString,
+    Map,
+    Array,
+    Integer,
+}
+
+#[derive(Serialize, Debug)]
+pub struct ArgSpec {
+    pub name: &'static str,
+    pub kind: ArgKind,
+    pub optional: bool,
+    /// Takes up every remaining word.
+    pub multiple: bool,
+}
+
+#[derive(Serialize, Debug)]
+pub struct CommandSpec {
+    pub name: &'static str,
+    pub summary: &'static str,
+    pub args: &'static [ArgSpec],
+    pub reply: ReplyKind,
+    /// Redis-style command flags reported by `COMMAND`, e.g. `write` or `admin`.
+    pub flags: &'static [&'static str],
+}
+
+#[derive(Serialize, Debug)]
+pub struct ErrorSpec {
+    pub code: &'static str,
+    pub description: &'static str,
+}
+
+#[derive(Serialize, Debug)]
+pub struct WireSchema {
+    pub version: &'static str,
+    pub commands: &'static [CommandSpec],
+    pub errors: &'static [ErrorSpec],
+}
+
+const fn arg(name: &'static str, kind: ArgKind) -> ArgSpec {
+    ArgSpec {
+        name,
+        kind,
+        optional: false,
+        multiple: false,
+    }
+}
+
+const fn optional_arg(name: &'static str, kind: ArgKind) -> ArgSpec {
+    ArgSpec {
+        name,
+        kind,
+        optional: true,
+        multiple: false,
+    }
+}
+
+/// Any number of trailing words, none included.
+const fn variadic_arg(name: &'static str, kind: ArgKind) -> ArgSpec {
+    ArgSpec {
+        name,
+        kind,
+        optional: true,
+        multiple: true,
+    }
+}
+
+impl ArgKind {
+    /// Argument type as `COMMAND DOCS` names it.
+    fn docs_type(&self) -> &'static str {
+        match self {
+            ArgKind::String | ArgKind::MessageId | ArgKind::Duration => "string",
+            ArgKind::Integer => "integer",
+            ArgKind::Queue => "key",
+            ArgKind::Keyword => "pure-token",
+        }
+    }
+}
+
+impl CommandSpec {
+    /// Redis arity: the number of words including the command name, negated when
+    /// more may follow.
+    pub fn arity(&self) -> i64 {
+        let required = self.args.iter().filter(|arg| !arg.optional).count() as i64 + 1;
+        if self.args.iter().any(|arg| arg.optional) {
+            -required
+        } else {
+            required
+        }
+    }
+
+    /// Fewest words a call has, including the command name.
+    pub fn min_words(&self) -> usize {
+        self.args.iter().filter(|arg| !arg.optional).count() + 1
+    }
+
+    /// Most words a call has, or `None` when the last argument repeats.
+    pub fn max_words(&self) -> Option<usize> {
+        if self.args.iter().any(|arg| arg.multiple) {
+            return None;
+        }
+        Some(self.args.len() + 1)
+    }
+
+    /// Position of the queue name among the words, or 0 when there is none.
+    fn key_position(&self) -> i64 {
+        self.args
+            .iter()
+            .position(|arg| arg.kind == ArgKind::Queue)
+            .map_or(0, |i| i as i64 + 1)
+    }
+
+    /// Position of the last queue name, counted from the end as -1 when any number of
+    /// them may follow.
+    fn last_key_position(&self) -> i64 {
+        let variadic = self
+            .args
+            .iter()
+            .any(|arg| arg.kind == ArgKind::Queue && arg.multiple);
+        if variadic {
+            -1
+        } else {
+            self.key_position()
+        }
+    }
+
+    /// One `COMMAND` / `COMMAND INFO` entry:
+    /// `[name, arity, flags, first key, last key, step]`.
+    pub fn info(&self) -> RespValue {
+        let key = self.key_position();
+        RespValue::array()
+            .item(RespValue::bulk(self.name.to_lowercase()))
+            .item(self.arity())
+            .item(RespValue::Set(
+                self.flags
+                    .iter()
+                    .map(|flag| RespValue::simple(flag))
+                    .collect(),
+            ))
+            .item(key)
+            .item(self.last_key_position())
+            .item(i64::from(key > 0))
+            .build()
+    }
+
+    /// The value of one `COMMAND DOCS` entry.
+    pub fn docs(&self) -> RespValue {
+        let args = self.args.iter().map(|arg| {
+            let mut doc = RespValue::map()
+                .field("name", RespValue::bulk(arg.name))
+                .field("type", RespValue::bulk(arg.kind.docs_type()));
+            let flags: Vec<RespValue> = [(arg.optional, "optional"), (arg.multiple, "multiple")]
+                .into_iter()
+                .filter(|(set, _)| *set)
+                .map(|(_, flag)| RespValue::simple(flag))
+                .collect();
+            if !flags.is_empty() {
+                doc = doc.field("flags", RespValue::Array(flags));
+            }
+            doc.build()
+        });
+        RespValue::map()
+            .field("summary", RespValue::bulk(self.summary))
+            .field("since", RespValue::bulk(env!("CARGO_PKG_VERSION")))
+            .field("arguments", RespValue::array().items(args))
+            .build()
+    }
+}
+
+/// Every command the dispatcher recognises. Keep in step with `resp::CommandSet`.
+pub const COMMANDS: &[CommandSpec] = &[
+    CommandSpec {
+        name: "HELLO",
+        summary: "Negotiates the protocol, authenticates and names the connection",
+        args: &[
+            arg("protover", ArgKind::Integer),
+            optional_arg("AUTH", ArgKind::Keyword),
+            optional_arg("username", ArgKind::String),
+            optional_arg("password", ArgKind::String),
+            optional_arg("SETNAME", ArgKind::Keyword),
+            optional_arg("clientname", ArgKind::String),
+            optional_arg("COMPRESS", ArgKind::Keyword),
+            optional_arg("algorithm", ArgKind::String),
+        ],
+        reply: ReplyKind::Map,
+        flags: &["fast", "no_auth"],
+    },
+    CommandSpec {
+        name: "PUSH",
+        summary: "Adds a message to a queue and returns its id",
+        args: &[
+            arg("queue", ArgKind::Queue),
+            arg("body", ArgKind::String),
+            optional_arg("CHECKSUM", ArgKind::Keyword),
+            optional_arg("crc32", ArgKind::Integer),
+            optional_arg("PRIORITY", ArgKind::Keyword),
+            optional_arg("priority", ArgKind::Integer),
+            optional_arg("TTL", ArgKind::Keyword),
+            optional_arg("ttl", ArgKind::Duration),
+            optional_arg("DEDUP", ArgKind::Keyword),
+            optional_arg("key", ArgKind::String),
+            optional_arg("GROUP", ArgKind::Keyword),
+            optional_arg("group", ArgKind::String),
+            optional_arg("ATTR", ArgKind::Keyword),
+            variadic_arg("attribute", ArgKind::String),
+        ],
+        reply: ReplyKind::BulkString,
+        flags: &["write", "fast"],
+    },
+    CommandSpec {
+        name: "MPUSH",
+        summary: "Adds several messages to a queue, or none if it can't take them all, and returns their ids",
+        args: &[
+            arg("queue", ArgKind::Queue),
+            optional_arg("PRIORITY", ArgKind::Keyword),
+            optional_arg("priority", ArgKind::Integer),
+            optional_arg("TTL", ArgKind::Keyword),
+            optional_arg("ttl", ArgKind::Duration),
+            optional_arg("GROUP", ArgKind::Keyword),
+            optional_arg("group", ArgKind::String),
+            optional_arg("ATTR", ArgKind::Keyword),
+            optional_arg("attribute", ArgKind::String),
+            optional_arg("BODIES", ArgKind::Keyword),
+            arg("body", ArgKind::String),
+            variadic_arg("body", ArgKind::String),
+        ],
+        reply: ReplyKind::Array,
+        flags: &["write"],
+    },
+    CommandSpec {
+        name: "FANOUT",
+        summary: "Adds a message to every named queue whose rule matches it, or to none if any is full, and returns the ids",
+        args: &[
+            arg("body", ArgKind::String),
+            arg("queue", ArgKind::Queue),
+            variadic_arg("queue|IF rule|ATTR name value", ArgKind::String),
+        ],
+        reply: ReplyKind::Array,
+        flags: &["write"],
+    },
+    CommandSpec {
+        name: "PEEK",
+        summary: "Returns the next messages of a queue without leasing them",
+        args: &[
+            arg("queue", ArgKind::Queue),
+            optional_arg("count", ArgKind::Integer),
+        ],
+        reply: ReplyKind::Array,
+        flags: &["readonly"],
+    },
+    CommandSpec {
+        name: "POP",
+        summary: "Leases messages from a queue, optionally waiting for one",
+        args: &[
+            arg("queue", ArgKind::Queue),
+            optional_arg("count", ArgKind::Integer),
+            optional_arg("ARRAY|NULL|BLOCK", ArgKind::Keyword),
+            optional_arg("timeout", ArgKind::Duration),
+            optional_arg("VISIBILITY", ArgKind::Keyword),
+            optional_arg("visibility", ArgKind::Duration),
+        ],
+        reply: ReplyKind::Array,
+        flags: &["write", "blocking"],
+    },
+    CommandSpec {
+        name: "ACK",
+        summary: "Acknowledges leased messages so they are not redelivered, and returns how many were",
+        args: &[
+            arg("queue", ArgKind::Queue),
+            arg("receipt", ArgKind::MessageId),
+            variadic_arg("receipt", ArgKind::MessageId),
+            optional_arg("CHECKSUM", ArgKind::Keyword),
+            optional_arg("crc32", ArgKind::Integer),
+        ],
+        reply: ReplyKind::Integer,
+        flags: &["write", "fast"],
+    },
+    CommandSpec {
+        name: "NACK",
+        summary:
+            "Hands a leased message back for redelivery without waiting for its lease to run out",
+        args: &[
+            arg("queue", ArgKind::Queue),
+            arg("receipt", ArgKind::MessageId),
+            optional_arg("DELAY", ArgKind::Keyword),
+            optional_arg("delay", ArgKind::Duration),
+        ],
+        reply: ReplyKind::Integer,
+        flags: &["write", "fast"],
+    },
+    CommandSpec {
+        name: "TOUCH",
+        summary: "Extends the lease on a message the consumer is still working on",
+        args: &[
+            arg("queue", ArgKind::Queue),
+            arg("receipt", ArgKind::MessageId),
+            optional_arg("visibility", ArgKind::Duration),
+        ],
+        reply: ReplyKind::Integer,
+        flags: &["write", "fast"],
+    },
+    CommandSpec {
+        name: "PROGRESS",
+        summary: "Records how far the consumer has got with a leased message, as a percentage or a status",
+        args: &[
+            arg("queue", ArgKind::Queue),
+            arg("receipt", ArgKind::MessageId),
+            arg("progress", ArgKind::String),
+        ],
+        reply: ReplyKind::Integer,
+        flags: &["write", "fast"],
+    },
+    CommandSpec {
+        name: "CANCEL",
+        summary: "Stops a leased message from being redelivered and tells its consumer to abandon it",
+        args: &[
+            arg("queue", ArgKind::Queue),
+            arg("id", ArgKind::MessageId),
+        ],
+        reply: ReplyKind::Integer,
+        flags: &["write", "fast"],
+    },
+    CommandSpec {
+        name: "USE",
+        summary: "Sets the queue used by PUSH, POP, ACK, NACK and TOUCH sent with an empty queue",
+        args: &[arg("queue", ArgKind::Queue)],
+        reply: ReplyKind::SimpleString,
+        flags: &["fast"],
+    },
+    CommandSpec {
+        name: "PREFETCH",
+        summary: "Limits how many unacked messages each consumer of the connection may hold",
+        args: &[arg("count", ArgKind::Integer)],
+        reply: ReplyKind::SimpleString,
+        flags: &["fast"],
+    },
+    CommandSpec {
+        name: "QUEUE",
+        summary: "Creates, lists, deletes, digests, pauses, configures and reports on queues and their leases; PURGE, CLONE, EXPORT and REDRIVE of dead letters run as jobs",
+        args: &[
+            arg(
+                "CREATE|DELETE|LIST|STATS|INFLIGHT|DIGEST|PURGE|CLONE|EXPORT|REDRIVE|PAUSE|RESUME|CONFIG",
+                ArgKind::Keyword,
+            ),
+            optional_arg("queue", ArgKind::Queue),
+            optional_arg(
+                "destination|path|count|FIFO|LIFO|PRIORITY|EPHEMERAL|EXCLUSIVE|FORCE|GET|SET",
+                ArgKind::String,
+            ),
+            variadic_arg(
+                "OVERFLOW n|CONCURRENCY n|MAXDEPTH n|ONFULL policy|DEDUP window|SHARDS n|MAXSIZE size|RATE n|TO target|BOOST|BACKLOG|INTERLEAVE every|param value",
+                ArgKind::String,
+            ),
+        ],
+        reply: ReplyKind::Array,
+        flags: &["write"],
+    },
+    CommandSpec {
+        name: "CHANNEL",
+        summary: "Runs a queue command on a virtual channel of the connection",
+        args: &[
+            arg("channel", ArgKind::Integer),
+            arg("command", ArgKind::Keyword),
+            variadic_arg("args", ArgKind::String),
+        ],
+        reply: ReplyKind::Array,
+        flags: &[],
+    },
+    CommandSpec {
+        name: "SERVER",
+        summary: "Controls and inspects the broker",
+        args: &[
+            arg(
+                "DRAIN|RESUME|TELEMETRY|METRICS|RESETSTATS|SNAPSHOT|SNAPSHOTREAD",
+                ArgKind::Keyword,
+            ),
+            optional_arg("id", ArgKind::String),
+            optional_arg("offset", ArgKind::Integer),
+            optional_arg("count", ArgKind::Integer),
+        ],
+        reply: ReplyKind::SimpleString,
+        flags: &["admin"],
+    },
+    CommandSpec {
+        name: "DEBUG",
+        summary: "Diagnostics such as CPU profiling and sampled command tracing",
+        args: &[
+            arg("PROFILE|TRACE", ArgKind::Keyword),
+            arg("seconds|COMMAND|QUEUE|LIST", ArgKind::String),
+            optional_arg("path|name", ArgKind::String),
+            optional_arg("every", ArgKind::Integer),
+        ],
+        reply: ReplyKind::BulkString,
+        flags: &["admin"],
+    },
+    CommandSpec {
+        name: "COMMAND",
+        summary: "Lists the commands the broker supports, with their arity, flags and docs",
+        args: &[
+            optional_arg("COUNT|INFO|DOCS", ArgKind::Keyword),
+            variadic_arg("command", ArgKind::String),
+        ],
+        reply: ReplyKind::Array,
+        flags: &["readonly"],
+    },
+    CommandSpec {
+        name: "JOB",
+        summary: "Polls and cancels the background jobs started by QUEUE",
+        args: &[
+            arg("STATUS|CANCEL|LIST", ArgKind::Keyword),
+            optional_arg("id", ArgKind::Integer),
+        ],
+        reply: ReplyKind::Map,
+        flags: &["admin"],
+    },
+    CommandSpec {
+        name: "SCHEDULE",
+        summary: "Pushes a message to a queue every time a cron expression comes round",
+        args: &[
+            arg("ADD|DEL|LIST", ArgKind::Keyword),
+            optional_arg("name", ArgKind::String),
+            optional_arg("queue", ArgKind::Queue),
+            optional_arg("cron", ArgKind::String),
+            optional_arg("body", ArgKind::String),
+        ],
+        reply: ReplyKind::Integer,
+        flags: &["write"],
+    },
+    CommandSpec {
+        name: "INFO",
+        summary: "Reports per-command statistics in Redis' INFO format",
+        args: &[optional_arg("commandstats", ArgKind::Keyword)],
+        reply: ReplyKind::BulkString,
+        flags: &["admin"],
+    },
+    CommandSpec {
+        name: "SHUTDOWN",
+        summary: "Stops the broker, optionally writing a snapshot first",
+        args: &[optional_arg("SAVE|NOSAVE", ArgKind::Keyword)],
+        reply: ReplyKind::SimpleString,
+        flags: &["admin"],
+    },
+];
+
+/// Error prefixes a client may receive. Keep in step with `RespError::code`.
+pub const ERRORS: &[ErrorSpec] = &[
+    ErrorSpec {
+        code: "ERR",
+        description: "Generic error; the message explains what went wrong",
+    },
+    ErrorSpec {
+        code: "WRONGPASS",
+        description: "HELLO AUTH credentials were rejected",
+    },
+    ErrorSpec {
+        code: "NOAUTH",
+        description: "The connection must authenticate with HELLO AUTH first",
+    },
+    ErrorSpec {
+        code: "QUEUEFULL",
+        description: "PUSH was rejected because the queue reached its capacity",
+    },
+    ErrorSpec {
+        code: "TOOLARGE",
+        description: "A pushed body is larger than the queue's MAXSIZE",
+    },
+    ErrorSpec {
+        code: "PREFETCH",
+        description: "The consumer already holds as many unacked messages as PREFETCH allows",
+    },
+    ErrorSpec {
+        code: "EXCLUSIVE",
+        description: "Another consumer holds the EXCLUSIVE queue until its connection closes",
+    },
+    ErrorSpec {
+        code: "BADCHECKSUM",
+        description: "A body or acknowledgement did not match the message's CRC32",
+    },
+    ErrorSpec {
+        code: "NOPERM",
+        description: "The authenticated user's ACL does not allow this command or queue",
+    },
+    ErrorSpec {
+        code: "BUSY",
+        description: "The server is overloaded and shed this low priority command",
+    },
+    ErrorSpec {
+        code: "TIMEOUT",
+        description: "The command used up the server's per-command time budget and was cancelled",
+    },
+    ErrorSpec {
+        code: "NOPROTO",
+        description: "The requested protocol version is not supported",
+    },
+    ErrorSpec {
+        code: "NOQUEUE",
+        description: "The named queue does not exist",
+    },
+    ErrorSpec {
+        code: "BUSYQUEUE",
+        description:
+            "A queue with that name already exists, or one being deleted still has leases out",
+    },
+    ErrorSpec {
+        code: "NOJOB",
+        description: "No job with that id is running or remembered",
+    },
+];
+
+pub const SCHEMA: WireSchema = WireSchema {
+    version: env!("CARGO_PKG_VERSION"),
+    commands: COMMANDS,
+    errors: ERRORS,
+};
+
+pub fn schema_json() -> String {
+    serde_json::to_string_pretty(&SCHEMA).expect("wire schema is always serializable")
+}
+
+pub fn find_command(name: &str) -> Option<&'static CommandSpec> {
+    COMMANDS.iter().find(|spec| spec.name == name)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error_code::ErrorCode;
+    use crate::resp::{CommandSet, RespError};
+    use crate::wire::*;
+    use std::str::FromStr;
+    use strum::IntoEnumIterator;
+
+    #[test]
+    fn test_every_command_has_a_spec() {
+        for cmd in CommandSet::iter() {
+            let name = format!("{:?}", cmd);
+            assert!(find_command(&name).is_some(), "{} missing from wire", name);
+        }
+    }
+
+    #[test]
+    fn test_every_spec_is_dispatched() {
+        for spec in COMMANDS {
+            let command = CommandSet::from_str(spec.name).unwrap();
+            assert_eq!(command.spec().name, spec.name);
+        }
+    }
+
+    #[test]
+    fn test_arity() {
+        let arity = |name| find_command(name).unwrap().arity();
+        assert_eq!(arity("PUSH"), -3);
+        assert_eq!(arity("SERVER"), -2);
+        assert_eq!(arity("COMMAND"), -1);
+    }
+
+    #[test]
+    fn test_word_bounds() {
+        let bounds = |name| {
+            let spec = find_command(name).unwrap();
+            (spec.min_words(), spec.max_words())
+        };
+        assert_eq!(bounds("PUSH"), (3, None));
+        assert_eq!(bounds("SERVER"), (2, Some(5)));
+        assert_eq!(bounds("SHUTDOWN"), (1, Some(2)));
+        assert_eq!(bounds("COMMAND"), (1, None));
+        assert_eq!(bounds("CHANNEL"), (3, None));
+    }
+
+    #[test]
+    fn test_every_error_code_is_exported() {
+        let errors = [
+            RespError::InvalidPassword("a".to_string()),
+            RespError::ProtocolOutOfRange("9".to_string()),
+            RespError::NoData,
+            RespError::AuthRequired,
+            RespError::QueueFull("jobs".to_string()),
+            RespError::MessageTooLarge("jobs".to_string(), 1024),
+            RespError::PrefetchReached(10),
+            RespError::ExclusiveQueue("jobs".to_string()),
+            RespError::ChecksumMismatch("id".to_string()),
+            RespError::Busy,
+            RespError::NoPermission("SHUTDOWN".to_string()),
+            RespError::Timeout("SERVER".to_string()),
+        ];
+        for err in errors {
+            assert!(ERRORS.iter().any(|spec| spec.code == err.code().as_str()));
+        }
+        for code in ErrorCode::iter() {
+            assert!(
+                ERRORS.iter().any(|spec| spec.code == code.as_str()),
+                "{} missing",
+                code
+            );
+        }
+        assert_eq!(ERRORS.len(), ErrorCode::iter().count());
+    }
+
+    #[test]
+    fn test_schema_json() {
+        let json: serde_json::Value = serde_json::from_str(&schema_json()).unwrap();
+        assert_eq!(json["commands"][0]["name"], "HELLO");
+        assert_eq!(json["commands"][0]["args"][0]["kind"], "integer");
+        assert_eq!(json["errors"][1]["code"], "WRONGPASS");
+    }
+}